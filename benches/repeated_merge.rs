@@ -0,0 +1,46 @@
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use solderium::{LinkStyle, merge, MergeOptions, Strategy};
+
+const DIRECTORIES: usize = 40;
+const FILES: usize = 50;
+
+/// Periodic enforcement run, every target entry already is the symlink the merge would create
+fn repeated_merge(criterion: &mut Criterion) {
+
+    let root = std::env::temp_dir().join(format!("solderium-bench-{}", std::process::id()));
+    let source = root.join("source");
+
+    for directory in 0..DIRECTORIES {
+        let path = source.join(format!("directory{directory}"));
+        create_dir_all(&path).unwrap();
+        for file in 0..FILES {
+            write(path.join(format!("file{file}.txt")), "lorem ipsum").unwrap();
+        }
+    }
+
+    let mut group = criterion.benchmark_group("repeated_merge");
+    group.throughput(Throughput::Elements((DIRECTORIES * FILES) as u64));
+
+    for link_style in [LinkStyle::Absolute, LinkStyle::Relative] {
+
+        let target = root.join(format!("{link_style:?}").to_lowercase());
+        let options = MergeOptions { strategy: Strategy::Deep, link_style, ..Default::default() };
+
+        create_dir_all(&target).unwrap();
+        merge(&source, &target, &options).unwrap();
+
+        group.bench_function(format!("{link_style:?}").to_lowercase(), |bencher| {
+            bencher.iter(|| merge(Path::new(&source), Path::new(&target), &options).unwrap())
+        });
+
+    }
+
+    group.finish();
+    remove_dir_all(&root).unwrap();
+
+}
+
+criterion_group!(benches, repeated_merge);
+criterion_main!(benches);
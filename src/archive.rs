@@ -0,0 +1,216 @@
+//! Archives (tarballs and zip files) used as merge sources
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(feature = "manifest")]
+use std::fs::canonicalize;
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use tar::Archive;
+use zip::ZipArchive;
+use crate::{ContentStore, SourceProvider};
+#[cfg(feature = "manifest")]
+use crate::{MergeOptions, MergeReport};
+#[cfg(feature = "manifest")]
+use crate::manifest::{merge_with_manifest, Manifest, release_tree, retain_tree};
+
+/// Supported archive formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip
+}
+
+impl Format {
+
+    /// Detect the format from the file name extension (`.tar`, `.tar.gz`, `.tgz` or `.zip`)
+    pub fn detect(path: &Path) -> Result<Self> {
+
+        let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Format::TarGz)
+        } else if name.ends_with(".tar") {
+            Ok(Format::Tar)
+        } else if name.ends_with(".zip") {
+            Ok(Format::Zip)
+        } else {
+            bail!("Unknown archive format ({path:?}), expected .tar, .tar.gz, .tgz or .zip")
+        }
+
+    }
+
+}
+
+/// Archive extracted into a [ContentStore] and merged from there.
+///
+/// The archive is extracted once per instance, straight from the file without unpacking it
+/// anywhere else first. Archives with identical content share the extracted tree, remove it with
+/// [ContentStore::remove] once no target uses it, or let the manifest of the target track it (feature `manifest`).
+pub struct ArchiveSource {
+    archive: PathBuf,
+    format: Format,
+    store: ContentStore,
+    extracted: Mutex<Option<PathBuf>>
+}
+
+impl ArchiveSource {
+
+    /// Source extracted from the `archive` into the `store`, format is detected from the file name
+    pub fn new(archive: impl Into<PathBuf>, store: ContentStore) -> Result<Self> {
+        let archive = archive.into();
+        let format = Format::detect(&archive)?;
+        Ok(Self { archive, format, store, extracted: Mutex::new(None) })
+    }
+
+    /// Path of the extracted tree, if the archive was extracted already
+    pub fn extracted(&self) -> Option<PathBuf> {
+        self.extracted.lock().unwrap().clone()
+    }
+
+    /// Merge the extracted archive like [merge_with_manifest], recording the extracted tree in the manifest.
+    ///
+    /// [Manifest::undo] of the target removes the tree once no other target holds it, a tree of the previously
+    /// merged archive is released right away.
+    #[cfg(feature = "manifest")]
+    pub fn merge_with_manifest(&self, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+
+        let tree = self.fetch()?;
+        let report = merge_with_manifest(&tree, target, options)?;
+
+        let holder = canonicalize(target).with_context(|| format!("Couldn't resolve target ({target:?})"))?;
+        let mut manifest = Manifest::read(target)?;
+        retain_tree(&tree, &holder)?;
+
+        if let Some(previous) = manifest.extracted.replace(tree).filter(|previous| Some(previous) != manifest.extracted.as_ref()) {
+            release_tree(&previous, &holder)?;
+        }
+
+        manifest.write(target)?;
+        Ok(report)
+
+    }
+
+    fn extract(&self, directory: &Path) -> Result<()> {
+
+        let file = File::open(&self.archive).with_context(|| format!("Couldn't open archive ({:?})", self.archive))?;
+
+        let result = match self.format {
+            Format::Tar => Archive::new(file).unpack(directory),
+            Format::TarGz => Archive::new(GzDecoder::new(file)).unpack(directory),
+            Format::Zip => ZipArchive::new(file).and_then(|mut archive| archive.extract(directory)).map_err(Into::into)
+        };
+
+        result.with_context(|| format!("Couldn't extract archive ({:?})", self.archive))
+
+    }
+
+}
+
+impl SourceProvider for ArchiveSource {
+
+    fn fetch(&self) -> Result<PathBuf> {
+
+        let mut extracted = self.extracted.lock().unwrap();
+
+        if let Some(path) = extracted.as_ref().filter(|path| path.is_dir()) {
+            return Ok(path.clone());
+        }
+
+        let path = self.store.insert_with(|staging| self.extract(staging))?;
+        *extracted = Some(path.clone());
+
+        Ok(path)
+
+    }
+
+    /// Forget the extracted tree, so the archive is extracted again (e.g. after it was replaced)
+    fn invalidate(&self) -> Result<()> {
+        *self.extracted.lock().unwrap() = None;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[cfg(feature = "manifest")]
+    use std::fs::create_dir;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+    use crate::{ContentStore, merge_from, MergeOptions, SourceProvider};
+    use crate::archive::{ArchiveSource, Format};
+    #[cfg(feature = "manifest")]
+    use crate::manifest::Manifest;
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn detect_formats() {
+        assert_eq!(Format::detect(Path::new("app-v3.tar.gz")).unwrap(), Format::TarGz);
+        assert_eq!(Format::detect(Path::new("app-v3.ZIP")).unwrap(), Format::Zip);
+        assert!(Format::detect(Path::new("app-v3.rar")).is_err());
+    }
+
+    #[test]
+    fn merge_from_archives() {
+
+        let _lock = prepare_test_directory();
+        let store = ContentStore::new("test_files/store");
+
+        let mut tarball = tar::Builder::new(GzEncoder::new(File::create("test_files/app.tar.gz").unwrap(), Compression::default()));
+        tarball.append_dir_all(".", "test_files/test_dir1").unwrap();
+        tarball.into_inner().unwrap().finish().unwrap();
+
+        let source = ArchiveSource::new("test_files/app.tar.gz", store.clone()).unwrap();
+
+        merge_from(&source, Path::new("test_files/test_dir2"), &MergeOptions::default()).unwrap();
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(source.extracted().unwrap().join("nested/dolor.cpp").is_file());
+
+        let mut zip = ZipWriter::new(File::create("test_files/app.zip").unwrap());
+        zip.start_file("lorem.txt", SimpleFileOptions::default()).unwrap();
+        zip.write_all(b"zipped").unwrap();
+        zip.finish().unwrap();
+
+        let source = ArchiveSource::new("test_files/app.zip", store).unwrap();
+            assert_eq!(std::fs::read_to_string(source.fetch().unwrap().join("lorem.txt")).unwrap(), "zipped");
+
+    }
+
+    #[cfg(feature = "manifest")]
+    #[test]
+    fn release_extracted_trees_on_undo() {
+
+        let _lock = prepare_test_directory();
+        let (first, second) = (Path::new("test_files/test_dir2"), Path::new("test_files/test_dir3"));
+        create_dir(second).unwrap();
+
+        let mut tarball = tar::Builder::new(File::create("test_files/app.tar").unwrap());
+        tarball.append_dir_all(".", "test_files/test_dir1").unwrap();
+        tarball.finish().unwrap();
+
+        let source = ArchiveSource::new("test_files/app.tar", ContentStore::new("test_files/store")).unwrap();
+        source.merge_with_manifest(first, &MergeOptions::default()).unwrap();
+        source.merge_with_manifest(second, &MergeOptions::default()).unwrap();
+
+        let tree = source.extracted().unwrap();
+            assert_eq!(Manifest::read(first).unwrap().extracted.as_ref(), Some(&tree));
+
+        // Second target still holds the tree
+        Manifest::read(first).unwrap().undo(first).unwrap();
+            assert!(tree.is_dir());
+
+        Manifest::read(second).unwrap().undo(second).unwrap();
+            assert!(!tree.exists());
+
+    }
+
+}
@@ -0,0 +1,21 @@
+//! Merges for async services, driven by `tokio` so they don't block the runtime
+
+use std::path::Path;
+use anyhow::Result;
+use crate::{MergeOptions, MergeReport, Overwrite};
+use crate::merge::Walk;
+
+/// Asynchronous variant of [generate_symlinks](crate::generate_symlinks), returning the same report.
+///
+/// Directories are listed, replaced paths removed and symlinks created through `tokio::fs`, the task yields
+/// after every entry. Checks of a single entry (keep markers, identity of the existing target) are the same
+/// metadata reads the blocking merge makes, done in place.
+pub async fn generate_symlinks_async(source: &Path, target: &Path, overwrite: Overwrite) -> Result<MergeReport> {
+
+    let options = MergeOptions { overwrite, record_skipped: true, ..Default::default() };
+    let walk = Walk::new(source, target, &options)?;
+    let ops = walk.plan_async().await?;
+
+    walk.execute_async(ops).await
+
+}
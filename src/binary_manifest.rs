@@ -0,0 +1,327 @@
+//! Compact binary list of managed links, memory mapped for fast lookups in huge link sets
+//!
+//! Layout (all integers little endian):
+//!
+//! - magic `SLDM` and format version (`u32`)
+//! - number of entries (`u64`)
+//! - index of record offsets (`u64` per entry), records are sorted by target path
+//! - records, each being a length prefixed (`u32`) target path followed by a length prefixed source path
+//!   and a length prefixed identity of the host which created the link (missing in version 1)
+//!
+//! Hosts sharing a target (e.g. over NFS) keep their links apart by [BinaryManifest::update], which replaces
+//! only the entries of the given host, and work only with their own entries by [BinaryManifest::iter_host].
+
+use std::collections::HashSet;
+use std::fs::{File, read_link, read_to_string, rename};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use memmap2::Mmap;
+use crate::platform::{os_str, remove_link};
+use crate::temp_path;
+
+const MAGIC: &[u8; 4] = b"SLDM";
+const VERSION: u32 = 2;
+/// Magic, version and entry count
+const HEADER: usize = 16;
+
+/// Read-only binary manifest mapped into memory, see [module documentation](self) for the format.
+///
+/// Nothing is parsed when opening, entries are decoded on access and looked up by binary search.
+pub struct BinaryManifest {
+    map: Mmap,
+    len: usize,
+    version: u32
+}
+
+/// Single managed link of a [BinaryManifest].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ManifestEntry {
+    pub target: PathBuf,
+    pub source: PathBuf,
+    /// Identity of the host which created the link, see [host_id]
+    pub host: String
+}
+
+impl BinaryManifest {
+
+    /// Write manifest of `(target, source)` link pairs created by this host to `path`, replacing it atomically
+    pub fn write(path: &Path, entries: impl IntoIterator<Item = (PathBuf, PathBuf)>) -> Result<()> {
+        let host = host_id();
+        Self::write_entries(path, entries.into_iter().map(|(target, source)| ManifestEntry { target, source, host: host.clone() }))
+    }
+
+    /// Replace entries of the `host` in the manifest at `path` (created if missing) by given link pairs,
+    /// entries of other hosts are kept unless they are at the same target paths
+    pub fn update(path: &Path, host: &str, entries: impl IntoIterator<Item = (PathBuf, PathBuf)>) -> Result<()> {
+
+        let mut updated: Vec<_> = entries.into_iter().map(|(target, source)| ManifestEntry { target, source, host: host.to_string() }).collect();
+
+        if path.exists() {
+            let current = Self::open(path)?;
+            let targets: HashSet<_> = updated.iter().map(|entry| entry.target.clone()).collect();
+            updated.extend(current.entries().filter(|entry| entry.host != host && !targets.contains(&entry.target)));
+        }
+
+        Self::write_entries(path, updated)
+
+    }
+
+    /// Write manifest of given entries to `path`, replacing it atomically
+    pub fn write_entries(path: &Path, entries: impl IntoIterator<Item = ManifestEntry>) -> Result<()> {
+
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort();
+        entries.dedup_by(|a, b| a.target == b.target);
+
+        let temporary = temp_path(path);
+        let mut writer = BufWriter::new(File::create(&temporary).with_context(|| format!("Couldn't create manifest ({temporary:?})"))?);
+        let mut offset = (HEADER + 8 * entries.len()) as u64;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+
+        for entry in &entries {
+            writer.write_all(&offset.to_le_bytes())?;
+            offset += (12 + entry.target.as_os_str().as_encoded_bytes().len() + entry.source.as_os_str().as_encoded_bytes().len() + entry.host.len()) as u64;
+        }
+
+        for entry in &entries {
+            for bytes in [entry.target.as_os_str().as_encoded_bytes(), entry.source.as_os_str().as_encoded_bytes(), entry.host.as_bytes()] {
+                writer.write_all(&u32::try_from(bytes.len()).with_context(|| format!("Entry ({:?}) is too long", entry.target))?.to_le_bytes())?;
+                writer.write_all(bytes)?;
+            }
+        }
+
+        writer.into_inner()?.sync_all()?;
+        rename(&temporary, path).with_context(|| format!("Couldn't replace manifest ({path:?})"))?;
+
+        Ok(())
+
+    }
+
+    /// Map manifest at given path into memory, only the header is validated
+    pub fn open(path: &Path) -> Result<Self> {
+
+        let file = File::open(path).with_context(|| format!("Couldn't open manifest ({path:?})"))?;
+        // Manifest is only replaced by rename, so the mapped file never changes underneath
+        let map = unsafe { Mmap::map(&file) }.with_context(|| format!("Couldn't map manifest ({path:?})"))?;
+
+        if map.len() < HEADER || &map[..4] != MAGIC {
+            bail!("File ({path:?}) is not a binary manifest");
+        }
+
+        let version = u32::from_le_bytes(map[4..8].try_into()?);
+
+        if !(1..=VERSION).contains(&version) {
+            bail!("Unsupported binary manifest version ({path:?})");
+        }
+
+        let len = usize::try_from(u64::from_le_bytes(map[8..16].try_into()?))?;
+
+        if map.len() < HEADER + 8 * len {
+            bail!("Binary manifest ({path:?}) is truncated");
+        }
+
+        Ok(Self { map, len, version })
+
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Target and source path of the entry at given index, entries are sorted by target
+    pub fn get(&self, index: usize) -> Option<(&Path, &Path)> {
+        self.record(index).map(|(target, source, _)| (target, source))
+    }
+
+    /// Identity of the host which created the entry at given index, empty for manifests written before hosts were recorded
+    pub fn host(&self, index: usize) -> Option<&str> {
+        self.record(index).map(|(_, _, host)| host)
+    }
+
+    /// Source path of the managed link at given target path
+    pub fn find(&self, target: &Path) -> Option<&Path> {
+
+        let (mut low, mut high) = (0, self.len);
+
+        while low < high {
+
+            let middle = (low + high) / 2;
+            let (current, source) = self.get(middle)?;
+
+            match current.cmp(target) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(source)
+            }
+
+        }
+
+        None
+
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        (0..self.len).map_while(|index| self.get(index))
+    }
+
+    /// Entries created by given host, e.g. [host_id] to leave links of other hosts sharing the target alone
+    pub fn iter_host<'a>(&'a self, host: &'a str) -> impl Iterator<Item = (&'a Path, &'a Path)> {
+        (0..self.len).map_while(|index| self.record(index)).filter(move |record| record.2 == host).map(|(target, source, _)| (target, source))
+    }
+
+    /// Owned copies of all entries, including their hosts
+    pub fn entries(&self) -> impl Iterator<Item = ManifestEntry> + '_ {
+        (0..self.len).map_while(|index| self.record(index)).map(|(target, source, host)| {
+            ManifestEntry { target: target.to_path_buf(), source: source.to_path_buf(), host: host.to_string() }
+        })
+    }
+
+    /// Entries whose source doesn't exist anymore
+    pub fn orphans(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        self.iter().filter(|(_, source)| !source.exists() && !source.is_symlink())
+    }
+
+    /// Target paths which are no longer symlinks pointing to their source
+    pub fn verify(&self) -> impl Iterator<Item = &Path> {
+        self.iter().filter(|(target, source)| read_link(target).ok().as_deref() != Some(*source)).map(|(target, _)| target)
+    }
+
+    /// Target paths created by given host which are no longer symlinks pointing to their source
+    pub fn verify_host<'a>(&'a self, host: &'a str) -> impl Iterator<Item = &'a Path> {
+        self.iter_host(host).filter(|(target, source)| read_link(target).ok().as_deref() != Some(*source)).map(|(target, _)| target)
+    }
+
+    /// Remove symlinks created by given host which still point to their source, returns removed paths.
+    ///
+    /// Unlike [unmerge](crate::unmerge), links created by other hosts sharing the target are never touched.
+    pub fn unmerge_host(&self, host: &str) -> Result<Vec<PathBuf>> {
+
+        let mut removed = Vec::new();
+
+        for (target, source) in self.iter_host(host) {
+            if read_link(target).ok().as_deref() == Some(source) {
+                remove_link(target).with_context(|| format!("Couldn't remove symlink ({target:?})"))?;
+                removed.push(target.to_path_buf());
+            }
+        }
+
+        Ok(removed)
+
+    }
+
+    /// Target path, source path and host of the entry at given index
+    fn record(&self, index: usize) -> Option<(&Path, &Path, &str)> {
+
+        if index >= self.len {
+            return None;
+        }
+
+        let position = HEADER + 8 * index;
+        let offset = usize::try_from(u64::from_le_bytes(self.map[position..position + 8].try_into().ok()?)).ok()?;
+        let (target, rest) = self.bytes_at(offset)?;
+        let (source, rest) = self.bytes_at(rest)?;
+
+        let host = match self.version {
+            1 => "",
+            _ => std::str::from_utf8(self.bytes_at(rest)?.0).ok()?
+        };
+
+        Some((Path::new(os_str(target)?), Path::new(os_str(source)?), host))
+
+    }
+
+    /// Length prefixed bytes at given offset, together with the offset following them
+    fn bytes_at(&self, offset: usize) -> Option<(&[u8], usize)> {
+        let len = u32::from_le_bytes(self.map.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        let bytes = self.map.get(offset + 4..offset + 4 + len)?;
+        Some((bytes, offset + 4 + len))
+    }
+
+}
+
+/// Identity of this host recorded in manifests, its hostname unless overridden by `SOLDERIUM_HOST`
+/// (e.g. when hostnames aren't unique across the hosts sharing a target)
+pub fn host_id() -> String {
+
+    if let Some(host) = std::env::var_os("SOLDERIUM_HOST").filter(|host| !host.is_empty()) {
+        return host.to_string_lossy().into_owned();
+    }
+
+    ["/proc/sys/kernel/hostname", "/etc/hostname"].into_iter()
+        .find_map(|path| read_to_string(path).ok().map(|name| name.trim().to_string()).filter(|name| !name.is_empty()))
+        .unwrap_or_default()
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::{Path, PathBuf};
+    use crate::{ChangeKind, merge, MergeOptions};
+    use crate::binary_manifest::{BinaryManifest, host_id};
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn write_and_query_binary_manifest() {
+
+        let _lock = prepare_test_directory();
+        let path = Path::new("test_files/links.manifest");
+
+        let report = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &MergeOptions::default()).unwrap();
+        let links = report.changes.iter().filter(|change| change.kind == ChangeKind::Symlink).map(|change| (change.target.clone(), change.source.clone()));
+
+        BinaryManifest::write(path, links).unwrap();
+
+        let manifest = BinaryManifest::open(path).unwrap();
+        let target = Path::new("test_files/test_dir2").canonicalize().unwrap();
+            assert_eq!(manifest.len(), 3);
+            assert_eq!(manifest.find(&target.join("lorem.txt")), Some(Path::new("test_files/test_dir1/lorem.txt").canonicalize().unwrap().as_path()));
+            assert_eq!(manifest.find(&target.join("index.html")), None);
+            assert_eq!(manifest.verify().count(), 0);
+
+        std::fs::remove_file(Path::new("test_files/test_dir1/lorem.txt")).unwrap();
+        std::fs::remove_file(target.join("keep/haha.yml")).unwrap();
+            assert_eq!(manifest.orphans().map(|(target, _)| target.to_path_buf()).collect::<Vec<_>>(), [target.join("lorem.txt")]);
+            assert_eq!(manifest.verify().collect::<Vec<_>>(), [target.join("keep/haha.yml")]);
+
+        assert!(BinaryManifest::open(Path::new("test_files/test_file1.txt")).is_err());
+        BinaryManifest::write(path, Vec::<(PathBuf, PathBuf)>::new()).unwrap();
+            assert!(BinaryManifest::open(path).unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn keep_links_of_hosts_apart() {
+
+        let _lock = prepare_test_directory();
+        let path = Path::new("test_files/links.manifest");
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        merge(source, target, &MergeOptions::default()).unwrap();
+
+        let link = |name: &str| (target.canonicalize().unwrap().join(name), source.canonicalize().unwrap().join(name));
+        BinaryManifest::update(path, "first", [link("lorem.txt")]).unwrap();
+        BinaryManifest::update(path, "second", [link("keep/haha.yml")]).unwrap();
+        BinaryManifest::update(path, "first", [link("lorem.txt")]).unwrap();
+
+        let manifest = BinaryManifest::open(path).unwrap();
+            assert_eq!(manifest.len(), 2);
+            assert_eq!(manifest.iter_host("second").map(|(target, _)| target.to_path_buf()).collect::<Vec<_>>(), [link("keep/haha.yml").0]);
+            assert_eq!(manifest.unmerge_host("first").unwrap(), [link("lorem.txt").0]);
+            assert!(target.join("keep/haha.yml").is_symlink());
+            assert_eq!(manifest.verify_host("second").count(), 0);
+
+        BinaryManifest::write(path, [link("lorem.txt")]).unwrap();
+            assert_eq!(BinaryManifest::open(path).unwrap().host(0), Some(host_id().as_str()));
+
+    }
+
+}
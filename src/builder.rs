@@ -0,0 +1,254 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use anyhow::Result;
+use crate::{Change, Concurrency, ConflictHook, DirectoryLinks, Filter, Folding, Glob, Hasher, Identity, LinkKind, LinkStyle, MaterializeRule, merge, MergeObserver, MergeOptions, MergeReport, Overwrite, plan_symlinks, PlannedAction, Render, Strategy, Trigger, verify};
+
+/// Merge of a single source directory into a target, configured by chained setters.
+///
+/// Every setter fills the matching [MergeOptions] field, see there for details. Options without
+/// a setter can be passed all at once by [options](SymlinkMerge::options).
+///
+/// ```no_run
+/// use solderium::{Overwrite, SymlinkMerge};
+///
+/// let report = SymlinkMerge::new("dotfiles", "/home/user")
+///     .overwrite(Overwrite::Files)
+///     .max_depth(2)
+///     .follow_symlinks(false)
+///     .run()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct SymlinkMerge {
+    source: PathBuf,
+    target: PathBuf,
+    options: MergeOptions
+}
+
+impl SymlinkMerge {
+
+    pub fn new(source: impl Into<PathBuf>, target: impl Into<PathBuf>) -> Self {
+        Self { source: source.into(), target: target.into(), options: MergeOptions::default() }
+    }
+
+    /// Replace all options at once, setters called later still apply
+    pub fn options(mut self, options: MergeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.options.overwrite = overwrite;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.options.strategy = strategy;
+        self
+    }
+
+    /// Whether directories may be symlinked as a whole, see [Folding]
+    pub fn folding(mut self, folding: Folding) -> Self {
+        self.options.folding = folding;
+        self
+    }
+
+    /// Deepest level of source entries merged one by one, see [MergeOptions::max_depth]
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.options.max_depth = Some(depth);
+        self
+    }
+
+    /// Shallowest level of source entries placed in the target, see [MergeOptions::min_depth]
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.options.min_depth = Some(depth);
+        self
+    }
+
+    /// Whether source symlinks are followed into the directories they point to (the default), see [MergeOptions::preserve_symlinks]
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.options.preserve_symlinks = !follow;
+        self
+    }
+
+    /// Leave out source entries matching the pattern, may be called repeatedly
+    pub fn exclude(mut self, pattern: Glob) -> Self {
+        self.options.exclude.push(pattern);
+        self
+    }
+
+    /// Merge only source files matching the pattern (or lying inside a matching directory), may be called repeatedly
+    pub fn include(mut self, pattern: Glob) -> Self {
+        self.options.include.push(pattern);
+        self
+    }
+
+    /// Run the command once the merge changed anything below its subpath, may be called repeatedly, see [MergeOptions::triggers]
+    pub fn trigger(mut self, trigger: Trigger) -> Self {
+        self.options.triggers.push(trigger);
+        self
+    }
+
+    /// Never replace existing target paths matching the pattern, may be called repeatedly
+    pub fn protect(mut self, pattern: Glob) -> Self {
+        self.options.protect.push(pattern);
+        self
+    }
+
+    /// Only report changes of source entries matching the pattern, may be called repeatedly
+    pub fn simulate(mut self, pattern: Glob) -> Self {
+        self.options.simulate.push(pattern);
+        self
+    }
+
+    /// Add materialize rule, later rules take precedence
+    pub fn materialize(mut self, rule: MaterializeRule) -> Self {
+        self.options.materialize.push(rule);
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.options.filter = filter;
+        self
+    }
+
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.options.identity = identity;
+        self
+    }
+
+    pub fn anchor(mut self, anchor: impl Into<PathBuf>) -> Self {
+        self.options.anchor = Some(anchor.into());
+        self
+    }
+
+    pub fn backup(mut self, suffix: impl Into<String>) -> Self {
+        self.options.backup = Some(suffix.into());
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: Concurrency) -> Self {
+        self.options.concurrency = concurrency;
+        self
+    }
+
+    pub fn render(mut self, render: Render) -> Self {
+        self.options.render = Some(render);
+        self
+    }
+
+    /// Notify the observer about every examined, skipped and changed entry, see [MergeObserver]
+    pub fn observer(mut self, observer: Arc<dyn MergeObserver>) -> Self {
+        self.options.observer = Some(observer);
+        self
+    }
+
+    /// Decide about every existing target path by the hook instead of the overwrite policy, see [MergeOptions::on_conflict]
+    pub fn on_conflict(mut self, hook: ConflictHook) -> Self {
+        self.options.on_conflict = Some(hook);
+        self
+    }
+
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.options.umask = Some(umask);
+        self
+    }
+
+    /// Form of the paths stored in created symlinks, see [MergeOptions::link_style]
+    pub fn link_style(mut self, style: LinkStyle) -> Self {
+        self.options.link_style = style;
+        self
+    }
+
+    /// Kind of links created for source files, see [LinkKind]
+    pub fn link_kind(mut self, kind: LinkKind) -> Self {
+        self.options.link_kind = kind;
+        self
+    }
+
+    /// Kind of links created for source directories on Windows, see [DirectoryLinks]
+    pub fn directory_links(mut self, kind: DirectoryLinks) -> Self {
+        self.options.directory_links = kind;
+        self
+    }
+
+    /// List skipped entries in the report, see [MergeOptions::record_skipped]
+    pub fn record_skipped(mut self, record: bool) -> Self {
+        self.options.record_skipped = record;
+        self
+    }
+
+    /// Roll back all changes when the merge fails, see [MergeOptions::transactional]
+    pub fn transactional(mut self, transactional: bool) -> Self {
+        self.options.transactional = transactional;
+        self
+    }
+
+    /// Skip source entries matching patterns of ignore files in the source tree, see [MergeOptions::ignore_files]
+    pub fn ignore_files(mut self, ignore_files: bool) -> Self {
+        self.options.ignore_files = ignore_files;
+        self
+    }
+
+    /// Hold the maintenance marker in the target root while making changes, see [MergeOptions::maintenance_marker]
+    pub fn maintenance_marker(mut self, marker: bool) -> Self {
+        self.options.maintenance_marker = marker;
+        self
+    }
+
+    /// Keep the logical target path instead of resolving its symlinks, see [MergeOptions::preserve_target_path]
+    pub fn preserve_target_path(mut self, preserve: bool) -> Self {
+        self.options.preserve_target_path = preserve;
+        self
+    }
+
+    /// Rename replaced target paths in place with a timestamp instead of removing them, see [MergeOptions::soft_delete]
+    pub fn soft_delete(mut self, soft_delete: bool) -> Self {
+        self.options.soft_delete = soft_delete;
+        self
+    }
+
+    /// Unfold directory symlinks in the way and fold directories back like GNU stow, see [MergeOptions::refold]
+    pub fn refold(mut self, refold: bool) -> Self {
+        self.options.refold = refold;
+        self
+    }
+
+    /// Never merge into or below target directories reached through a symlink, see [MergeOptions::confine_target]
+    pub fn confine_target(mut self, confine_target: bool) -> Self {
+        self.options.confine_target = confine_target;
+        self
+    }
+
+    /// Fail on any warning, see [MergeOptions::strict]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    pub fn hasher(mut self, hasher: Arc<dyn Hasher>) -> Self {
+        self.options.hasher = Some(hasher);
+        self
+    }
+
+    /// Options the merge runs with
+    pub fn merge_options(&self) -> &MergeOptions {
+        &self.options
+    }
+
+    /// Merge the source into the target, see [merge]
+    pub fn run(&self) -> Result<MergeReport> {
+        merge(&self.source, &self.target, &self.options)
+    }
+
+    /// Actions the merge would take without taking them, see [plan_symlinks]
+    pub fn dry_run(&self) -> Result<Vec<PlannedAction>> {
+        plan_symlinks(&self.source, &self.target, &self.options)
+    }
+
+    /// Changes the merge would make without making them, see [verify]
+    pub fn verify(&self) -> Result<Vec<Change>> {
+        verify(&self.source, &self.target, &self.options)
+    }
+
+}
@@ -0,0 +1,160 @@
+use std::fs::{create_dir, File, hard_link, read_to_string, remove_dir, remove_file};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use anyhow::{Context, Result};
+use crate::merge::symlinks_unsupported;
+use crate::platform::{junction, remove_link, symlink, unprivileged_symlinks};
+use crate::temp::{temp_path, TEMP_PREFIX};
+
+/// Filesystems able to share extents between files (copy-on-write clones)
+const REFLINK_FILESYSTEMS: [&str; 4] = ["btrfs", "xfs", "bcachefs", "ocfs2"];
+/// Longest name tried when probing the name length limit
+const NAME_LEN_LIMIT: usize = 4096;
+
+/// Features of the filesystem holding a directory, see [probe].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsCapabilities {
+    pub symlinks: bool,
+    pub hardlinks: bool,
+    /// Files can be cloned sharing their content, known from the filesystem type
+    pub reflink: bool,
+    /// Names differing only in case lead to the same entry
+    pub case_insensitive: bool,
+    /// Longest entry name in bytes
+    pub max_name_len: usize,
+    /// Filesystem type from `/proc/self/mountinfo` (e.g. `ext4`), unknown without procfs
+    pub fs_type: Option<String>
+}
+
+/// Find out features of the filesystem holding the `directory`, by creating (and removing) temporary entries in it.
+///
+/// Useful for choosing the approach up front, e.g. copying instead of symlinking on filesystems without
+/// symlinks, or checking names for collisions on case-insensitive filesystems.
+pub fn probe(directory: &Path) -> Result<FsCapabilities> {
+
+    let links = probe_links(directory)?;
+    let directory = directory.canonicalize().with_context(|| format!("Couldn't resolve probed directory ({directory:?})"))?;
+    let fs_type = filesystem_type(&directory);
+
+    Ok(FsCapabilities {
+        symlinks: links.symlinks,
+        hardlinks: links.hardlinks,
+        reflink: fs_type.as_deref().is_some_and(|fs_type| REFLINK_FILESYSTEMS.contains(&fs_type)),
+        case_insensitive: probe_case_insensitive(&directory)?,
+        max_name_len: probe_name_len(&directory)?,
+        fs_type
+    })
+
+}
+
+/// Kinds of links which can be created in a directory, see [probe_links].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkCapabilities {
+    /// Symlinks don't need elevated privileges, on Windows read from the Developer Mode switch (any user can create them on Unix)
+    pub unprivileged_symlinks: bool,
+    /// Symlinks can be created, by the filesystem and by the privileges of the current process
+    pub symlinks: bool,
+    /// Directory junctions can be created, they exist only on Windows
+    pub junctions: bool,
+    /// Filesystem supports hardlinks
+    pub hardlinks: bool
+}
+
+/// Find out which links can be created in the `directory`, by creating (and removing) temporary ones.
+///
+/// Lets callers pick [FallbackStrategy](crate::FallbackStrategy) or another approach before merging,
+/// instead of finding out in the middle of it.
+pub fn probe_links(directory: &Path) -> Result<LinkCapabilities> {
+
+    let file = temp_path(&directory.join("probe"));
+    File::create(&file).with_context(|| format!("Couldn't create probe file in ({directory:?})"))?;
+
+    let symlinks = try_link(|link| symlink(&file, link), directory);
+    let hardlinks = try_link(|link| hard_link(&file, link), directory);
+
+    remove_file(&file).with_context(|| format!("Couldn't remove probe file ({file:?})"))?;
+
+    let linked = temp_path(&directory.join("probe"));
+    create_dir(&linked).with_context(|| format!("Couldn't create probe directory in ({directory:?})"))?;
+
+    let junctions = try_link(|link| junction(&linked, link), directory);
+
+    remove_dir(&linked).with_context(|| format!("Couldn't remove probe directory ({linked:?})"))?;
+
+    Ok(LinkCapabilities { unprivileged_symlinks: unprivileged_symlinks(), symlinks: symlinks?, junctions: junctions?, hardlinks: hardlinks? })
+
+}
+
+/// Try to create a link using `create`, errors other than missing support are returned
+fn try_link(create: impl FnOnce(&Path) -> io::Result<()>, directory: &Path) -> Result<bool> {
+
+    let link = temp_path(&directory.join("probe"));
+
+    match create(&link) {
+        Ok(()) => {
+            remove_link(&link).with_context(|| format!("Couldn't remove probe link ({link:?})"))?;
+            Ok(true)
+        },
+        Err(error) if symlinks_unsupported(&error) => Ok(false),
+        Err(error) => Err(error).with_context(|| format!("Couldn't probe links in ({directory:?})"))
+    }
+
+}
+
+fn probe_case_insensitive(directory: &Path) -> Result<bool> {
+
+    let file = temp_path(&directory.join("probe"));
+    File::create(&file).with_context(|| format!("Couldn't create probe file in ({directory:?})"))?;
+
+    let upper = file.with_file_name(file.file_name().unwrap_or_default().to_string_lossy().to_uppercase());
+    let case_insensitive = upper.symlink_metadata().is_ok();
+
+    remove_file(&file).with_context(|| format!("Couldn't remove probe file ({file:?})"))?;
+    Ok(case_insensitive)
+
+}
+
+/// Find the longest name which can be created by bisection, names start with [TEMP_PREFIX] to be recognized as leftovers
+fn probe_name_len(directory: &Path) -> Result<usize> {
+
+    let prefix = format!("{TEMP_PREFIX}{}-", process::id());
+    let (mut low, mut high) = (prefix.len(), NAME_LEN_LIMIT);
+
+    while low < high {
+
+        let len = (low + high).div_ceil(2);
+        let path = directory.join(format!("{prefix}{}", "x".repeat(len - prefix.len())));
+
+        match File::create(&path) {
+            Ok(_) => {
+                remove_file(&path).with_context(|| format!("Couldn't remove probe file ({path:?})"))?;
+                low = len;
+            },
+            Err(error) if error.kind() == ErrorKind::InvalidFilename => high = len - 1,
+            Err(error) => return Err(error).with_context(|| format!("Couldn't probe name length in ({directory:?})"))
+        }
+
+    }
+
+    Ok(low)
+
+}
+
+/// Type of the filesystem mounted at the longest mount point containing the path
+fn filesystem_type(path: &Path) -> Option<String> {
+
+    let mountinfo = read_to_string("/proc/self/mountinfo").ok()?;
+
+    mountinfo.lines()
+        .filter_map(|line| {
+            // Mount point is the fifth field, filesystem type follows the separator of optional fields
+            let mut fields = line.split(' ');
+            let mount_point = PathBuf::from(fields.nth(4)?.replace("\\040", " "));
+            let fs_type = fields.skip_while(|&field| field != "-").nth(1)?;
+            path.starts_with(&mount_point).then(|| (mount_point.as_os_str().len(), fs_type.to_string()))
+        })
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, fs_type)| fs_type)
+
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fmt;
+use crate::{OutOfSpace, SourceUnavailable, TooManyEntries, Warning};
+
+/// Stable identifier of an error or warning kind.
+///
+/// Codes never change between releases (unlike the English messages), so user interfaces can
+/// localize them through a [MessageCatalog] or link them to their own help pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ErrorCode {
+    OutOfSpace,
+    TooManyEntries,
+    SourceUnavailable,
+    SkippedSpecialFile,
+    CaseCollision,
+    KeepMarkerShadowing,
+    SymlinksUnsupported,
+    EntryFailed,
+    NestedDeployment,
+    LinkChainTooLong,
+    TriggerFailed,
+    SkippedSymlink,
+    NotStaged
+}
+
+impl ErrorCode {
+
+    /// Stable textual form of the code, e.g. `SLD0001`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::OutOfSpace => "SLD0001",
+            ErrorCode::TooManyEntries => "SLD0002",
+            ErrorCode::SourceUnavailable => "SLD0003",
+            ErrorCode::SkippedSpecialFile => "SLD1001",
+            ErrorCode::CaseCollision => "SLD1002",
+            ErrorCode::KeepMarkerShadowing => "SLD1003",
+            ErrorCode::SymlinksUnsupported => "SLD1004",
+            ErrorCode::EntryFailed => "SLD1005",
+            ErrorCode::NestedDeployment => "SLD1006",
+            ErrorCode::LinkChainTooLong => "SLD1007",
+            ErrorCode::TriggerFailed => "SLD1008",
+            ErrorCode::SkippedSymlink => "SLD1009",
+            ErrorCode::NotStaged => "SLD1010"
+        }
+    }
+
+    /// Short English description of the code, used when a catalog has no message for it
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::OutOfSpace => "The target filesystem ran out of space",
+            ErrorCode::TooManyEntries => "A target directory would exceed the entry limit",
+            ErrorCode::SourceUnavailable => "The source directory disappeared during the merge",
+            ErrorCode::SkippedSpecialFile => "A special file was not copied",
+            ErrorCode::CaseCollision => "Source names differ only in case",
+            ErrorCode::KeepMarkerShadowing => "A keep marker prevented paths from being merged",
+            ErrorCode::SymlinksUnsupported => "The target filesystem doesn't support symlinks",
+            ErrorCode::EntryFailed => "A change of the target failed and was skipped",
+            ErrorCode::NestedDeployment => "A directory managed by another deployment was skipped",
+            ErrorCode::LinkChainTooLong => "A symlink chain in the target is too long to follow",
+            ErrorCode::TriggerFailed => "A command triggered by the changes failed",
+            ErrorCode::SkippedSymlink => "A symlink was not copied by the copy fallback",
+            ErrorCode::NotStaged => "A replaced path couldn't be rolled back by the transaction"
+        }
+    }
+
+    /// Code of the typed error or warning found anywhere in the chain of `error`
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+
+        // Typed errors attached as a context are only found by downcasting the whole error
+        if error.is::<OutOfSpace>() {
+            return Some(ErrorCode::OutOfSpace);
+        }
+
+        if error.is::<SourceUnavailable>() {
+            return Some(ErrorCode::SourceUnavailable);
+        }
+
+        if error.is::<TooManyEntries>() {
+            return Some(ErrorCode::TooManyEntries);
+        }
+
+        error.chain().find_map(|cause| cause.downcast_ref::<Warning>().map(Warning::code))
+
+    }
+
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Warning {
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Warning::TooManyEntries { .. } => ErrorCode::TooManyEntries,
+            Warning::SkippedSpecialFile { .. } => ErrorCode::SkippedSpecialFile,
+            Warning::CaseCollision { .. } => ErrorCode::CaseCollision,
+            Warning::KeepMarkerShadowing { .. } => ErrorCode::KeepMarkerShadowing,
+            Warning::SymlinksUnsupported { .. } => ErrorCode::SymlinksUnsupported,
+            Warning::EntryFailed { .. } => ErrorCode::EntryFailed,
+            Warning::NestedDeployment { .. } => ErrorCode::NestedDeployment,
+            Warning::LinkChainTooLong { .. } => ErrorCode::LinkChainTooLong,
+            Warning::TriggerFailed { .. } => ErrorCode::TriggerFailed,
+            Warning::SkippedSymlink { .. } => ErrorCode::SkippedSymlink,
+            Warning::NotStaged { .. } => ErrorCode::NotStaged
+        }
+    }
+
+}
+
+/// Messages of a user interface language, keyed by [ErrorCode].
+///
+/// Codes missing in the catalog fall back to their English [description](ErrorCode::description).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageCatalog {
+    messages: HashMap<ErrorCode, String>
+}
+
+impl MessageCatalog {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use given message for the code
+    pub fn with(mut self, code: ErrorCode, message: impl Into<String>) -> Self {
+        self.messages.insert(code, message.into());
+        self
+    }
+
+    pub fn message(&self, code: ErrorCode) -> &str {
+        self.messages.get(&code).map(String::as_str).unwrap_or(code.description())
+    }
+
+    /// Catalog message of the error code found in the chain, errors without a code keep their own message
+    pub fn describe(&self, error: &anyhow::Error) -> String {
+        match ErrorCode::of(error) {
+            Some(code) => format!("{} ({code})", self.message(code)),
+            None => error.to_string()
+        }
+    }
+
+}
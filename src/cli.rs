@@ -0,0 +1,382 @@
+//! Command line interface of the `solderium` binary, built with the `cli` feature
+//!
+//! ```text
+//! solderium link SOURCE TARGET [--overwrite POLICY] [--strategy STRATEGY] [--no-folding] [--exclude PATTERN]... [--json]
+//! solderium unlink SOURCE TARGET [--prune-empty] [--json]
+//! solderium verify SOURCE TARGET [--overwrite POLICY] [--strategy STRATEGY] [--no-folding] [--exclude PATTERN]... [--json]
+//! solderium plan SOURCE TARGET [--overwrite POLICY] [--strategy STRATEGY] [--no-folding] [--exclude PATTERN]... [--json]
+//! solderium owns PATH [--json]
+//! solderium status PATH [--json]
+//! ```
+//!
+//! Exits with `0` on success, `1` when `verify` finds changes to make, `owns` an unmanaged path or `status`
+//! an unhealthy one and `2` on any error.
+
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use crate::{Change, ChangeKind, ColorChoice, Folding, MergeOptions, plan_symlinks, PlannedAction, unmerge, UnmergeOptions, verify};
+use crate::manifest::{Health, Manifest, MANIFEST_NAME, merge_with_manifest, provenance, Provenance};
+
+/// Help printed by `--help` and after usage errors
+pub const USAGE: &str = "\
+Usage: solderium <COMMAND> SOURCE TARGET [OPTIONS]
+       solderium <owns|status> PATH [--json]
+
+Commands:
+  link      Merge SOURCE into TARGET using symlinks, recorded in the manifest of TARGET
+  unlink    Remove symlinks pointing into SOURCE from TARGET together with the manifest
+  verify    List changes a merge would make, fails when there are any
+  plan      List actions a merge would take, without taking them
+  owns      Print the deployment managing PATH, fails for unmanaged paths
+  status    Print health of the symlink managing PATH, fails unless it's linked
+
+Options:
+  --overwrite POLICY   all, dirs, files, foreign-links-only, if-different or none (default)
+  --strategy STRATEGY  shallow, deep or fold (default)
+  --no-folding         Recreate all directories and link only files, whatever the strategy is
+  --exclude PATTERN    Leave out source entries matching the pattern, may be repeated
+  --prune-empty        Remove directories left empty by unlink
+  --json               Print machine readable output
+  -h, --help           Print this help
+";
+
+/// Subcommand of the binary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Merge the source into the target, see [merge_with_manifest]
+    Link,
+    /// [unmerge] the source from the target, removing the manifest of its merge
+    Unlink,
+    /// Changes found by [verify]
+    Verify,
+    /// Actions of [plan_symlinks]
+    Plan,
+    /// Deployment managing the target path, see [provenance]
+    Owns,
+    /// Health of the symlink managing the target path, see [provenance]
+    Status
+}
+
+/// Parsed command line, see [Command::parse].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command {
+    pub action: Action,
+    /// Source directory, missing for the single path queries
+    pub source: Option<PathBuf>,
+    /// Target directory, or the queried target path
+    pub target: PathBuf,
+    pub options: MergeOptions,
+    /// Remove directories left empty, only used by [Action::Unlink]
+    pub prune_empty: bool,
+    pub json: bool
+}
+
+impl Command {
+
+    /// Parse the arguments following the program name, `None` when the help was asked for
+    pub fn parse<I, S>(args: I) -> Result<Option<Self>>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>
+    {
+
+        let mut args = args.into_iter().map(Into::into);
+        let mut positional = Vec::new();
+        let mut options = MergeOptions::default();
+        let (mut prune_empty, mut json) = (false, false);
+
+        while let Some(arg) = args.next() {
+
+            let mut value = |name: &str| -> Result<String> {
+                args.next().and_then(|value| value.into_string().ok()).with_context(|| format!("Option ({name}) needs a value"))
+            };
+
+            match arg.to_str() {
+                Some("-h" | "--help") => return Ok(None),
+                Some("--overwrite") => options.overwrite = value("--overwrite")?.parse()?,
+                Some("--strategy") => options.strategy = value("--strategy")?.parse()?,
+                Some("--no-folding") => options.folding = Folding::Never,
+                Some("--exclude") => options.exclude.push(value("--exclude")?.parse()?),
+                Some("--prune-empty") => prune_empty = true,
+                Some("--json") => json = true,
+                Some(flag) if flag.starts_with('-') => bail!("Unknown option ({flag})"),
+                _ => positional.push(arg)
+            }
+
+        }
+
+        let mut positional = positional.into_iter();
+        let action = positional.next().with_context(|| "Expected a command")?;
+        let action = match action.to_str() {
+            Some("link") => Action::Link,
+            Some("unlink") => Action::Unlink,
+            Some("verify") => Action::Verify,
+            Some("plan") => Action::Plan,
+            Some("owns") => Action::Owns,
+            Some("status") => Action::Status,
+            _ => bail!("Unknown command ({action:?}), expected one of: link, unlink, verify, plan, owns, status")
+        };
+
+        let (source, target) = match (action, positional.next(), positional.next(), positional.next()) {
+            (Action::Owns | Action::Status, Some(path), None, _) => (None, path.into()),
+            (Action::Owns | Action::Status, ..) => bail!("Expected a single path"),
+            (_, Some(source), Some(target), None) => (Some(source.into()), target.into()),
+            _ => bail!("Expected a source and a target")
+        };
+
+        Ok(Some(Self { action, source, target, options, prune_empty, json }))
+
+    }
+
+    /// Run the command printing its output, returns the exit code
+    pub fn run(&self, out: &mut dyn Write) -> Result<i32> {
+
+        let target = self.target.as_path();
+        let source = || self.source.as_deref().with_context(|| "Command needs a source directory");
+
+        let (output, code) = match self.action {
+            Action::Link => {
+                let report = merge_with_manifest(source()?, target, &self.options)?;
+                let output = json!({
+                    "changes": report.changes.iter().map(change).collect::<Vec<_>>(),
+                    "warnings": report.warnings.iter().map(ToString::to_string).collect::<Vec<_>>()
+                });
+                (Output { json: output, text: report.display(ColorChoice::Auto).to_string() }, 0)
+            },
+            Action::Unlink => {
+                let mut report = unmerge(source()?, target, &UnmergeOptions { prune_empty: self.prune_empty, ..Default::default() })?;
+
+                // Manifest of the source merge is stale once its links are gone
+                let manifest = target.join(MANIFEST_NAME);
+                if manifest.is_file() && Manifest::read(target)?.source == source()?.canonicalize()? {
+                    report.removed.append(&mut Manifest::read(target)?.undo(target)?);
+                }
+
+                let text = report.removed.iter().chain(&report.pruned).map(|path| format!("- {}\n", path.display())).collect();
+                (Output { json: json!({ "removed": paths(&report.removed), "pruned": paths(&report.pruned) }), text }, 0)
+            },
+            Action::Verify => {
+                let changes = verify(source()?, target, &self.options)?;
+                let text = changes.iter().map(|change| format!("{}\n", change.target.display())).collect();
+                let code = i32::from(!changes.is_empty());
+                (Output { json: json!({ "changes": changes.iter().map(change).collect::<Vec<_>>() }), text }, code)
+            },
+            Action::Plan => {
+                let actions = plan_symlinks(source()?, target, &self.options)?;
+                let text = actions.iter().map(|action| format!("{}\n", action_line(action))).collect();
+                (Output { json: json!({ "actions": actions.iter().map(planned).collect::<Vec<_>>() }), text }, 0)
+            },
+            Action::Owns => {
+                let found = provenance(target)?;
+                let text = match &found {
+                    Some(found) => format!("{} -> {} ({})\n", target.display(), found.source.display(), owner(found)),
+                    None => format!("{} is not managed\n", target.display())
+                };
+                let code = i32::from(found.is_none());
+                (Output { json: owned(target, found.as_ref()), text }, code)
+            },
+            Action::Status => {
+                let found = provenance(target)?;
+                let health = found.as_ref().map(|found| health(found.health)).unwrap_or("unmanaged");
+                let mut json = owned(target, found.as_ref());
+                json["health"] = json!(health);
+                let code = i32::from(found.as_ref().is_none_or(|found| found.health != Health::Linked));
+                (Output { json, text: format!("{health} {}\n", target.display()) }, code)
+            }
+        };
+
+        match self.json {
+            true => writeln!(out, "{}", output.json),
+            false => write!(out, "{}", output.text)
+        }.with_context(|| "Couldn't write output")?;
+
+        Ok(code)
+
+    }
+
+}
+
+/// Output of a command in both forms
+struct Output {
+    json: Value,
+    text: String
+}
+
+/// Run the binary with the arguments following the program name, returns the exit code.
+///
+/// Errors are printed to the standard error, in JSON too when asked for.
+pub fn main<I, S>(args: I) -> i32
+where
+    I: IntoIterator<Item = S>,
+    S: Into<OsString>
+{
+
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let json = args.iter().any(|arg| arg == "--json");
+
+    let command = match Command::parse(args) {
+        Ok(Some(command)) => command,
+        Ok(None) => {
+            print!("{USAGE}");
+            return 0;
+        },
+        Err(error) => return fail(&error, json, true)
+    };
+
+    match command.run(&mut io::stdout().lock()) {
+        Ok(code) => code,
+        Err(error) => fail(&error, json, false)
+    }
+
+}
+
+/// Print the error, followed by the help when the command line was wrong, returns the exit code
+fn fail(error: &anyhow::Error, json: bool, usage: bool) -> i32 {
+
+    match (json, usage) {
+        (true, _) => eprintln!("{}", json!({ "error": format!("{error:#}") })),
+        (false, true) => eprintln!("Error: {error:#}\n\n{USAGE}"),
+        (false, false) => eprintln!("Error: {error:#}")
+    }
+
+    2
+
+}
+
+fn change(change: &Change) -> Value {
+
+    let kind = match change.kind {
+        ChangeKind::Symlink => "symlink",
+        ChangeKind::Hardlink => "hardlink",
+        ChangeKind::Copy => "copy",
+        ChangeKind::Directory => "directory"
+    };
+
+    json!({ "kind": kind, "source": path(&change.source), "target": path(&change.target), "replace": change.replace })
+
+}
+
+fn owned(target: &Path, found: Option<&Provenance>) -> Value {
+    match found {
+        Some(found) => json!({
+            "path": path(target), "managed": true, "source": path(&found.source), "link": path(&found.link.target),
+            "manifest": path(&found.manifest), "package": found.package
+        }),
+        None => json!({ "path": path(target), "managed": false })
+    }
+}
+
+/// Package or the manifest of the deployment
+fn owner(found: &Provenance) -> String {
+    match &found.package {
+        Some(package) => format!("package {package}"),
+        None => format!("manifest {}", found.manifest.display())
+    }
+}
+
+fn health(health: Health) -> &'static str {
+    match health {
+        Health::Linked => "linked",
+        Health::Broken => "broken",
+        Health::Missing => "missing",
+        Health::Replaced => "replaced"
+    }
+}
+
+fn planned(action: &PlannedAction) -> Value {
+    match action {
+        PlannedAction::CreateSymlink { source, target } => json!({ "action": "create-symlink", "source": path(source), "target": path(target) }),
+        PlannedAction::CreateHardlink { source, target } => json!({ "action": "create-hardlink", "source": path(source), "target": path(target) }),
+        PlannedAction::CopyFile { source, target } => json!({ "action": "copy-file", "source": path(source), "target": path(target) }),
+        PlannedAction::CreateDirectory { target } => json!({ "action": "create-directory", "target": path(target) }),
+        PlannedAction::RemoveFile { path: removed } => json!({ "action": "remove-file", "path": path(removed) }),
+        PlannedAction::RemoveDirectory { path: removed } => json!({ "action": "remove-directory", "path": path(removed) }),
+        PlannedAction::Backup { path: saved, backup } => json!({ "action": "backup", "path": path(saved), "backup": path(backup) }),
+        PlannedAction::SkipKept { path: kept, marker } => json!({ "action": "skip-kept", "path": path(kept), "marker": path(marker) })
+    }
+}
+
+fn action_line(action: &PlannedAction) -> String {
+    match action {
+        PlannedAction::CreateSymlink { source, target } => format!("+ {} -> {}", target.display(), source.display()),
+        PlannedAction::CreateHardlink { source, target } => format!("+ {} => {}", target.display(), source.display()),
+        PlannedAction::CopyFile { source, target } => format!("+ {} (copy of {})", target.display(), source.display()),
+        PlannedAction::CreateDirectory { target } => format!("+ {}/", target.display()),
+        PlannedAction::RemoveFile { path } | PlannedAction::RemoveDirectory { path } => format!("- {}", path.display()),
+        PlannedAction::Backup { path, backup } => format!("~ {} -> {}", path.display(), backup.display()),
+        PlannedAction::SkipKept { path, marker } => format!("= {} (kept by {})", path.display(), marker.display())
+    }
+}
+
+fn path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn paths(paths: &[PathBuf]) -> Vec<String> {
+    paths.iter().map(|item| path(item)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::Path;
+    use serde_json::{json, Value};
+    use crate::{Overwrite, Strategy};
+    use crate::cli::{Action, Command};
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn run_command_line() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = ("test_files/test_dir1", "test_files/test_dir2");
+
+        let command = Command::parse(["plan", source, target, "--overwrite", "files", "--strategy", "deep", "--json"]).unwrap().unwrap();
+            assert_eq!((command.action, command.options.overwrite, command.options.strategy, command.json), (Action::Plan, Overwrite::Files, Strategy::Deep, true));
+            assert!(Command::parse(["link", source]).is_err());
+            assert!(Command::parse(["link", source, target, "--overwrite", "some"]).is_err());
+            assert!(Command::parse(["--help"]).unwrap().is_none());
+
+        let run = |args: &[&str]| {
+            let mut out = Vec::new();
+            let code = Command::parse(args.iter().copied()).unwrap().unwrap().run(&mut out).unwrap();
+            (code, serde_json::from_slice::<Value>(&out).unwrap())
+        };
+
+        let (code, plan) = run(&["plan", source, target, "--json"]);
+            assert_eq!(code, 0);
+            assert_eq!(plan["actions"].as_array().unwrap().len(), 3);
+            assert!(!Path::new(target).join("lorem.txt").exists());
+
+        let (code, _) = run(&["verify", source, target, "--json"]);
+            assert_eq!(code, 1);
+
+        let (code, report) = run(&["link", source, target, "--json"]);
+            assert_eq!(code, 0);
+            assert_eq!(report["changes"][0]["kind"], "symlink");
+            assert_eq!(run(&["verify", source, target, "--json"]).0, 0);
+
+        // Single path queries answer from the manifest of the merge
+        let (code, owner) = run(&["owns", &format!("{target}/lorem.txt"), "--json"]);
+            assert_eq!(code, 0);
+            assert!(owner["source"].as_str().unwrap().ends_with("test_dir1/lorem.txt"));
+            assert_eq!(run(&["status", &format!("{target}/lorem.txt"), "--json"]).1["health"], "linked");
+            assert_eq!(run(&["owns", &format!("{target}/index.html"), "--json"]), (1, json!({ "path": format!("{target}/index.html"), "managed": false })));
+
+        std::fs::remove_file(Path::new(target).join("lorem.txt")).unwrap();
+        let (code, status) = run(&["status", &format!("{target}/lorem.txt"), "--json"]);
+            assert_eq!((code, status["health"].as_str()), (1, Some("missing")));
+
+        run(&["link", source, target, "--json"]);
+        let (code, report) = run(&["unlink", source, target, "--json"]);
+            assert_eq!(code, 0);
+            assert_eq!(report["removed"].as_array().unwrap().len(), 3);
+            assert!(!Path::new(target).join("lorem.txt").exists());
+            assert_eq!(run(&["status", &format!("{target}/lorem.txt"), "--json"]).1["health"], "unmanaged");
+
+    }
+
+}
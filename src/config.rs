@@ -0,0 +1,189 @@
+//! Merge jobs declared once in a configuration file and re-applied by [run_config]
+//!
+//! ```toml
+//! [[job]]
+//! name = "dotfiles"
+//! source = "dotfiles"
+//! target = "/home/user"
+//! preset = "dotfiles"
+//! exclude = ["/.git"]
+//!
+//! [[job]]
+//! source = "site"
+//! target = "/var/www"
+//! overwrite = "files"
+//! strategy = "deep"
+//! keep = ["/uploads", "/uploads/**"]
+//! ```
+//!
+//! The types are [Deserialize], so other formats (e.g. YAML through `serde_yaml`) can describe the jobs as well.
+
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::{merge, MergeOptions, MergeReport};
+
+/// Merge jobs of a configuration file, see [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Jobs in the order they run
+    #[serde(default, rename = "job")]
+    pub jobs: Vec<JobConfig>
+}
+
+/// Single source to target mapping of a [Config].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    /// Name of the job in reports, the target path when missing
+    pub name: Option<String>,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// [Preset](MergeOptions::preset) adjusted by the rest of the fields, the default options when missing
+    pub preset: Option<String>,
+    /// Lowercase name of the [Overwrite](crate::Overwrite) policy, e.g. `files`
+    pub overwrite: Option<String>,
+    /// Lowercase name of the [Strategy](crate::Strategy), e.g. `deep`
+    pub strategy: Option<String>,
+    /// Patterns added to [MergeOptions::exclude]
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Patterns of existing target paths kept whatever the overwrite policy, added to [MergeOptions::protect]
+    #[serde(default)]
+    pub keep: Vec<String>
+}
+
+/// Outcome of a single job, see [run_config].
+#[derive(Debug)]
+pub struct JobReport {
+    pub name: String,
+    pub result: Result<MergeReport>
+}
+
+impl Config {
+
+    /// Parse the configuration from TOML, relative paths are kept as they are
+    pub fn from_toml(content: &str) -> Result<Self> {
+        toml::from_str(content).with_context(|| "Configuration is invalid")
+    }
+
+    /// Read the TOML configuration file, relative paths of the jobs start in the directory holding it
+    pub fn load(path: &Path) -> Result<Self> {
+
+        let content = read_to_string(path).with_context(|| format!("Couldn't read configuration ({path:?})"))?;
+        let mut config = Self::from_toml(&content).with_context(|| format!("Couldn't load configuration ({path:?})"))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+
+        for job in &mut config.jobs {
+            job.source = base.join(&job.source);
+            job.target = base.join(&job.target);
+        }
+
+        Ok(config)
+
+    }
+
+}
+
+impl JobConfig {
+
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.target.display().to_string())
+    }
+
+    /// Merge options described by the job
+    pub fn options(&self) -> Result<MergeOptions> {
+
+        let mut options = match &self.preset {
+            Some(preset) => MergeOptions::preset(preset)?,
+            None => MergeOptions::default()
+        };
+
+        if let Some(overwrite) = &self.overwrite {
+            options.overwrite = overwrite.parse()?;
+        }
+
+        if let Some(strategy) = &self.strategy {
+            options.strategy = strategy.parse()?;
+        }
+
+        for pattern in &self.exclude {
+            options.exclude.push(pattern.parse()?);
+        }
+
+        for pattern in &self.keep {
+            options.protect.push(pattern.parse()?);
+        }
+
+        Ok(options)
+
+    }
+
+}
+
+/// Run all jobs of the configuration in their order.
+///
+/// Options of every job are checked before any of them runs, so a mistake in the configuration changes nothing.
+/// A failure of one job doesn't stop the others, every job gets own [JobReport].
+pub fn run_config(config: &Config) -> Result<Vec<JobReport>> {
+
+    let jobs = config.jobs.iter()
+        .map(|job| job.options().map(|options| (job, options)).with_context(|| format!("Invalid job ({})", job.name())))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(jobs.into_iter().map(|(job, options)| JobReport { name: job.name(), result: merge(&job.source, &job.target, &options) }).collect())
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir, write};
+    use std::path::Path;
+    use crate::Overwrite;
+    use crate::config::{Config, run_config};
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn run_configured_jobs() {
+
+        let _lock = prepare_test_directory();
+        let path = Path::new("test_files/jobs.toml");
+        create_dir(Path::new("test_files/test_dir3")).unwrap();
+        write(path, r#"
+            [[job]]
+            name = "site"
+            source = "test_dir1"
+            target = "test_dir2"
+            overwrite = "files"
+            keep = ["/ipsum.php"]
+
+            [[job]]
+            source = "test_dir1"
+            target = "test_dir3"
+            strategy = "deep"
+            exclude = ["/nested"]
+        "#).unwrap();
+
+        let config = Config::load(path).unwrap();
+            assert_eq!(config.jobs[0].source, Path::new("test_files/test_dir1"));
+            assert_eq!(config.jobs[0].options().unwrap().overwrite, Overwrite::Files);
+
+        let reports = run_config(&config).unwrap();
+            assert_eq!(reports.iter().map(|report| report.name.as_str()).collect::<Vec<_>>(), ["site", "test_files/test_dir3"]);
+            assert!(reports.iter().all(|report| report.result.is_ok()));
+            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+            assert!(Path::new("test_files/test_dir3/keep").is_dir() && !Path::new("test_files/test_dir3/keep").is_symlink());
+            assert!(!Path::new("test_files/test_dir3/nested").exists());
+
+        // Mistakes are found before any job runs
+        let config = Config::from_toml("[[job]]\nsource = \"test_files/test_dir1\"\ntarget = \"test_files/test_dir4\"\noverwrite = \"some\"").unwrap();
+            assert!(run_config(&config).is_err());
+            assert!(Config::from_toml("[[job]]\nsource = \"a\"\ntarget = \"b\"\nunknown = 1").is_err());
+
+    }
+
+}
@@ -0,0 +1,624 @@
+//! Long-running service enforcing configured merge jobs, controlled through a Unix socket
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{canonicalize, read_dir, read_link, remove_file, symlink_metadata};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, symlink};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::{explain, Glob, merge, MergeOptions, Verdict, verify};
+use crate::merge::{backup, backup_suffix, remove_path};
+
+/// How often the accept loop checks whether the daemon is stopping
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Named merge job held by the [Daemon].
+#[derive(Clone)]
+pub struct Job {
+    pub name: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub options: MergeOptions
+}
+
+impl Job {
+    pub fn new(name: impl Into<String>, source: impl Into<PathBuf>, target: impl Into<PathBuf>, options: MergeOptions) -> Self {
+        Self { name: name.into(), source: source.into(), target: target.into(), options }
+    }
+}
+
+/// Last known state of a job, as reported by the `status` command.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct JobStatus {
+    /// Number of finished apply runs
+    pub runs: u64,
+    /// Unix timestamp of the last apply run
+    pub last_run: Option<u64>,
+    /// Error of the last apply run, if it failed
+    pub last_error: Option<String>,
+    /// Number of warnings reported by the last apply run
+    pub warnings: usize,
+    /// Number of managed symlinks restored by the target guard, see [Daemon::guard_targets]
+    pub repairs: u64
+}
+
+/// Single line of the control protocol
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    Apply { job: Option<String> },
+    Verify { job: Option<String> },
+    Status,
+    Shutdown
+}
+
+/// Service holding configured merge jobs and enforcing them on request (or on source changes).
+///
+/// The control socket accepts newline-delimited JSON requests and answers each with a single JSON line:
+///
+/// - `{"command": "apply", "job": "www"}` merges the job (all jobs when `job` is omitted)
+/// - `{"command": "verify", "job": "www"}` reports the number of changes the job would make, without making them
+/// - `{"command": "status"}` reports [JobStatus] of every job
+/// - `{"command": "shutdown"}` stops the daemon
+///
+/// Every response contains `"ok": true`, or `"ok": false` together with an `"error"` message.
+pub struct Daemon {
+    jobs: Vec<Job>,
+    status: Mutex<BTreeMap<String, JobStatus>>,
+    watch: Option<Duration>,
+    guard: Option<Guard>,
+    /// Symlinks leading into the source of each job together with their content, as found after its last apply run
+    managed: Mutex<BTreeMap<String, Vec<(PathBuf, PathBuf)>>>,
+    /// Jobs are never applied concurrently
+    running: Mutex<()>,
+    stopping: AtomicBool
+}
+
+/// Settings of the target guard, see [Daemon::guard_targets]
+struct Guard {
+    interval: Duration,
+    grace: Duration,
+    allow: Vec<Glob>
+}
+
+impl Daemon {
+
+    pub fn new(jobs: Vec<Job>) -> Self {
+        let status = jobs.iter().map(|job| (job.name.clone(), JobStatus::default())).collect();
+        Self { jobs, status: Mutex::new(status), watch: None, guard: None, managed: Mutex::default(), running: Mutex::new(()), stopping: AtomicBool::new(false) }
+    }
+
+    /// Check job sources for changes in given interval and apply jobs whose source changed
+    pub fn watch_sources(mut self, interval: Duration) -> Self {
+        self.watch = Some(interval);
+        self
+    }
+
+    /// Check symlinks managed by the jobs in given interval and restore those broken (removed or replaced) for longer than `grace`.
+    ///
+    /// Only the symlinks leading into the job source after its last apply run are checked, the target isn't
+    /// walked again. Paths (relative to the target) matching any of the `allow` patterns may be taken over
+    /// and are never restored. Whatever took the place of a symlink is replaced only when the merge of the job
+    /// would replace it (overwrite policy, protect patterns and keep markers apply), being backed up when
+    /// the job options ask for backups.
+    pub fn guard_targets(mut self, interval: Duration, grace: Duration, allow: Vec<Glob>) -> Self {
+        self.guard = Some(Guard { interval, grace, allow });
+        self
+    }
+
+    /// Make [Daemon::serve] return as soon as possible
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    /// Listen on given Unix socket path until stopped, replacing a stale socket file left by a previous run.
+    ///
+    /// Fails when something else than a socket is in the way, or when another process still listens on it.
+    pub fn serve(&self, socket: &Path) -> Result<()> {
+
+        if let Ok(metadata) = symlink_metadata(socket) {
+
+            if !metadata.file_type().is_socket() {
+                bail!("Control socket path is taken by something else than a socket ({socket:?})");
+            }
+
+            if UnixStream::connect(socket).is_ok() {
+                bail!("Control socket is in use by another process ({socket:?})");
+            }
+
+            remove_file(socket).with_context(|| format!("Couldn't remove stale control socket ({socket:?})"))?;
+
+        }
+
+        let listener = UnixListener::bind(socket).with_context(|| format!("Couldn't bind control socket ({socket:?})"))?;
+        listener.set_nonblocking(true)?;
+
+        let result = thread::scope(|scope| {
+
+            if let Some(interval) = self.watch {
+                scope.spawn(move || self.watch_loop(interval));
+            }
+
+            if let Some(guard) = &self.guard {
+                scope.spawn(move || self.guard_loop(guard));
+            }
+
+            while !self.stopping.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        scope.spawn(move || self.serve_connection(stream));
+                    },
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(error) => {
+                        self.stop();
+                        return Err(error).with_context(|| "Accepting control connection failed");
+                    }
+                }
+            }
+
+            Ok(())
+
+        });
+
+        let _ = remove_file(socket);
+        result
+
+    }
+
+    /// Answer a single protocol request
+    pub fn handle(&self, request: &str) -> String {
+
+        let response = match serde_json::from_str::<Request>(request) {
+            Ok(request) => self.execute(request),
+            Err(error) => Err(anyhow!("Invalid request: {error}"))
+        };
+
+        match response {
+            Ok(mut value) => {
+                value["ok"] = json!(true);
+                value.to_string()
+            },
+            Err(error) => json!({ "ok": false, "error": format!("{error:#}") }).to_string()
+        }
+
+    }
+
+    /// Current status of all jobs
+    pub fn status(&self) -> BTreeMap<String, JobStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn execute(&self, request: Request) -> Result<Value> {
+        match request {
+            Request::Apply { job } => {
+
+                let mut results = serde_json::Map::new();
+
+                for job in self.select(job.as_deref())? {
+                    let status = self.apply(job);
+                    results.insert(job.name.clone(), json!(status));
+                }
+
+                Ok(json!({ "jobs": results }))
+
+            },
+            Request::Verify { job } => {
+
+                let mut results = serde_json::Map::new();
+
+                for job in self.select(job.as_deref())? {
+                    results.insert(job.name.clone(), json!({ "pending": verify(&job.source, &job.target, &job.options)?.len() }));
+                }
+
+                Ok(json!({ "jobs": results }))
+
+            },
+            Request::Status => Ok(json!({ "jobs": self.status() })),
+            Request::Shutdown => {
+                self.stop();
+                Ok(json!({}))
+            }
+        }
+    }
+
+    fn select(&self, name: Option<&str>) -> Result<Vec<&Job>> {
+        match name {
+            None => Ok(self.jobs.iter().collect()),
+            Some(name) => match self.jobs.iter().find(|job| job.name == name) {
+                Some(job) => Ok(vec![job]),
+                None => Err(anyhow!("Unknown job ({name})"))
+            }
+        }
+    }
+
+    fn apply(&self, job: &Job) -> JobStatus {
+
+        let _running = self.running.lock().unwrap();
+        let result = merge(&job.source, &job.target, &job.options);
+
+        if self.guard.is_some() {
+            self.manage(job);
+        }
+
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(job.name.clone()).or_default();
+
+        status.runs += 1;
+        status.last_run = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|time| time.as_secs());
+
+        match result {
+            Ok(report) => {
+                status.last_error = None;
+                status.warnings = report.warnings.len();
+            },
+            Err(error) => {
+                status.last_error = Some(format!("{error:#}"));
+                status.warnings = 0;
+            }
+        }
+
+        status.clone()
+
+    }
+
+    fn serve_connection(&self, stream: UnixStream) {
+
+        // Reads time out regularly, so an idle client can't keep a stopping daemon alive
+        if stream.set_nonblocking(false).is_err() || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err() {
+            return;
+        }
+
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return
+        };
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            match reader.read_line(&mut line) {
+                Ok(0) => return,
+                Ok(_) => {
+
+                    if !line.trim().is_empty() && writeln!(writer, "{}", self.handle(line.trim())).is_err() {
+                        return;
+                    }
+
+                    line.clear();
+
+                },
+                // Partially read line stays in the buffer
+                Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(_) => return
+            }
+        }
+
+    }
+
+    fn watch_loop(&self, interval: Duration) {
+
+        let mut fingerprints: Vec<Option<u64>> = self.jobs.iter().map(|job| fingerprint(&job.source).ok()).collect();
+
+        while !self.stopping.load(Ordering::Relaxed) {
+
+            let mut waited = Duration::ZERO;
+
+            while waited < interval && !self.stopping.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL.min(interval));
+                waited += POLL_INTERVAL.min(interval);
+            }
+
+            for (job, previous) in self.jobs.iter().zip(fingerprints.iter_mut()) {
+
+                let current = fingerprint(&job.source).ok();
+
+                if current != *previous {
+                    *previous = current;
+                    self.apply(job);
+                }
+
+            }
+
+        }
+
+    }
+
+    /// Remember the symlinks of the job to be guarded, unreadable target leaves nothing to guard
+    fn manage(&self, job: &Job) {
+        let links = managed_links(&job.source, &job.target).unwrap_or_default();
+        self.managed.lock().unwrap().insert(job.name.clone(), links);
+    }
+
+    fn guard_loop(&self, guard: &Guard) {
+
+        // Jobs not applied by the daemon yet are guarded in their current state
+        for job in &self.jobs {
+            let _running = self.running.lock().unwrap();
+            self.manage(job);
+        }
+
+        let mut broken = HashMap::new();
+
+        while !self.stopping.load(Ordering::Relaxed) {
+
+            let mut waited = Duration::ZERO;
+
+            while waited < guard.interval && !self.stopping.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL.min(guard.interval));
+                waited += POLL_INTERVAL.min(guard.interval);
+            }
+
+            self.guard_pass(guard, &mut broken);
+
+        }
+
+    }
+
+    /// Restore managed symlinks broken for longer than the grace period, `broken` holds the time each was first found broken
+    fn guard_pass(&self, guard: &Guard, broken: &mut HashMap<PathBuf, Instant>) {
+
+        let _running = self.running.lock().unwrap();
+        let managed = self.managed.lock().unwrap().clone();
+        let now = Instant::now();
+        let mut still_broken = HashMap::new();
+
+        for job in &self.jobs {
+
+            let mut repairs = 0;
+
+            for (path, content) in managed.get(&job.name).into_iter().flatten() {
+
+                if read_link(path).is_ok_and(|current| current == *content) {
+                    continue;
+                }
+
+                let relative = path.strip_prefix(&job.target).unwrap_or(path);
+
+                if guard.allow.iter().any(|glob| glob.matches(relative, path.is_dir())) {
+                    continue;
+                }
+
+                let since = broken.get(path).copied().unwrap_or(now);
+
+                // Replacement the merge wouldn't touch stays, failed restoration is retried in the next pass
+                let restorable = || explain(&job.source, &job.target, &job.options, relative)
+                    .is_ok_and(|explanation| matches!(explanation.verdict, Verdict::Link | Verdict::Replace));
+
+                match now.duration_since(since) >= guard.grace && restorable() && restore(path, content, backup_suffix(&job.options).as_deref()).is_ok() {
+                    true => repairs += 1,
+                    false => {
+                        still_broken.insert(path.clone(), since);
+                    }
+                }
+
+            }
+
+            if repairs > 0 {
+                self.status.lock().unwrap().entry(job.name.clone()).or_default().repairs += repairs;
+            }
+
+        }
+
+        *broken = still_broken;
+
+    }
+
+}
+
+/// Hash of all entry paths, sizes and modification times in the tree, symlinks are not followed
+fn fingerprint(root: &Path) -> std::io::Result<u64> {
+
+    let mut hasher = DefaultHasher::new();
+    let mut stack = vec![root.to_path_buf()];
+
+    while let Some(directory) = stack.pop() {
+
+        let mut entries: Vec<_> = read_dir(&directory)?.collect::<std::io::Result<_>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+
+            let path = entry.path();
+            let metadata = symlink_metadata(&path)?;
+
+            path.hash(&mut hasher);
+            metadata.len().hash(&mut hasher);
+            metadata.modified().ok().hash(&mut hasher);
+
+            if metadata.is_dir() {
+                stack.push(path);
+            }
+
+        }
+
+    }
+
+    Ok(hasher.finish())
+
+}
+
+/// Symlinks in the target tree resolving into the source tree together with their content, symlinks are not followed
+fn managed_links(source: &Path, target: &Path) -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+
+    let source = canonicalize(source)?;
+    let mut links = Vec::new();
+    let mut stack = vec![target.to_path_buf()];
+
+    while let Some(directory) = stack.pop() {
+        for entry in read_dir(&directory)? {
+
+            let path = entry?.path();
+            let metadata = symlink_metadata(&path)?;
+
+            if metadata.is_symlink() && canonicalize(&path).is_ok_and(|resolved| resolved.starts_with(&source)) {
+                links.push((path.clone(), read_link(&path)?));
+            }
+            else if metadata.is_dir() {
+                stack.push(path);
+            }
+
+        }
+    }
+
+    Ok(links)
+
+}
+
+/// Put the symlink back in place, moving aside (or removing) whatever took its place
+fn restore(path: &Path, content: &Path, suffix: Option<&str>) -> std::io::Result<()> {
+
+    if path.symlink_metadata().is_ok() {
+        match suffix {
+            Some(suffix) => backup(path, suffix)?,
+            None => remove_path(path)?
+        }
+    }
+
+    symlink(content, path)
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use std::collections::HashMap;
+    use std::fs::{create_dir, read_to_string, remove_file, write};
+    use serde_json::Value;
+    use crate::{Glob, MergeOptions, Overwrite};
+    use crate::daemon::{Daemon, Job};
+    use crate::tests::prepare_test_directory;
+
+    fn daemon() -> Daemon {
+        let options = MergeOptions { overwrite: Overwrite::Files, ..Default::default() };
+        Daemon::new(vec![Job::new("www", "test_files/test_dir1", "test_files/test_dir2", options)])
+    }
+
+    #[test]
+    fn handle_protocol_requests() {
+
+        let _lock = prepare_test_directory();
+        let daemon = daemon();
+
+        let verify: Value = serde_json::from_str(&daemon.handle(r#"{"command": "verify", "job": "www"}"#)).unwrap();
+            assert_eq!(verify["ok"], true);
+            assert!(verify["jobs"]["www"]["pending"].as_u64().unwrap() > 0);
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+
+        let apply: Value = serde_json::from_str(&daemon.handle(r#"{"command": "apply"}"#)).unwrap();
+            assert_eq!(apply["jobs"]["www"]["runs"], 1);
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+
+        let unknown: Value = serde_json::from_str(&daemon.handle(r#"{"command": "apply", "job": "db"}"#)).unwrap();
+            assert_eq!(unknown["ok"], false);
+
+        let invalid: Value = serde_json::from_str(&daemon.handle("not json")).unwrap();
+            assert_eq!(invalid["ok"], false);
+
+    }
+
+    #[test]
+    fn guard_managed_links() {
+
+        let _lock = prepare_test_directory();
+        let daemon = daemon().guard_targets(Duration::from_secs(1), Duration::from_secs(60), vec![Glob::new("keep/haha.yml").unwrap()]);
+        let guard = daemon.guard.as_ref().unwrap();
+        let mut broken = HashMap::new();
+
+        daemon.handle(r#"{"command": "apply"}"#);
+        remove_file("test_files/test_dir2/lorem.txt").unwrap();
+        remove_file("test_files/test_dir2/keep/haha.yml").unwrap();
+        write("test_files/test_dir2/keep/haha.yml", "taken over").unwrap();
+
+        // Job overwrites only files, so a directory taking the place of a symlink is the user's
+        remove_file("test_files/test_dir2/nested/lorem").unwrap();
+        create_dir("test_files/test_dir2/nested/lorem").unwrap();
+        write("test_files/test_dir2/nested/lorem/notes.txt", "mine").unwrap();
+
+        daemon.guard_pass(guard, &mut broken);
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+            assert_eq!(broken.len(), 2);
+
+        // Grace period is over
+        broken.values_mut().for_each(|since| *since -= Duration::from_secs(60));
+        daemon.guard_pass(guard, &mut broken);
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
+            assert_eq!(read_to_string("test_files/test_dir2/nested/lorem/notes.txt").unwrap(), "mine");
+            assert_eq!(broken.len(), 1);
+            assert_eq!(daemon.status()["www"].repairs, 1);
+
+    }
+
+    #[test]
+    fn serve_control_socket() {
+
+        let _lock = prepare_test_directory();
+        let daemon = daemon();
+        let socket = Path::new("test_files/control.sock");
+
+        thread::scope(|scope| {
+
+            let server = scope.spawn(|| daemon.serve(socket));
+
+            while !socket.exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let mut stream = UnixStream::connect(socket).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+
+            writeln!(stream, r#"{{"command": "status"}}"#).unwrap();
+            reader.read_line(&mut line).unwrap();
+                assert_eq!(serde_json::from_str::<Value>(&line).unwrap()["jobs"]["www"]["runs"], 0);
+
+            writeln!(stream, r#"{{"command": "shutdown"}}"#).unwrap();
+            drop(stream);
+
+            assert!(server.join().unwrap().is_ok());
+
+        });
+
+        assert!(!socket.exists());
+
+    }
+
+    #[test]
+    fn keep_foreign_control_socket_paths() {
+
+        let _lock = prepare_test_directory();
+        let daemon = daemon();
+        let socket = Path::new("test_files/control.sock");
+
+        write(socket, "not a socket").unwrap();
+        assert!(daemon.serve(socket).is_err());
+            assert!(socket.is_file());
+
+        remove_file(socket).unwrap();
+        let listener = UnixListener::bind(socket).unwrap();
+        assert!(daemon.serve(socket).is_err());
+            assert!(UnixStream::connect(socket).is_ok());
+
+        // Nobody listens anymore, the socket is stale
+        drop(listener);
+        daemon.stop();
+        assert!(daemon.serve(socket).is_ok());
+
+    }
+
+}
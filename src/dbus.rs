@@ -0,0 +1,214 @@
+//! D-Bus service letting desktop frontends drive merges without spawning processes
+
+use std::path::Path;
+use anyhow::Result;
+use async_channel::Receiver;
+use blocking::unblock;
+use zbus::blocking::Connection;
+use zbus::blocking::connection::Builder;
+use zbus::fdo;
+use zbus::interface;
+use zbus::message::Header;
+use zbus::object_server::SignalEmitter;
+use crate::{MergeOptions, Operation, OperationKind, Overwrite, UnmergeOptions, verify};
+use crate::merge::Walk;
+
+/// Well-known bus name claimed by [serve_session] and [serve_system]
+pub const BUS_NAME: &str = "io.github.lamka02sk.Solderium";
+/// Object path the [Service] is exported at
+pub const OBJECT_PATH: &str = "/io/github/lamka02sk/Solderium";
+/// Progress updates waiting for the bus beyond this number are dropped, so a slow bus never holds up the work
+const PROGRESS_BACKLOG: usize = 64;
+
+/// Object implementing the `io.github.lamka02sk.Solderium` interface.
+///
+/// - `Merge(source, target, overwrite) -> warnings` merges the directories, `overwrite` is one of
+///   `all`, `dirs`, `files`, `foreign-links-only`, `if-different` or `none`, see [Overwrite]
+/// - `Verify(source, target, overwrite) -> pending` reports the number of changes a merge would make
+/// - `Unmerge(source, target, materialize) -> count` removes symlinks pointing into the source (or replaces
+///   them with copies, see [UnmergeOptions::materialize](crate::UnmergeOptions::materialize))
+///
+/// Progress is reported by the `Started(operation, target, total)` signal, emitted once the changes
+/// are planned, the `Progress(operation, target, done, total)` signal after processed entries (changes
+/// made by merge, symlinks handled by unmerge, `total` is `0` when not known up front) and the
+/// `Finished(operation, target, success, message)` signal. The work itself runs on a blocking thread
+/// pool, so a long merge doesn't stall other requests or the signals. Progress updates the bus can't
+/// keep up with are dropped.
+///
+/// Only root and the user running the service may call the methods, the caller is identified by the
+/// Unix user of its bus connection. This keeps the service exported on the system bus (running as root)
+/// from letting other local users replace or remove arbitrary paths.
+#[derive(Clone, Debug, Default)]
+pub struct Service;
+
+#[interface(name = "io.github.lamka02sk.Solderium")]
+impl Service {
+
+    async fn merge(
+        &self, source: &str, target: &str, overwrite: &str,
+        #[zbus(header)] header: Header<'_>, #[zbus(connection)] connection: &zbus::Connection, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>
+    ) -> fdo::Result<Vec<String>> {
+
+        authorize(&header, connection).await?;
+        let result = Self::run(source, target, overwrite, &emitter).await;
+
+        let message = match &result {
+            Ok(warnings) => format!("{} warnings", warnings.len()),
+            Err(error) => error.to_string()
+        };
+
+        Self::finished(&emitter, "merge", target, result.is_ok(), &message).await?;
+        result
+
+    }
+
+    async fn verify(&self, source: &str, target: &str, overwrite: &str, #[zbus(header)] header: Header<'_>, #[zbus(connection)] connection: &zbus::Connection) -> fdo::Result<u64> {
+
+        authorize(&header, connection).await?;
+
+        let options = options(overwrite)?;
+        let (source, target) = (source.to_owned(), target.to_owned());
+
+        unblock(move || verify(Path::new(&source), Path::new(&target), &options).map(|pending| pending.len() as u64).map_err(failed)).await
+
+    }
+
+    async fn unmerge(
+        &self, source: &str, target: &str, materialize: bool,
+        #[zbus(header)] header: Header<'_>, #[zbus(connection)] connection: &zbus::Connection, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>
+    ) -> fdo::Result<u64> {
+
+        authorize(&header, connection).await?;
+        let (source_path, target_path) = (source.to_owned(), target.to_owned());
+        let (operation, updates) = reporting();
+
+        let task = unblock(move || {
+            operation.unmerge(Path::new(&source_path), Path::new(&target_path), &UnmergeOptions { materialize, ..Default::default() })
+                .map(|report| (report.removed.len() + report.materialized.len()) as u64)
+                .map_err(failed)
+        });
+
+        Self::forward(&emitter, "unmerge", target, updates).await;
+        let result = task.await;
+
+        let message = match &result {
+            Ok(count) => format!("{count} symlinks"),
+            Err(error) => error.to_string()
+        };
+
+        Self::finished(&emitter, "unmerge", target, result.is_ok(), &message).await?;
+        result
+
+    }
+
+    /// Changes were planned and `total` of them are about to be made
+    #[zbus(signal)]
+    async fn started(emitter: &SignalEmitter<'_>, operation: &str, target: &str, total: u64) -> zbus::Result<()>;
+
+    /// Operation processed `done` entries out of `total`, which is `0` when not known up front
+    #[zbus(signal)]
+    async fn progress(emitter: &SignalEmitter<'_>, operation: &str, target: &str, done: u64, total: u64) -> zbus::Result<()>;
+
+    /// Operation ended, `message` holds the error when it failed
+    #[zbus(signal)]
+    async fn finished(emitter: &SignalEmitter<'_>, operation: &str, target: &str, success: bool, message: &str) -> zbus::Result<()>;
+
+}
+
+impl Service {
+
+    async fn run(source: &str, target: &str, overwrite: &str, emitter: &SignalEmitter<'_>) -> fdo::Result<Vec<String>> {
+
+        let options = options(overwrite)?;
+        let (source, target_path) = (source.to_owned(), target.to_owned());
+        let (planned, total) = async_channel::bounded(1);
+        let (operation, updates) = reporting();
+
+        // Planning and execution share the walk, so both run on the same blocking thread
+        let task = unblock(move || {
+
+            let walk = Walk::new(Path::new(&source), Path::new(&target_path), &options).map_err(failed)?
+                .operation(operation, OperationKind::Merge);
+            let ops = walk.plan().map_err(failed)?;
+
+            let _ = planned.send_blocking(ops.len() as u64);
+            walk.execute(ops).map_err(failed)
+
+        });
+
+        // Closed channel means the planning failed, the task holds the error
+        if let Ok(total) = total.recv().await {
+            Self::started(emitter, "merge", target, total).await?;
+        }
+
+        Self::forward(emitter, "merge", target, updates).await;
+
+        let report = task.await?;
+        Ok(report.warnings.iter().map(ToString::to_string).collect())
+
+    }
+
+    /// Emit progress updates until the operation is done, updates of the bus failing are dropped
+    async fn forward(emitter: &SignalEmitter<'_>, operation: &str, target: &str, updates: Receiver<(u64, u64)>) {
+        while let Ok((done, total)) = updates.recv().await {
+            if Self::progress(emitter, operation, target, done, total).await.is_err() {
+                break;
+            }
+        }
+    }
+
+}
+
+/// Export the [Service] on the session bus, the connection serves requests until dropped
+pub fn serve_session() -> Result<Connection> {
+    serve(Builder::session()?)
+}
+
+/// Export the [Service] on the system bus, the connection serves requests until dropped
+pub fn serve_system() -> Result<Connection> {
+    serve(Builder::system()?)
+}
+
+fn serve(builder: Builder<'_>) -> Result<Connection> {
+    Ok(builder.name(BUS_NAME)?.serve_at(OBJECT_PATH, Service)?.build()?)
+}
+
+/// Allow only root and the user running the service to call it
+async fn authorize(header: &Header<'_>, connection: &zbus::Connection) -> fdo::Result<()> {
+
+    let sender = header.sender().ok_or_else(|| fdo::Error::AccessDenied("Caller of the method is unknown".into()))?;
+    let caller = fdo::DBusProxy::new(connection).await?.get_connection_unix_user(sender.clone().into()).await?;
+    // SAFETY: geteuid has no preconditions and can't fail
+    let service = unsafe { libc::geteuid() };
+
+    match caller == 0 || caller == service {
+        true => Ok(()),
+        false => Err(fdo::Error::AccessDenied(format!("User ({caller}) isn't allowed to use the service running as user ({service})")))
+    }
+
+}
+
+/// Operation sending its progress (processed entries and their total, `0` when not known) through the channel.
+///
+/// Merge planning only counts examined source entries, those updates are left out, as the changes follow.
+fn reporting() -> (Operation, Receiver<(u64, u64)>) {
+
+    let (sender, updates) = async_channel::bounded(PROGRESS_BACKLOG);
+    let operation = Operation::new().on_progress(move |progress| {
+        if progress.operation != OperationKind::Merge || progress.total.is_some() {
+            let _ = sender.try_send((progress.done as u64, progress.total.unwrap_or_default() as u64));
+        }
+    });
+
+    (operation, updates)
+
+}
+
+fn options(overwrite: &str) -> fdo::Result<MergeOptions> {
+    let overwrite = overwrite.parse::<Overwrite>().map_err(|error| fdo::Error::InvalidArgs(error.to_string()))?;
+    Ok(MergeOptions { overwrite, ..Default::default() })
+}
+
+fn failed(error: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{error:#}"))
+}
@@ -0,0 +1,169 @@
+use std::collections::{HashMap, HashSet};
+#[cfg(unix)]
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::{ChangeKind, MergeOptions};
+use crate::merge::{backup_path, Op, Walk};
+#[cfg(unix)]
+use libc::{R_OK as READ, W_OK as WRITE, X_OK as SEARCH};
+
+/// Access modes checked by [access], named after their `access(2)` counterparts
+#[cfg(windows)]
+const READ: libc::c_int = 4;
+#[cfg(windows)]
+const WRITE: libc::c_int = 2;
+#[cfg(windows)]
+const SEARCH: libc::c_int = 1;
+
+/// Single action a merge would take, see [plan_symlinks].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlannedAction {
+    CreateSymlink { source: PathBuf, target: PathBuf },
+    CreateHardlink { source: PathBuf, target: PathBuf },
+    CopyFile { source: PathBuf, target: PathBuf },
+    CreateDirectory { target: PathBuf },
+    /// Existing file or symlink is removed to make room
+    RemoveFile { path: PathBuf },
+    /// Existing directory is removed to make room, including its content
+    RemoveDirectory { path: PathBuf },
+    /// Existing path is renamed to make room, see [MergeOptions::backup] and [MergeOptions::soft_delete]
+    Backup { path: PathBuf, backup: PathBuf },
+    /// Existing path is left untouched because of the keep marker
+    SkipKept { path: PathBuf, marker: PathBuf }
+}
+
+/// Actions merging `source` into `target` would take, in the order they would be taken, without taking any of them.
+///
+/// Changes only [simulated](MergeOptions::simulate) are left out, target paths kept by keep markers come last.
+pub fn plan_symlinks(source: &Path, target: &Path, options: &MergeOptions) -> Result<Vec<PlannedAction>> {
+
+    let walk = Walk::new(source, target, options)?;
+    let (_, ops) = walk.simulated(walk.plan()?)?;
+    let mut actions = Vec::new();
+
+    for change in ops.iter().map(Op::change) {
+
+        if change.replace {
+            actions.push(removal(change.target.clone(), walk.backup.as_deref()));
+        }
+
+        actions.push(match change.kind {
+            ChangeKind::Symlink => PlannedAction::CreateSymlink { source: change.source, target: change.target },
+            ChangeKind::Hardlink => PlannedAction::CreateHardlink { source: change.source, target: change.target },
+            ChangeKind::Copy => PlannedAction::CopyFile { source: change.source, target: change.target },
+            ChangeKind::Directory => PlannedAction::CreateDirectory { target: change.target }
+        });
+
+    }
+
+    actions.extend(walk.kept().into_iter().map(|(path, marker)| PlannedAction::SkipKept { path, marker }));
+
+    Ok(actions)
+
+}
+
+/// Planned action the process lacks permissions for, see [check_permissions].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Denial {
+    /// Index of the action in the plan
+    pub action: usize,
+    /// Path the action can't access as it needs to
+    pub path: PathBuf,
+    /// How the access fails, e.g. [ErrorKind::PermissionDenied] or [ErrorKind::ReadOnlyFilesystem]
+    pub kind: ErrorKind
+}
+
+/// Predict which of the planned actions would fail on permissions, without taking any of them.
+///
+/// Every directory an action modifies is checked for write and search access of the effective user
+/// (like `access(2)` would), copied sources for read access. Directories created by earlier actions
+/// are assumed accessible. Restrictions `access(2)` can't see (e.g. the sticky bit) aren't predicted.
+/// Windows has no `access(2)`, only missing paths and read-only files are predicted there.
+pub fn check_permissions(actions: &[PlannedAction]) -> Vec<Denial> {
+
+    let mut created: HashSet<&Path> = HashSet::new();
+    let mut checked: HashMap<(&Path, libc::c_int), Option<ErrorKind>> = HashMap::new();
+    let mut denials = Vec::new();
+    let modify = WRITE | SEARCH;
+
+    for (index, action) in actions.iter().enumerate() {
+
+        let needed = match action {
+            PlannedAction::CreateSymlink { target, .. } | PlannedAction::CreateHardlink { target, .. } => vec![(parent(target), modify)],
+            PlannedAction::CopyFile { source, target } => vec![(source.as_path(), READ), (parent(target), modify)],
+            PlannedAction::CreateDirectory { target } => {
+                created.insert(target);
+                vec![(parent(target), modify)]
+            },
+            PlannedAction::RemoveFile { path } | PlannedAction::Backup { path, .. } => vec![(parent(path), modify)],
+            // Content of the directory is removed as well
+            PlannedAction::RemoveDirectory { path } => vec![(parent(path), modify), (path.as_path(), modify)],
+            PlannedAction::SkipKept { .. } => Vec::new()
+        };
+
+        for (path, mode) in needed.into_iter().filter(|(path, _)| !created.contains(path)) {
+
+            let denied = *checked.entry((path, mode)).or_insert_with(|| access(path, mode).err().map(|error| error.kind()));
+
+            if let Some(kind) = denied {
+                denials.push(Denial { action: index, path: path.to_path_buf(), kind });
+            }
+
+        }
+
+    }
+
+    denials
+
+}
+
+/// Directory modified when changing the path
+fn parent(path: &Path) -> &Path {
+    path.parent().unwrap_or(path)
+}
+
+/// Check access of the effective user to the path, see `faccessat(2)`
+#[cfg(unix)]
+fn access(path: &Path, mode: libc::c_int) -> io::Result<()> {
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    // SAFETY: the path is a valid NUL terminated string living until the call returns
+    match unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error())
+    }
+
+}
+
+/// Check the path exists and files needing write access aren't read-only
+#[cfg(windows)]
+fn access(path: &Path, mode: libc::c_int) -> io::Result<()> {
+
+    let metadata = path.metadata()?;
+
+    match mode & WRITE != 0 && metadata.is_file() && metadata.permissions().readonly() {
+        true => Err(ErrorKind::PermissionDenied.into()),
+        false => Ok(())
+    }
+
+}
+
+/// How the existing target path makes room for the change
+fn removal(path: PathBuf, backup: Option<&str>) -> PlannedAction {
+
+    if let Some(suffix) = backup {
+        return PlannedAction::Backup { backup: backup_path(&path, suffix), path };
+    }
+
+    // Symlinks to directories are removed as files
+    match path.symlink_metadata().is_ok_and(|metadata| metadata.is_dir()) {
+        true => PlannedAction::RemoveDirectory { path },
+        false => PlannedAction::RemoveFile { path }
+    }
+
+}
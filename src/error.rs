@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use libc::ELOOP;
+use crate::MergeOptions;
+use crate::merge::Walk;
+
+/// Kind of failure of any solderium call, to match on instead of inspecting [anyhow::Error] messages.
+///
+/// Public functions keep returning [anyhow::Result], convert their errors using `SolderiumError::from`.
+/// Errors the kind isn't known for end up as [SolderiumError::Other], the whole original error is
+/// available as the [source](std::error::Error::source) of the io based kinds.
+#[derive(Debug)]
+pub enum SolderiumError {
+    /// Source directory doesn't exist
+    SourceMissing(PathBuf),
+    /// Source contains a keep marker, see [SourceKeepMarkers::Error](crate::SourceKeepMarkers::Error)
+    KeepFileConflict(PathBuf),
+    /// Operating system refused access to a path
+    PermissionDenied(anyhow::Error),
+    /// Path couldn't be moved, because it would cross filesystems
+    CrossFilesystem(anyhow::Error),
+    /// Path couldn't be resolved, because of too many (or cyclic) symlinks
+    SymlinkLoop(anyhow::Error),
+    OutOfSpace(OutOfSpace),
+    SourceUnavailable(SourceUnavailable),
+    TooManyEntries(TooManyEntries),
+    PackageConflicts(PackageConflicts),
+    Other(anyhow::Error)
+}
+
+impl From<anyhow::Error> for SolderiumError {
+    fn from(error: anyhow::Error) -> Self {
+
+        // Typed errors attached as a context are only found by downcasting the whole error
+        let error = match error.downcast::<SolderiumError>() {
+            Ok(typed) => return typed,
+            Err(error) => error
+        };
+
+        let error = match error.downcast::<OutOfSpace>() {
+            Ok(typed) => return SolderiumError::OutOfSpace(typed),
+            Err(error) => error
+        };
+
+        let error = match error.downcast::<SourceUnavailable>() {
+            Ok(typed) => return SolderiumError::SourceUnavailable(typed),
+            Err(error) => error
+        };
+
+        let error = match error.downcast::<TooManyEntries>() {
+            Ok(typed) => return SolderiumError::TooManyEntries(typed),
+            Err(error) => error
+        };
+
+        let error = match error.downcast::<PackageConflicts>() {
+            Ok(typed) => return SolderiumError::PackageConflicts(typed),
+            Err(error) => error
+        };
+
+        let cause = error.chain().find_map(|cause| cause.downcast_ref::<io::Error>()).map(|cause| (cause.kind(), cause.raw_os_error()));
+
+        match cause {
+            Some((ErrorKind::PermissionDenied, _)) => SolderiumError::PermissionDenied(error),
+            Some((ErrorKind::CrossesDevices, _)) => SolderiumError::CrossFilesystem(error),
+            Some((_, Some(ELOOP))) => SolderiumError::SymlinkLoop(error),
+            _ => SolderiumError::Other(error)
+        }
+
+    }
+}
+
+impl fmt::Display for SolderiumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolderiumError::SourceMissing(source) => write!(f, "Source directory ({source:?}) doesn't exist"),
+            SolderiumError::KeepFileConflict(path) => write!(f, "Source ({path:?}) contains a keep marker"),
+            SolderiumError::PermissionDenied(error) => write!(f, "Permission denied: {error}"),
+            SolderiumError::CrossFilesystem(error) => write!(f, "Can't move across filesystems: {error}"),
+            SolderiumError::SymlinkLoop(error) => write!(f, "Too many levels of symlinks: {error}"),
+            SolderiumError::OutOfSpace(error) => error.fmt(f),
+            SolderiumError::SourceUnavailable(error) => error.fmt(f),
+            SolderiumError::TooManyEntries(error) => error.fmt(f),
+            SolderiumError::PackageConflicts(error) => error.fmt(f),
+            SolderiumError::Other(error) => error.fmt(f)
+        }
+    }
+}
+
+impl std::error::Error for SolderiumError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SolderiumError::PermissionDenied(error) | SolderiumError::CrossFilesystem(error) | SolderiumError::SymlinkLoop(error) | SolderiumError::Other(error) => error.source(),
+            _ => None
+        }
+    }
+}
+
+/// Merge stopped, because the target filesystem ran out of space (or the disk quota was exceeded).
+///
+/// No new changes are started once it happens, partially copied file is removed and the error
+/// is returned from [merge](crate::merge) instead of the underlying io error. Find it using
+/// [anyhow::Error::downcast_ref] and call [OutOfSpace::resume] after freeing some space.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutOfSpace {
+    /// Number of target changes completed before running out of space
+    pub completed: usize,
+    /// Target path being created when running out of space
+    pub failed: PathBuf,
+    /// Target paths not created yet, including the failed one
+    pub remaining: Vec<PathBuf>
+}
+
+impl OutOfSpace {
+
+    /// Merge only the entries remaining from the interrupted run, using the same source, target and options
+    pub fn resume(&self, source: &Path, target: &Path, options: &MergeOptions) -> Result<()> {
+        resume(&self.remaining, source, target, options)
+    }
+
+}
+
+impl fmt::Display for OutOfSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Out of space after {} entries while creating ({:?}), {} entries remaining", self.completed, self.failed, self.remaining.len())
+    }
+}
+
+impl std::error::Error for OutOfSpace {}
+
+/// Merge stopped, because the source directory was removed, unmounted or replaced while running.
+///
+/// Returned from [merge](crate::merge) instead of errors of the individual entries. When it happens while
+/// planning, nothing was changed and `remaining` is empty. Otherwise call [SourceUnavailable::resume]
+/// once the source is back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceUnavailable {
+    pub source: PathBuf,
+    /// Number of target changes completed before the source disappeared
+    pub completed: usize,
+    /// Target paths not created yet
+    pub remaining: Vec<PathBuf>
+}
+
+impl SourceUnavailable {
+
+    /// Merge only the entries remaining from the interrupted run, using the same source, target and options
+    pub fn resume(&self, source: &Path, target: &Path, options: &MergeOptions) -> Result<()> {
+        resume(&self.remaining, source, target, options)
+    }
+
+}
+
+impl fmt::Display for SourceUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Source directory ({:?}) disappeared after {} entries, {} entries remaining", self.source, self.completed, self.remaining.len())
+    }
+}
+
+impl std::error::Error for SourceUnavailable {}
+
+fn resume(remaining: &[PathBuf], source: &Path, target: &Path, options: &MergeOptions) -> Result<()> {
+
+    let walk = Walk::new(source, target, options)?;
+    let remaining: HashSet<&Path> = remaining.iter().map(PathBuf::as_path).collect();
+
+    let mut ops = walk.plan()?;
+    ops.retain(|op| remaining.contains(op.target.as_path()));
+
+    let _maintenance = walk.maintenance()?;
+    walk.apply(ops)
+
+}
+
+/// Check whether the error was caused by a full filesystem or exceeded quota
+pub(crate) fn is_out_of_space(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded))
+    })
+}
+
+/// Merge would push a target directory over the configured entry limit, see [EntryLimit](crate::EntryLimit).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TooManyEntries {
+    pub directory: PathBuf,
+    /// Number of entries the directory would contain after the merge
+    pub entries: usize,
+    pub limit: usize
+}
+
+impl fmt::Display for TooManyEntries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "Merge would put {} entries into ({:?}), over the limit of {}, consider symlinking the directory as a whole instead",
+            self.entries, self.directory, self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooManyEntries {}
+
+/// Package can't be stowed, because its entries would replace paths it doesn't own, see `Package::stow` of the `manifest` feature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageConflicts {
+    pub package: String,
+    /// Existing target paths in the way, ordered
+    pub paths: Vec<PathBuf>
+}
+
+impl fmt::Display for PackageConflicts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Package ({}) conflicts with {} existing target paths, first one ({:?})", self.package, self.paths.len(), self.paths.first().map(PathBuf::as_path).unwrap_or(Path::new("")))
+    }
+}
+
+impl std::error::Error for PackageConflicts {}
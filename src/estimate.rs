@@ -0,0 +1,154 @@
+use std::fs::{read_dir, symlink_metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use anyhow::{bail, Context, Result};
+use crate::{Concurrency, MergeOptions};
+use crate::merge::{OpKind, source_root, Walk};
+use crate::pool::for_each_queued;
+
+/// Size of the work a merge would do, see [estimate] function.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// Number of examined source entries
+    pub entries: usize,
+    /// Number of symlinks the merge would create
+    pub expected_links: usize,
+    /// Number of existing target paths the merge would remove before replacing them
+    pub expected_removals: usize,
+    /// Size of regular files inside the target paths the merge would remove, symlinks don't count
+    pub bytes_at_risk: u64
+}
+
+/// Estimate what merging `source` into `target` with given options would do, without changing anything.
+///
+/// The source is walked exactly like by [merge](crate::merge) (including the parallel mode), so the
+/// estimate is exact at the time of the call. Useful for ordering batch jobs or as a sanity check
+/// before confirming a destructive run.
+pub fn estimate(source: &Path, target: &Path, options: &MergeOptions) -> Result<Estimate> {
+
+    let walk = Walk::new(source, target, options)?;
+    let ops = walk.plan()?;
+
+    let mut estimate = Estimate { entries: walk.visited.load(Ordering::Relaxed), ..Default::default() };
+
+    for op in &ops {
+
+        if matches!(op.kind, OpKind::Symlink | OpKind::Hardlink) {
+            estimate.expected_links += 1;
+        }
+
+        if op.replace {
+            estimate.expected_removals += 1;
+            estimate.bytes_at_risk += file_bytes(&op.target);
+        }
+
+    }
+
+    Ok(estimate)
+
+}
+
+/// Size and shape of a source tree, see [analyze] function.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Number of regular files and special files
+    pub files: usize,
+    /// Number of directories, not counting the root
+    pub dirs: usize,
+    pub symlinks: usize,
+    /// Depth of the deepest entry, top-level entries have depth 1
+    pub max_depth: usize,
+    /// Size of regular files
+    pub total_bytes: u64,
+    /// Directory with the most entries together with their number, `None` for an empty tree
+    pub widest_dir: Option<(PathBuf, usize)>
+}
+
+impl TreeStats {
+
+    /// Number of all entries, i.e. inodes a full copy would take
+    pub fn entries(&self) -> usize {
+        self.files + self.dirs + self.symlinks
+    }
+
+    fn add(&mut self, other: TreeStats) {
+
+        self.files += other.files;
+        self.dirs += other.dirs;
+        self.symlinks += other.symlinks;
+        self.max_depth = self.max_depth.max(other.max_depth);
+        self.total_bytes += other.total_bytes;
+
+        if other.widest_dir.as_ref().map(|(_, entries)| entries) > self.widest_dir.as_ref().map(|(_, entries)| entries) {
+            self.widest_dir = other.widest_dir;
+        }
+
+    }
+
+}
+
+/// Gather statistics of the `source` tree, e.g. to size targets, quotas and inode budgets before the first deployment.
+///
+/// Directories are listed on up to [Concurrency::traversal] workers like by [merge](crate::merge), symlinks are
+/// counted but never followed.
+pub fn analyze(source: &Path, concurrency: Concurrency) -> Result<TreeStats> {
+
+    let source = source_root(source)?;
+
+    if !source.is_dir() {
+        bail!("Make sure the source path is a directory");
+    }
+
+    let stats = Mutex::new(TreeStats::default());
+
+    for_each_queued(concurrency.traversal, vec![(source, 1)], |(directory, depth), queue| {
+
+        let mut found = TreeStats::default();
+        let mut entries = 0;
+
+        for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let entry = entry.with_context(|| "Reading source directory entry has failed")?;
+            let metadata = entry.metadata().with_context(|| format!("Couldn't read metadata ({:?})", entry.path()))?;
+
+            entries += 1;
+            found.max_depth = depth;
+
+            if metadata.is_dir() {
+                found.dirs += 1;
+                queue.push((entry.path(), depth + 1));
+            } else if metadata.is_symlink() {
+                found.symlinks += 1;
+            } else {
+                found.files += 1;
+                // Special files have no size of their own
+                if metadata.is_file() {
+                    found.total_bytes += metadata.len();
+                }
+            }
+
+        }
+
+        found.widest_dir = Some((directory, entries)).filter(|_| entries > 0);
+        stats.lock().unwrap().add(found);
+
+        Ok(())
+
+    })?;
+
+    Ok(stats.into_inner().unwrap())
+
+}
+
+/// Total size of regular files at the path, unreadable entries are skipped
+fn file_bytes(path: &Path) -> u64 {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(metadata) if metadata.is_dir() => match read_dir(path) {
+            Ok(listing) => listing.flatten().map(|entry| file_bytes(&entry.path())).sum(),
+            Err(_) => 0
+        },
+        _ => 0
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use crate::{Glob, Identity, MaterializeRule, MergeOptions, Overwrite, Resolution, Strategy};
+use crate::merge::{Materialization, Step, Walk};
+use crate::normalize::normalize_rel_path;
+
+/// What the merge would do with a single path and why, see [explain] function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Explanation {
+    /// Explained path, relative to the source (and target) directory
+    pub path: PathBuf,
+    /// What would happen with the path
+    pub verdict: Verdict,
+    /// Everything that led to the verdict, in the order it was considered
+    pub reasons: Vec<Reason>
+}
+
+/// What the merge would do with a single path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Symlink would be created, nothing exists in the target yet
+    Link,
+    /// Existing target path would be deleted and replaced by a symlink
+    Replace,
+    /// File would be copied, optionally replacing the existing target path
+    Copy { replace: bool },
+    /// Real directory would be created, optionally replacing the existing target path, and its entries merged individually
+    CreateDirectory { replace: bool },
+    /// Both paths are directories, their entries would be merged individually
+    Descend,
+    /// Path would be left untouched
+    Skip,
+    /// Path would be reachable through a symlink created for its ancestor
+    LinkedByAncestor(PathBuf),
+    /// Path would be left untouched together with its whole ancestor
+    SkippedWithAncestor(PathBuf),
+    /// Path doesn't exist in the source directory
+    MissingInSource
+}
+
+/// Single fact the merge considered when deciding about a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// Nothing exists at the target path
+    TargetMissing,
+    /// Something already exists at the target path
+    TargetExists { directory: bool },
+    /// Overwrite policy applied to the existing target path
+    Overwrite(Overwrite),
+    /// Conflict hook decided about the existing target path, see [MergeOptions::on_conflict](crate::MergeOptions::on_conflict)
+    Resolved(Resolution),
+    /// Keep marker protecting the target path
+    KeepMarker(PathBuf),
+    /// Existing target path can't be merged into, as it's not a directory on both sides
+    NotBothDirectories,
+    /// Last materialize rule matching the path
+    MaterializeRule(MaterializeRule),
+    /// Directory contains entries which have to be copied, so it can't be symlinked as a whole
+    CopiedBelow,
+    /// Target path was copied once already and is kept, see [Materialize::CopyOnce](crate::Materialize::CopyOnce)
+    CopiedOnce,
+    /// Directories can't be hard linked, see [LinkKind::Hardlink](crate::LinkKind::Hardlink)
+    HardlinkedDirectory,
+    /// Source entry is a keep marker left out, see [SourceKeepMarkers](crate::SourceKeepMarkers)
+    SourceKeepMarker,
+    /// Entry name is reserved for solderium's own metadata, see [is_reserved](crate::is_reserved)
+    Reserved,
+    /// Path matches the exclude pattern
+    Excluded(Glob),
+    /// Source entry is an ignore file, see [MergeOptions::ignore_files](crate::MergeOptions::ignore_files)
+    IgnoreFile,
+    /// Path matches the pattern of given ignore file
+    Ignored { file: PathBuf, pattern: Glob },
+    /// Directory holds an ignore file, so it can't be symlinked as a whole
+    IgnoreFileBelow,
+    /// File doesn't pass the size or modification time limits
+    Filtered,
+    /// File doesn't match any include pattern
+    NotIncluded,
+    /// Directory doesn't match any include pattern, so only some of its entries may be merged
+    PartlyIncluded,
+    /// Merge strategy changed what happens with the directory
+    Strategy(Strategy),
+    /// Target path already leads to the source entry, recognized using given identity
+    AlreadyMerged(Identity),
+    /// Existing target path matches the protect pattern, so it can't be replaced
+    Protected(Glob),
+    /// Target directory is managed by another deployment, recognized by given marker
+    NestedDeployment(PathBuf),
+    /// Symlink chain at the target is longer than the limit, so it was not followed
+    LinkChainTooLong(usize),
+    /// Target file has the same content as the source, see [Overwrite::IfDifferent](crate::Overwrite::IfDifferent)
+    SameContent,
+    /// Source entry is at the deepest level merged one by one, see [MergeOptions::max_depth](crate::MergeOptions::max_depth)
+    MaxDepth(usize),
+    /// Source entry is above the shallowest placed level, see [MergeOptions::min_depth](crate::MergeOptions::min_depth)
+    MinDepth(usize),
+    /// Target directory is a symlink, which a confined merge never follows, see [MergeOptions::confine_target](crate::MergeOptions::confine_target)
+    SymlinkedTarget
+}
+
+/// Collects reasons behind a decision, only when explaining
+pub(crate) enum Trace {
+    Off,
+    On(Vec<Reason>)
+}
+
+impl Trace {
+
+    pub(crate) fn note(&mut self, reason: impl FnOnce() -> Reason) {
+        if let Trace::On(reasons) = self {
+            reasons.push(reason());
+        }
+    }
+
+    pub(crate) fn into_reasons(self) -> Vec<Reason> {
+        match self {
+            Trace::Off => Vec::new(),
+            Trace::On(reasons) => reasons
+        }
+    }
+
+}
+
+/// Explain what the merge of `source` into `target` would do with a single `path` (relative to both of them).
+///
+/// Nothing is modified, only the given path and its ancestors are examined, so it's much cheaper than a full dry run.
+pub fn explain(source: &Path, target: &Path, options: &MergeOptions, path: &Path) -> Result<Explanation> {
+
+    let walk = Walk::new(source, target, options)?;
+    let relative = normalize_rel_path(path).with_context(|| format!("Path to explain ({path:?}) has to be relative to the source directory"))?;
+
+    let depth = relative.components().count();
+
+    if depth == 0 {
+        bail!("Path to explain must not be empty");
+    }
+
+    let source_path = walk.source.join(&relative);
+
+    if !source_path.exists() && !source_path.is_symlink() {
+        return Ok(Explanation { path: relative, verdict: Verdict::MissingInSource, reasons: Vec::new() });
+    }
+
+    let mut current = PathBuf::new();
+    let mut fresh = false;
+    let mut materialize = Materialization::default();
+
+    for (level, name) in relative.components().enumerate() {
+
+        current.push(name);
+
+        let mut trace = Trace::On(Vec::new());
+        let step = walk.step(&walk.source.join(&current), &current, fresh, materialize, &mut trace)?;
+        let last = level + 1 == depth;
+
+        let verdict = match step {
+            Step::Descend { materialize: inherited } if !last => {
+                materialize = inherited;
+                continue;
+            },
+            Step::Mirror { materialize: inherited, .. } if !last => {
+                (fresh, materialize) = (true, inherited);
+                continue;
+            },
+            Step::Descend { .. } => Verdict::Descend,
+            Step::Mirror { replace, .. } => Verdict::CreateDirectory { replace },
+            Step::Symlink { replace } if last => match replace {
+                true => Verdict::Replace,
+                false => Verdict::Link
+            },
+            Step::Symlink { .. } => Verdict::LinkedByAncestor(current),
+            Step::Copy { replace, .. } => Verdict::Copy { replace },
+            Step::Skip | Step::Merged if last => Verdict::Skip,
+            Step::Skip | Step::Merged => Verdict::SkippedWithAncestor(current)
+        };
+
+        return Ok(Explanation { path: relative, verdict, reasons: trace.into_reasons() });
+
+    }
+
+    unreachable!("Explained path has at least one component")
+
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{}: ", self.path.display())?;
+
+        match &self.verdict {
+            Verdict::Link => writeln!(f, "will be symlinked")?,
+            Verdict::Replace => writeln!(f, "will be replaced by a symlink")?,
+            Verdict::Copy { replace: false } => writeln!(f, "will be copied")?,
+            Verdict::Copy { replace: true } => writeln!(f, "will be replaced by a copy")?,
+            Verdict::CreateDirectory { replace: false } => writeln!(f, "will be created as a directory and merged entry by entry")?,
+            Verdict::CreateDirectory { replace: true } => writeln!(f, "will be replaced by a new directory and merged entry by entry")?,
+            Verdict::Descend => writeln!(f, "will be merged entry by entry")?,
+            Verdict::Skip => writeln!(f, "will be left untouched")?,
+            Verdict::LinkedByAncestor(ancestor) => writeln!(f, "will be reachable through symlinked ({})", ancestor.display())?,
+            Verdict::SkippedWithAncestor(ancestor) => writeln!(f, "will be left untouched together with ({})", ancestor.display())?,
+            Verdict::MissingInSource => writeln!(f, "doesn't exist in the source directory")?
+        }
+
+        for reason in &self.reasons {
+            match reason {
+                Reason::TargetMissing => writeln!(f, "  - nothing exists in the target")?,
+                Reason::TargetExists { directory: true } => writeln!(f, "  - directory exists in the target")?,
+                Reason::TargetExists { directory: false } => writeln!(f, "  - file exists in the target")?,
+                Reason::Overwrite(overwrite) => writeln!(f, "  - overwrite policy is {overwrite:?}")?,
+                Reason::Resolved(resolution) => writeln!(f, "  - conflict hook resolved it as {resolution:?}")?,
+                Reason::KeepMarker(marker) => writeln!(f, "  - protected by keep marker ({})", marker.display())?,
+                Reason::NotBothDirectories => writeln!(f, "  - source and target are not both directories")?,
+                Reason::MaterializeRule(rule) => match rule.mode {
+                    Some(mode) => writeln!(f, "  - matches {:?} rule ({}) with mode {mode:o}", rule.materialize, rule.pattern)?,
+                    None => writeln!(f, "  - matches {:?} rule ({})", rule.materialize, rule.pattern)?
+                },
+                Reason::CopiedBelow => writeln!(f, "  - contains entries which have to be copied")?,
+                Reason::CopiedOnce => writeln!(f, "  - was copied once already, the copy is kept")?,
+                Reason::HardlinkedDirectory => writeln!(f, "  - directories can't be hard linked")?,
+                Reason::SourceKeepMarker => writeln!(f, "  - keep marker of the source tree")?,
+                Reason::Reserved => writeln!(f, "  - name is reserved for solderium's own metadata")?,
+                Reason::Excluded(pattern) => writeln!(f, "  - excluded by pattern ({pattern})")?,
+                Reason::IgnoreFile => writeln!(f, "  - ignore file of the source tree")?,
+                Reason::Ignored { file, pattern } => writeln!(f, "  - ignored by pattern ({pattern}) of {}", file.display())?,
+                Reason::IgnoreFileBelow => writeln!(f, "  - contains an ignore file")?,
+                Reason::Filtered => writeln!(f, "  - doesn't pass the size or modification time limits")?,
+                Reason::NotIncluded => writeln!(f, "  - doesn't match any include pattern")?,
+                Reason::PartlyIncluded => writeln!(f, "  - doesn't match any include pattern, its entries are picked one by one")?,
+                Reason::Strategy(strategy) => writeln!(f, "  - merge strategy is {strategy:?}")?,
+                Reason::AlreadyMerged(identity) => writeln!(f, "  - target already leads to the source entry (compared by {identity:?})")?,
+                Reason::Protected(pattern) => writeln!(f, "  - protected by pattern ({pattern})")?,
+                Reason::NestedDeployment(marker) => writeln!(f, "  - managed by another deployment ({})", marker.display())?,
+                Reason::LinkChainTooLong(limit) => writeln!(f, "  - symlink chain longer than {limit} links")?,
+                Reason::SameContent => writeln!(f, "  - file with the same content exists in the target")?,
+                Reason::MaxDepth(depth) => writeln!(f, "  - depth {depth} is the deepest merged level")?,
+                Reason::MinDepth(depth) => writeln!(f, "  - depth {depth} is above the shallowest placed level")?,
+                Reason::SymlinkedTarget => writeln!(f, "  - target directory is a symlink, which isn't followed")?
+            }
+        }
+
+        Ok(())
+
+    }
+}
@@ -0,0 +1,116 @@
+//! Link farms split into category subdirectories (e.g. `movies/` and `shows/` of a media server), each merging
+//! its own source libraries with its own options
+
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use crate::{Change, LayeredReport, LayerPriority, merge, merge_layered, MergeOptions, normalize_rel_path};
+
+/// Category subdirectory of a [LinkFarm] together with its source libraries.
+#[derive(Clone, Debug)]
+pub struct Category {
+    /// Subdirectory of the farm target, created when missing
+    pub name: String,
+    /// Libraries merged into the subdirectory, several ones are layered by the `priority`
+    pub sources: Vec<PathBuf>,
+    pub options: MergeOptions,
+    pub priority: LayerPriority
+}
+
+impl Category {
+    pub fn new<I, S>(name: impl Into<String>, sources: I, options: MergeOptions) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<PathBuf>
+    {
+        Self { name: name.into(), sources: sources.into_iter().map(Into::into).collect(), options, priority: LayerPriority::default() }
+    }
+}
+
+/// Named sub-merges of a single target run together, see [LinkFarm::run].
+#[derive(Clone, Debug)]
+pub struct LinkFarm {
+    pub target: PathBuf,
+    pub categories: Vec<Category>
+}
+
+/// Outcome of a single category, see [FarmReport].
+#[derive(Debug)]
+pub struct CategoryReport {
+    pub name: String,
+    pub result: Result<LayeredReport>
+}
+
+/// Combined outcome of all categories of a [LinkFarm], in the order they're configured.
+#[derive(Debug, Default)]
+pub struct FarmReport {
+    pub categories: Vec<CategoryReport>
+}
+
+impl FarmReport {
+
+    /// Changes made in all categories
+    pub fn changes(&self) -> impl Iterator<Item = &Change> {
+        self.categories.iter()
+            .filter_map(|category| category.result.as_ref().ok())
+            .flat_map(|report| report.layers.iter().flat_map(|(_, report)| &report.changes))
+    }
+
+    /// Categories which failed together with the error
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &anyhow::Error)> {
+        self.categories.iter().filter_map(|category| category.result.as_ref().err().map(|error| (category.name.as_str(), error)))
+    }
+
+}
+
+impl LinkFarm {
+
+    pub fn new(target: impl Into<PathBuf>, categories: Vec<Category>) -> Self {
+        Self { target: target.into(), categories }
+    }
+
+    /// Merge every category into its subdirectory.
+    ///
+    /// Category names have to be distinct relative paths. A failure of one category doesn't stop the others,
+    /// every category gets own [CategoryReport].
+    pub fn run(&self) -> Result<FarmReport> {
+
+        let mut names = Vec::new();
+
+        for category in &self.categories {
+
+            let name = normalize_rel_path(Path::new(&category.name)).with_context(|| format!("Invalid category name ({:?})", category.name))?;
+
+            if name.as_os_str().is_empty() || names.contains(&name) {
+                bail!("Category name ({:?}) has to be a distinct subdirectory of the target", category.name);
+            }
+
+            names.push(name);
+
+        }
+
+        let categories = self.categories.iter().zip(names)
+            .map(|(category, name)| {
+                let result = self.run_category(category, &self.target.join(name)).with_context(|| format!("Category ({}) failed", category.name));
+                CategoryReport { name: category.name.clone(), result }
+            })
+            .collect();
+
+        Ok(FarmReport { categories })
+
+    }
+
+    fn run_category(&self, category: &Category, target: &Path) -> Result<LayeredReport> {
+
+        create_dir_all(target).with_context(|| format!("Couldn't create category directory ({target:?})"))?;
+        let sources: Vec<&Path> = category.sources.iter().map(PathBuf::as_path).collect();
+
+        // Single library is merged as it is, layering would force recreating its directories
+        match sources[..] {
+            [source] => Ok(LayeredReport { layers: vec![(source.to_path_buf(), merge(source, target, &category.options)?)] }),
+            _ => merge_layered(&sources, target, &category.options, category.priority)
+        }
+
+    }
+
+}
@@ -0,0 +1,187 @@
+//! Tree folding of GNU stow kept up across merges: directory symlinks in the way of merged entries are
+//! unfolded into real directories, directories holding nothing but links into a single source directory
+//! are folded back into a symlink to it
+
+use std::collections::HashSet;
+use std::fs::{create_dir, read_dir, read_link, remove_dir_all, rename};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::{MergeReport, Strategy};
+use crate::merge::{Op, Walk};
+use crate::platform::{create_link, remove_link, symlink};
+use crate::temp::temp_path;
+
+/// Replace the directory symlink by a real directory holding a symlink to each entry of the directory it leads to.
+///
+/// Entries lead through the destination stored in the original symlink, so a relative one stays relative.
+/// Returns the created symlinks.
+pub fn unfold(link: &Path) -> Result<Vec<PathBuf>> {
+
+    let stored = read_link(link).with_context(|| format!("Couldn't read symlink ({link:?})"))?;
+
+    // Relative destination starts in the directory holding the symlink, one level above the new entries
+    let base = match stored.is_relative() {
+        true => Path::new("..").join(&stored),
+        false => stored
+    };
+
+    let staged = temp_path(link);
+    create_dir(&staged).with_context(|| format!("Couldn't create directory ({staged:?})"))?;
+
+    let created = link_entries(link, &base, &staged).inspect_err(|_| {
+        let _ = remove_dir_all(&staged);
+    })?;
+
+    replace(link, &staged).inspect_err(|_| {
+        let _ = remove_dir_all(&staged);
+    })?;
+
+    Ok(created)
+
+}
+
+/// Symlink every entry of the directory to the base destination inside the staged directory
+fn link_entries(directory: &Path, base: &Path, staged: &Path) -> Result<Vec<PathBuf>> {
+
+    let mut created = Vec::new();
+
+    for entry in read_dir(directory).with_context(|| format!("Couldn't read directory ({directory:?})"))? {
+        let name = entry.with_context(|| format!("Couldn't read directory entry ({directory:?})"))?.file_name();
+        symlink(base.join(&name), staged.join(&name)).with_context(|| format!("Couldn't create symlink ({:?})", staged.join(&name)))?;
+        created.push(directory.join(name));
+    }
+
+    created.sort();
+    Ok(created)
+
+}
+
+/// Move the staged entry to the path, removing what was there once the staged one is in place
+fn replace(path: &Path, staged: &Path) -> Result<()> {
+
+    let displaced = temp_path(path);
+    rename(path, &displaced).with_context(|| format!("Couldn't move path out of the way ({path:?})"))?;
+
+    if let Err(error) = rename(staged, path) {
+        let _ = rename(&displaced, path);
+        return Err(error).with_context(|| format!("Couldn't move staged entry into place ({path:?})"));
+    }
+
+    match displaced.is_symlink() {
+        true => remove_link(&displaced),
+        false => remove_dir_all(&displaced)
+    }.with_context(|| format!("Couldn't remove displaced path ({displaced:?})"))
+
+}
+
+impl Walk<'_> {
+
+    /// Make the changes, unfolding directory symlinks on the way to them first and folding the result afterwards,
+    /// see [MergeOptions::refold](crate::MergeOptions::refold)
+    pub(crate) fn apply_refolded(&self, report: &mut MergeReport, ops: Vec<Op>) -> Result<()> {
+
+        report.unfolded = self.unfold_parents(&ops)?;
+        self.apply(ops)?;
+
+        if self.strategy() == Strategy::Fold {
+            report.folded = self.fold()?;
+        }
+
+        Ok(())
+
+    }
+
+    /// Unfold directory symlinks between the target root and the planned changes, returns the unfolded directories
+    fn unfold_parents(&self, ops: &[Op]) -> Result<Vec<PathBuf>> {
+
+        let mut checked = HashSet::new();
+        let mut unfolded = Vec::new();
+
+        for op in ops {
+
+            let Some(parent) = op.target.strip_prefix(&self.target).ok().and_then(Path::parent) else {
+                continue;
+            };
+
+            // Shallowest first, entries of an unfolded directory may be directory symlinks themselves
+            let mut directory = self.target.clone();
+            for component in parent.components() {
+
+                directory.push(component);
+
+                if checked.insert(directory.clone()) && directory.is_symlink() && directory.is_dir() {
+                    unfold(&directory).with_context(|| format!("Couldn't unfold directory symlink ({directory:?})"))?;
+                    unfolded.push(directory.clone());
+                }
+
+            }
+
+        }
+
+        Ok(unfolded)
+
+    }
+
+    /// Fold target directories holding symlinks to all entries of their source directory (and nothing else)
+    /// into a symlink to that directory, deepest first, returns the folded directories
+    fn fold(&self) -> Result<Vec<PathBuf>> {
+
+        let mut folded = Vec::new();
+        self.fold_into(&self.source, &self.target, 1, &mut folded)?;
+
+        folded.sort();
+        Ok(folded)
+
+    }
+
+    /// Fold subdirectories of the target directory, returns whether the directory itself can be folded
+    fn fold_into(&self, source: &Path, target: &Path, depth: usize, folded: &mut Vec<PathBuf>) -> Result<bool> {
+
+        let mut names = HashSet::new();
+        let mut foldable = true;
+
+        for entry in read_dir(target).with_context(|| format!("Couldn't read directory ({target:?})"))? {
+
+            let entry = entry.with_context(|| format!("Couldn't read directory entry ({target:?})"))?;
+            let (path, source_path) = (entry.path(), source.join(entry.file_name()));
+            let is_dir = entry.file_type().with_context(|| format!("Couldn't read file type ({path:?})"))?.is_dir();
+
+            if is_dir && source_path.is_dir() && self.fold_into(&source_path, &path, depth + 1, folded)? {
+                self.fold_directory(&source_path, &path)?;
+                folded.push(path.clone());
+            }
+
+            foldable &= path.is_symlink() && matches!((path.canonicalize(), source_path.canonicalize()), (Ok(a), Ok(b)) if a == b);
+            names.insert(entry.file_name());
+
+        }
+
+        // Directories above the shallowest placed level stay real ones
+        if !foldable || names.is_empty() || self.options.min_depth.is_some_and(|min| depth <= min) {
+            return Ok(false);
+        }
+
+        // Folding would bring in source entries the merge left out
+        for entry in read_dir(source).with_context(|| format!("Couldn't read directory ({source:?})"))? {
+            if !names.contains(&entry.with_context(|| format!("Couldn't read directory entry ({source:?})"))?.file_name()) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+
+    }
+
+    /// Replace the target directory by a symlink to the source directory
+    fn fold_directory(&self, source: &Path, target: &Path) -> Result<()> {
+
+        let staged = temp_path(target);
+        create_link(&self.link_destination(source, target)?, &staged, self.options.directory_links).with_context(|| format!("Couldn't create symlink ({staged:?})"))?;
+
+        replace(target, &staged).inspect_err(|_| {
+            let _ = remove_link(&staged);
+        }).with_context(|| format!("Couldn't fold directory ({target:?})"))
+
+    }
+
+}
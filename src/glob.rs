@@ -0,0 +1,243 @@
+use std::fmt;
+use std::path::{Component, Path};
+use std::str::FromStr;
+use anyhow::{bail, Error, Result};
+
+/// Simple gitignore-like pattern matched against paths relative to the source directory.
+///
+/// - `*` matches anything except `/`, `?` matches a single character, `[a-z]` and `[!a-z]` match character classes
+/// - `**` as a whole segment matches any number of directories, `dir/**` matches everything inside `dir`
+/// - Pattern without `/` matches the entry name at any depth, otherwise it's matched from the source root
+/// - Trailing `/` matches only directories
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+    pattern: String,
+    segments: Vec<Segment>,
+    directory_only: bool
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    AnyDepth,
+    Name(Vec<Token>)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    Any,
+    One,
+    Class { negated: bool, ranges: Vec<(char, char)> }
+}
+
+impl Glob {
+
+    pub fn new(pattern: &str) -> Result<Self> {
+
+        let directory_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+
+        if trimmed.is_empty() {
+            bail!("Pattern ({pattern:?}) doesn't match any path");
+        }
+
+        let mut segments = Vec::new();
+
+        // Patterns without a slash match entry names at any depth
+        if !trimmed.contains('/') {
+            segments.push(Segment::AnyDepth);
+        }
+
+        for segment in trimmed.trim_start_matches('/').split('/').filter(|s| !s.is_empty() && *s != ".") {
+            segments.push(match segment {
+                "**" => Segment::AnyDepth,
+                _ => Segment::Name(parse_segment(segment).map_err(|e| e.context(format!("Invalid pattern ({pattern:?})")))?)
+            });
+        }
+
+        Ok(Self { pattern: pattern.to_string(), segments, directory_only })
+
+    }
+
+    /// Original pattern
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Check whether the pattern matches given relative path
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+
+        if self.directory_only && !is_dir {
+            return false;
+        }
+
+        let names: Vec<_> = path.components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None
+            })
+            .collect();
+
+        let names: Vec<&str> = names.iter().map(|name| name.as_ref()).collect();
+        match_segments(&self.segments, &names)
+
+    }
+
+}
+
+impl FromStr for Glob {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        Self::new(pattern)
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+fn parse_segment(segment: &str) -> Result<Vec<Token>> {
+
+    let chars: Vec<char> = segment.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+
+        let token = match chars[i] {
+            '*' => Token::Any,
+            '?' => Token::One,
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                Token::Literal(chars[i])
+            },
+            '[' => {
+
+                let mut j = i + 1;
+                let negated = matches!(chars.get(j), Some('!' | '^'));
+
+                if negated {
+                    j += 1;
+                }
+
+                let start = j;
+                let mut ranges = Vec::new();
+
+                // Closing bracket right after the opening one is taken literally
+                while j < chars.len() && (chars[j] != ']' || j == start) {
+                    match (chars.get(j + 1), chars.get(j + 2)) {
+                        (Some('-'), Some(&end)) if end != ']' => {
+                            ranges.push((chars[j], end));
+                            j += 3;
+                        },
+                        _ => {
+                            ranges.push((chars[j], chars[j]));
+                            j += 1;
+                        }
+                    }
+                }
+
+                if j >= chars.len() {
+                    bail!("Unclosed character class");
+                }
+
+                i = j;
+                Token::Class { negated, ranges }
+
+            },
+            c => Token::Literal(c)
+        };
+
+        tokens.push(token);
+        i += 1;
+
+    }
+
+    Ok(tokens)
+
+}
+
+fn match_segments(segments: &[Segment], names: &[&str]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        // Trailing `**` matches only the content, not the directory itself
+        Some((Segment::AnyDepth, [])) => !names.is_empty(),
+        Some((Segment::AnyDepth, rest)) => (0..=names.len()).any(|skip| match_segments(rest, &names[skip..])),
+        Some((Segment::Name(tokens), rest)) => match names.split_first() {
+            Some((name, names)) => match_name(tokens, &name.chars().collect::<Vec<_>>()) && match_segments(rest, names),
+            None => false
+        }
+    }
+}
+
+fn match_name(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::Any, rest)) => (0..=name.len()).any(|skip| match_name(rest, &name[skip..])),
+        Some((token, rest)) => match name.split_first() {
+            Some((&c, name)) => {
+
+                let matched = match token {
+                    Token::Literal(literal) => *literal == c,
+                    Token::One => true,
+                    Token::Class { negated, ranges } => ranges.iter().any(|&(start, end)| (start..=end).contains(&c)) != *negated,
+                    Token::Any => unreachable!("Handled above")
+                };
+
+                matched && match_name(rest, name)
+
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::Path;
+    use crate::Glob;
+
+    fn matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+        Glob::new(pattern).unwrap().matches(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn matches_names_at_any_depth() {
+        assert!(matches("*.tmpl", "app.conf.tmpl", false));
+        assert!(matches("*.tmpl", "etc/app/app.conf.tmpl", false));
+        assert!(!matches("*.tmpl", "etc/app.tmpl/app.conf", false));
+        assert!(matches("lorem.???", "nested/lorem.txt", false));
+        assert!(matches("[a-c]*.php", "nested/b.php", false));
+        assert!(!matches("[!a-c]*.php", "nested/b.php", false));
+    }
+
+    #[test]
+    fn matches_anchored_paths() {
+        assert!(matches("etc/*.conf", "etc/app.conf", false));
+        assert!(!matches("etc/*.conf", "srv/etc/app.conf", false));
+        assert!(matches("/app.conf", "app.conf", false));
+        assert!(!matches("/app.conf", "etc/app.conf", false));
+        assert!(matches("**/etc/*.conf", "srv/etc/app.conf", false));
+    }
+
+    #[test]
+    fn matches_directory_content() {
+        assert!(matches("secrets/**", "secrets/key.pem", false));
+        assert!(matches("secrets/**", "secrets/nested/key.pem", false));
+        assert!(!matches("secrets/**", "secrets", true));
+        assert!(matches("node_modules/", "web/node_modules", true));
+        assert!(!matches("node_modules/", "web/node_modules", false));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(Glob::new("").is_err());
+        assert!(Glob::new("/").is_err());
+        assert!(Glob::new("[a-z").is_err());
+    }
+
+}
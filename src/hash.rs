@@ -0,0 +1,121 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use sha2::Digest;
+
+/// Algorithm hashing content, used to compare files ([Overwrite::IfDifferent](crate::Overwrite::IfDifferent))
+/// and to key stored trees ([ContentStore](crate::ContentStore)).
+///
+/// Provided are [Sha256] for audits, [Blake3] (feature `blake3`, enabled by default) and [Xxh3] (feature `xxh3`)
+/// when speed matters more than collision resistance.
+pub trait Hasher: fmt::Debug + Send + Sync {
+    /// Short lowercase name of the algorithm, e.g. `sha256`
+    fn name(&self) -> &'static str;
+    /// Start hashing new content, which is written into the returned state
+    fn start(&self) -> Box<dyn HashState>;
+}
+
+/// Content hashed so far, see [Hasher]
+pub trait HashState: Write {
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+/// SHA-256
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(sha2::Sha256::new())
+    }
+
+}
+
+impl HashState for sha2::Sha256 {
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().to_vec()
+    }
+}
+
+/// BLAKE3
+#[cfg(feature = "blake3")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Blake3;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3 {
+
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(blake3::Hasher::new())
+    }
+
+}
+
+#[cfg(feature = "blake3")]
+impl HashState for blake3::Hasher {
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+/// 128-bit XXH3, not cryptographic
+#[cfg(feature = "xxh3")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Xxh3;
+
+#[cfg(feature = "xxh3")]
+impl Hasher for Xxh3 {
+
+    fn name(&self) -> &'static str {
+        "xxh3"
+    }
+
+    fn start(&self) -> Box<dyn HashState> {
+        Box::new(xxhash_rust::xxh3::Xxh3Default::new())
+    }
+
+}
+
+#[cfg(feature = "xxh3")]
+impl HashState for xxhash_rust::xxh3::Xxh3Default {
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        self.digest128().to_be_bytes().to_vec()
+    }
+}
+
+/// [Blake3] when the `blake3` feature is enabled, [Sha256] otherwise
+pub fn default_hasher() -> Arc<dyn Hasher> {
+
+    #[cfg(feature = "blake3")]
+    return Arc::new(Blake3);
+
+    #[cfg(not(feature = "blake3"))]
+    return Arc::new(Sha256);
+
+}
+
+/// Hash content of the file (following symlinks)
+pub fn hash_file(hasher: &dyn Hasher, path: &Path) -> io::Result<Vec<u8>> {
+
+    let mut state = hasher.start();
+    io::copy(&mut File::open(path)?, &mut state)?;
+
+    Ok(state.finish())
+
+}
+
+/// Lowercase hex form of the hash
+pub(crate) fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
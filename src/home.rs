@@ -0,0 +1,170 @@
+use std::env;
+#[cfg(unix)]
+use std::fs::{read_dir, read_to_string};
+#[cfg(unix)]
+use std::os::unix::fs::lchown;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::path::Path;
+use anyhow::{bail, Result};
+#[cfg(unix)]
+use anyhow::Context;
+use crate::{Glob, MergeOptions, Overwrite};
+#[cfg(unix)]
+use crate::{ChangeKind, merge, MergeReport};
+#[cfg(unix)]
+use crate::merge::through_symlink;
+
+/// Environment variable holding the home directory of the current user
+#[cfg(unix)]
+const HOME_VARIABLE: &str = "HOME";
+#[cfg(windows)]
+const HOME_VARIABLE: &str = "USERPROFILE";
+
+/// Home directory entries never replaced by [home] options, they hold keys and browser profiles
+pub const PROTECTED_HOME_PATHS: [&str; 3] = [".ssh", ".gnupg", ".mozilla"];
+
+/// Home directory of the current user (`HOME`, `USERPROFILE` on Windows), together with options suited to merging dotfiles into it, see [home_at].
+pub fn home() -> Result<(PathBuf, MergeOptions)> {
+    match env::var_os(HOME_VARIABLE) {
+        Some(home) if !home.is_empty() => home_at(home),
+        _ => bail!("Home directory is unknown, {HOME_VARIABLE} environment variable is not set")
+    }
+}
+
+/// Given home directory, together with options suited to merging dotfiles into it.
+///
+/// Only symlinks are replaced ([Overwrite::ForeignLinksOnly]), each of them is backed up with
+/// `.solderium-backup` suffix first and [PROTECTED_HOME_PATHS] including their content are protected.
+/// Adjust the returned options as needed before merging.
+pub fn home_at(home: impl Into<PathBuf>) -> Result<(PathBuf, MergeOptions)> {
+    Ok((home.into(), dotfiles()?))
+}
+
+/// Options of [home], also available as the `dotfiles` [preset](MergeOptions::preset)
+pub(crate) fn dotfiles() -> Result<MergeOptions> {
+
+    let mut protect = Vec::new();
+
+    for path in PROTECTED_HOME_PATHS {
+        protect.push(Glob::new(&format!("/{path}"))?);
+        protect.push(Glob::new(&format!("/{path}/**"))?);
+    }
+
+    Ok(MergeOptions {
+        overwrite: Overwrite::ForeignLinksOnly,
+        protect,
+        backup: Some(".solderium-backup".to_string()),
+        ..Default::default()
+    })
+
+}
+
+/// User account with a home directory, see [home_users].
+#[cfg(unix)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HomeUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf
+}
+
+/// Outcome of merging into a single home directory, see [deploy_to_homes].
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct HomeDeployment {
+    pub user: HomeUser,
+    pub result: Result<MergeReport>
+}
+
+/// Users from `/etc/passwd` whose home directory exists inside `/home`
+#[cfg(unix)]
+pub fn home_users() -> Result<Vec<HomeUser>> {
+
+    let passwd = read_to_string("/etc/passwd").with_context(|| "Couldn't read user list (\"/etc/passwd\")")?;
+
+    Ok(passwd.lines().filter_map(parse_passwd_line).filter(|user| user.home.starts_with("/home") && user.home.is_dir()).collect())
+
+}
+
+/// Merge the `source` directory into home directory of every user from [home_users] accepted by the `filter`.
+///
+/// A failure of one user doesn't stop the others, every user gets own [HomeDeployment]. Everything the merge
+/// creates is owned by the user (symlinks and content of copied directories included), so it can be managed by them later.
+///
+/// Home directories are controlled by their users, so the merges are [confined](MergeOptions::confine_target)
+/// and never follow symlinks found there (home directories being symlinks fail).
+#[cfg(unix)]
+pub fn deploy_to_homes(source: &Path, filter: impl Fn(&HomeUser) -> bool, options: &MergeOptions) -> Result<Vec<HomeDeployment>> {
+    Ok(deploy(source, home_users()?.into_iter().filter(|user| filter(user)), options))
+}
+
+#[cfg(unix)]
+pub(crate) fn deploy(source: &Path, users: impl IntoIterator<Item = HomeUser>, options: &MergeOptions) -> Vec<HomeDeployment> {
+    users.into_iter()
+        .map(|user| {
+            let result = deploy_one(source, &user, options).with_context(|| format!("Deployment to home of {} ({:?}) failed", user.name, user.home));
+            HomeDeployment { user, result }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn deploy_one(source: &Path, user: &HomeUser, options: &MergeOptions) -> Result<MergeReport> {
+
+    if user.home.is_symlink() {
+        bail!("Home directory ({:?}) is a symlink", user.home);
+    }
+
+    let home = user.home.canonicalize().with_context(|| format!("Couldn't resolve home directory ({:?})", user.home))?;
+    let report = merge(source, &home, &MergeOptions { confine_target: true, ..options.clone() })?;
+
+    for change in &report.changes {
+
+        // Directories created by the merge are handed over one by one, the user may replace them meanwhile
+        if through_symlink(&home, &change.target) {
+            bail!("Target path ({:?}) is reached through a symlink, its owner isn't changed", change.target);
+        }
+
+        match change.kind {
+            ChangeKind::Copy => hand_over_tree(&change.target, user)?,
+            _ => lchown(&change.target, Some(user.uid), Some(user.gid)).with_context(|| format!("Couldn't change owner of ({:?})", change.target))?
+        }
+
+    }
+
+    Ok(report)
+
+}
+
+/// Change owner of the copied tree, its content goes first, so the user can't replace entries of a directory
+/// until it's done. Symlinks are never walked into.
+#[cfg(unix)]
+pub(crate) fn hand_over_tree(path: &Path, user: &HomeUser) -> Result<()> {
+
+    let metadata = path.symlink_metadata().with_context(|| format!("Couldn't read metadata ({path:?})"))?;
+
+    if metadata.is_dir() {
+        for entry in read_dir(path).with_context(|| format!("Directory listing ({path:?}) failed"))? {
+            hand_over_tree(&entry.with_context(|| format!("Couldn't read directory entry ({path:?})"))?.path(), user)?;
+        }
+    }
+
+    lchown(path, Some(user.uid), Some(user.gid)).with_context(|| format!("Couldn't change owner of ({path:?})"))
+
+}
+
+/// Parse `name:password:uid:gid:gecos:home:shell` line
+#[cfg(unix)]
+fn parse_passwd_line(line: &str) -> Option<HomeUser> {
+
+    let fields: Vec<&str> = line.split(':').collect();
+
+    if fields.len() < 7 || fields[0].starts_with('#') {
+        return None;
+    }
+
+    Some(HomeUser { name: fields[0].to_string(), uid: fields[2].parse().ok()?, gid: fields[3].parse().ok()?, home: PathBuf::from(fields[5]) })
+
+}
@@ -0,0 +1,275 @@
+//! Adoption of links created by other tools, so existing deployments can be migrated without recreating them
+//!
+//! Existing symlinks in the target pointing into the source are recorded in a [BinaryManifest] under
+//! the identity of this host (see [host_id]) and rewritten into the form solderium creates them in,
+//! see [LinkStyle]. With the `manifest` feature they are recorded in the JSON [Manifest] of the target
+//! as well (in manifests of their [Package]s when imported from Stow), so [provenance] and unlinking
+//! treat them like merged ones. Nothing else in the target is touched.
+//!
+//! [Manifest]: crate::manifest::Manifest
+//! [Package]: crate::Package
+//! [provenance]: crate::manifest::provenance
+
+#[cfg(feature = "manifest")]
+use std::collections::BTreeMap;
+#[cfg(feature = "manifest")]
+use std::fs::create_dir_all;
+use std::fs::{read_dir, read_link, rename};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use crate::binary_manifest::{BinaryManifest, host_id};
+use crate::LinkStyle;
+#[cfg(feature = "manifest")]
+use crate::{MANIFEST_NAME, Package};
+#[cfg(feature = "manifest")]
+use crate::manifest::Manifest;
+use crate::merge::{relative_path, source_root};
+use crate::platform::symlink;
+use crate::temp::temp_path;
+use crate::unmerge::leads_into;
+
+/// Outcome of a single import, see [import_from_stow] and [import_from_manual_links].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Adopted symlinks together with their destination, as recorded in the manifest
+    pub adopted: Vec<(PathBuf, PathBuf)>,
+    /// Adopted symlinks which were rewritten into the requested link style
+    pub normalized: Vec<PathBuf>,
+    /// Symlinks leading into the source whose destination doesn't exist, left untouched
+    pub dangling: Vec<PathBuf>
+}
+
+/// Adopt symlinks created by GNU Stow from the packages in `stow_dir` into `target`.
+///
+/// Only symlinks leading inside a package are adopted, folded directories (linked by Stow as a whole)
+/// are adopted as a single link. The stow directory itself is never walked.
+pub fn import_from_stow(stow_dir: &Path, target: &Path, manifest: &Path, style: LinkStyle) -> Result<ImportReport> {
+    let stow_dir = stow_dir.canonicalize().with_context(|| "Couldn't resolve stow directory path")?;
+    adopt(&stow_dir, target, manifest, style, true)
+}
+
+/// Adopt symlinks created by hand from the `source` directory into `target`.
+pub fn import_from_manual_links(target: &Path, source: &Path, manifest: &Path, style: LinkStyle) -> Result<ImportReport> {
+    let source = source_root(source)?;
+    adopt(&source, target, manifest, style, false)
+}
+
+/// Adopt symlinks in the `target` leading inside the `root`, with `packages` only those leading inside
+/// its subdirectories
+fn adopt(root: &Path, target: &Path, manifest: &Path, style: LinkStyle, packages: bool) -> Result<ImportReport> {
+
+    let target = target.canonicalize().with_context(|| "Couldn't resolve target path")?;
+
+    if !root.is_dir() || !target.is_dir() {
+        bail!("Make sure both source and target paths are directories");
+    }
+
+    let mut links = Vec::new();
+    collect_links(&target, root, &mut links)?;
+
+    let mut report = ImportReport::default();
+    let mut destinations = Vec::new();
+
+    for link in links {
+
+        let parent = link.parent().unwrap_or(&target);
+        let current = read_link(&link).with_context(|| format!("Couldn't read symlink ({link:?})"))?;
+
+        // Links to links are followed as well, the adopted link leads straight to the final destination
+        let destination = match parent.join(&current).canonicalize() {
+            Ok(destination) => destination,
+            Err(_) => {
+                if leads_into(parent, &current, root) {
+                    report.dangling.push(link);
+                }
+                continue;
+            }
+        };
+
+        if !destination.strip_prefix(root).is_ok_and(|relative| !packages || relative.components().count() > 1) {
+            continue;
+        }
+
+        let normalized = match style {
+            LinkStyle::Absolute => destination.clone(),
+            LinkStyle::Relative => relative_path(parent, &destination)
+        };
+
+        if normalized != current {
+            let temporary = temp_path(&link);
+            symlink(&normalized, &temporary).and_then(|()| rename(&temporary, &link))
+                .with_context(|| format!("Couldn't rewrite symlink ({link:?})"))?;
+            report.normalized.push(link.clone());
+        }
+
+        destinations.push((link.clone(), destination));
+        report.adopted.push((link, normalized));
+
+    }
+
+    BinaryManifest::update(manifest, &host_id(), report.adopted.iter().cloned())?;
+
+    #[cfg(feature = "manifest")]
+    record(root, &target, destinations, packages)?;
+
+    Ok(report)
+
+}
+
+/// Record adopted symlinks together with their destinations in the JSON manifest of the `target`,
+/// or with `packages` in manifests of the packages they lead into
+#[cfg(feature = "manifest")]
+fn record(root: &Path, target: &Path, links: Vec<(PathBuf, PathBuf)>, packages: bool) -> Result<()> {
+
+    let mut manifests: BTreeMap<PathBuf, (PathBuf, Vec<_>)> = BTreeMap::new();
+
+    for (link, destination) in links {
+
+        let (path, source) = match destination.strip_prefix(root)?.components().next() {
+            Some(name) if packages => {
+                let package = Package { name: name.as_os_str().to_string_lossy().into_owned(), path: root.join(name) };
+                (package.manifest_path(target), package.path)
+            },
+            _ => (target.join(MANIFEST_NAME), root.to_path_buf())
+        };
+
+        manifests.entry(path).or_insert_with(|| (source, Vec::new())).1.push((link, destination));
+
+    }
+
+    for (path, (source, links)) in manifests {
+
+        let mut manifest = if path.is_file() { Manifest::read_from(&path)? } else { Manifest::default() };
+        manifest.adopt(&source, links);
+
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory).with_context(|| format!("Couldn't create manifest directory ({directory:?})"))?;
+        }
+        manifest.write_to(&path)?;
+
+    }
+
+    Ok(())
+
+}
+
+/// Symlinks below the directory, neither symlinks nor the `skip` directory are walked into
+fn collect_links(directory: &Path, skip: &Path, links: &mut Vec<PathBuf>) -> Result<()> {
+
+    let listing = read_dir(directory).with_context(|| format!("Directory listing ({directory:?}) failed"))?;
+
+    for entry in listing {
+
+        let entry = entry.with_context(|| "Reading target directory entry has failed")?;
+        let (path, file_type) = (entry.path(), entry.file_type()?);
+
+        if file_type.is_symlink() {
+            links.push(path);
+        } else if file_type.is_dir() && path != skip {
+            collect_links(&path, skip, links)?;
+        }
+
+    }
+
+    links.sort();
+
+    Ok(())
+
+}
+
+// Stow and hand-made links are created by the tests as Unix symlinks
+#[cfg(all(test, unix))]
+mod tests {
+
+    use std::fs::{create_dir, read_link};
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use crate::binary_manifest::BinaryManifest;
+    use crate::import::{import_from_manual_links, import_from_stow};
+    use crate::LinkStyle;
+    #[cfg(feature = "manifest")]
+    use crate::{MANIFEST_NAME, Package};
+    #[cfg(feature = "manifest")]
+    use crate::manifest::{Health, provenance};
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn adopt_stow_links() {
+
+        let _lock = prepare_test_directory();
+        let (stow, target, manifest) = (Path::new("test_files/stow"), Path::new("test_files/test_dir2"), Path::new("test_files/links.manifest"));
+        create_dir(stow).unwrap();
+        std::fs::rename("test_files/test_dir1", stow.join("dotfiles")).unwrap();
+
+        // Stow creates relative links, folding directories missing in the target
+        symlink("../stow/dotfiles/lorem.txt", target.join("lorem.txt")).unwrap();
+        symlink("../../stow/dotfiles/nested/lorem", target.join("nested/lorem")).unwrap();
+        symlink("../stow/dotfiles/gone.txt", target.join("gone.txt")).unwrap();
+        symlink("../stow/dotfiles", target.join("package")).unwrap();
+
+        let report = import_from_stow(stow, target, manifest, LinkStyle::Absolute).unwrap();
+        let stow = stow.canonicalize().unwrap();
+            assert_eq!(report.adopted.len(), 2);
+            assert_eq!(report.normalized.len(), 2);
+            assert_eq!(report.dangling, [target.canonicalize().unwrap().join("gone.txt")]);
+            assert_eq!(read_link(target.join("lorem.txt")).unwrap(), stow.join("dotfiles/lorem.txt"));
+            assert_eq!(read_link(target.join("package")).unwrap(), Path::new("../stow/dotfiles"));
+
+        let manifest = BinaryManifest::open(manifest).unwrap();
+            assert_eq!(manifest.len(), 2);
+            assert_eq!(manifest.verify().count(), 0);
+
+    }
+
+    #[test]
+    fn adopt_manual_links() {
+
+        let _lock = prepare_test_directory();
+        let (source, target, manifest) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Path::new("test_files/links.manifest"));
+
+        symlink(source.canonicalize().unwrap().join("keep/haha.yml"), target.join("keep/haha.yml")).unwrap();
+        symlink("../test_file1.txt", target.join("unrelated.txt")).unwrap();
+
+        let report = import_from_manual_links(target, source, manifest, LinkStyle::Relative).unwrap();
+            assert_eq!(report.adopted, [(target.canonicalize().unwrap().join("keep/haha.yml"), Path::new("../../test_dir1/keep/haha.yml").to_path_buf())]);
+            assert_eq!(read_link(target.join("keep/haha.yml")).unwrap(), Path::new("../../test_dir1/keep/haha.yml"));
+            assert_eq!(read_link(target.join("unrelated.txt")).unwrap(), Path::new("../test_file1.txt"));
+            assert_eq!(BinaryManifest::open(manifest).unwrap().verify().count(), 0);
+
+    }
+
+    #[cfg(feature = "manifest")]
+    #[test]
+    fn record_adopted_links_in_manifests() {
+
+        let _lock = prepare_test_directory();
+        let (stow, target, manifest) = (Path::new("test_files/stow"), Path::new("test_files/test_dir2"), Path::new("test_files/links.manifest"));
+        create_dir(stow).unwrap();
+        std::fs::rename("test_files/test_dir1", stow.join("dotfiles")).unwrap();
+        symlink("../stow/dotfiles/lorem.txt", target.join("lorem.txt")).unwrap();
+        symlink("../../stow/dotfiles/nested/lorem", target.join("nested/lorem")).unwrap();
+
+        import_from_stow(stow, target, manifest, LinkStyle::Relative).unwrap();
+        let package = Package::new(stow.join("dotfiles")).unwrap();
+            assert!(package.is_stowed(target));
+            assert!(!target.join(MANIFEST_NAME).exists());
+
+        let found = provenance(&target.join("nested/lorem")).unwrap().unwrap();
+            assert_eq!(found.package.as_deref(), Some("dotfiles"));
+            assert_eq!(found.source, stow.canonicalize().unwrap().join("dotfiles/nested/lorem"));
+            assert_eq!(found.health, Health::Linked);
+
+        // Adopted links are removed like stowed ones
+        let removed = package.unstow(target).unwrap();
+            assert_eq!(removed.len(), 2);
+            assert!(!target.join("lorem.txt").exists());
+
+        symlink("../stow/dotfiles/keep/haha.yml", target.join("haha.yml")).unwrap();
+        import_from_manual_links(target, &stow.join("dotfiles"), manifest, LinkStyle::Relative).unwrap();
+        let found = provenance(&target.join("haha.yml")).unwrap().unwrap();
+            assert_eq!(found.manifest, target.canonicalize().unwrap().join(MANIFEST_NAME));
+            assert_eq!(found.health, Health::Linked);
+
+    }
+
+}
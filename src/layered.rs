@@ -0,0 +1,105 @@
+//! Several source directories layered into a single target, like overlay filesystems or theme overrides
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{bail, Context, Result};
+use crate::{Conflict, merge, MergeOptions, MergeReport, Overwrite, Resolution, Strategy};
+use crate::explain::Trace;
+use crate::merge::{decide, Decision};
+use crate::unmerge::points_into;
+
+/// Which layer wins when several layers hold the same path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LayerPriority {
+    /// Earlier layers override the later ones
+    #[default]
+    FirstWins,
+    /// Later layers override the earlier ones
+    LastWins
+}
+
+/// Outcome of a layered merge, attributing every change to its layer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LayeredReport {
+    /// Source directories and their reports, in the order the layers were given
+    pub layers: Vec<(PathBuf, MergeReport)>
+}
+
+impl LayeredReport {
+
+    /// Layer which provided the target path, `None` when no layer changed it
+    pub fn layer_of(&self, target: &Path) -> Option<&Path> {
+        self.layers.iter()
+            .find(|(_, report)| report.changes.iter().any(|change| change.target == target))
+            .map(|(layer, _)| layer.as_path())
+    }
+
+}
+
+/// Layer the `sources` into the `target` directory, the [priority](LayerPriority) picks the layer winning a conflict.
+///
+/// Returns what happened in every layer, skipped entries included (see [MergeOptions::record_skipped]).
+///
+/// For overwriting options, see [Overwrite] enum. For more options, see [merge_layered].
+pub fn generate_symlinks_layered(sources: &[&Path], target: &Path, overwrite: Overwrite, priority: LayerPriority) -> Result<LayeredReport> {
+    merge_layered(sources, target, &MergeOptions { overwrite, record_skipped: true, ..Default::default() }, priority)
+}
+
+/// Layer the `sources` into the `target` directory, the [priority](LayerPriority) picks the layer winning a conflict.
+///
+/// Layers are merged from the winning one using the [Strategy::Deep] strategy, so directories present in
+/// several layers end up as real directories holding entries of all of them. Symlinks of a winning layer
+/// are never replaced by the losing layers, other target paths are handled by the `options` as usual
+/// ([on_conflict](MergeOptions::on_conflict) hook included). The strategy of the `options` is ignored.
+pub fn merge_layered(sources: &[&Path], target: &Path, options: &MergeOptions, priority: LayerPriority) -> Result<LayeredReport> {
+
+    if sources.is_empty() {
+        bail!("Make sure at least one source directory is given");
+    }
+
+    let mut order: Vec<usize> = (0..sources.len()).collect();
+    if priority == LayerPriority::LastWins {
+        order.reverse();
+    }
+
+    let mut reports: Vec<Option<MergeReport>> = vec![None; sources.len()];
+    let mut winners: Vec<PathBuf> = Vec::new();
+
+    for index in order {
+
+        let layer = sources[index];
+        let root = layer.canonicalize().with_context(|| format!("Couldn't resolve layer ({layer:?})"))?;
+        let fallback = options.on_conflict.clone();
+        let (above, overwrite) = (winners.clone(), options.overwrite);
+
+        let on_conflict = Arc::new(move |conflict: &Conflict| {
+
+            if conflict.target_metadata.is_symlink() && above.iter().any(|winner| points_into(conflict.target, winner).unwrap_or(false)) {
+                return Resolution::Skip;
+            }
+
+            if conflict.target_metadata.is_dir() && conflict.source_metadata.is_dir() {
+                return Resolution::Descend;
+            }
+
+            match &fallback {
+                Some(hook) => hook(conflict),
+                None => match decide(conflict.source, conflict.target, overwrite, &mut Trace::Off) {
+                    Decision::Place { .. } => Resolution::Replace,
+                    Decision::Descend => Resolution::Descend,
+                    Decision::Skip => Resolution::Skip
+                }
+            }
+
+        });
+
+        let options = MergeOptions { strategy: Strategy::Deep, on_conflict: Some(on_conflict), ..options.clone() };
+        reports[index] = Some(merge(layer, target, &options).with_context(|| format!("Couldn't merge layer ({layer:?})"))?);
+        winners.push(root);
+
+    }
+
+    let layers = sources.iter().zip(reports).map(|(layer, report)| (layer.to_path_buf(), report.unwrap_or_default())).collect();
+    Ok(LayeredReport { layers })
+
+}
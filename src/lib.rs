@@ -1,280 +1,2383 @@
 //! Simple Rust library for merging directories using symlinks
 //!
-//! Currently supports only Unix-like operating systems
+//! Supports Unix-like operating systems and Windows. Symlinks on Windows need Developer Mode or the
+//! `SeCreateSymbolicLinkPrivilege`, file modes are emulated by the read-only attribute there and the
+//! daemon, D-Bus service and deployment to other users' homes are available only on Unix.
+
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "tokio")]
+mod asynchronous;
+#[cfg(feature = "mmap")]
+pub mod binary_manifest;
+mod builder;
+#[cfg(all(unix, feature = "daemon"))]
+pub mod daemon;
+#[cfg(all(unix, feature = "dbus"))]
+pub mod dbus;
+mod capabilities;
+mod catalog;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "config")]
+pub mod config;
+mod dry_run;
+mod error;
+mod estimate;
+mod explain;
+mod farm;
+mod fold;
+mod glob;
+mod hash;
+mod home;
+mod layered;
+mod maintenance;
+#[cfg(feature = "mmap")]
+pub mod import;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+mod merge;
+mod mtime_cache;
+mod normalize;
+#[cfg(feature = "oci")]
+pub mod oci;
+mod operation;
+mod options;
+#[cfg(feature = "manifest")]
+mod package;
+mod platform;
+mod pool;
+mod preset;
+mod preview;
+mod privileged;
+mod recommend;
+mod report;
+mod reserved;
+mod source;
+mod spill;
+mod store;
+mod swap;
+mod temp;
+mod transaction;
+mod unmerge;
+mod verify;
+
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{bail, Result};
+
+#[cfg(feature = "tokio")]
+pub use asynchronous::generate_symlinks_async;
+pub use builder::SymlinkMerge;
+pub use capabilities::{FsCapabilities, LinkCapabilities, probe, probe_links};
+pub use catalog::{ErrorCode, MessageCatalog};
+pub use dry_run::{check_permissions, Denial, plan_symlinks, PlannedAction};
+pub use error::{OutOfSpace, PackageConflicts, SolderiumError, SourceUnavailable, TooManyEntries};
+pub use estimate::{analyze, estimate, Estimate, TreeStats};
+pub use explain::{explain, Explanation, Reason, Verdict};
+pub use farm::{Category, CategoryReport, FarmReport, LinkFarm};
+pub use fold::unfold;
+pub use glob::Glob;
+pub use hash::{default_hasher, hash_file, Hasher, HashState, Sha256};
+#[cfg(feature = "blake3")]
+pub use hash::Blake3;
+#[cfg(feature = "xxh3")]
+pub use hash::Xxh3;
+pub use home::{home, home_at, PROTECTED_HOME_PATHS};
+#[cfg(unix)]
+pub use home::{deploy_to_homes, home_users, HomeDeployment, HomeUser};
+pub use layered::{generate_symlinks_layered, LayeredReport, LayerPriority, merge_layered};
+pub use maintenance::{is_maintenance_active, MAINTENANCE_MARKER};
+pub use merge::{IGNORE_FILE, MANAGED_MARKER, MAX_LINK_DEPTH, merge, merge_listed, SAVED_SUFFIX};
+pub use normalize::normalize_rel_path;
+pub use operation::{Cancelled, ErrorPolicy, MergeObserver, Operation, OperationKind, Progress, ProgressHook};
+pub use options::{Concurrency, Conflict, ConflictHook, Delegate, DirectoryLinks, EntryLimit, FallbackStrategy, Filter, Folding, Identity, LimitAction, LinkKind, LinkStyle, Materialize, MaterializeRule, MergeOptions, NestedManagement, Render, Resolution, SourceKeepMarkers, Strategy, Trigger};
+#[cfg(feature = "manifest")]
+pub use package::{Package, stow, unstow};
+pub use preset::{BUILTIN_PRESETS, preset_names, register_preset};
+pub use preview::{PlanNode, PlanTotals, PlanTree};
+pub use privileged::{CommandExecutor, PrivilegedExecutor};
+pub use recommend::{Recommendation, recommend_strategy, Sample};
+pub use report::{Change, ChangeKind, ColorChoice, MergeReport, ReportCounts, ReportDisplay, Skipped, Usage, Warning};
+pub use reserved::{is_reserved, MANIFEST_NAME, PACKAGES_DIR, RESERVED_NAMES, RESERVED_PREFIXES};
+pub use source::{CommandSource, merge_from, SourceProvider};
+pub use store::{ContentStore, digest_tree, digest_tree_with, StoredSource};
+pub use swap::{swap_source, Swapped};
+pub use temp::{clean_stale_state, remove_stale_temp, temp_path, TEMP_PREFIX};
+pub use unmerge::{prune_broken_symlinks, PruneScope, unmerge, UnmergeOptions, UnmergeReport};
+pub use verify::{DriftDelta, SampleBudget, SampleReport, SymlinkDiff, verify, verify_sample, verify_symlinks};
 
-use std::fs::{DirEntry, read_dir, remove_dir_all, remove_file};
-use std::os::unix::fs::symlink;
-use std::path::{Path, PathBuf};
-use anyhow::{bail, Context, Result};
+/// Represents the type of action, that takes place when an existing path is to be replaced by a symlink.
+///
+/// - Put `.keep` file into the target directory that you do not want to overwrite, and nor its' nested files or directories
+/// - Put `.keep_files` file into the target directory that you do not want to overwrite, and nor its' nested files
+/// - Put `.keep_dirs` file into the target directory that you do not want to overwrite, and nor its' nested directories
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Automatically overwrite existing paths to introduce a symlink
+    All,
+    /// Automatically overwrite existing target directories with symlinks
+    Dirs,
+    /// Automatically overwrite existing target files (or symlinks) with symlinks
+    Files,
+    /// Overwrite only existing symlinks (e.g. left by another dotfiles manager), never real files or directories
+    ForeignLinksOnly,
+    /// Like [Files](Overwrite::Files), but target files with the same content as the source are left in place,
+    /// compared by [MergeOptions::hasher]
+    IfDifferent,
+    /// Don't overwrite any existing paths with symlinks
+    #[default]
+    None
+}
+
+impl FromStr for Overwrite {
+    type Err = anyhow::Error;
+
+    /// Parse lowercase variant name, e.g. `files` or `foreign-links-only`
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "all" => Ok(Overwrite::All),
+            "dirs" => Ok(Overwrite::Dirs),
+            "files" => Ok(Overwrite::Files),
+            "foreign-links-only" => Ok(Overwrite::ForeignLinksOnly),
+            "if-different" => Ok(Overwrite::IfDifferent),
+            "none" => Ok(Overwrite::None),
+            _ => bail!("Unknown overwrite policy ({value}), expected one of: all, dirs, files, foreign-links-only, if-different, none")
+        }
+    }
+}
+
+/// Generate symlinks pointing to the `source` directory content in the `target` directory.
+///
+/// Simply said, everything from the `source` directory will be symlinked to the `target` directory.
+///
+/// Returns what happened, skipped entries included (see [MergeOptions::record_skipped]).
+///
+/// For overwriting options, see [Overwrite] enum. For more options, see [SymlinkMerge] builder or [merge] function.
+pub fn generate_symlinks(source: &Path, target: &Path, overwrite: Overwrite) -> Result<MergeReport> {
+    SymlinkMerge::new(source, target).overwrite(overwrite).record_skipped(true).run()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir, File, read_link, read_to_string, remove_dir_all, remove_file, write};
+    #[cfg(unix)]
+    use std::fs::set_permissions;
+    #[cfg(unix)]
+    use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use crate::merge::{copy_tree, TreeLinks};
+    use crate::transaction;
+    #[cfg(unix)]
+    use crate::{analyze, check_permissions, HomeUser, LinkKind, PrivilegedExecutor, prune_broken_symlinks, PruneScope, RESERVED_NAMES, SolderiumError};
+    use crate::{Cancelled, Category, ChangeKind, clean_stale_state, ColorChoice, CommandSource, Concurrency, Conflict, ContentStore, digest_tree_with, DirectoryLinks, EntryLimit, ErrorCode, ErrorPolicy, estimate, Estimate, explain, Filter, Folding, generate_symlinks, generate_symlinks_layered, Glob, hash_file, home_at, Identity, IGNORE_FILE, is_maintenance_active, LayerPriority, LimitAction, LinkFarm, LinkStyle, MAINTENANCE_MARKER, MANAGED_MARKER, MaterializeRule, merge, merge_from, merge_listed, MergeObserver, MergeOptions, MessageCatalog, NestedManagement, normalize_rel_path, Operation, OperationKind, OutOfSpace, Overwrite, PlannedAction, PlanTree, preset_names, probe, probe_links, Reason, recommend_strategy, register_preset, remove_stale_temp, Resolution, SampleBudget, SAVED_SUFFIX, Sha256, SourceKeepMarkers, SourceProvider, SourceUnavailable, Strategy, StoredSource, swap_source, SymlinkMerge, TEMP_PREFIX, temp_path, TooManyEntries, Trigger, unmerge, UnmergeOptions, Verdict, verify, verify_sample, verify_symlinks, Warning};
+
+    /// All tests share the `test_files` directory, so they have to take turns
+    static TEST_DIRECTORY: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn accepts_only_directories() {
+
+        let _lock = prepare_test_directory();
+
+        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_file1.txt"), Overwrite::All).is_err());
+        assert!(generate_symlinks(Path::new("test_files/test_file2.json"), Path::new("test_files/test_dir2"), Overwrite::All).is_err());
+        assert!(generate_symlinks(Path::new("test_files/test_file2.json"), Path::new("test_files/test_file1.txt"), Overwrite::All).is_err());
+
+    }
+
+    #[test]
+    fn parse_overwrite_policies() {
+
+        assert_eq!("dirs".parse::<Overwrite>().unwrap(), Overwrite::Dirs);
+        assert_eq!("none".parse::<Overwrite>().unwrap(), Overwrite::None);
+        assert_eq!("foreign-links-only".parse::<Overwrite>().unwrap(), Overwrite::ForeignLinksOnly);
+        assert!("Files".parse::<Overwrite>().is_err());
+
+    }
+
+    #[test]
+    fn merge_directories_without_overwrite() {
+
+        let _lock = prepare_test_directory();
+
+        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::None).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
+                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/nested").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+                assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_directories_with_files_overwrite() {
+
+        let _lock = prepare_test_directory();
+
+        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::Files).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
+                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/nested").is_symlink());
+                assert!(Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+                assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_directories_with_directories_overwrite() {
+
+        let _lock = prepare_test_directory();
+
+        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::Dirs).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
+                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_directories_with_all_overwrite() {
+
+        let _lock = prepare_test_directory();
+
+        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::All).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
+                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_directories_in_parallel() {
+
+        let _lock = prepare_test_directory();
+
+        let options = MergeOptions {
+            overwrite: Overwrite::All,
+            concurrency: Concurrency { traversal: 4, mutation: 2, verification: 1, prefetch: 0 },
+            ..Default::default()
+        };
+
+        assert!(merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
+                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested").is_symlink());
+
+        let options = MergeOptions { concurrency: Concurrency::available(), ..options };
+            assert!(options.concurrency.traversal >= 1);
+            assert!(verify(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn explain_single_paths() {
+
+        let _lock = prepare_test_directory();
+
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { overwrite: Overwrite::All, ..Default::default() };
+
+        let explanation = explain(source, target, &options, Path::new("keep/do_not_overwrite.txt")).unwrap();
+        assert_eq!(explanation.verdict, Verdict::Skip);
+        assert!(matches!(explanation.reasons.last(), Some(Reason::KeepMarker(_))));
+
+        assert_eq!(explain(source, target, &options, Path::new("lorem.txt")).unwrap().verdict, Verdict::Link);
+        assert_eq!(explain(source, target, &options, Path::new("nested")).unwrap().verdict, Verdict::Replace);
+        assert_eq!(explain(source, target, &options, Path::new("nested/lorem")).unwrap().verdict, Verdict::LinkedByAncestor("nested".into()));
+        assert_eq!(explain(source, target, &options, Path::new("missing.txt")).unwrap().verdict, Verdict::MissingInSource);
+        assert!(explain(source, target, &options, Path::new("../test_file1.txt")).is_err());
+
+        // Nothing was touched
+        assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+
+    }
+
+    #[test]
+    fn render_copied_templates() {
+
+        let _lock = prepare_test_directory();
+
+        create_dir(Path::new("test_files/test_dir1/templates")).unwrap();
+            write(Path::new("test_files/test_dir1/templates/app.conf.tmpl"), "port={{port}}").unwrap();
+            File::create(Path::new("test_files/test_dir1/templates/static.conf")).unwrap();
+        write(Path::new("test_files/test_dir1/nested/dolor.conf.tmpl"), "host={{host}}").unwrap();
+
+        let options = MergeOptions {
+            materialize: vec![MaterializeRule::copy("*.tmpl").unwrap()],
+            render: Some(Arc::new(|_, content| {
+                String::from_utf8_lossy(content).replace("{{port}}", "8080").replace("{{host}}", "localhost").into_bytes()
+            })),
+            ..Default::default()
+        };
+
+        assert!(merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/templates").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/templates/app.conf.tmpl").is_symlink());
+                assert_eq!(read_to_string(Path::new("test_files/test_dir2/templates/app.conf.tmpl")).unwrap(), "port=8080");
+                assert!(Path::new("test_files/test_dir2/templates/static.conf").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/nested/dolor.conf.tmpl").is_symlink());
+                assert_eq!(read_to_string(Path::new("test_files/test_dir2/nested/dolor.conf.tmpl")).unwrap(), "host=localhost");
+
+    }
+
+    #[test]
+    fn copy_over_merged_symlinks() {
+
+        let _lock = prepare_test_directory();
+
+        let source = Path::new("test_files/test_dir1");
+        let target = Path::new("test_files/test_dir2");
+
+        assert!(merge(source, target, &MergeOptions::default()).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+
+        let options = MergeOptions { overwrite: Overwrite::All, materialize: vec![MaterializeRule::copy("lorem.txt").unwrap()], ..Default::default() };
+        let explanation = explain(source, target, &options, Path::new("lorem.txt")).unwrap();
+
+        assert_eq!(explanation.verdict, Verdict::Copy { replace: true });
+            assert!(!explanation.reasons.iter().any(|reason| matches!(reason, Reason::AlreadyMerged(_))));
+        assert_eq!(explain(source, target, &MergeOptions { overwrite: Overwrite::All, ..Default::default() }, Path::new("lorem.txt")).unwrap().verdict, Verdict::Skip);
+
+        assert!(merge(source, target, &options).is_ok());
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_file());
+            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_secrets_with_mode() {
+
+        let _lock = prepare_test_directory();
+
+        create_dir(Path::new("test_files/test_dir1/secrets")).unwrap();
+            write(Path::new("test_files/test_dir1/secrets/key.pem"), "secret").unwrap();
+            create_dir(Path::new("test_files/test_dir1/secrets/nested")).unwrap();
+                write(Path::new("test_files/test_dir1/secrets/nested/token"), "secret").unwrap();
+
+        let options = MergeOptions {
+            materialize: vec![MaterializeRule::copy("secrets/**").unwrap().mode(0o600)],
+            ..Default::default()
+        };
+
+        assert!(merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/secrets").is_symlink());
+                assert!(!Path::new("test_files/test_dir2/secrets/key.pem").is_symlink());
+                assert_eq!(Path::new("test_files/test_dir2/secrets/key.pem").metadata().unwrap().permissions().mode() & 0o777, 0o600);
+                assert!(!Path::new("test_files/test_dir2/secrets/nested").is_symlink());
+                    assert_eq!(Path::new("test_files/test_dir2/secrets/nested/token").metadata().unwrap().permissions().mode() & 0o777, 0o600);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn override_modes_of_created_entries() {
+
+        let _lock = prepare_test_directory();
+
+        create_dir(Path::new("test_files/test_dir1/config")).unwrap();
+            write(Path::new("test_files/test_dir1/config/app.toml"), "debug = false").unwrap();
+            write(Path::new("test_files/test_dir1/config/db.toml"), "password = 1234").unwrap();
+
+        let options = MergeOptions {
+            materialize: vec![MaterializeRule::copy("config/").unwrap().mode(0o644)],
+            mode_overrides: vec![(Glob::new("config/").unwrap(), 0o750), (Glob::new("db.toml").unwrap(), 0o600)],
+            ..Default::default()
+        };
+
+        let mode = |path: &str| Path::new(path).metadata().unwrap().permissions().mode() & 0o777;
+
+        assert!(merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).is_ok());
+            assert_eq!(mode("test_files/test_dir2/config"), 0o750);
+                assert_eq!(mode("test_files/test_dir2/config/app.toml"), 0o644);
+                assert_eq!(mode("test_files/test_dir2/config/db.toml"), 0o600);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retry_denied_operations_through_privileged_executor() {
+
+        /// Creates the symlink after making the directory writable, like a privileged helper would
+        struct Helper(Mutex<Vec<PathBuf>>);
+
+        impl PrivilegedExecutor for Helper {
+            fn create_link(&self, source: &Path, target: &Path) -> std::io::Result<()> {
+                let parent = target.parent().unwrap();
+                set_permissions(parent, std::fs::Permissions::from_mode(0o755))?;
+                self.0.lock().unwrap().push(target.to_path_buf());
+                symlink(source, target)
+            }
+            fn remove(&self, _: &Path) -> std::io::Result<()> {
+                unreachable!()
+            }
+        }
+
+        let _lock = prepare_test_directory();
+
+        set_permissions(Path::new("test_files/test_dir2/nested"), std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Permissions are not enforced for privileged users, nothing to test then
+        if File::create(Path::new("test_files/test_dir2/nested/probe")).is_ok() {
+            return;
+        }
+
+        let helper = Arc::new(Helper(Mutex::new(Vec::new())));
+        let options = MergeOptions { privileged: Some(helper.clone()), ..Default::default() };
+
+        assert!(merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+            assert_eq!(helper.0.lock().unwrap().len(), 1);
+
+    }
+
+    #[test]
+    fn resume_after_running_out_of_space() {
+
+        let _lock = prepare_test_directory();
+
+        let target = Path::new("test_files/test_dir2").canonicalize().unwrap();
+        let interrupted = OutOfSpace { completed: 2, failed: target.join("lorem.txt"), remaining: vec![target.join("lorem.txt")] };
+
+        assert!(interrupted.to_string().contains("1 entries remaining"));
+        assert!(interrupted.resume(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &MergeOptions::default()).is_ok());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/nested/lorem").exists());
+
+    }
+
+    #[test]
+    fn guard_entry_count_per_directory() {
+
+        let _lock = prepare_test_directory();
+
+        let mut options = MergeOptions {
+            entry_limit: Some(EntryLimit { max_entries: 3, exceeded: LimitAction::Error }),
+            ..Default::default()
+        };
+
+        // Target root would contain index.html, ipsum.php, keep, nested and lorem.txt
+        let error = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap_err();
+            assert_eq!(error.downcast_ref::<TooManyEntries>().unwrap().entries, 5);
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+
+        options.entry_limit = Some(EntryLimit { max_entries: 3, exceeded: LimitAction::Warn });
+
+        let report = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert!(matches!(&report.warnings[..], [Warning::TooManyEntries { entries: 5, limit: 3, .. }]));
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_with_deep_strategy_and_excludes() {
+
+        let _lock = prepare_test_directory();
+
+        let options = MergeOptions { strategy: Strategy::Deep, exclude: vec![Glob::new("*.php").unwrap()], ..Default::default() };
+
+        merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert!(!Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested/lorem").is_dir());
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+
+    }
+
+    #[test]
+    fn never_fold_whatever_the_strategy() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        SymlinkMerge::new(source, target).strategy(Strategy::Shallow).folding(Folding::Never).run().unwrap();
+            assert!(target.join("nested/lorem").is_dir() && !target.join("nested/lorem").is_symlink());
+            assert!(target.join("keep/haha.yml").is_symlink());
+            assert!(target.join("lorem.txt").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_with_shallow_strategy() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions { strategy: Strategy::Shallow, ..Default::default() };
+
+        merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep/haha.yml").exists());
+            assert!(!Path::new("test_files/test_dir2/nested/lorem").exists());
+
+    }
+
+    #[test]
+    fn recognize_already_merged_entries() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        generate_symlinks(source, target, Overwrite::Files).unwrap();
+
+        let explanation = explain(source, target, &MergeOptions { overwrite: Overwrite::Files, ..Default::default() }, Path::new("lorem.txt")).unwrap();
+            assert_eq!(explanation.verdict, Verdict::Skip);
+            assert!(explanation.reasons.contains(&Reason::AlreadyMerged(Identity::Path)));
+
+        // Hardlink is the same object only when compared by inode
+        std::fs::remove_file(target.join("ipsum.php")).unwrap();
+        std::fs::hard_link(source.join("ipsum.php"), target.join("ipsum.php")).unwrap();
+
+        let options = MergeOptions { overwrite: Overwrite::Files, identity: Identity::DevInode, ..Default::default() };
+            assert_eq!(explain(source, target, &options, Path::new("ipsum.php")).unwrap().verdict, Verdict::Skip);
+            assert_eq!(explain(source, target, &MergeOptions { overwrite: Overwrite::Files, ..Default::default() }, Path::new("ipsum.php")).unwrap().verdict, Verdict::Replace);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skip_matching_symlinks() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { strategy: Strategy::Deep, anchor: Some(PathBuf::from("../test_dir1")), ..Default::default() };
+
+        assert!(!merge(source, target, &options).unwrap().changes.is_empty());
+        assert!(merge(source, target, &options).unwrap().changes.is_empty());
+
+        // Symlink stored differently still leads to the source entry
+        std::fs::remove_file(target.join("nested/dolor.cpp")).unwrap();
+        symlink(source.join("nested/dolor.cpp").canonicalize().unwrap(), target.join("nested/dolor.cpp")).unwrap();
+            assert!(merge(source, target, &options).unwrap().changes.is_empty());
+
+    }
+
+    #[test]
+    fn plan_from_target_listing() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        let listed = estimate(source, target, &MergeOptions { overwrite: Overwrite::Files, ..Default::default() }).unwrap();
+        let checked = estimate(source, target, &MergeOptions { overwrite: Overwrite::Files, stat_each_target: true, ..Default::default() }).unwrap();
+            assert_eq!(listed, checked);
+
+        let report = merge(source, target, &MergeOptions { overwrite: Overwrite::Files, ..Default::default() }).unwrap();
+            assert!(report.changes.iter().any(|change| change.target.ends_with("nested/lorem") && !change.replace));
+            assert!(report.changes.iter().any(|change| change.target.ends_with("ipsum.php") && change.replace));
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn merge_into_home() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let foreign = Path::new("test_files/test_file1.txt").canonicalize().unwrap();
+
+        let (home, options) = home_at(target.canonicalize().unwrap()).unwrap();
+            assert_eq!(home, target.canonicalize().unwrap());
+
+        create_dir(source.join(".ssh")).unwrap();
+        File::create(source.join(".ssh/config")).unwrap();
+        File::create(source.join("broken.txt")).unwrap();
+        create_dir(target.join(".ssh")).unwrap();
+        symlink(&foreign, target.join(".ssh/config")).unwrap();
+        symlink(&foreign, target.join("lorem.txt")).unwrap();
+        symlink("missing.txt", target.join("broken.txt")).unwrap();
+
+        merge(source, &home, &options).unwrap();
+            assert_eq!(std::fs::read_link(target.join(".ssh/config")).unwrap(), foreign);
+            assert_eq!(std::fs::read_link(target.join("lorem.txt.solderium-backup")).unwrap(), foreign);
+            assert_eq!(target.join("lorem.txt").canonicalize().unwrap(), source.join("lorem.txt").canonicalize().unwrap());
+            assert_eq!(target.join("broken.txt").canonicalize().unwrap(), source.join("broken.txt").canonicalize().unwrap());
+            assert!(!target.join("ipsum.php").is_symlink());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deploy_to_multiple_homes() {
+
+        let _lock = prepare_test_directory();
+        let metadata = std::fs::metadata("test_files").unwrap();
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        let user = |name: &str, home: &str| HomeUser { name: name.to_string(), uid, gid, home: PathBuf::from(home) };
+
+        create_dir(Path::new("test_files/alice")).unwrap();
+
+        let deployments = crate::home::deploy(Path::new("test_files/test_dir1"), [user("alice", "test_files/alice"), user("bob", "test_files/bob")], &MergeOptions::default());
+            assert_eq!(deployments.len(), 2);
+            assert!(deployments[0].result.as_ref().is_ok_and(|report| !report.changes.is_empty()));
+            assert!(deployments[1].result.is_err());
+            assert_eq!(std::fs::symlink_metadata("test_files/alice/lorem.txt").unwrap().uid(), uid);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hand_over_copied_directories_in_homes() {
+
+        let _lock = prepare_test_directory();
+
+        // Handing paths over to another user needs root
+        // SAFETY: geteuid has no preconditions and can't fail
+        if unsafe { libc::geteuid() } != 0 {
+            eprintln!("Skipped, changing owners to another user needs root");
+            return;
+        }
+
+        let (uid, gid) = (4242, 4242);
+        let alice = HomeUser { name: "alice".to_string(), uid, gid, home: PathBuf::from("test_files/alice") };
+        create_dir(Path::new("test_files/alice")).unwrap();
+        create_dir(Path::new("test_files/test_dir1/nested/lorem/deep")).unwrap();
+        write("test_files/test_dir1/nested/lorem/deep/ipsum.txt", "ipsum").unwrap();
+        symlink("../dolor.cpp", "test_files/test_dir1/nested/lorem/dolor.cpp").unwrap();
+
+        let options = MergeOptions { materialize: vec![MaterializeRule::copy("nested/").unwrap()], ..Default::default() };
+        let deployments = crate::home::deploy(Path::new("test_files/test_dir1"), [alice.clone()], &options);
+            assert!(deployments[0].result.is_ok());
+
+        // Directory copied as a whole, like the copy fallback does
+        copy_tree(Path::new("test_files/test_dir1/nested"), Path::new("test_files/alice/fallback"), TreeLinks::Copy).unwrap();
+        crate::home::hand_over_tree(Path::new("test_files/alice/fallback"), &alice).unwrap();
+
+        for path in ["nested", "fallback"].iter().flat_map(|copied| ["", "/dolor.cpp", "/lorem", "/lorem/deep", "/lorem/deep/ipsum.txt", "/lorem/dolor.cpp"].map(|path| format!("{copied}{path}"))) {
+            let metadata = std::fs::symlink_metadata(Path::new("test_files/alice").join(&path)).unwrap();
+                assert_eq!((metadata.uid(), metadata.gid()), (uid, gid), "{path}");
+        }
+
+        // Symlinks inside copied trees are handed over themselves, their destinations aren't
+            assert_ne!(std::fs::metadata("test_files/test_dir1/nested/dolor.cpp").unwrap().uid(), uid);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn never_follow_symlinks_in_homes() {
+
+        let _lock = prepare_test_directory();
+        let metadata = std::fs::metadata("test_files").unwrap();
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        let user = |name: &str, home: &str| HomeUser { name: name.to_string(), uid, gid, home: PathBuf::from(home) };
+
+        // Planted by the users, leading out of their homes
+        create_dir(Path::new("test_files/alice")).unwrap();
+        create_dir(Path::new("test_files/outside")).unwrap();
+        symlink("../outside", "test_files/alice/nested").unwrap();
+        symlink("test_dir3", "test_files/mallory").unwrap();
+        create_dir(Path::new("test_files/test_dir3")).unwrap();
+
+        let deployments = crate::home::deploy(Path::new("test_files/test_dir1"), [user("alice", "test_files/alice"), user("mallory", "test_files/mallory")], &MergeOptions::default());
+            assert!(deployments[0].result.as_ref().is_ok_and(|report| report.changes.iter().all(|change| !change.target.ends_with("nested/dolor.cpp"))));
+            assert!(Path::new("test_files/alice/lorem.txt").is_symlink());
+            assert_eq!(std::fs::read_dir("test_files/outside").unwrap().count(), 0);
+            assert!(deployments[1].result.is_err());
+            assert_eq!(std::fs::read_dir("test_files/test_dir3").unwrap().count(), 0);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_typed_warnings() {
+
+        let _lock = prepare_test_directory();
+
+        File::create(Path::new("test_files/test_dir1/LOREM.txt")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind("test_files/test_dir1/nested/socket").unwrap();
+
+        let options = MergeOptions { overwrite: Overwrite::Files, materialize: vec![MaterializeRule::copy("nested/").unwrap()], ..Default::default() };
+        let report = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+        let target = Path::new("test_files/test_dir2").canonicalize().unwrap();
+
+            assert!(report.warnings.contains(&Warning::SkippedSpecialFile { path: Path::new("test_files/test_dir1/nested/socket").canonicalize().unwrap() }));
+            assert!(report.warnings.contains(&Warning::CaseCollision { directory: target.clone(), names: vec!["LOREM.txt".into(), "lorem.txt".into()] }));
+            assert!(report.warnings.contains(&Warning::KeepMarkerShadowing { marker: target.join("keep/.keep"), skipped: 1 }));
+            assert!(!Path::new("test_files/test_dir2/nested/socket").exists());
+
+        assert!(report.clone().escalate(|warning| matches!(warning, Warning::TooManyEntries { .. })).is_ok());
+        assert!(report.escalate(|warning| matches!(warning, Warning::CaseCollision { .. })).is_err());
+
+    }
+
+    #[test]
+    fn estimate_work_size() {
+
+        let _lock = prepare_test_directory();
+
+        write(Path::new("test_files/test_dir2/ipsum.php"), "<?php echo 1;").unwrap();
+
+        let options = MergeOptions { overwrite: Overwrite::Files, ..Default::default() };
+        let estimate = estimate(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert_eq!(estimate, Estimate { entries: 8, expected_links: 5, expected_removals: 2, bytes_at_risk: 13 });
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+
+    }
+
+    #[test]
+    fn simulate_matching_entries() {
+
+        let _lock = prepare_test_directory();
+
+        let options = MergeOptions {
+            overwrite: Overwrite::Files,
+            materialize: vec![MaterializeRule::copy("nested/lorem/").unwrap()],
+            simulate: vec![Glob::new("nested/**").unwrap(), Glob::new("lorem.txt").unwrap()],
+            ..Default::default()
+        };
+
+        File::create(Path::new("test_files/test_dir1/nested/lorem/sit.txt")).unwrap();
+
+        let report = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+        let simulated: Vec<_> = report.simulated.iter().map(|change| (change.target.file_name().unwrap().to_str().unwrap(), change.kind, change.replace)).collect();
+            assert_eq!(simulated, [("lorem.txt", ChangeKind::Symlink, false), ("dolor.cpp", ChangeKind::Symlink, true), ("lorem", ChangeKind::Directory, false), ("sit.txt", ChangeKind::Copy, false)]);
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+            assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/nested/lorem").exists());
+            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_through_anchor() {
+
+        let _lock = prepare_test_directory();
+        let target = Path::new("test_files/test_dir2");
+
+        symlink("test_dir1", Path::new("test_files/anchor")).unwrap();
+
+        let options = MergeOptions { overwrite: Overwrite::Files, anchor: Some(PathBuf::from("../anchor")), ..Default::default() };
+
+        merge(Path::new("test_files/test_dir1"), target, &options).unwrap();
+            assert_eq!(std::fs::read_link(target.join("lorem.txt")).unwrap(), Path::new("../anchor/lorem.txt"));
+            assert_eq!(std::fs::read_link(target.join("nested/dolor.cpp")).unwrap(), Path::new("../../anchor/nested/dolor.cpp"));
+            assert!(target.join("nested/dolor.cpp").is_file());
+
+        let options = MergeOptions { anchor: Some(PathBuf::from("../test_dir2")), ..Default::default() };
+            assert!(merge(Path::new("test_files/test_dir1"), target, &options).is_err());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unmerge_with_materialization() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        write(source.join("lorem.txt"), "lorem").unwrap();
+        generate_symlinks(source, target, Overwrite::None).unwrap();
+        symlink("../test_file1.txt", target.join("unrelated")).unwrap();
+
+        let report = unmerge(source, target, &UnmergeOptions { materialize: true, ..Default::default() }).unwrap();
+            assert_eq!(report.materialized.len(), 3);
+            assert!(report.removed.is_empty());
+            assert!(!target.join("lorem.txt").is_symlink());
+            assert_eq!(read_to_string(target.join("lorem.txt")).unwrap(), "lorem");
+            assert!(!target.join("nested/lorem").is_symlink());
+            assert!(target.join("nested/lorem").is_dir());
+            assert!(target.join("unrelated").is_symlink());
+
+        generate_symlinks(source, target, Overwrite::Files).unwrap();
+        remove_dir_all(source.join("nested")).unwrap();
+
+        let report = unmerge(source, target, &UnmergeOptions::default()).unwrap();
+            assert!(report.removed.contains(&target.canonicalize().unwrap().join("nested/dolor.cpp")));
+            assert!(!target.join("lorem.txt").exists());
+            assert!(!target.join("nested/dolor.cpp").is_symlink());
+            assert!(target.join("unrelated").is_symlink());
+
+    }
+
+    #[test]
+    fn filter_files_by_size_and_time() {
+
+        let _lock = prepare_test_directory();
+
+        write(Path::new("test_files/test_dir1/lorem.txt"), "lorem ipsum").unwrap();
+
+        let options = MergeOptions { filter: Filter { min_size: Some(1), ..Default::default() }, ..Default::default() };
+
+        merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep/haha.yml").exists());
+            assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+
+        let future = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+        let options = MergeOptions { filter: Filter { modified_after: Some(future), ..Default::default() }, ..Default::default() };
+            assert_eq!(explain(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options, Path::new("ipsum.php")).unwrap().reasons, [Reason::Filtered]);
+
+    }
+
+    #[test]
+    fn remove_stale_temporary_entries() {
+
+        let _lock = prepare_test_directory();
+        let directory = Path::new("test_files/test_dir2");
+
+        let fresh = temp_path(&directory.join("lorem.txt"));
+            assert_ne!(fresh, temp_path(&directory.join("lorem.txt")));
+            assert_eq!(fresh.parent(), Some(directory));
+
+        // Process ids are never this high
+        let stale = directory.join(format!("{TEMP_PREFIX}4294967295-0123456789abcdef"));
+
+        write(&fresh, "").unwrap();
+        create_dir(&stale).unwrap();
+
+        assert_eq!(remove_stale_temp(directory).unwrap(), [stale.as_path()]);
+            assert!(fresh.exists());
+            assert!(!stale.exists());
+
+    }
+
+    #[test]
+    fn clean_stale_state_recursively() {
+
+        let _lock = prepare_test_directory();
+        let target = Path::new("test_files/test_dir2");
+
+        let staging = target.join(format!("{TEMP_PREFIX}4294967295-0000000000000001"));
+        let file = target.join(format!("keep/{TEMP_PREFIX}4294967295-0000000000000002"));
+        let running = temp_path(&target.join("keep/lorem.txt"));
+
+        create_dir(&staging).unwrap();
+        write(staging.join("lorem.txt"), "").unwrap();
+        write(&file, "").unwrap();
+        write(&running, "").unwrap();
+
+        assert_eq!(clean_stale_state(target).unwrap(), [staging.as_path(), file.as_path()]);
+            assert!(!staging.exists());
+            assert!(!file.exists());
+            assert!(running.exists());
+
+        assert!(clean_stale_state(target).unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn verify_in_parallel() {
+
+        let _lock = prepare_test_directory();
+        let source = Path::new("test_files/test_dir1");
+        let target = Path::new("test_files/test_dir2");
+        let options = MergeOptions { strategy: Strategy::Deep, ..Default::default() };
+
+        let pending = verify(source, target, &options).unwrap();
+        let parallel = verify(source, target, &MergeOptions { concurrency: Concurrency { verification: 4, ..Default::default() }, ..options.clone() }).unwrap();
+            assert!(!pending.is_empty());
+            assert_eq!(pending, parallel);
+            assert!(pending.windows(2).all(|pair| pair[0].target < pair[1].target));
+
+        merge(source, target, &options).unwrap();
+            assert!(verify(source, target, &options).unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn display_report() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions { overwrite: Overwrite::Files, ..Default::default() };
+        let report = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+
+        let plain = report.display(ColorChoice::Never).to_string();
+            assert!(!plain.contains('\x1b'));
+            assert!(plain.lines().any(|line| line.starts_with("- ") && line.ends_with("test_dir2/ipsum.php")));
+            assert!(plain.lines().any(|line| line.starts_with("+ ") && line.contains("test_dir2/ipsum.php -> ")));
+            assert_eq!(plain.lines().last(), Some(format!("{} created, 2 removed, 0 simulated, {} warnings", report.changes.len(), report.warnings.len()).as_str()));
+
+        let colored = report.display(ColorChoice::Always).to_string();
+            assert!(colored.contains("\x1b[31m- "));
+            assert!(colored.contains("\x1b[32m+ "));
+
+    }
+
+    #[test]
+    fn map_errors_to_codes() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions { entry_limit: Some(EntryLimit { max_entries: 3, exceeded: LimitAction::Error }), ..Default::default() };
+        let error = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap_err();
+            assert_eq!(ErrorCode::of(&error), Some(ErrorCode::TooManyEntries));
+            assert_eq!(ErrorCode::TooManyEntries.to_string(), "SLD0002");
+
+        let catalog = MessageCatalog::new().with(ErrorCode::TooManyEntries, "Příliš mnoho položek");
+            assert_eq!(catalog.describe(&error), "Příliš mnoho položek (SLD0002)");
+            assert_eq!(catalog.message(ErrorCode::OutOfSpace), ErrorCode::OutOfSpace.description());
+            assert_eq!(catalog.describe(&anyhow::anyhow!("Other")), "Other");
+
+    }
+
+    #[test]
+    fn catch_panicking_hooks() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions {
+            materialize: vec![MaterializeRule::copy("lorem.txt").unwrap()],
+            render: Some(Arc::new(|_: &Path, _: &[u8]| panic!("Broken template"))),
+            catch_panics: true,
+            ..Default::default()
+        };
+
+        let error = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap_err();
+            assert!(format!("{error:#}").contains("Render hook panicked: Broken template"));
+            assert!(!Path::new("test_files/test_dir2/lorem.txt").exists());
+
+    }
+
+    #[test]
+    fn propagate_hook_panics_from_workers() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions {
+            materialize: vec![MaterializeRule::copy("*").unwrap()],
+            render: Some(Arc::new(|_: &Path, _: &[u8]| panic!("Broken template"))),
+            concurrency: Concurrency::uniform(4),
+            ..Default::default()
+        };
+
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options))).unwrap_err();
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"Broken template"));
+
+    }
+
+    #[test]
+    fn probe_link_capabilities() {
+
+        let _lock = prepare_test_directory();
+        let directory = Path::new("test_files/test_dir2");
+
+        let capabilities = probe_links(directory).unwrap();
+            assert!(capabilities.symlinks && capabilities.unprivileged_symlinks && capabilities.hardlinks);
+            assert!(!capabilities.junctions);
+            assert_eq!(std::fs::read_dir(directory).unwrap().count(), 4);
+
+        assert!(probe_links(Path::new("test_files/missing")).is_err());
+
+    }
+
+    #[test]
+    fn probe_filesystem_features() {
+
+        let _lock = prepare_test_directory();
+        let directory = Path::new("test_files/test_dir2");
+
+        let capabilities = probe(directory).unwrap();
+            assert!(capabilities.symlinks && capabilities.hardlinks);
+            assert!(capabilities.max_name_len >= 255);
+            assert!(capabilities.fs_type.is_some());
+            assert_eq!(std::fs::read_dir(directory).unwrap().count(), 4);
+
+    }
+
+    #[test]
+    fn run_operations_with_progress() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let progress = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = progress.clone();
+        let operation = Operation::new().on_progress(move |progress| recorded.lock().unwrap().push(*progress));
+
+        let pending = operation.verify(source, target, &MergeOptions::default()).unwrap();
+            assert!(progress.lock().unwrap().iter().all(|progress| progress.operation == OperationKind::Verify && progress.total.is_none()));
+
+        progress.lock().unwrap().clear();
+        let report = operation.merge(source, target, &MergeOptions::default()).unwrap();
+            assert_eq!(report.changes.len(), pending.len());
+            assert_eq!(progress.lock().unwrap().last().map(|progress| (progress.done, progress.total)), Some((pending.len(), Some(pending.len()))));
+
+        progress.lock().unwrap().clear();
+        let report = operation.unmerge(source, target, &UnmergeOptions::default()).unwrap();
+            assert_eq!(progress.lock().unwrap().len(), report.removed.len());
+
+        operation.cancel();
+        let error = operation.merge(source, target, &MergeOptions::default()).unwrap_err();
+            assert_eq!(error.downcast_ref::<Cancelled>(), Some(&Cancelled { operation: OperationKind::Merge }));
+            assert!(!target.join("lorem.txt").exists());
+
+    }
+
+    #[test]
+    fn skip_failed_entries() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions {
+            materialize: vec![MaterializeRule::copy("lorem.txt").unwrap()],
+            render: Some(Arc::new(|_: &Path, _: &[u8]| panic!("Broken template"))),
+            catch_panics: true,
+            ..Default::default()
+        };
+
+        let report = Operation::new().errors(ErrorPolicy::Skip).merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert!(matches!(&report.warnings[..], [.., Warning::EntryFailed { path, .. }] if path.ends_with("lorem.txt")));
+            assert!(report.changes.iter().all(|change| !change.target.ends_with("lorem.txt")));
+            assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_umask_to_created_entries() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        set_permissions(source.join("ipsum.php"), std::fs::Permissions::from_mode(0o700)).unwrap();
+        set_permissions(source.join("nested/dolor.cpp"), std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let options = MergeOptions {
+            overwrite: Overwrite::Files,
+            materialize: vec![MaterializeRule::copy("*").unwrap()],
+            umask: Some(0o002),
+            ..Default::default()
+        };
+
+        merge(source, target, &options).unwrap();
+            assert_eq!(std::fs::metadata(target.join("ipsum.php")).unwrap().permissions().mode() & 0o777, 0o775);
+            assert_eq!(std::fs::metadata(target.join("nested/dolor.cpp")).unwrap().permissions().mode() & 0o777, 0o664);
+            assert_eq!(std::fs::metadata(target.join("nested/lorem")).unwrap().permissions().mode() & 0o777, 0o775);
+
+    }
+
+    #[test]
+    fn stop_when_source_disappears() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        // Source is replaced by another directory as soon as the first change is made
+        let operation = Operation::new().on_progress(|progress| {
+            if progress.done == 1 {
+                std::fs::rename("test_files/test_dir1", "test_files/moved").unwrap();
+                create_dir("test_files/test_dir1").unwrap();
+            }
+        });
+
+        let options = MergeOptions { materialize: vec![MaterializeRule::copy("*").unwrap()], ..Default::default() };
+        let error = operation.merge(source, target, &options).unwrap_err();
+        let unavailable = error.downcast_ref::<SourceUnavailable>().unwrap();
+            assert_eq!(unavailable.completed, 1);
+            assert!(!unavailable.remaining.is_empty());
+            assert_eq!(ErrorCode::of(&error), Some(ErrorCode::SourceUnavailable));
+
+    }
+
+    #[test]
+    fn merge_listed_paths() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        create_dir(source.join("nested/lorem/deep")).unwrap();
+        File::create(source.join("nested/lorem/deep/ipsum.txt")).unwrap();
+
+        let report = merge_listed(source, target, "lorem.txt\r\n./nested/lorem/deep/ipsum.txt\n\n", &MergeOptions::default()).unwrap();
+            assert_eq!(report.changes.len(), 4);
+            assert!(target.join("lorem.txt").is_symlink());
+            assert!(!target.join("nested/lorem").is_symlink());
+            assert!(target.join("nested/lorem/deep/ipsum.txt").is_symlink());
+            assert!(!target.join("keep/haha.yml").exists());
+
+        assert!(merge_listed(source, target, "../test_file1.txt", &MergeOptions::default()).is_err());
+        assert!(merge_listed(source, target, "missing.txt", &MergeOptions::default()).is_err());
+
+    }
+
+    #[test]
+    fn record_effective_options() {
+
+        let _lock = prepare_test_directory();
+        let options = MergeOptions { overwrite: Overwrite::Files, exclude: vec![Glob::new("*.php").unwrap()], ..Default::default() };
+
+        let report = merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).unwrap();
+            assert_eq!(report.options(), Some(&options));
+            assert!(format!("{:?}", report.options().unwrap()).contains("*.php"));
+            assert_eq!(crate::MergeReport::default().options(), None);
+
+    }
+
+    #[test]
+    fn handle_nested_deployments() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        File::create(target.join("nested").join(MANAGED_MARKER)).unwrap();
+
+        let options = MergeOptions { nested: NestedManagement::Error, ..Default::default() };
+            assert!(merge(source, target, &options).is_err());
+            assert!(!target.join("lorem.txt").exists());
+
+        let delegated = Arc::new(Mutex::new(Vec::new()));
+        let recorded = delegated.clone();
+        let options = MergeOptions {
+            nested: NestedManagement::Delegate(Arc::new(move |_: &Path, target: &Path| {
+                recorded.lock().unwrap().push(target.to_path_buf());
+                Ok(())
+            })),
+            ..Default::default()
+        };
+
+        merge(source, target, &options).unwrap();
+            assert!(!target.join("nested/lorem").exists());
+            assert_eq!(*delegated.lock().unwrap(), [target.join("nested").canonicalize().unwrap()]);
+
+        let options = MergeOptions { nested: NestedManagement::Skip, ..Default::default() };
+            assert!(matches!(&merge(source, target, &options).unwrap().warnings[..], [Warning::NestedDeployment { .. }]));
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn limit_symlink_chain_depth() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        symlink(source.join("lorem.txt").canonicalize().unwrap(), target.join("second")).unwrap();
+        symlink("second", target.join("first")).unwrap();
+        symlink("first", target.join("lorem.txt")).unwrap();
+
+        let options = MergeOptions { overwrite: Overwrite::Files, ..Default::default() };
+            assert!(!merge(source, target, &options).unwrap().changes.iter().any(|change| change.target.ends_with("lorem.txt")));
+
+        let options = MergeOptions { overwrite: Overwrite::Files, max_link_depth: Some(2), ..Default::default() };
+        let report = merge(source, target, &options).unwrap();
+            assert!(report.warnings.iter().any(|warning| matches!(warning, Warning::LinkChainTooLong { path, limit: 2 } if path.ends_with("lorem.txt"))));
+            assert_eq!(read_link(target.join("lorem.txt")).unwrap(), source.join("lorem.txt").canonicalize().unwrap());
+
+    }
+
+    #[test]
+    fn overwrite_only_different_content() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        std::fs::copy(source.join("lorem.txt"), target.join("lorem.txt")).unwrap();
+        write(target.join("ipsum.php"), "changed").unwrap();
+
+        let report = merge(source, target, &MergeOptions { overwrite: "if-different".parse().unwrap(), ..Default::default() }).unwrap();
+            assert!(!report.changes.iter().any(|change| change.target.ends_with("lorem.txt")));
+            assert!(!target.join("lorem.txt").is_symlink());
+            assert!(target.join("ipsum.php").is_symlink());
+
+        let digest = hash_file(&Sha256, Path::new("test_files/test_file1.txt")).unwrap();
+            assert_eq!(digest.len(), 32);
+            assert_ne!(digest_tree_with(source, &Sha256).unwrap(), digest_tree_with(target, &Sha256).unwrap());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn merge_with_builder() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        symlink("nested", source.join("linked")).unwrap();
+
+        let merge = SymlinkMerge::new(source, target).overwrite(Overwrite::Files).max_depth(1).follow_symlinks(false);
+            assert_eq!(merge.merge_options().max_depth, Some(1));
+            assert!(merge.merge_options().preserve_symlinks);
+
+        merge.run().unwrap();
+            assert!(target.join("lorem.txt").is_symlink());
+            assert!(target.join("linked").is_symlink());
+            assert!(!target.join("nested/lorem").exists());
+            assert!(!target.join("nested/dolor.cpp").is_symlink());
+
+    }
+
+    #[test]
+    fn preview_plan_tree() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { overwrite: Overwrite::Files, ..Default::default() };
+
+        let tree = PlanTree::preview(source, target, &options).unwrap();
+        let keep = tree.find(&target.canonicalize().unwrap().join("keep")).unwrap();
+            assert_eq!(tree.root.totals.total(), verify(source, target, &options).unwrap().len());
+            assert!(keep.change.is_none());
+            assert_eq!(keep.children.iter().map(|child| child.name.to_str().unwrap()).collect::<Vec<_>>(), ["haha.yml"]);
+            assert_eq!(keep.totals.symlinks, 1);
+            assert!(target.join("lorem.txt").symlink_metadata().is_err());
+
+    }
+
+    #[test]
+    fn dry_run_plans_actions() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let resolved = target.canonicalize().unwrap();
+
+        let actions = SymlinkMerge::new(source, target).overwrite(Overwrite::All).dry_run().unwrap();
+            assert!(actions.contains(&PlannedAction::RemoveFile { path: resolved.join("ipsum.php") }));
+            assert!(actions.contains(&PlannedAction::CreateSymlink { source: source.canonicalize().unwrap().join("ipsum.php"), target: resolved.join("ipsum.php") }));
+            assert!(actions.contains(&PlannedAction::SkipKept { path: resolved.join("keep/do_not_overwrite.txt"), marker: resolved.join("keep/.keep") }));
+            assert!(!target.join("ipsum.php").is_symlink());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dry_run_predicts_denied_actions() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let resolved = target.canonicalize().unwrap();
+        set_permissions(target.join("nested"), std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        let actions = SymlinkMerge::new(source, target).dry_run().unwrap();
+        let denials = check_permissions(&actions);
+
+        // Permissions are not enforced for privileged users
+        if File::create(target.join("nested/probe")).is_ok() {
+            assert!(denials.is_empty());
+            return;
+        }
+
+        assert_eq!(denials.len(), 1);
+            assert_eq!(denials[0].path, resolved.join("nested"));
+            assert_eq!(denials[0].kind, std::io::ErrorKind::PermissionDenied);
+            assert!(matches!(&actions[denials[0].action], PlannedAction::CreateSymlink { target, .. } if target.ends_with("nested/lorem")));
+
+    }
+
+    #[test]
+    fn strict_merge_fails_on_warnings() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { overwrite: Overwrite::All, strict: true, ..Default::default() };
+
+        let error = merge(source, target, &options).unwrap_err();
+            assert!(matches!(error.downcast_ref::<Warning>(), Some(Warning::KeepMarkerShadowing { .. })));
+            assert!(!target.join("lorem.txt").exists());
+
+        std::fs::remove_file(target.join("keep/.keep")).unwrap();
+            assert!(merge(source, target, &options).unwrap().warnings.is_empty());
+
+    }
+
+    #[test]
+    fn merge_with_bounded_plan_memory() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let expected = verify(source, target, &MergeOptions { overwrite: Overwrite::Files, ..Default::default() }).unwrap();
+
+        let report = merge(source, target, &MergeOptions { overwrite: Overwrite::Files, plan_memory: Some(1), ..Default::default() }).unwrap();
+            assert!(report.changes.is_empty());
+            assert_eq!(report.unlisted, expected.len());
+            assert!(expected.iter().all(|change| change.target.symlink_metadata().is_ok()));
+            assert!(remove_stale_temp(target).unwrap().is_empty());
+            assert!(!std::fs::read_dir(target).unwrap().any(|entry| entry.unwrap().file_name().to_string_lossy().starts_with(TEMP_PREFIX)));
+
+        let options = MergeOptions { plan_memory: Some(1), entry_limit: Some(EntryLimit { max_entries: 10, exceeded: LimitAction::Warn }), ..Default::default() };
+            assert!(merge(source, target, &options).is_err());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_of_generated_symlinks() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        let report = generate_symlinks(source, target, Overwrite::All).unwrap();
+        let counts = report.counts();
+        let kept = report.skipped.iter().find(|skipped| skipped.target.ends_with("keep/do_not_overwrite.txt")).unwrap();
+            assert_eq!(counts.symlinks, report.changes.len());
+            assert_eq!(counts.overwritten, report.overwritten().count());
+            assert!(report.overwritten().any(|path| path.ends_with("ipsum.php")));
+            assert!(matches!(kept.reasons.last(), Some(Reason::KeepMarker(_))));
+            assert_eq!(counts.failed, 0);
+
+        // Correct symlinks are left in place even when stored in another form
+        remove_file(target.join("lorem.txt")).unwrap();
+        symlink("../test_dir1/lorem.txt", target.join("lorem.txt")).unwrap();
+        let inode = target.join("lorem.txt").symlink_metadata().unwrap().ino();
+
+        let report = generate_symlinks(source, target, Overwrite::All).unwrap();
+            assert!(report.changes.is_empty());
+            assert!(report.skipped.iter().any(|skipped| skipped.reasons == [Reason::AlreadyMerged(Identity::Path)]));
+            assert_eq!(report.counts().already_linked, counts.symlinks);
+            assert_eq!(target.join("lorem.txt").symlink_metadata().unwrap().ino(), inode);
+
+    }
+
+    #[test]
+    fn create_relative_symlinks() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        SymlinkMerge::new(source, target).link_style(LinkStyle::Relative).run().unwrap();
+            assert_eq!(read_link(target.join("lorem.txt")).unwrap(), Path::new("../test_dir1/lorem.txt"));
+            assert_eq!(read_link(target.join("keep/haha.yml")).unwrap(), Path::new("../../test_dir1/keep/haha.yml"));
+            assert!(target.join("keep/haha.yml").exists());
+            assert!(SymlinkMerge::new(source, target).link_style(LinkStyle::Relative).verify().unwrap().is_empty());
+
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn link_directories_by_junctions() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/junctions"), Path::new("test_files/test_dir2"));
+
+        // Only directories, file symlinks need Developer Mode or the symlink privilege
+        create_dir(source).unwrap();
+            create_dir(source.join("lorem")).unwrap();
+                write(source.join("lorem/ipsum.txt"), "dolor").unwrap();
+
+        SymlinkMerge::new(source, target).directory_links(DirectoryLinks::Junction).run().unwrap();
+            assert!(crate::platform::is_junction(&target.join("lorem")));
+            assert_eq!(read_to_string(target.join("lorem/ipsum.txt")).unwrap(), "dolor");
+
+        // Repeated merge recognizes the junction as already merged
+        SymlinkMerge::new(source, target).directory_links(DirectoryLinks::Junction).run().unwrap();
+            assert!(crate::platform::is_junction(&target.join("lorem")));
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_directories_where_junctions_are_missing() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        SymlinkMerge::new(source, target).link_style(LinkStyle::Relative).directory_links(DirectoryLinks::Junction).run().unwrap();
+            assert_eq!(read_link(target.join("nested/lorem")).unwrap(), Path::new("../../test_dir1/nested/lorem"));
+            assert!(target.join("nested/lorem").is_dir());
+
+    }
+
+    #[test]
+    fn verify_rotating_samples() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let state = Path::new("test_files/verify.state");
+        let options = MergeOptions { strategy: Strategy::Deep, ..Default::default() };
+
+        merge(source, target, &options).unwrap();
+        remove_file(target.join("lorem.txt")).unwrap();
+
+        let first = verify_sample(source, target, &options, SampleBudget::Fraction(0.5), state).unwrap();
+            assert_eq!((first.checked, first.total, first.passes), (4, 8, 0));
+            assert_eq!(first.coverage, 0.5);
+
+        let second = verify_sample(source, target, &options, SampleBudget::Fraction(0.5), state).unwrap();
+            assert_eq!((second.checked, second.passes), (4, 1));
+            assert_eq!(second.coverage, 0.0);
+
+        let drifted: Vec<_> = first.changes.iter().chain(&second.changes).map(|change| change.target.file_name().unwrap()).collect();
+            assert_eq!(drifted, ["lorem.txt"]);
+
+        let third = verify_sample(source, target, &options, SampleBudget::Time(std::time::Duration::ZERO), state).unwrap();
+            assert_eq!(third.checked, 8);
+            assert_eq!(third.passes, 2);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_hardlinks() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        write(source.join("lorem.txt"), "lorem").unwrap();
+
+        let report = SymlinkMerge::new(source, target).link_kind(LinkKind::Hardlink).run().unwrap();
+        let counts = report.counts();
+            assert_eq!((counts.hardlinks, counts.symlinks), (2, 0));
+            assert_eq!(counts.directories, 1);
+            assert!(!target.join("lorem.txt").is_symlink());
+            assert!(!target.join("nested/lorem").is_symlink());
+            assert_eq!(target.join("lorem.txt").metadata().unwrap().ino(), source.join("lorem.txt").metadata().unwrap().ino());
+            assert!(SymlinkMerge::new(source, target).link_kind(LinkKind::Hardlink).verify().unwrap().is_empty());
+
+        remove_dir_all(source).unwrap();
+            assert_eq!(read_to_string(target.join("lorem.txt")).unwrap(), "lorem");
+
+    }
+
+    #[test]
+    fn skip_unchanged_directories() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { mtime_cache: Some(PathBuf::from("test_files/mtimes")), ..Default::default() };
+
+        assert!(!merge(source, target, &options).unwrap().changes.is_empty());
+        assert!(merge(source, target, &options).unwrap().changes.is_empty());
+            assert_eq!(estimate(source, target, &options).unwrap().entries, 0);
+            assert_ne!(estimate(source, target, &MergeOptions::default()).unwrap().entries, 0);
+
+        // New entry changes the modification time of its directory only, parents are still skipped
+        File::create(source.join("keep/new.txt")).unwrap();
+
+        let report = merge(source, target, &options).unwrap();
+            assert_eq!(report.changes.len(), 1);
+            assert!(target.join("keep/new.txt").is_symlink());
+
+    }
+
+    #[test]
+    fn handle_source_keep_markers() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        File::create(source.join("nested/.keep_files")).unwrap();
+
+        let options = |markers| MergeOptions { source_keep_markers: markers, ..Default::default() };
+
+        assert!(merge(source, target, &options(SourceKeepMarkers::Error)).is_err());
+            assert!(!target.join("lorem.txt").exists());
+
+        merge(source, target, &options(SourceKeepMarkers::Skip)).unwrap();
+            assert!(target.join("lorem.txt").is_symlink());
+            assert!(!target.join("nested/.keep_files").exists());
+
+        merge(source, target, &options(SourceKeepMarkers::LinkAsNormalFile)).unwrap();
+            assert!(target.join("nested/.keep_files").is_symlink());
 
-/// Represents the type of action, that takes place when an existing path is to be replaced by a symlink.
-///
-/// - Put `.keep` file into the target directory that you do not want to overwrite, and nor its' nested files or directories
-/// - Put `.keep_files` file into the target directory that you do not want to overwrite, and nor its' nested files
-/// - Put `.keep_dirs` file into the target directory that you do not want to overwrite, and nor its' nested directories
-pub enum Overwrite {
-    /// Automatically overwrite existing paths to introduce a symlink
-    All,
-    /// Automatically overwrite existing target directories with symlinks
-    Dirs,
-    /// Automatically overwrite existing target files (or symlinks) with symlinks
-    Files,
-    /// Don't overwrite any existing paths with symlinks
-    None
-}
+    }
 
-/// Generate symlinks pointing to the `source` directory content in the `target` directory.
-///
-/// Simply said, everything from the `source` directory will be symlinked to the `target` directory.
-///
-/// For overwriting options, see [Overwrite] enum.
-pub fn generate_symlinks(source: &Path, target: &Path, overwrite: Overwrite) -> Result<()> {
+    #[test]
+    fn unmerge_pruning_empty_directories() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        create_dir(source.join("nested/lorem/deep")).unwrap();
+        File::create(source.join("nested/lorem/deep/ipsum.txt")).unwrap();
+
+        merge(source, target, &MergeOptions { strategy: Strategy::Deep, ..Default::default() }).unwrap();
+            assert!(target.join("nested/lorem/deep/ipsum.txt").is_symlink());
+
+        let report = unmerge(source, target, &UnmergeOptions { prune_empty: true, ..Default::default() }).unwrap();
+        let target = target.canonicalize().unwrap();
+            assert_eq!(report.pruned, [target.join("nested/lorem"), target.join("nested/lorem/deep")]);
+            assert!(target.join("nested/original.rs").exists());
+            assert!(target.join("keep").is_dir());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn analyze_source_tree() {
+
+        let _lock = prepare_test_directory();
+        let source = Path::new("test_files/test_dir1");
+        write(source.join("lorem.txt"), "lorem").unwrap();
+        symlink("lorem.txt", source.join("nested/link")).unwrap();
+
+        let stats = analyze(source, Concurrency::uniform(4)).unwrap();
+            assert_eq!((stats.files, stats.dirs, stats.symlinks), (5, 3, 1));
+            assert_eq!(stats.entries(), 9);
+            assert_eq!(stats.max_depth, 2);
+            assert_eq!(stats.total_bytes, 5);
+            assert_eq!(stats.widest_dir, Some((source.canonicalize().unwrap(), 4)));
+
+        assert!(analyze(Path::new("test_files/test_file1.txt"), Concurrency::default()).is_err());
+
+    }
 
-    let source = source.canonicalize().with_context(|| "Couldn't resolve source path")?;
-    let target = target.canonicalize().with_context(|| "Couldn't resolve target path")?;
+    #[test]
+    fn roll_back_failed_transaction() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        write(target.join("ipsum.php"), "original").unwrap();
+
+        let options = MergeOptions {
+            overwrite: Overwrite::All,
+            materialize: vec![MaterializeRule::copy("lorem.txt").unwrap()],
+            render: Some(Arc::new(|_: &Path, _: &[u8]| panic!("Broken template"))),
+            catch_panics: true,
+            transactional: true,
+            ..Default::default()
+        };
+
+        let error = merge(source, target, &options).unwrap_err();
+            assert!(format!("{error:#}").contains("rolled back"));
+            assert!(!target.join("lorem.txt").exists());
+            assert_eq!(read_to_string(target.join("ipsum.php")).unwrap(), "original");
+            assert!(!target.join("nested").is_symlink());
+            assert!(target.join("nested/original.rs").is_file());
+            assert!(std::fs::read_dir(target).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(TEMP_PREFIX)));
+
+        let report = merge(source, target, &MergeOptions { materialize: Vec::new(), ..options }).unwrap();
+            assert!(target.join("nested").is_symlink());
+            assert!(report.changes.iter().any(|change| change.replace));
+            assert!(std::fs::read_dir(target).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(TEMP_PREFIX)));
 
-    // Both source and target have to be directories for this to work
-    if !source.is_dir() || !target.is_dir() {
-        bail!("Make sure both source and target paths are directories");
     }
 
-    let mut stack = Vec::new();
-    go_deeper(&mut stack, &resolve_symlink(&source).with_context(|| "Couldn't resolve source path")?)
-        .with_context(|| format!("Directory listing ({source:?}) failed"))?;
+    #[test]
+    fn roll_back_spilled_transaction() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        write(target.join("ipsum.php"), "original").unwrap();
+
+        let options = MergeOptions {
+            overwrite: Overwrite::All,
+            materialize: vec![MaterializeRule::copy("lorem.txt").unwrap()],
+            render: Some(Arc::new(|_: &Path, _: &[u8]| panic!("Broken template"))),
+            catch_panics: true,
+            transactional: true,
+            plan_memory: Some(1),
+            ..Default::default()
+        };
+
+        // Journal holding a single record spills all the others
+        let error = merge(source, target, &options).unwrap_err();
+            assert!(format!("{error:#}").contains("rolled back"));
+            assert!(!target.join("lorem.txt").exists());
+            assert_eq!(read_to_string(target.join("ipsum.php")).unwrap(), "original");
+            assert!(std::fs::read_dir(target).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(TEMP_PREFIX)));
+
+        let report = merge(source, target, &MergeOptions { materialize: Vec::new(), ..options }).unwrap();
+            assert!(target.join("nested").is_symlink());
+            assert!(report.unlisted > 0);
+            assert!(std::fs::read_dir(target).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(TEMP_PREFIX)));
+
+    }
 
-    loop {
+    #[cfg(unix)]
+    #[test]
+    fn stage_across_filesystems() {
 
-        let source_entry = match stack.pop() {
-            Some(source_path) => source_path,
-            None => break
-        }.with_context(|| "Reading source directory entry has failed")?;
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let elsewhere = Path::new("/dev/shm").join(format!("solderium-{}", std::process::id()));
 
-        let source_path = source_entry.path();
-        let mut target_path = target.to_path_buf();
-        target_path.push(
-            &source_path.strip_prefix(&source)
-                .with_context(|| format!("Couldn't strip base path ({source:?}) from source path ({source_path:?})"))?
-        );
+        // Needs a directory on another filesystem, linked into the target, there's nothing to test without one
+        if !Path::new("/dev/shm").metadata().is_ok_and(|metadata| metadata.dev() != target.metadata().unwrap().dev()) {
+            eprintln!("Skipped, /dev/shm isn't on another filesystem than the working directory");
+            return;
+        }
 
-        // Overwrite existing target path
-        if target_path.exists() {
+        copy_tree(&target.join("nested"), &elsewhere, TreeLinks::Copy).unwrap();
+        remove_dir_all(target.join("nested")).unwrap();
+        symlink(&elsewhere, target.join("nested")).unwrap();
 
-            match overwrite {
-                Overwrite::All => {
-                    match target_path.is_file() {
-                        true => {
+        let options = MergeOptions { overwrite: Overwrite::Files, strategy: Strategy::Deep, transactional: true, ..Default::default() };
+        let report = merge(source, target, &options);
+        let _ = remove_dir_all(&elsewhere);
 
-                            // Check for .keep or .keep_files file existence
-                            if keep_path(&target_path, &[".keep", ".keep_files"]) {
-                                continue;
-                            }
+        let report = report.unwrap();
+            assert!(report.changes.iter().any(|change| change.replace && change.target.ends_with("nested/dolor.cpp")));
+            assert_eq!(report.staging.len(), 2);
+            assert!(report.staging.iter().all(|directory| !directory.exists()));
 
-                            remove_path(&target_path).with_context(|| format!("Error while deleting file ({target_path:?}) before overwriting it with ({source_path:?})"))?;
+    }
 
-                        },
-                        false => {
+    #[test]
+    fn replace_unstageable_paths_with_warning() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        write(target.join("ipsum.php"), "original").unwrap();
+
+        // Path moved aside fails like a mount point would
+        *transaction::UNSTAGEABLE.lock().unwrap() = vec![PathBuf::from("test_dir2/ipsum.php")];
+        let options = MergeOptions { overwrite: Overwrite::Files, transactional: true, ..Default::default() };
+        let report = merge(source, target, &options);
+        transaction::UNSTAGEABLE.lock().unwrap().clear();
+
+        let report = report.unwrap();
+        let not_staged = report.warnings.iter().find(|warning| matches!(warning, Warning::NotStaged { .. })).unwrap();
+            assert!(matches!(not_staged, Warning::NotStaged { path } if path.ends_with("test_dir2/ipsum.php")));
+            assert_eq!(not_staged.code(), ErrorCode::NotStaged);
+            assert!(target.join("ipsum.php").is_symlink());
+            assert_eq!(read_to_string(target.join("ipsum.php")).unwrap(), "");
+            assert!(report.changes.iter().any(|change| change.replace && change.target.ends_with("ipsum.php")));
+            assert!(report.staging.iter().all(|directory| !directory.exists()));
 
-                            // Check for .keep or .keep_dirs file existence
-                            if keep_path(&target_path, &[".keep", ".keep_dirs"]) {
-                                go_deeper(&mut stack, &source_path).with_context(|| format!("Directory listing ({source_path:?}) failed"))?;
-                                continue;
-                            }
+    }
 
-                            remove_path(&target_path).with_context(|| format!("Error while deleting directory ({target_path:?}) before overwriting it with ({source_path:?})"))?;
+    #[test]
+    fn load_presets_by_name() {
 
-                        }
-                    };
-                },
-                Overwrite::Dirs => {
+        let _lock = prepare_test_directory();
 
-                    if !target_path.is_dir() {
-                        continue;
-                    }
+        let webroot = MergeOptions::preset("webroot").unwrap();
+            assert_eq!(webroot.overwrite, Overwrite::Files);
+            assert!(webroot.transactional);
+            assert_eq!(MergeOptions::preset("dotfiles").unwrap(), home_at("test_files").unwrap().1);
+            assert!(MergeOptions::preset("missing").is_err());
 
-                    // Check for .keep or .keep_dirs file existence
-                    if keep_path(&target_path, &[".keep", ".keep_dirs"]) {
-                        go_deeper(&mut stack, &source_path).with_context(|| format!("Directory listing ({source_path:?}) failed"))?;
-                        continue;
-                    }
+        register_preset("strict-webroot", MergeOptions { strict: true, ..MergeOptions::preset("webroot").unwrap() });
+            assert!(preset_names().contains(&"strict-webroot".to_string()));
 
-                    remove_path(&target_path).with_context(|| format!("Error while deleting directory ({target_path:?}) before overwriting it with ({source_path:?})"))?;
+        let options = MergeOptions { transactional: false, ..MergeOptions::preset("strict-webroot").unwrap() };
+            assert!(options.strict && !options.transactional);
+            assert_eq!(options.exclude, webroot.exclude);
 
-                },
-                Overwrite::Files => {
+        // Keep marker of the target is worth a warning
+        assert!(merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &options).is_err());
 
-                    if !target_path.is_file() {
+        merge(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), &MergeOptions { strict: false, ..options }).unwrap();
+            assert!(Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
 
-                        if source_path.is_dir() {
-                            go_deeper(&mut stack, &source_path).with_context(|| format!("Directory listing ({source_path:?}) failed"))?;
-                        }
+    }
 
-                        continue;
+    #[test]
+    fn merge_only_included_entries() {
+
+        let _lock = prepare_test_directory();
+        SymlinkMerge::new("test_files/test_dir1", "test_files/test_dir2")
+            .overwrite(Overwrite::Files)
+            .include(Glob::new("*.txt").unwrap())
+            .include(Glob::new("nested/lorem/").unwrap())
+            .run()
+            .unwrap();
+            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
+            assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
+            assert!(!Path::new("test_files/test_dir2/keep/haha.yml").exists());
 
-                    }
+    }
 
-                    // Check for .keep or .keep_files file existence
-                    if keep_path(&target_path, &[".keep", ".keep_files"]) {
-                        continue;
-                    }
+    #[test]
+    fn skip_entries_of_ignore_files() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/target"));
+        write(source.join(IGNORE_FILE), "# Sources only\n*.php\n\nnested/lorem/\n").unwrap();
+        write(source.join("nested").join(IGNORE_FILE), "dolor.cpp").unwrap();
+        create_dir(target).unwrap();
+
+        SymlinkMerge::new(source, target).ignore_files(true).run().unwrap();
+            assert!(target.join("lorem.txt").is_symlink());
+            assert!(target.join("keep").is_symlink());
+            assert!(!target.join("ipsum.php").exists());
+            assert!(!target.join(IGNORE_FILE).exists());
+            assert!(target.join("nested").is_dir() && !target.join("nested").is_symlink());
+            assert!(!target.join("nested/lorem").exists());
+            assert!(!target.join("nested/dolor.cpp").exists());
+
+        write(source.join(IGNORE_FILE), "!*.php").unwrap();
+            assert!(SymlinkMerge::new(source, target).ignore_files(true).run().is_err());
 
-                    remove_path(&target_path).with_context(|| format!("Error while deleting file ({target_path:?}) before overwriting it with ({source_path:?})"))?;
+    }
 
-                },
-                Overwrite::None => { // Don't overwrite anything, try to find differences and symlink individual files/folders
+    #[test]
+    fn resolve_conflicts_by_hook() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        let report = SymlinkMerge::new(source, target)
+            .on_conflict(Arc::new(|conflict: &Conflict| match (conflict.target_metadata.is_dir(), conflict.source.ends_with("ipsum.php")) {
+                (true, _) => Resolution::Descend,
+                (false, true) => Resolution::Replace,
+                (false, false) => Resolution::Skip
+            }))
+            .run()
+            .unwrap();
+            assert_eq!(report.overwritten().collect::<Vec<_>>(), [target.canonicalize().unwrap().join("ipsum.php")]);
+            assert!(target.join("keep/haha.yml").is_symlink());
+            assert!(!target.join("keep/do_not_overwrite.txt").is_symlink());
+            assert!(!target.join("nested/dolor.cpp").is_symlink());
+
+        let error = SymlinkMerge::new(source, target).on_conflict(Arc::new(|_: &Conflict| Resolution::Abort)).run().unwrap_err();
+            assert!(error.to_string().contains("aborted"));
 
-                    if source_path.is_dir() {
-                        go_deeper(&mut stack, &source_path).with_context(|| format!("Directory listing ({source_path:?}) failed"))?;
-                    }
+    }
 
-                    continue;
+    #[test]
+    fn hold_maintenance_marker_while_merging() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let active = Arc::new(Mutex::new(false));
+        let observed = active.clone();
+
+        let merge = SymlinkMerge::new(source, target)
+            .materialize(MaterializeRule::copy("lorem.txt").unwrap())
+            .render(Arc::new(move |_: &Path, content: &[u8]| {
+                *observed.lock().unwrap() = is_maintenance_active(Path::new("test_files/test_dir2"));
+                content.to_vec()
+            }))
+            .maintenance_marker(true);
+
+        merge.run().unwrap();
+            assert!(*active.lock().unwrap());
+            assert!(!is_maintenance_active(target));
+            assert!(!target.join(MAINTENANCE_MARKER).exists());
+
+        // Marker of a running process blocks the merge, marker of a crashed one is replaced
+        write(target.join(MAINTENANCE_MARKER), "1 0").unwrap();
+            assert!(is_maintenance_active(target));
+            assert!(merge.run().is_err());
+
+        write(target.join(MAINTENANCE_MARKER), "4294967295 0").unwrap();
+            assert!(!is_maintenance_active(target));
+            assert!(merge.run().is_ok());
 
-                }
-            };
+    }
 
+    #[test]
+    fn observe_merged_entries() {
+
+        /// Logs every notification as a single line
+        #[derive(Default)]
+        struct Log(Mutex<Vec<String>>);
+
+        impl MergeObserver for Log {
+            fn on_entry_start(&self, source: &Path) {
+                self.0.lock().unwrap().push(format!("start {}", source.file_name().unwrap().to_string_lossy()));
+            }
+            fn on_skip(&self, source: &Path, _: &Path) {
+                self.0.lock().unwrap().push(format!("skip {}", source.file_name().unwrap().to_string_lossy()));
+            }
+            fn on_remove(&self, target: &Path) {
+                self.0.lock().unwrap().push(format!("remove {}", target.file_name().unwrap().to_string_lossy()));
+            }
+            fn on_symlink_created(&self, _: &Path, target: &Path) {
+                self.0.lock().unwrap().push(format!("symlink {}", target.file_name().unwrap().to_string_lossy()));
+            }
         }
 
-        symlink(&source_path, &target_path).with_context(|| format!("Failed to create symlink from ({source_path:?}) to ({target_path:?})"))?;
+        let _lock = prepare_test_directory();
+        let log = Arc::new(Log::default());
+
+        SymlinkMerge::new("test_files/test_dir1", "test_files/test_dir2")
+            .overwrite(Overwrite::Files)
+            .observer(log.clone())
+            .run()
+            .unwrap();
+
+        let log = log.0.lock().unwrap();
+            assert_eq!(log.iter().filter(|line| line.starts_with("start")).count(), 8);
+            assert!(log.contains(&"skip do_not_overwrite.txt".to_string()));
+            assert!(log.contains(&"remove ipsum.php".to_string()));
+            assert!(log.contains(&"symlink lorem.txt".to_string()));
 
     }
 
-    Ok(())
+    #[test]
+    fn record_resource_usage() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { materialize: vec![MaterializeRule::copy("lorem.txt").unwrap()], ..Default::default() };
+
+        let report = merge(source, target, &options).unwrap();
+        let usage = report.usage;
+            assert_eq!(usage.links, 2);
+            assert_eq!(usage.copies, 1);
+            assert_eq!(usage.removals, 0);
+            assert_eq!(usage.bytes_copied, source.join("lorem.txt").metadata().unwrap().len());
+            assert_eq!(usage.listings, 6);
+            assert!(usage.plan_memory > 0);
+            assert!(usage.elapsed > std::time::Duration::ZERO);
 
-}
+    }
 
-fn go_deeper(stack: &mut Vec<std::io::Result<DirEntry>>, path: &Path) -> Result<()> {
-    let path = resolve_symlink(&path).with_context(|| format!("Couldn't resolve path ({path:?})"))?;
-    let listing = read_dir(&path).with_context(|| format!("Directory listing ({path:?}) has failed"))?;
-    stack.extend(listing);
-    Ok(())
-}
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn merge_directories_asynchronously() {
+
+        use crate::{generate_symlinks_async, MergeReport};
+
+        let _lock = prepare_test_directory();
+        let (source, target, copy) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Path::new("test_files/test_dir3"));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        copy_tree(target, copy, TreeLinks::Copy).unwrap();
+
+        // Spawning proves the future can move between threads of a service runtime
+        let report = runtime.block_on(runtime.spawn(generate_symlinks_async(source, target, Overwrite::Files))).unwrap().unwrap();
+            assert!(target.join("ipsum.php").is_symlink());
+            assert!(target.join("nested/dolor.cpp").is_symlink());
+            assert!(!target.join("keep/do_not_overwrite.txt").is_symlink());
+
+        // Blocking merge of the same target makes the very same changes
+        let expected = generate_symlinks(source, copy, Overwrite::Files).unwrap();
+        let relative = |report: &MergeReport, root: &Path| {
+            let root = root.canonicalize().unwrap();
+            report.changes.iter().map(|change| (change.target.strip_prefix(&root).unwrap().to_path_buf(), change.kind, change.replace)).collect::<Vec<_>>()
+        };
+            assert_eq!(relative(&report, target), relative(&expected, copy));
+            assert_eq!(report.skipped.len(), expected.skipped.len());
+            assert_eq!(report.usage.links, 5);
 
-fn resolve_symlink(path: &Path) -> std::io::Result<PathBuf> {
-    match path.is_symlink() {
-        true => path.read_link(),
-        false => Ok(path.to_path_buf())
     }
-}
 
-fn keep_path(path: &Path, keep: &[&str]) -> bool {
+    #[cfg(unix)]
+    #[test]
+    fn preserve_logical_target_path() {
 
-    if keep.iter().any(|&k| {
-        let mut p = path.to_path_buf();
-        p.push(k);
-        p.exists()
-    }) { return true; }
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/www"));
 
-    path.ancestors().any(|ancestor| {
-        keep.iter().any(|&k| {
-            let mut p = ancestor.to_path_buf();
-            p.set_file_name(k);
-            p.exists()
-        })
-    })
+        symlink("test_dir2", target).unwrap();
 
-}
+        let report = SymlinkMerge::new(source, target).link_style(LinkStyle::Relative).preserve_target_path(true).run().unwrap();
+        let logical = std::path::absolute(target).unwrap();
+            assert!(report.changes.iter().all(|change| change.target.starts_with(&logical)));
+            assert_eq!(read_link(target.join("lorem.txt")).unwrap(), Path::new("../test_dir1/lorem.txt"));
+            assert!(target.join("lorem.txt").is_file());
+
+        // Logical path has to lead to a directory all the same
+        symlink("test_file1.txt", "test_files/file").unwrap();
+            assert!(SymlinkMerge::new(source, "test_files/file").preserve_target_path(true).run().is_err());
 
-fn remove_path(path: &Path) -> std::io::Result<()> {
-    match path.is_file() {
-        true => remove_file(path),
-        false => remove_dir_all(path)
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn swap_source_of_target() {
+
+        let _lock = prepare_test_directory();
+        let (blue, green, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/green"), Path::new("test_files/test_dir2"));
 
-    use std::fs::{create_dir, File, remove_dir_all};
-    use std::path::Path;
-    use crate::{generate_symlinks, Overwrite};
+        copy_tree(blue, green, TreeLinks::Copy).unwrap();
+        SymlinkMerge::new(blue, target).link_style(LinkStyle::Relative).run().unwrap();
+
+        let swapped = swap_source(target, blue, green).unwrap();
+            assert_eq!(swapped.len(), 3);
+            assert_eq!(read_link(target.join("lorem.txt")).unwrap(), Path::new("../green/lorem.txt"));
+            assert_eq!(read_link(target.join("nested/lorem")).unwrap(), Path::new("../../green/nested/lorem"));
+            assert!(target.join("keep/haha.yml").canonicalize().unwrap().starts_with(green.canonicalize().unwrap()));
+
+        // Nothing is swapped back while the other source is incomplete
+        remove_file(blue.join("lorem.txt")).unwrap();
+            assert!(swap_source(target, green, blue).is_err());
+            assert_eq!(read_link(target.join("keep/haha.yml")).unwrap(), Path::new("../../green/keep/haha.yml"));
+
+    }
 
+    #[cfg(unix)]
     #[test]
-    fn accepts_only_directories() {
+    fn swap_dangling_relative_symlinks() {
 
-        prepare_test_directory();
+        let _lock = prepare_test_directory();
+        let (blue, green, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/green"), Path::new("test_files/test_dir2"));
 
-        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_file1.txt"), Overwrite::All).is_err());
-        assert!(generate_symlinks(Path::new("test_files/test_file2.json"), Path::new("test_files/test_dir2"), Overwrite::All).is_err());
-        assert!(generate_symlinks(Path::new("test_files/test_file2.json"), Path::new("test_files/test_file1.txt"), Overwrite::All).is_err());
+        copy_tree(blue, green, TreeLinks::Copy).unwrap();
+        File::create(green.join("gone.txt")).unwrap();
+        symlink("../test_dir1/gone.txt", target.join("gone.txt")).unwrap();
+
+        let swapped = swap_source(target, blue, green).unwrap();
+            assert_eq!(swapped.len(), 1);
+            assert_eq!(read_link(target.join("gone.txt")).unwrap(), Path::new("../green/gone.txt"));
+            assert!(target.join("gone.txt").is_file());
 
     }
 
+    #[cfg(unix)]
     #[test]
-    fn merge_directories_without_overwrite() {
+    fn prune_dangling_symlinks() {
 
-        prepare_test_directory();
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
 
-        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::None).is_ok());
-            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
-                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/nested").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
-                assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+        SymlinkMerge::new(source, target).link_style(LinkStyle::Relative).run().unwrap();
+        symlink("../gone.txt", target.join("unrelated")).unwrap();
+        remove_file(source.join("lorem.txt")).unwrap();
+        remove_dir_all(source.join("nested")).unwrap();
+
+        let target_root = target.canonicalize().unwrap();
+        let pruned = prune_broken_symlinks(target, &PruneScope::Source(source.to_path_buf())).unwrap();
+            assert_eq!(pruned, [target_root.join("lorem.txt"), target_root.join("nested/lorem")]);
+            assert!(target.join("keep/haha.yml").is_symlink());
+            assert!(target.join("unrelated").is_symlink());
+
+            assert_eq!(prune_broken_symlinks(target, &PruneScope::All).unwrap(), [target_root.join("unrelated")]);
 
     }
 
     #[test]
-    fn merge_directories_with_files_overwrite() {
+    fn run_triggers_of_changed_subpaths() {
 
-        prepare_test_directory();
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        std::fs::create_dir_all(source.join("share/man/man1")).unwrap();
+        File::create(source.join("share/man/man1/lorem.1")).unwrap();
 
-        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::Files).is_ok());
-            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
-            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
-                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/nested").is_symlink());
-                assert!(Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
-                assert!(Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+        let merge = SymlinkMerge::new(source, target)
+            .trigger(Trigger::new("share/man", ["touch", "indexed"]))
+            .trigger(Trigger::new("nested", ["false"]))
+            .trigger(Trigger::new("ipsum.php", ["touch", "unrelated"]));
+
+        // Symlinked parent directory counts as a change of the subpath
+        let report = merge.clone().run().unwrap();
+            assert_eq!(report.triggered.iter().map(|trigger| trigger.path.as_path()).collect::<Vec<_>>(), [Path::new("share/man"), Path::new("nested")]);
+            assert!(!target.join("unrelated").exists());
+            assert!(target.join("indexed").is_file());
+            assert!(matches!(&report.warnings[..], [Warning::TriggerFailed { path, .. }] if path == Path::new("nested")));
+
+        remove_file(target.join("indexed")).unwrap();
+            assert!(merge.run().unwrap().triggered.is_empty());
+            assert!(!target.join("indexed").exists());
 
     }
 
     #[test]
-    fn merge_directories_with_directories_overwrite() {
+    fn soft_delete_replaced_paths() {
 
-        prepare_test_directory();
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        write(target.join("ipsum.php"), "local").unwrap();
 
-        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::Dirs).is_ok());
-            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/ipsum.php").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
-                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
-            assert!(Path::new("test_files/test_dir2/nested").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+        SymlinkMerge::new(source, target).overwrite(Overwrite::Files).soft_delete(true).run().unwrap();
+
+        let saved: Vec<_> = std::fs::read_dir(target).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(&format!("ipsum.php{SAVED_SUFFIX}")))
+            .collect();
+            assert!(target.join("ipsum.php").is_symlink());
+            assert_eq!(saved.len(), 1);
+            assert_eq!(read_to_string(target.join(&saved[0])).unwrap(), "local");
+
+            assert!(SymlinkMerge::new(source, target).soft_delete(true).backup(".bak").run().is_err());
 
     }
 
+    #[cfg(unix)]
     #[test]
-    fn merge_directories_with_all_overwrite() {
+    fn verify_and_repair_symlinks() {
 
-        prepare_test_directory();
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
 
-        assert!(generate_symlinks(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Overwrite::All).is_ok());
-            assert!(Path::new("test_files/test_dir2/lorem.txt").is_symlink());
-            assert!(Path::new("test_files/test_dir2/ipsum.php").is_symlink());
-            assert!(!Path::new("test_files/test_dir2/keep").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/.keep").is_symlink());
-                assert!(Path::new("test_files/test_dir2/keep/haha.yml").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/keep/do_not_overwrite.txt").is_symlink());
-            assert!(Path::new("test_files/test_dir2/nested").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/nested/dolor.cpp").is_symlink());
-                assert!(!Path::new("test_files/test_dir2/nested/lorem").is_symlink());
+        generate_symlinks(source, target, Overwrite::None).unwrap();
+        remove_file(target.join("lorem.txt")).unwrap();
+        remove_file(target.join("nested/lorem")).unwrap();
+        symlink("../../test_file1.txt", target.join("nested/lorem")).unwrap();
+        symlink("../test_dir1/gone.txt", target.join("gone.txt")).unwrap();
+
+        let target_root = target.canonicalize().unwrap();
+        let diff = verify_symlinks(source, target, &MergeOptions::default()).unwrap();
+            assert_eq!(diff.missing, [target_root.join("lorem.txt")]);
+            assert_eq!(diff.wrong_target, [(target_root.join("nested/lorem"), PathBuf::from("../../test_file1.txt"))]);
+            assert_eq!(diff.replaced, [target_root.join("ipsum.php"), target_root.join("nested/dolor.cpp")]);
+            assert_eq!(diff.extra, [target_root.join("gone.txt")]);
+
+        let options = MergeOptions { overwrite: Overwrite::Files, ..Default::default() };
+        diff.repair(source, target, &options).unwrap();
+            assert!(!target.join("gone.txt").is_symlink());
+            assert!(verify_symlinks(source, target, &options).unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn diff_drift_between_runs() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let target_root = target.canonicalize().unwrap();
+
+        generate_symlinks(source, target, Overwrite::None).unwrap();
+        remove_file(target.join("nested/lorem")).unwrap();
+        let previous = verify_symlinks(source, target, &MergeOptions::default()).unwrap();
+
+        // Missing symlink is back, another one got shadowed by a real file
+        generate_symlinks(source, target, Overwrite::None).unwrap();
+        remove_file(target.join("lorem.txt")).unwrap();
+        File::create(target.join("lorem.txt")).unwrap();
+
+        let current = verify_symlinks(source, target, &MergeOptions::default()).unwrap();
+        let delta = current.diff(&previous);
+            assert_eq!(delta.appeared.replaced, [target_root.join("lorem.txt")]);
+            assert!(delta.appeared.missing.is_empty() && delta.appeared.wrong_target.is_empty() && delta.appeared.extra.is_empty());
+            assert_eq!(delta.repaired, [target_root.join("nested/lorem")]);
+            assert!(current.diff(&current).is_empty());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn leave_reserved_names_alone() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        for name in RESERVED_NAMES {
+            std::fs::write(source.join(name), "source").unwrap();
+            std::fs::write(target.join(name), "target").unwrap();
+        }
+        std::fs::write(source.join(format!("{TEMP_PREFIX}1-staged")), "source").unwrap();
+        symlink("../gone.txt", target.join(format!("{TEMP_PREFIX}1-staged"))).unwrap();
+
+        let report = SymlinkMerge::new(source, target).overwrite(Overwrite::All).run().unwrap();
+            assert_eq!(report.usage.links, 4);
+            for name in RESERVED_NAMES {
+                assert_eq!(std::fs::read_to_string(target.join(name)).unwrap(), "target");
+            }
+
+        let explanation = explain(source, target, &MergeOptions::default(), Path::new(RESERVED_NAMES[0])).unwrap();
+            assert_eq!(explanation.verdict, Verdict::Skip);
+            assert_eq!(explanation.reasons, [Reason::Reserved]);
+
+        assert!(verify_symlinks(source, target, &MergeOptions { overwrite: Overwrite::All, ..MergeOptions::default() }).unwrap().is_empty());
+        assert!(prune_broken_symlinks(target, &PruneScope::All).unwrap().is_empty());
+            assert!(target.join(format!("{TEMP_PREFIX}1-staged")).is_symlink());
+
+    }
+
+    #[test]
+    fn layer_sources_by_priority() {
+
+        let _lock = prepare_test_directory();
+        let (first, last) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let resolve = |path: PathBuf| path.canonicalize().unwrap();
+
+        create_dir(Path::new("test_files/test_dir3")).unwrap();
+        let target = resolve(PathBuf::from("test_files/test_dir3"));
+        let report = generate_symlinks_layered(&[first, last], &target, Overwrite::None, LayerPriority::FirstWins).unwrap();
+            assert_eq!(resolve(target.join("ipsum.php")), resolve(first.join("ipsum.php")));
+            assert_eq!(resolve(target.join("keep/do_not_overwrite.txt")), resolve(first.join("keep/do_not_overwrite.txt")));
+            assert_eq!(resolve(target.join("nested/original.rs")), resolve(last.join("nested/original.rs")));
+            assert!(!target.join("nested").is_symlink());
+            assert_eq!(report.layer_of(&target.join("ipsum.php")), Some(first));
+            assert_eq!(report.layer_of(&target.join("index.html")), Some(last));
+            assert_eq!(report.layer_of(&target.join("missing")), None);
+
+        create_dir(Path::new("test_files/test_dir4")).unwrap();
+        let target = resolve(PathBuf::from("test_files/test_dir4"));
+        let report = generate_symlinks_layered(&[first, last], &target, Overwrite::None, LayerPriority::LastWins).unwrap();
+            assert_eq!(resolve(target.join("ipsum.php")), resolve(last.join("ipsum.php")));
+            assert_eq!(resolve(target.join("nested/dolor.cpp")), resolve(last.join("nested/dolor.cpp")));
+            assert_eq!(resolve(target.join("lorem.txt")), resolve(first.join("lorem.txt")));
+            assert_eq!(report.layer_of(&target.join("nested/dolor.cpp")), Some(last));
+
+    }
+
+    #[test]
+    fn normalize_paths_of_other_tools() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        assert_eq!(normalize_rel_path(Path::new("./share//man/")).unwrap(), Path::new("share/man"));
+        assert_eq!(normalize_rel_path(Path::new(".")).unwrap(), Path::new(""));
+        assert!(normalize_rel_path(Path::new("share/../../etc")).is_err());
+        assert!(normalize_rel_path(Path::new("/etc")).is_err());
+
+        assert!(Glob::new("./nested/*.cpp").unwrap().matches(Path::new("nested/dolor.cpp"), false));
+        assert!(Trigger::new("./share//man/", ["true"]).fires(Path::new("./share/man/man1")));
+        assert_eq!(explain(source, target, &MergeOptions::default(), Path::new("./nested//lorem/")).unwrap().path, Path::new("nested/lorem"));
+
+        merge_listed(source, target, "./lorem.txt\nnested//lorem/\n", &MergeOptions::default()).unwrap();
+            assert!(target.join("lorem.txt").is_symlink());
+            assert!(target.join("nested/lorem").is_dir());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn classify_typed_errors() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        let error = generate_symlinks(Path::new("test_files/missing"), target, Overwrite::None).unwrap_err();
+            assert!(matches!(SolderiumError::from(error), SolderiumError::SourceMissing(path) if path == Path::new("test_files/missing")));
+
+        symlink("loop", Path::new("test_files/loop")).unwrap();
+        let error = generate_symlinks(Path::new("test_files/loop"), target, Overwrite::None).unwrap_err();
+            assert!(matches!(SolderiumError::from(error), SolderiumError::SymlinkLoop(_)));
+
+        File::create(source.join("nested/.keep")).unwrap();
+        let options = MergeOptions { source_keep_markers: SourceKeepMarkers::Error, ..MergeOptions::default() };
+        let error = merge(source, target, &options).unwrap_err();
+            assert!(matches!(SolderiumError::from(error), SolderiumError::KeepFileConflict(path) if path.ends_with("nested/.keep")));
+
+        let interrupted = OutOfSpace { completed: 0, failed: target.join("lorem.txt"), remaining: Vec::new() };
+        assert!(matches!(SolderiumError::from(anyhow::Error::new(interrupted.clone())), SolderiumError::OutOfSpace(error) if error == interrupted));
+        assert!(matches!(SolderiumError::from(anyhow::anyhow!("unknown")), SolderiumError::Other(_)));
+
+    }
+
+    #[test]
+    fn continue_after_failed_entries() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { source_keep_markers: SourceKeepMarkers::Error, ..MergeOptions::default() };
+        File::create(source.join("nested/.keep")).unwrap();
+
+        assert!(Operation::new().errors(ErrorPolicy::Skip).merge(source, target, &options).is_err());
+
+        let report = Operation::new().errors(ErrorPolicy::Continue).merge(source, target, &options).unwrap();
+            assert!(matches!(&report.failed().collect::<Vec<_>>()[..], [(path, error)] if path.ends_with("nested/.keep") && error.contains("keep marker")));
+            assert!(target.join("lorem.txt").is_symlink());
+            assert!(target.join("nested/lorem").is_symlink());
+
+    }
+
+    #[test]
+    fn prefetch_metadata_of_upcoming_entries() {
+
+        let _lock = prepare_test_directory();
+        let (source, target, copy) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Path::new("test_files/test_dir3"));
+        copy_tree(target, copy, TreeLinks::Copy).unwrap();
+
+        let prefetched = MergeOptions { overwrite: Overwrite::Files, concurrency: Concurrency { prefetch: 2, ..Default::default() }, ..Default::default() };
+        let report = merge(source, target, &prefetched).unwrap();
+            assert_eq!(report.usage.links, 5);
+            assert!(target.join("nested/dolor.cpp").is_symlink());
+
+        // Prefetching only reads ahead, the changes are the same as without it
+        let expected = merge(source, copy, &MergeOptions { overwrite: Overwrite::Files, ..Default::default() }).unwrap();
+        let relative = |report: &crate::MergeReport, root: &Path| {
+            let root = root.canonicalize().unwrap();
+            report.changes.iter().map(|change| change.target.strip_prefix(&root).unwrap().to_path_buf()).collect::<Vec<_>>()
+        };
+            assert_eq!(relative(&report, target), relative(&expected, copy));
+
+    }
+
+    #[test]
+    fn limit_merged_depth() {
+
+        let _lock = prepare_test_directory();
+        let (source, target, empty) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Path::new("test_files/test_dir3"));
+        create_dir(empty).unwrap();
+
+        let merge = SymlinkMerge::new(source, target).min_depth(2);
+            assert!(merge.clone().max_depth(1).run().is_err());
+
+        let explanation = explain(source, target, merge.merge_options(), Path::new("lorem.txt")).unwrap();
+            assert_eq!(explanation.verdict, Verdict::Skip);
+            assert_eq!(explanation.reasons, [Reason::MinDepth(1)]);
+
+        merge.run().unwrap();
+            assert!(!target.join("lorem.txt").exists());
+            assert!(target.join("keep/haha.yml").is_symlink());
+            assert!(target.join("nested/lorem").is_symlink());
+
+        SymlinkMerge::new(source, empty).min_depth(2).max_depth(2).run().unwrap();
+            assert!(!empty.join("ipsum.php").exists());
+            assert!(empty.join("nested").is_dir() && !empty.join("nested").is_symlink());
+            assert!(empty.join("nested/lorem").is_symlink());
+            assert!(empty.join("keep/haha.yml").is_symlink());
+
+    }
+
+    #[test]
+    fn merge_link_farm_categories() {
+
+        let _lock = prepare_test_directory();
+        let (movies, shows, farm) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Path::new("test_files/test_dir3"));
+
+        let farm = LinkFarm::new(farm, vec![
+            Category::new("movies", [movies], MergeOptions::default()),
+            Category::new("./shows/", [shows, movies], MergeOptions { exclude: vec![Glob::new("*.php").unwrap()], ..Default::default() }),
+            Category::new("music", ["test_files/missing"], MergeOptions::default())
+        ]);
+
+        let report = farm.run().unwrap();
+            assert!(farm.target.join("movies/lorem.txt").is_symlink());
+            assert!(farm.target.join("movies/nested").is_symlink());
+            assert!(farm.target.join("shows/index.html").is_symlink());
+            assert!(farm.target.join("shows/lorem.txt").is_symlink());
+            assert!(!farm.target.join("shows/ipsum.php").exists());
+            assert!(report.changes().any(|change| change.target.ends_with("shows/nested/original.rs")));
+            assert!(matches!(&report.failed().collect::<Vec<_>>()[..], [("music", _)]));
+
+        let duplicate = LinkFarm::new("test_files/test_dir3", vec![Category::new("shows", [shows], MergeOptions::default()), Category::new("shows/", [movies], MergeOptions::default())]);
+            assert!(duplicate.run().is_err());
+
+    }
+
+    #[test]
+    fn copy_mutable_directories_once() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { overwrite: Overwrite::All, materialize: vec![MaterializeRule::copy_once("var/").unwrap()], ..Default::default() };
+        create_dir(source.join("var")).unwrap();
+        write(source.join("var/cache.db"), "seed").unwrap();
+
+        merge(source, target, &options).unwrap();
+            assert!(target.join("var").is_dir() && !target.join("var").is_symlink());
+            assert_eq!(read_to_string(target.join("var/cache.db")).unwrap(), "seed");
+
+        // Later merges never replace the copy, whatever the overwrite policy
+        write(target.join("var/cache.db"), "local").unwrap();
+        let report = merge(source, target, &options).unwrap();
+            assert!(report.changes.iter().all(|change| !change.target.ends_with("var")));
+            assert_eq!(read_to_string(target.join("var/cache.db")).unwrap(), "local");
+            assert_eq!(explain(source, target, &options, Path::new("var")).unwrap().reasons.last(), Some(&Reason::CopiedOnce));
+
+        remove_dir_all(target.join("var")).unwrap();
+        merge(source, target, &options).unwrap();
+            assert_eq!(read_to_string(target.join("var/cache.db")).unwrap(), "seed");
+
+    }
+
+    #[test]
+    fn unfold_and_fold_target_directories() {
+
+        let _lock = prepare_test_directory();
+        let (package, other, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"), Path::new("test_files/test_dir3"));
+        let options = MergeOptions { refold: true, ..Default::default() };
+        create_dir(target).unwrap();
+
+        // Directory folded by another package is unfolded instead of merged into
+        merge(other, target, &MergeOptions::default()).unwrap();
+        let report = merge(package, target, &options).unwrap();
+            assert!(report.unfolded.iter().any(|path| path.ends_with("nested")));
+            assert!(!target.join("nested").is_symlink());
+            assert!(!other.join("nested/lorem").exists());
+            assert_eq!(target.join("nested/original.rs").canonicalize().unwrap(), other.join("nested/original.rs").canonicalize().unwrap());
+            assert_eq!(target.join("nested/lorem").canonicalize().unwrap(), package.join("nested/lorem").canonicalize().unwrap());
+
+        // Once the other package is gone, the directory holds only links into the package and is folded back
+        remove_file(target.join("nested/dolor.cpp")).unwrap();
+        remove_file(target.join("nested/original.rs")).unwrap();
+
+        let report = merge(package, target, &options).unwrap();
+            assert!(report.folded.iter().any(|path| path.ends_with("nested")));
+            assert!(target.join("nested").is_symlink());
+            assert_eq!(target.join("nested").canonicalize().unwrap(), package.join("nested").canonicalize().unwrap());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_trees_without_following_symlinks() {
+
+        let _lock = prepare_test_directory();
+        let (source, copied, skipped) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir3"), Path::new("test_files/test_dir4"));
+
+        symlink("missing.txt", source.join("dangling")).unwrap();
+        symlink("..", source.join("nested/cycle")).unwrap();
+
+        assert!(copy_tree(source, copied, TreeLinks::Copy).unwrap().is_empty());
+            assert_eq!(read_link(copied.join("dangling")).unwrap(), Path::new("missing.txt"));
+            assert_eq!(read_link(copied.join("nested/cycle")).unwrap(), Path::new(".."));
+            assert!(copied.join("nested/dolor.cpp").is_file());
+
+        let mut left_out = copy_tree(source, skipped, TreeLinks::Skip).unwrap();
+        left_out.sort();
+            assert_eq!(left_out, [source.join("dangling"), source.join("nested/cycle")]);
+            assert!(!skipped.join("dangling").is_symlink() && !skipped.join("nested/cycle").is_symlink());
+            assert!(skipped.join("keep/haha.yml").is_file());
+
+    }
+
+    #[test]
+    fn recommend_strategy_for_trees() {
+
+        let _lock = prepare_test_directory();
+
+        // Target contains local files next to the conflicting ones
+        let recommendation = recommend_strategy(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2")).unwrap();
+            assert_eq!(recommendation.strategy, Strategy::Deep);
+            assert_eq!(recommendation.overwrite, Overwrite::None);
+            assert_eq!(recommendation.sample.conflicts, 5);
+
+        // Empty target
+        create_dir(Path::new("test_files/test_dir3")).unwrap();
+
+        let recommendation = recommend_strategy(Path::new("test_files/test_dir1"), Path::new("test_files/test_dir3")).unwrap();
+            assert_eq!(recommendation.strategy, Strategy::Fold);
+            assert_eq!(recommendation.sample.entries, 4);
+
+    }
+
+    #[test]
+    fn merge_fetched_source() {
+
+        let _lock = prepare_test_directory();
+        let cache = Path::new("test_files/cache");
+        let source = CommandSource::new(cache, ["cp", "-r", "test_files/test_dir1/."]);
+
+        merge_from(&source, Path::new("test_files/test_dir2"), &MergeOptions::default()).unwrap();
+            assert!(cache.join("lorem.txt").is_file());
+            assert_eq!(Path::new("test_files/test_dir2/lorem.txt").canonicalize().unwrap(), cache.join("lorem.txt").canonicalize().unwrap());
+
+        // Cached copy is reused until invalidated
+        std::fs::remove_file(cache.join("lorem.txt")).unwrap();
+            assert!(!source.fetch().unwrap().join("lorem.txt").exists());
+
+        source.invalidate().unwrap();
+            assert!(!cache.exists());
+            assert!(source.fetch().unwrap().join("lorem.txt").is_file());
+
+    }
+
+    #[test]
+    fn store_sources_by_content() {
+
+        let _lock = prepare_test_directory();
+        let store = ContentStore::new("test_files/store");
+
+        let first = store.insert(Path::new("test_files/test_dir1")).unwrap();
+        let second = StoredSource::new(store.clone(), PathBuf::from("test_files/test_dir1")).fetch().unwrap();
+            assert_eq!(first, second);
+            assert!(first.join("nested/dolor.cpp").is_file());
+            assert_eq!(store.get(first.file_name().unwrap().to_str().unwrap()), Some(first.clone()));
+            assert_eq!(std::fs::read_dir("test_files/store/tmp").unwrap().count(), 0);
+
+        write(Path::new("test_files/test_dir1/lorem.txt"), "changed").unwrap();
+
+        let changed = store.insert(Path::new("test_files/test_dir1")).unwrap();
+            assert_ne!(first, changed);
+            assert!(first.is_dir());
+
+        merge(&changed, Path::new("test_files/test_dir2"), &MergeOptions::default()).unwrap();
+            assert_eq!(read_to_string(Path::new("test_files/test_dir2/lorem.txt")).unwrap(), "changed");
+
+        store.remove(first.file_name().unwrap().to_str().unwrap()).unwrap();
+            assert!(!first.exists());
 
     }
 
     fn cleanup_test_directory() {
-        remove_dir_all(Path::new("test_files")).unwrap();
+        if Path::new("test_files").exists() {
+            remove_dir_all(Path::new("test_files")).unwrap();
+        }
     }
 
-    fn prepare_test_directory() {
+    pub(crate) fn prepare_test_directory() -> MutexGuard<'static, ()> {
+
+        // A failed test must not block the rest
+        let lock = TEST_DIRECTORY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
 
         cleanup_test_directory();
 
@@ -305,6 +2408,8 @@ mod tests {
                 File::create(Path::new("test_files/test_dir2/nested/dolor.cpp")).unwrap();
                 File::create(Path::new("test_files/test_dir2/nested/original.rs")).unwrap();
 
+        lock
+
     }
 
 }
@@ -0,0 +1,8 @@
+//! The `solderium` binary, see [solderium::cli]
+
+use std::env;
+use std::process::exit;
+
+fn main() {
+    exit(solderium::cli::main(env::args_os().skip(1)));
+}
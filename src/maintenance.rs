@@ -0,0 +1,88 @@
+use std::fs::{OpenOptions, read_to_string, remove_file};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use crate::temp::{owner_gone, STALE_AGE};
+
+/// Name of the file marking a merge making changes in the target directory, see [MergeOptions::maintenance_marker](crate::MergeOptions::maintenance_marker)
+pub const MAINTENANCE_MARKER: &str = ".solderium-maintenance";
+
+/// Maintenance marker of the running merge, removed once dropped
+pub(crate) struct Maintenance {
+    path: PathBuf
+}
+
+impl Maintenance {
+
+    /// Create the marker in the `target` root, fails while another process holds it.
+    ///
+    /// The marker holds the process id and the Unix timestamp it was created at, marker of a crashed
+    /// process is replaced.
+    pub fn start(target: &Path) -> Result<Self> {
+
+        let path = target.join(MAINTENANCE_MARKER);
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+
+                    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+                    let result = writeln!(file, "{} {created}", process::id());
+
+                    // Marker is removed right away when writing it fails
+                    let maintenance = Self { path };
+                    result.with_context(|| format!("Couldn't write maintenance marker ({:?})", maintenance.path))?;
+
+                    return Ok(maintenance);
+
+                },
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+
+                    if is_maintenance_active(target) {
+                        bail!("Target ({target:?}) is under maintenance by another process ({path:?})");
+                    }
+
+                    match remove_file(&path) {
+                        Err(error) if error.kind() != ErrorKind::NotFound => {
+                            return Err(error).with_context(|| format!("Couldn't remove stale maintenance marker ({path:?})"));
+                        },
+                        _ => continue
+                    }
+
+                },
+                Err(error) => return Err(error).with_context(|| format!("Couldn't create maintenance marker ({path:?})"))
+            }
+        }
+
+    }
+
+}
+
+impl Drop for Maintenance {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Check whether a merge is making changes in the `target` directory right now, so cooperating
+/// processes (e.g. backup or rsync jobs) can wait for it to finish.
+///
+/// Markers left by crashed processes don't count, marker of unknown format counts until it's a day old.
+pub fn is_maintenance_active(target: &Path) -> bool {
+
+    let path = target.join(MAINTENANCE_MARKER);
+
+    let content = match read_to_string(&path) {
+        Ok(content) => content,
+        Err(_) => return false
+    };
+
+    match content.split_whitespace().next().and_then(|pid| pid.parse().ok()) {
+        Some(pid) => pid == process::id() || !owner_gone(pid, &path),
+        // Marker being written right now is empty as well
+        None => path.metadata().and_then(|metadata| metadata.modified()).is_ok_and(|modified| modified.elapsed().unwrap_or_default() < STALE_AGE)
+    }
+
+}
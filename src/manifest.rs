@@ -0,0 +1,628 @@
+//! Human readable record of the symlinks created by merges, kept in the target directory
+//!
+//! Stored as JSON in [MANIFEST_NAME] inside the target, so undoing or checking a deployment doesn't need
+//! the source tree (or even the original options) at hand. For huge link sets see the binary manifest.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{canonicalize, create_dir_all, read_dir, read_to_string, remove_file, rename, write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use crate::{ChangeKind, default_hasher, hash_file, is_reserved, Materialize, merge, MergeOptions, MergeReport};
+use crate::hash::hex;
+use crate::merge::{materialized, source_root, target_root, Walk};
+use crate::normalize::clean_path;
+use crate::platform::{identity, remove_link};
+use crate::reserved::PACKAGES_DIR;
+use crate::store::{references, remove_tree};
+use crate::temp::temp_path;
+
+pub use crate::reserved::MANIFEST_NAME;
+/// Format version written into the manifest
+const VERSION: u32 = 1;
+
+/// Symlinks created in a single target directory, see [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Source directory of the last merge
+    pub source: PathBuf,
+    /// Symlinks ordered by their path
+    pub links: Vec<ManifestLink>,
+    /// Paths copied by [Materialize::CopyOnce] rules, ordered. They are never
+    /// copied again, even when the deployment removes them.
+    #[serde(default)]
+    pub seeded: Vec<PathBuf>,
+    /// Tree of a content store the source was extracted into (see the archive source), released by
+    /// [Manifest::undo], so it's removed once no deployment uses it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extracted: Option<PathBuf>
+}
+
+/// Single symlink recorded in a [Manifest].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestLink {
+    /// Path of the symlink
+    pub target: PathBuf,
+    /// Source entry the symlink leads to
+    pub source: PathBuf,
+    /// Unix timestamp of the merge which created the symlink
+    pub created: u64,
+    /// Device of the source file, inodes are only unique within a single one, see [Manifest::renames]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<u64>,
+    /// Inode of the source file, to recognize it once renamed, see [Manifest::renames]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    /// Content digest of the source file prefixed by the algorithm (e.g. `blake3:…`), see [Manifest::renames]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>
+}
+
+impl ManifestLink {
+
+    /// Record of the symlink leading to the source entry, files are identified by their inode and digest
+    fn new(target: PathBuf, source: PathBuf, created: u64) -> Self {
+        let (device, inode) = source.is_file().then(|| identity(&source).ok()).flatten().unzip();
+        let digest = inode.and_then(|_| digest(&source));
+        Self { target, source, created, device, inode, digest }
+    }
+
+}
+
+impl Manifest {
+
+    /// Read the manifest of the `target` directory
+    pub fn read(target: &Path) -> Result<Self> {
+        Self::read_from(&target.join(MANIFEST_NAME))
+    }
+
+    /// Read the manifest stored in the file
+    pub fn read_from(path: &Path) -> Result<Self> {
+
+        let content = read_to_string(path).with_context(|| format!("Couldn't read manifest ({path:?})"))?;
+        let mut manifest: Manifest = serde_json::from_str(&content).with_context(|| format!("Manifest ({path:?}) is invalid"))?;
+
+        if manifest.version > VERSION {
+            bail!("Manifest ({path:?}) has unsupported version {}", manifest.version);
+        }
+
+        // Manifests written by other tools may spell the same paths differently
+        manifest.source = clean_path(&manifest.source);
+        for link in &mut manifest.links {
+            link.target = clean_path(&link.target);
+            link.source = clean_path(&link.source);
+        }
+        manifest.seeded = manifest.seeded.iter().map(|path| clean_path(path)).collect();
+
+        Ok(manifest)
+
+    }
+
+    /// Add symlinks created by the merge, replacing records of the same paths.
+    ///
+    /// Records of symlinks which no longer lead to their source are dropped, so the manifest holds only
+    /// the links which are actually in place.
+    pub fn record(&mut self, source: &Path, report: &MergeReport) {
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        let links = report.changes.iter().filter(|change| change.kind == ChangeKind::Symlink)
+            .map(|change| ManifestLink::new(change.target.clone(), change.source.clone(), created));
+
+        self.version = VERSION;
+        self.source = source.to_path_buf();
+        self.links.retain(|link| leads_to_source(link) && !report.changes.iter().any(|change| change.target == link.target));
+        self.links.extend(links);
+        self.links.sort_by(|a, b| a.target.cmp(&b.target));
+
+        if let Some(options) = report.options() {
+            let seeded = report.changes.iter()
+                .filter(|change| matches!(change.kind, ChangeKind::Copy | ChangeKind::Directory))
+                .filter(|change| change.source.strip_prefix(source).is_ok_and(|relative| materialized(options, relative, change.kind == ChangeKind::Directory) == Materialize::CopyOnce))
+                .map(|change| change.target.clone());
+            self.seeded.extend(seeded);
+            self.seeded.sort();
+            self.seeded.dedup();
+        }
+
+    }
+
+    /// Add symlinks adopted from another tool (see [import](crate::import)) together with their
+    /// destinations, replacing records of the same paths
+    #[cfg(feature = "mmap")]
+    pub(crate) fn adopt(&mut self, source: &Path, links: impl IntoIterator<Item = (PathBuf, PathBuf)>) {
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        let links: Vec<_> = links.into_iter().map(|(target, destination)| ManifestLink::new(target, destination, created)).collect();
+
+        self.version = VERSION;
+        self.source = source.to_path_buf();
+        self.links.retain(|link| leads_to_source(link) && !links.iter().any(|adopted| adopted.target == link.target));
+        self.links.extend(links);
+        self.links.sort_by(|a, b| a.target.cmp(&b.target));
+
+    }
+
+    /// Replace the manifest of the `target` directory atomically
+    pub fn write(&self, target: &Path) -> Result<()> {
+        self.write_to(&target.join(MANIFEST_NAME))
+    }
+
+    /// Replace the manifest stored in the file atomically
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+
+        let temporary = temp_path(path);
+        let content = serde_json::to_string_pretty(self).with_context(|| "Couldn't serialize manifest")?;
+
+        write(&temporary, content).and_then(|()| rename(&temporary, path)).with_context(|| format!("Couldn't write manifest ({path:?})"))
+
+    }
+
+    /// Recorded symlinks which are missing or lead somewhere else than to their source
+    pub fn verify(&self) -> Vec<&Path> {
+        self.links.iter().filter(|link| !leads_to_source(link)).map(|link| link.target.as_path()).collect()
+    }
+
+    /// Remove recorded symlinks still leading to their source together with the manifest itself, returns removed symlinks.
+    ///
+    /// The [extracted](Manifest::extracted) source tree is removed as well, unless another deployment holds it.
+    pub fn undo(&self, target: &Path) -> Result<Vec<PathBuf>> {
+
+        let removed = self.remove_links()?;
+
+        if let Some(tree) = &self.extracted {
+            let holder = canonicalize(target).with_context(|| format!("Couldn't resolve target ({target:?})"))?;
+            release_tree(tree, &holder)?;
+        }
+
+        let path = target.join(MANIFEST_NAME);
+        remove_file(&path).with_context(|| format!("Couldn't remove manifest ({path:?})"))?;
+
+        Ok(removed)
+
+    }
+
+    /// Recorded files whose source entry was renamed since, as the old and the new target path.
+    ///
+    /// Source files of recorded symlinks are recognized by their device and inode (renames within the source tree keep
+    /// them) together with the recorded digest, so a reused inode isn't mistaken for a rename, or by their content digest
+    /// alone (unique and non-empty), [seeded](Manifest::seeded) copies by the content of the copy, so only unmodified
+    /// ones are followed. The `source` and `target` are resolved roots of the deployment.
+    pub fn renames(&self, source: &Path, target: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+
+        let seeded: Vec<(&Path, PathBuf)> = self.seeded.iter()
+            .filter_map(|path| path.strip_prefix(target).ok().map(|relative| (path.as_path(), self.source.join(relative))))
+            .collect();
+
+        let missing = |path: &Path| path.symlink_metadata().is_err();
+        let links: Vec<&ManifestLink> = self.links.iter().filter(|link| missing(&link.source) && (link.inode.is_some() || link.digest.is_some())).collect();
+        let copies: Vec<&Path> = seeded.iter().filter(|(path, source)| missing(source) && path.is_file()).map(|(path, _)| *path).collect();
+
+        if links.is_empty() && copies.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Only files nothing was recorded for can be the new names
+        let recorded: HashSet<&Path> = self.links.iter().map(|link| link.source.as_path()).chain(seeded.iter().map(|(_, source)| source.as_path())).collect();
+        let mut added = Vec::new();
+        collect_files(source, &recorded, &mut added)?;
+
+        let mut digests: HashMap<PathBuf, Option<String>> = HashMap::new();
+        let mut taken = HashSet::new();
+        let mut renames = Vec::new();
+
+        let mut find = |identified: Option<(u64, u64)>, expected: Option<String>, taken: &HashSet<PathBuf>| added.iter()
+            .filter(|path| !taken.contains(*path))
+            .find(|path| {
+                identified.is_some_and(|identified| identity(path).is_ok_and(|found| found == identified))
+                    && expected.as_ref().is_none_or(|expected| digests.entry(path.to_path_buf()).or_insert_with(|| digest(path)).as_ref() == Some(expected))
+            })
+            .or_else(|| expected.and_then(|expected| {
+                // Same content under several new names doesn't tell which one is the renamed file
+                let mut matching = added.iter()
+                    .filter(|path| !taken.contains(*path))
+                    .filter(|path| digests.entry(path.to_path_buf()).or_insert_with(|| digest(path)).as_ref() == Some(&expected));
+                matching.next().filter(|_| matching.next().is_none())
+            }))
+            .cloned();
+
+        let candidates = links.iter().map(|link| (link.target.as_path(), link.device.zip(link.inode), link.digest.clone()))
+            .chain(copies.iter().map(|path| (*path, None, digest(path))));
+
+        for (path, identified, expected) in candidates {
+            if let Some(found) = find(identified, expected, &taken) {
+                renames.push((path.to_path_buf(), target.join(found.strip_prefix(source)?)));
+                taken.insert(found);
+            }
+        }
+
+        renames.sort();
+        Ok(renames)
+
+    }
+
+    /// Carry decisions about the old target paths over to the new ones: stale symlinks are removed (the merge links
+    /// the new name), local files and seeded copies are moved to the new name, when it's free
+    fn carry(&mut self, renames: &[(PathBuf, PathBuf)]) -> Result<()> {
+
+        for (from, to) in renames {
+
+            if from.is_symlink() && !from.exists() {
+                remove_link(from).with_context(|| format!("Couldn't remove stale symlink ({from:?})"))?;
+                continue;
+            }
+
+            if from.is_symlink() || from.symlink_metadata().is_err() || to.symlink_metadata().is_ok() {
+                continue;
+            }
+
+            if let Some(parent) = to.parent() {
+                create_dir_all(parent).with_context(|| format!("Couldn't create directory ({parent:?})"))?;
+            }
+
+            rename(from, to).with_context(|| format!("Couldn't move renamed path ({from:?}) to ({to:?})"))?;
+
+            for seeded in self.seeded.iter_mut().filter(|seeded| *seeded == from) {
+                seeded.clone_from(to);
+            }
+
+        }
+
+        self.seeded.sort();
+        Ok(())
+
+    }
+
+    /// Remove recorded symlinks still leading to their source, returns removed symlinks
+    pub(crate) fn remove_links(&self) -> Result<Vec<PathBuf>> {
+
+        let mut removed = Vec::new();
+
+        for link in self.links.iter().filter(|link| link.target.is_symlink() && leads_to_source(link)) {
+            remove_link(&link.target).with_context(|| format!("Couldn't remove symlink ({:?})", link.target))?;
+            removed.push(link.target.clone());
+        }
+
+        Ok(removed)
+
+    }
+
+}
+
+/// Merge `source` into `target` like [merge], recording created symlinks in the manifest of the target.
+///
+/// Paths [seeded](Manifest::seeded) by earlier merges are left out, so removing them from the target is final.
+/// Source files [renamed](Manifest::renames) since the previous merge take local files and seeded copies of
+/// the old name along, see [MergeReport::renamed].
+pub fn merge_with_manifest(source: &Path, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+    merge_recorded(source, target, options, &target.join(MANIFEST_NAME))
+}
+
+/// Merge recording created symlinks in the manifest stored in the file
+pub(crate) fn merge_recorded(source: &Path, target: &Path, options: &MergeOptions, path: &Path) -> Result<MergeReport> {
+
+    let mut manifest = match path.exists() {
+        true => Manifest::read_from(path)?,
+        false => Manifest::default()
+    };
+
+    let (source_root, target_root) = (source_root(source)?, target_root(target, options)?);
+    let renamed = manifest.renames(&source_root, &target_root)?;
+    manifest.carry(&renamed)?;
+
+    let mut report = match manifest.seeded.is_empty() {
+        true => merge(source, target, options)?,
+        false => {
+            let walk = Walk::new(source, target, options)?;
+            let mut ops = walk.plan()?;
+            ops.retain(|op| !manifest.seeded.iter().any(|seeded| op.target.starts_with(seeded)));
+            walk.execute(ops)?
+        }
+    };
+
+    report.renamed = renamed;
+    manifest.record(&source_root, &report);
+    manifest.write_to(path)?;
+
+    Ok(report)
+
+}
+
+/// Record that the `holder` (e.g. a target directory) uses the stored tree, see [release_tree]
+#[cfg(feature = "archive")]
+pub(crate) fn retain_tree(tree: &Path, holder: &Path) -> Result<()> {
+
+    let references = references(tree);
+    create_dir_all(&references).with_context(|| format!("Couldn't create references of stored tree ({references:?})"))?;
+
+    let reference = references.join(reference_name(holder));
+    write(&reference, holder.as_os_str().as_encoded_bytes()).with_context(|| format!("Couldn't write reference of stored tree ({reference:?})"))
+
+}
+
+/// Drop the reference of the `holder`, the stored tree is removed once nothing holds it anymore, returns whether it was
+pub(crate) fn release_tree(tree: &Path, holder: &Path) -> Result<bool> {
+
+    let references = references(tree);
+    let reference = references.join(reference_name(holder));
+
+    if reference.exists() {
+        remove_file(&reference).with_context(|| format!("Couldn't remove reference of stored tree ({reference:?})"))?;
+    }
+
+    if read_dir(&references).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Ok(false);
+    }
+
+    remove_tree(tree)?;
+    Ok(true)
+
+}
+
+/// File name of the holder reference, digest of the holder path
+fn reference_name(holder: &Path) -> String {
+    hex(&sha2::Sha256::digest(holder.as_os_str().as_encoded_bytes()))
+}
+
+/// Content digest prefixed by the algorithm, `None` for empty files (telling nothing apart) and unreadable ones
+fn digest(path: &Path) -> Option<String> {
+
+    if path.metadata().is_ok_and(|metadata| metadata.len() == 0) {
+        return None;
+    }
+
+    let hasher = default_hasher();
+    hash_file(hasher.as_ref(), path).ok().map(|hash| format!("{}:{}", hasher.name(), hex(&hash)))
+
+}
+
+/// Regular files of the directory tree not recorded yet, symlinks and reserved entries are left out
+fn collect_files(directory: &Path, recorded: &HashSet<&Path>, files: &mut Vec<PathBuf>) -> Result<()> {
+
+    for entry in read_dir(directory).with_context(|| format!("Couldn't read directory ({directory:?})"))? {
+
+        let entry = entry.with_context(|| format!("Couldn't read directory entry ({directory:?})"))?;
+        let file_type = entry.file_type().with_context(|| format!("Couldn't read file type ({:?})", entry.path()))?;
+
+        if is_reserved(&entry.file_name()) {
+            continue;
+        }
+
+        match file_type {
+            file_type if file_type.is_dir() => collect_files(&entry.path(), recorded, files)?,
+            file_type if file_type.is_file() && !recorded.contains(entry.path().as_path()) => files.push(entry.path()),
+            _ => {}
+        }
+
+    }
+
+    Ok(())
+
+}
+
+/// State of a recorded symlink, see [Provenance].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    /// Path leads to its recorded source
+    Linked,
+    /// Symlink is in place, but its destination doesn't exist
+    Broken,
+    /// Nothing exists at the path
+    Missing,
+    /// Something else took the place of the symlink
+    Replaced
+}
+
+/// Deployment managing a target path according to the manifests, see [provenance].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Provenance {
+    /// Manifest file holding the record
+    pub manifest: PathBuf,
+    /// Package the manifest belongs to, for [stowed](crate::Package) ones
+    pub package: Option<String>,
+    /// Recorded symlink, either the path itself or a directory symlink above it
+    pub link: ManifestLink,
+    /// Source entry the path leads to when healthy
+    pub source: PathBuf,
+    pub health: Health
+}
+
+/// Find the deployment managing the path in manifests of its ancestor directories, the nearest one wins.
+///
+/// Both the manifest of a [merge_with_manifest] and the manifests of stowed packages are searched. The path
+/// itself isn't resolved, so its symlink is what gets checked. Returns `None` for unmanaged paths.
+pub fn provenance(path: &Path) -> Result<Option<Provenance>> {
+
+    let name = path.file_name().with_context(|| format!("Path ({path:?}) doesn't name an entry"))?;
+    let parent = std::path::absolute(path).with_context(|| format!("Couldn't resolve path ({path:?})"))?;
+    let parent = parent.parent().unwrap_or(Path::new("/"));
+    let path = canonicalize(parent).with_context(|| format!("Couldn't resolve parent directory ({parent:?})"))?.join(name);
+
+    for directory in path.ancestors().skip(1) {
+
+        let mut manifests = vec![(directory.join(MANIFEST_NAME), None)];
+
+        if let Ok(entries) = read_dir(directory.join(PACKAGES_DIR)) {
+            let mut packages: Vec<_> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| file.extension().is_some_and(|extension| extension == "json"))
+                .map(|file| {
+                    let package = file.file_stem().map(|stem| stem.to_string_lossy().into_owned());
+                    (file, package)
+                })
+                .collect();
+            packages.sort();
+            manifests.append(&mut packages);
+        }
+
+        for (file, package) in manifests.into_iter().filter(|(file, _)| file.is_file()) {
+
+            let manifest = Manifest::read_from(&file)?;
+            let Some(link) = manifest.links.iter().find(|link| path.starts_with(&link.target)) else {
+                continue;
+            };
+
+            // Path below a folded directory is checked through the directory symlink
+            let (source, health) = match path.strip_prefix(&link.target)? {
+                below if below.as_os_str().is_empty() => (link.source.clone(), health(link)),
+                below => match health(link) {
+                    Health::Linked if path.symlink_metadata().is_err() => (link.source.join(below), Health::Missing),
+                    health => (link.source.join(below), health)
+                }
+            };
+
+            return Ok(Some(Provenance { manifest: file, package, link: link.clone(), source, health }));
+
+        }
+
+    }
+
+    Ok(None)
+
+}
+
+fn health(link: &ManifestLink) -> Health {
+    match link.target.symlink_metadata() {
+        Err(_) => Health::Missing,
+        Ok(_) if leads_to_source(link) => Health::Linked,
+        Ok(metadata) if metadata.is_symlink() && !link.target.exists() => Health::Broken,
+        Ok(_) => Health::Replaced
+    }
+}
+
+/// Check whether the symlink resolves to its recorded source, whatever the form of the stored path
+fn leads_to_source(link: &ManifestLink) -> bool {
+    link.target.is_symlink() && canonicalize(&link.target).is_ok_and(|resolved| canonicalize(&link.source).is_ok_and(|source| source == resolved))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir, read_to_string, remove_dir_all, remove_file, rename, write};
+    use std::path::Path;
+    use crate::{MaterializeRule, MergeOptions, Strategy};
+    use crate::manifest::{Manifest, MANIFEST_NAME, merge_with_manifest};
+    use crate::platform::identity;
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn record_created_symlinks() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+
+        merge_with_manifest(source, target, &MergeOptions::default()).unwrap();
+
+        let manifest = Manifest::read(target).unwrap();
+        let target_root = target.canonicalize().unwrap();
+            assert_eq!(manifest.source, source.canonicalize().unwrap());
+            assert_eq!(manifest.links.len(), 3);
+            assert!(manifest.links.iter().any(|link| link.target == target_root.join("lorem.txt")));
+            assert!(manifest.verify().is_empty());
+
+        // Later merges add their links to the recorded ones
+        remove_file(target.join("ipsum.php")).unwrap();
+        merge_with_manifest(source, target, &MergeOptions::default()).unwrap();
+            assert_eq!(Manifest::read(target).unwrap().links.len(), 4);
+
+        remove_file(target.join("lorem.txt")).unwrap();
+
+        let manifest = Manifest::read(target).unwrap();
+            assert_eq!(manifest.verify(), [target_root.join("lorem.txt")]);
+            assert_eq!(manifest.undo(target).unwrap().len(), 3);
+            assert!(!target.join("keep/haha.yml").exists());
+            assert!(!target.join(MANIFEST_NAME).exists());
+
+    }
+
+    #[test]
+    fn never_reseed_removed_copies() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { materialize: vec![MaterializeRule::copy_once("var/").unwrap()], ..Default::default() };
+        create_dir(source.join("var")).unwrap();
+        write(source.join("var/cache.db"), "seed").unwrap();
+
+        merge_with_manifest(source, target, &options).unwrap();
+
+        let target_root = target.canonicalize().unwrap();
+            assert_eq!(Manifest::read(target).unwrap().seeded, [target_root.join("var"), target_root.join("var/cache.db")]);
+            assert!(!target.join("var/cache.db").is_symlink());
+
+        remove_dir_all(target.join("var")).unwrap();
+        merge_with_manifest(source, target, &options).unwrap();
+            assert!(!target.join("var").exists());
+            assert_eq!(Manifest::read(target).unwrap().seeded.len(), 2);
+
+    }
+
+    #[test]
+    fn carry_local_files_across_renames() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        let options = MergeOptions { strategy: Strategy::Deep, materialize: vec![MaterializeRule::copy_once("var/").unwrap()], ..Default::default() };
+        create_dir(source.join("conf")).unwrap();
+        create_dir(source.join("var")).unwrap();
+        write(source.join("conf/app.toml"), "port = 80").unwrap();
+        write(source.join("lorem.txt"), "lorem ipsum").unwrap();
+        write(source.join("var/cache.db"), "seed").unwrap();
+
+        merge_with_manifest(source, target, &options).unwrap();
+        remove_file(target.join("conf/app.toml")).unwrap();
+        write(target.join("conf/app.toml"), "port = 8080").unwrap();
+
+        // Renamed in place, rewritten under a new name and renamed seeded file
+        rename(source.join("conf/app.toml"), source.join("conf/application.toml")).unwrap();
+        write(source.join("lorem.md"), read_to_string(source.join("lorem.txt")).unwrap()).unwrap();
+        remove_file(source.join("lorem.txt")).unwrap();
+        rename(source.join("var/cache.db"), source.join("var/data.db")).unwrap();
+
+        let target_root = target.canonicalize().unwrap();
+        let report = merge_with_manifest(source, target, &options).unwrap();
+            assert_eq!(report.renamed, [
+                (target_root.join("conf/app.toml"), target_root.join("conf/application.toml")),
+                (target_root.join("lorem.txt"), target_root.join("lorem.md")),
+                (target_root.join("var/cache.db"), target_root.join("var/data.db"))
+            ]);
+            assert_eq!(read_to_string(target.join("conf/application.toml")).unwrap(), "port = 8080");
+            assert!(!target.join("conf/application.toml").is_symlink() && !target.join("conf/app.toml").exists());
+            assert!(target.join("lorem.md").is_symlink() && !target.join("lorem.txt").is_symlink());
+            assert_eq!(read_to_string(target.join("var/data.db")).unwrap(), "seed");
+            assert!(Manifest::read(target).unwrap().seeded.contains(&target_root.join("var/data.db")));
+
+    }
+
+    #[test]
+    fn ignore_reused_inodes() {
+
+        let _lock = prepare_test_directory();
+        let (source, target) = (Path::new("test_files/test_dir1"), Path::new("test_files/test_dir2"));
+        write(source.join("lorem.txt"), "lorem ipsum").unwrap();
+        merge_with_manifest(source, target, &MergeOptions::default()).unwrap();
+
+        // Deleted file whose inode went to an unrelated one, as the filesystem may hand it out again
+        remove_file(source.join("lorem.txt")).unwrap();
+        write(source.join("unrelated.txt"), "dolor sit amet").unwrap();
+        let (device, inode) = identity(&source.join("unrelated.txt")).unwrap();
+
+        let (source_root, target_root) = (source.canonicalize().unwrap(), target.canonicalize().unwrap());
+        let mut manifest = Manifest::read(target).unwrap();
+        let link = manifest.links.iter_mut().find(|link| link.target == target_root.join("lorem.txt")).unwrap();
+        (link.device, link.inode) = (Some(device), Some(inode));
+            assert!(manifest.renames(&source_root, &target_root).unwrap().is_empty());
+
+        // Inode of another device doesn't identify the file either
+        let link = manifest.links.iter_mut().find(|link| link.target == target_root.join("lorem.txt")).unwrap();
+        (link.device, link.digest) = (Some(device.wrapping_add(1)), None);
+            assert!(manifest.renames(&source_root, &target_root).unwrap().is_empty());
+
+        let link = manifest.links.iter_mut().find(|link| link.target == target_root.join("lorem.txt")).unwrap();
+        link.device = Some(device);
+            assert_eq!(manifest.renames(&source_root, &target_root).unwrap(), [(target_root.join("lorem.txt"), target_root.join("unrelated.txt"))]);
+
+    }
+
+}
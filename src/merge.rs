@@ -0,0 +1,2071 @@
+use std::fs::{copy, create_dir, create_dir_all, FileType, hard_link, read, read_dir, read_link, read_to_string, remove_dir_all, remove_file, rename, set_permissions, symlink_metadata, write};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, bail, Context, Result};
+use crate::{Change, ChangeKind, Conflict, ConflictHook, default_hasher, FallbackStrategy, Folding, Glob, hash_file, Identity, is_reserved, LimitAction, LinkKind, LinkStyle, Materialize, MergeOptions, MergeReport, NestedManagement, OutOfSpace, Overwrite, PrivilegedExecutor, Resolution, Skipped, SourceKeepMarkers, Strategy, Usage, Warning};
+use crate::error::{is_out_of_space, SolderiumError, SourceUnavailable, TooManyEntries};
+use crate::explain::{Reason, Trace};
+use crate::maintenance::Maintenance;
+use crate::mtime_cache::{CachedDirectory, fingerprint, MtimeCache, stamp, Stamp};
+use crate::normalize::normalize_rel_path;
+use crate::operation::{Operation, OperationKind};
+use crate::platform::{create_link, device, identity, LINK_REFUSED, mode, plain_path, same_volume, set_mode, symlink};
+#[cfg(feature = "tokio")]
+use crate::platform::create_link_async;
+use crate::transaction::Journal;
+use crate::pool::{consume_prefetched, for_each_queued};
+use crate::spill::SpilledPlan;
+use crate::temp::temp_path;
+
+/// Target directories containing this file are managed by another deployment, see [NestedManagement]
+pub const MANAGED_MARKER: &str = ".solderium-managed";
+
+/// Files protecting the target directory holding them, see [Overwrite](crate::Overwrite)
+const KEEP_MARKERS: [&str; 3] = [".keep", ".keep_files", ".keep_dirs"];
+/// Name of the file holding ignore patterns of its source directory, see [MergeOptions::ignore_files]
+pub const IGNORE_FILE: &str = ".solderiumignore";
+/// Suffix of replaced target paths renamed by a soft delete, followed by the Unix timestamp of the merge, see [MergeOptions::soft_delete]
+pub const SAVED_SUFFIX: &str = ".solderium-saved-";
+
+/// Longest chain of symlinks followed by default, the same as the Linux kernel limit
+pub const MAX_LINK_DEPTH: usize = 40;
+
+/// Number of entries metadata is prefetched ahead of the sequential mutation, see [Concurrency::prefetch](crate::Concurrency::prefetch)
+const PREFETCH_WINDOW: usize = 64;
+
+/// Single change of the target, optionally replacing the existing target path
+pub(crate) struct Op {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub replace: bool,
+    pub kind: OpKind,
+    /// Permissions of a copied file
+    pub mode: Option<u32>
+}
+
+impl Op {
+
+    /// Memory held by the planned change, including its paths
+    fn memory(&self) -> usize {
+        size_of::<Op>() + self.source.capacity() + self.target.capacity()
+    }
+
+    pub(crate) fn change(&self) -> Change {
+
+        let kind = match self.kind {
+            OpKind::Symlink => ChangeKind::Symlink,
+            OpKind::Hardlink => ChangeKind::Hardlink,
+            OpKind::Copy => ChangeKind::Copy,
+            OpKind::Directory => ChangeKind::Directory
+        };
+
+        Change { source: self.source.clone(), target: self.target.clone(), kind, replace: self.replace }
+
+    }
+
+}
+
+pub(crate) enum OpKind {
+    Symlink,
+    Hardlink,
+    Copy,
+    Directory
+}
+
+/// Source directory queued for traversal
+struct Directory {
+    path: PathBuf,
+    /// Target counterpart is going to be created by the merge, so nothing can exist inside it
+    fresh: bool,
+    /// Materialization of entries not matched by any rule
+    materialize: Materialization
+}
+
+/// Materialization resolved for a single entry, inherited by the entries of a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Materialization {
+    pub materialize: Materialize,
+    pub mode: Option<u32>
+}
+
+/// What the overwrite policy allows for a single source entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Place { replace: bool },
+    Descend,
+    Skip
+}
+
+/// What happens with a single source entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Step {
+    Symlink { replace: bool },
+    Copy { replace: bool, mode: Option<u32> },
+    /// Create a real directory and materialize its entries individually
+    Mirror { replace: bool, materialize: Materialization },
+    Descend { materialize: Materialization },
+    Skip,
+    /// Target already is the symlink the merge would create, see [Reason::AlreadyMerged]
+    Merged
+}
+
+/// Resolved roots of a single merge together with its options
+pub(crate) struct Walk<'a> {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub options: &'a MergeOptions,
+    /// Suffix replaced target paths are renamed with, see [backup_suffix]
+    pub backup: Option<String>,
+    /// Warnings found while planning
+    warnings: Mutex<Vec<Warning>>,
+    /// Target paths skipped because of each keep marker
+    shadowed: Mutex<BTreeMap<PathBuf, Vec<PathBuf>>>,
+    /// Number of source entries examined while planning
+    pub visited: AtomicUsize,
+    /// Number of target paths found already leading to their source entry
+    linked: AtomicUsize,
+    /// Devices of target filesystems which turned out not to support symlinks
+    downgraded: Mutex<HashSet<u64>>,
+    /// Target paths copied by the fallback instead of being symlinked
+    copied: Mutex<HashSet<PathBuf>>,
+    /// Directories unchanged since the previous merge, see [MergeOptions::mtime_cache]
+    mtimes: Option<MtimeCache>,
+    /// Changes made so far, when they may have to be rolled back
+    journal: Option<Journal>,
+    /// Patterns of ignore files read so far, by the relative source directory holding them
+    ignores: Mutex<HashMap<PathBuf, Arc<Vec<Glob>>>>,
+    /// Skipped source entries, when recorded
+    skipped: Mutex<Vec<Skipped>>,
+    /// Nested deployments left to the delegate, as source and target directory
+    delegated: Mutex<Vec<(PathBuf, PathBuf)>>,
+    /// Indexes of the triggers fired by the changes made so far
+    fired: Mutex<BTreeSet<usize>>,
+    /// Identity of the source root, to recognize it disappeared
+    source_identity: (u64, u64),
+    started: Instant,
+    /// Operations attempted so far, see [Usage]
+    counters: Counters,
+    /// Progress, cancellation and error policy of the run
+    operation: Operation,
+    kind: OperationKind
+}
+
+/// Counters of the attempted operations, turned into [Usage] of the report
+#[derive(Default)]
+struct Counters {
+    listings: AtomicUsize,
+    links: AtomicUsize,
+    copies: AtomicUsize,
+    directories: AtomicUsize,
+    removals: AtomicUsize,
+    bytes_copied: AtomicU64,
+    plan_memory: AtomicUsize
+}
+
+impl Counters {
+
+    fn count(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn usage(&self, elapsed: std::time::Duration) -> Usage {
+        Usage {
+            elapsed,
+            listings: self.listings.load(Ordering::Relaxed),
+            links: self.links.load(Ordering::Relaxed),
+            copies: self.copies.load(Ordering::Relaxed),
+            directories: self.directories.load(Ordering::Relaxed),
+            removals: self.removals.load(Ordering::Relaxed),
+            bytes_copied: self.bytes_copied.load(Ordering::Relaxed),
+            plan_memory: self.plan_memory.load(Ordering::Relaxed)
+        }
+    }
+
+}
+
+/// Merge the `source` directory into the `target` directory using given options.
+///
+/// The source is walked first (without touching the target) and all changes are made afterwards,
+/// so both phases can run in parallel with separate limits, see [Concurrency](crate::Concurrency).
+pub fn merge(source: &Path, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+
+    let walk = Walk::new(source, target, options)?;
+
+    if let Some(limit) = options.plan_memory {
+        return walk.execute_spilled(walk.plan_spilled(limit)?);
+    }
+
+    let ops = walk.plan()?;
+
+    walk.execute(ops)
+
+}
+
+/// Merge only the paths listed in `list` (one source-relative path per line, e.g. output of `git ls-files`).
+///
+/// The source isn't walked, so the target mirrors exactly the listed set. Directories holding the listed
+/// paths are created in the target (never linked as a whole, as if using [Strategy::Deep]), a listed
+/// directory is only created, its content has to be listed as well. Other options apply as usual.
+pub fn merge_listed(source: &Path, target: &Path, list: &str, options: &MergeOptions) -> Result<MergeReport> {
+
+    let options = MergeOptions { strategy: Strategy::Deep, ..options.clone() };
+    let walk = Walk::new(source, target, &options)?;
+    let ops = walk.plan_listed(list)?;
+
+    walk.execute(ops)
+
+}
+
+impl<'a> Walk<'a> {
+
+    pub(crate) fn new(source: &Path, target: &Path, options: &'a MergeOptions) -> Result<Self> {
+
+        let started = Instant::now();
+        let source = source_root(source)?;
+        let target = target_root(target, options)?;
+
+        // Both source and target have to be directories for this to work
+        if !source.is_dir() || !target.is_dir() {
+            bail!("Make sure both source and target paths are directories");
+        }
+
+        if let (Some(min), Some(max)) = (options.min_depth, options.max_depth) {
+            if min > max {
+                bail!("Shallowest placed level ({min}) is deeper than the deepest merged level ({max})");
+            }
+        }
+
+        if let Some(anchor) = &options.anchor {
+            if target.join(anchor).canonicalize().ok().as_ref() != Some(&source) {
+                bail!("Anchor ({anchor:?}) doesn't resolve to the source directory ({source:?})");
+            }
+        }
+
+        let source_identity = identity(&source).with_context(|| format!("Couldn't read metadata ({source:?})"))?;
+        let mtimes = options.mtime_cache.as_deref().map(|path| MtimeCache::load(path, fingerprint(&source, &target, options)));
+        let journal = options.transactional.then(|| Journal::new(&target, options.plan_memory));
+
+        if options.soft_delete && options.backup.is_some() {
+            bail!("Soft delete renames replaced paths on its own, it can't be combined with a backup suffix");
+        }
+
+        if options.refold && (options.transactional || options.link_kind == LinkKind::Hardlink) {
+            bail!("Refolding isn't journaled and creates directory symlinks, it can't be combined with a transactional merge or hard links");
+        }
+
+        Ok(Self {
+            source_identity, source, target, options, backup: backup_suffix(options),
+            warnings: Mutex::default(), shadowed: Mutex::default(), visited: AtomicUsize::new(0), linked: AtomicUsize::new(0), downgraded: Mutex::default(), copied: Mutex::default(), mtimes, journal, ignores: Mutex::default(), skipped: Mutex::default(), delegated: Mutex::default(), fired: Mutex::default(),
+            started, counters: Counters::default(), operation: Operation::default(), kind: OperationKind::Merge
+        })
+
+    }
+
+    /// Run as given operation, see [Operation]
+    pub(crate) fn operation(mut self, operation: Operation, kind: OperationKind) -> Self {
+        self.operation = operation;
+        self.kind = kind;
+        self
+    }
+
+    /// Decide what happens with a single source entry
+    pub(crate) fn step(&self, source_path: &Path, relative: &Path, fresh: bool, inherited: Materialization, trace: &mut Trace) -> Result<Step> {
+
+        let is_dir = source_path.is_dir() && !(self.options.preserve_symlinks && source_path.is_symlink());
+        let depth = relative.components().count();
+        let deepest = self.options.max_depth.is_some_and(|max| depth >= max);
+        let shallow = self.options.min_depth.is_some_and(|min| depth < min);
+
+        if source_path.file_name().is_some_and(is_reserved) {
+            trace.note(|| Reason::Reserved);
+            return Ok(Step::Skip);
+        }
+
+        if let Some(pattern) = self.options.exclude.iter().find(|pattern| pattern.matches(relative, is_dir)) {
+            trace.note(|| Reason::Excluded(pattern.clone()));
+            return Ok(Step::Skip);
+        }
+
+        if self.options.ignore_files {
+
+            if !is_dir && source_path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+                trace.note(|| Reason::IgnoreFile);
+                return Ok(Step::Skip);
+            }
+
+            if let Some((file, pattern)) = self.ignored(relative, is_dir)? {
+                trace.note(|| Reason::Ignored { file, pattern });
+                return Ok(Step::Skip);
+            }
+
+        }
+
+        if !is_dir && !self.included(relative, false) {
+            trace.note(|| Reason::NotIncluded);
+            return Ok(Step::Skip);
+        }
+
+        if !is_dir && shallow {
+            trace.note(|| Reason::MinDepth(depth));
+            return Ok(Step::Skip);
+        }
+
+        let marker = !is_dir && source_path.file_name().is_some_and(|name| KEEP_MARKERS.iter().any(|marker| name == *marker));
+
+        if marker {
+            match self.options.source_keep_markers {
+                SourceKeepMarkers::LinkAsNormalFile => {},
+                SourceKeepMarkers::Skip => {
+                    trace.note(|| Reason::SourceKeepMarker);
+                    return Ok(Step::Skip);
+                },
+                SourceKeepMarkers::Error => return Err(SolderiumError::KeepFileConflict(source_path.to_path_buf()).into())
+            }
+        }
+
+        let filter = &self.options.filter;
+
+        if !is_dir && !filter.is_empty() && !source_path.metadata().is_ok_and(|metadata| filter.matches(&metadata)) {
+            trace.note(|| Reason::Filtered);
+            return Ok(Step::Skip);
+        }
+
+        // Hard links can be told only by the inode
+        let identity = match self.options.link_kind {
+            LinkKind::Symlink => self.options.identity,
+            LinkKind::Hardlink => Identity::DevInode
+        };
+        let target_path = self.target.join(relative);
+
+        // Copy rules turn even the very symlink the merge would create into a copy
+        let linked = !fresh
+            && self.materialization(relative, is_dir, inherited, &mut Trace::Off).materialize == Materialize::Link
+            && !(is_dir && self.copies_below(source_path)?);
+
+        if linked && self.linked_already(source_path, &target_path) {
+            trace.note(|| Reason::AlreadyMerged(identity));
+            self.linked.fetch_add(1, Ordering::Relaxed);
+            return Ok(Step::Merged);
+        }
+
+        if is_dir && !fresh && !matches!(self.options.nested, NestedManagement::Merge) && target_path.join(MANAGED_MARKER).is_file() {
+
+            let marker = target_path.join(MANAGED_MARKER);
+            trace.note(|| Reason::NestedDeployment(marker.clone()));
+
+            match &self.options.nested {
+                NestedManagement::Error => bail!("Target directory ({target_path:?}) is managed by another deployment ({marker:?})"),
+                NestedManagement::Delegate(_) => self.delegated.lock().unwrap().push((source_path.to_path_buf(), target_path)),
+                _ => self.warn(Warning::NestedDeployment { directory: target_path, marker })
+            }
+
+            return Ok(Step::Skip);
+
+        }
+
+        let limit = self.options.max_link_depth.unwrap_or(MAX_LINK_DEPTH);
+        let chain_too_long = !fresh && chain_exceeds(&target_path, limit);
+
+        if chain_too_long {
+            trace.note(|| Reason::LinkChainTooLong(limit));
+            self.warn(Warning::LinkChainTooLong { path: target_path.clone(), limit });
+        }
+
+        if linked && !chain_too_long && identity.same(source_path, &target_path) {
+            trace.note(|| Reason::AlreadyMerged(identity));
+            self.linked.fetch_add(1, Ordering::Relaxed);
+            return Ok(Step::Merged);
+        }
+
+        if !fresh && self.options.overwrite == Overwrite::IfDifferent && self.same_content(source_path, &target_path) {
+            trace.note(|| Reason::SameContent);
+            return Ok(Step::Skip);
+        }
+
+        let decision = match fresh {
+            true => {
+                trace.note(|| Reason::TargetMissing);
+                Decision::Place { replace: false }
+            },
+            false => match &self.options.on_conflict {
+                Some(hook) => self.resolve(hook, source_path, &target_path, trace)?,
+                None => decide(source_path, &target_path, self.options.overwrite, trace)
+            }
+        };
+
+        // Protected target paths and directories above the shallowest placed level are never replaced, only merged into
+        let decision = match decision {
+            Decision::Place { replace: true } if shallow && is_dir && target_path.is_dir() => {
+                trace.note(|| Reason::MinDepth(depth));
+                Decision::Descend
+            },
+            Decision::Place { replace: true } => match self.options.protect.iter().find(|pattern| pattern.matches(relative, target_path.is_dir())) {
+                Some(pattern) => {
+                    trace.note(|| Reason::Protected(pattern.clone()));
+                    match is_dir && target_path.is_dir() {
+                        true => Decision::Descend,
+                        false => Decision::Skip
+                    }
+                },
+                None => decision
+            },
+            // Symlink may lead anywhere, e.g. when planted by the owner of the target
+            Decision::Descend if self.options.confine_target && target_path.is_symlink() => {
+                trace.note(|| Reason::SymlinkedTarget);
+                Decision::Skip
+            },
+            decision => decision
+        };
+
+        let materialize = self.materialization(relative, is_dir, inherited, trace);
+
+        Ok(match decision {
+            // Copies made once belong to the deployment from then on
+            _ if materialize.materialize == Materialize::CopyOnce && !fresh && target_path.symlink_metadata().is_ok() => {
+                trace.note(|| Reason::CopiedOnce);
+                Step::Skip
+            },
+            Decision::Skip => Step::Skip,
+            // Preserved symlink can't be merged into
+            Decision::Descend if !is_dir => Step::Skip,
+            Decision::Descend if deepest => {
+                trace.note(|| Reason::MaxDepth(depth));
+                Step::Skip
+            },
+            Decision::Descend if shallow => Step::Descend { materialize },
+            // Existing directories are never merged into, only replaced
+            Decision::Descend if self.strategy() == Strategy::Shallow => {
+                trace.note(|| Reason::Strategy(Strategy::Shallow));
+                Step::Skip
+            },
+            Decision::Descend => Step::Descend { materialize },
+            Decision::Place { replace } => match (is_dir, materialize.materialize) {
+                (false, Materialize::Link) => Step::Symlink { replace },
+                (false, Materialize::Copy | Materialize::CopyOnce) => Step::Copy { replace, mode: materialize.mode },
+                (true, Materialize::Copy | Materialize::CopyOnce) => Step::Mirror { replace, materialize },
+                (true, Materialize::Link) if shallow => {
+                    trace.note(|| Reason::MinDepth(depth));
+                    Step::Mirror { replace, materialize }
+                },
+                (true, Materialize::Link) if self.options.ignore_files && self.ignore_file_below(source_path)? => {
+                    trace.note(|| Reason::IgnoreFileBelow);
+                    Step::Mirror { replace, materialize }
+                },
+                (true, Materialize::Link) if !self.included(relative, true) => {
+                    trace.note(|| Reason::PartlyIncluded);
+                    Step::Mirror { replace, materialize }
+                },
+                (true, Materialize::Link) if self.options.link_kind == LinkKind::Hardlink => {
+                    trace.note(|| Reason::HardlinkedDirectory);
+                    Step::Mirror { replace, materialize }
+                },
+                // Symlinked source directories stay symlinks
+                (true, Materialize::Link) if self.strategy() == Strategy::Deep && !source_path.is_symlink() && !deepest => {
+                    trace.note(|| Reason::Strategy(Strategy::Deep));
+                    Step::Mirror { replace, materialize }
+                },
+                // Directory can't be symlinked as a whole, when anything inside it has to be copied
+                (true, Materialize::Link) => match self.copies_below(source_path)? {
+                    true => {
+                        trace.note(|| Reason::CopiedBelow);
+                        Step::Mirror { replace, materialize }
+                    },
+                    false => Step::Symlink { replace }
+                }
+            }
+        })
+
+    }
+
+    /// Let the hook decide about an existing target path, see [MergeOptions::on_conflict]
+    fn resolve(&self, hook: &ConflictHook, source_path: &Path, target_path: &Path, trace: &mut Trace) -> Result<Decision> {
+
+        let target_metadata = match target_path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                trace.note(|| Reason::TargetMissing);
+                return Ok(Decision::Place { replace: false });
+            }
+        };
+
+        let source_metadata = match self.options.preserve_symlinks {
+            true => source_path.symlink_metadata(),
+            false => source_path.metadata()
+        };
+        let source_metadata = source_metadata.with_context(|| format!("Couldn't read metadata of source ({source_path:?})"))?;
+
+        let conflict = Conflict { source: source_path, target: target_path, source_metadata: &source_metadata, target_metadata: &target_metadata };
+        let resolution = self.hook("Conflict hook", || hook(&conflict))?;
+
+        trace.note(|| Reason::TargetExists { directory: target_path.is_dir() });
+        trace.note(|| Reason::Resolved(resolution));
+
+        Ok(match resolution {
+            Resolution::Replace => Decision::Place { replace: true },
+            Resolution::Skip => Decision::Skip,
+            Resolution::Descend => match source_metadata.is_dir() && target_path.is_dir() {
+                true => Decision::Descend,
+                false => {
+                    trace.note(|| Reason::NotBothDirectories);
+                    Decision::Skip
+                }
+            },
+            Resolution::Abort => bail!("Conflict hook aborted the merge at target path ({target_path:?})")
+        })
+
+    }
+
+    /// Check whether the path or any of its parent directories matches an include pattern, everything is included without patterns
+    fn included(&self, relative: &Path, is_dir: bool) -> bool {
+
+        let include = &self.options.include;
+
+        include.is_empty() || include.iter().any(|pattern| pattern.matches(relative, is_dir))
+            || relative.ancestors().skip(1).filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| include.iter().any(|pattern| pattern.matches(ancestor, true)))
+
+    }
+
+    /// Ignore file pattern matching the path together with the file, patterns are relative to the directory holding the file
+    fn ignored(&self, relative: &Path, is_dir: bool) -> Result<Option<(PathBuf, Glob)>> {
+
+        for directory in relative.ancestors().skip(1) {
+
+            let within = relative.strip_prefix(directory).unwrap_or(relative);
+
+            if let Some(pattern) = self.ignore_patterns(directory)?.iter().find(|pattern| pattern.matches(within, is_dir)) {
+                return Ok(Some((self.source.join(directory).join(IGNORE_FILE), pattern.clone())));
+            }
+
+        }
+
+        Ok(None)
+
+    }
+
+    /// Patterns of the ignore file in given relative source directory, read only once
+    fn ignore_patterns(&self, directory: &Path) -> Result<Arc<Vec<Glob>>> {
+
+        if let Some(patterns) = self.ignores.lock().unwrap().get(directory) {
+            return Ok(patterns.clone());
+        }
+
+        let path = self.source.join(directory).join(IGNORE_FILE);
+
+        let patterns = match read_to_string(&path) {
+            Ok(content) => content.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| match line.starts_with('!') {
+                    true => Err(anyhow!("Negated pattern ({line:?}) isn't supported")),
+                    false => Glob::new(line)
+                })
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Invalid ignore file ({path:?})"))?,
+            Err(error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error).with_context(|| format!("Couldn't read ignore file ({path:?})"))
+        };
+
+        let patterns = Arc::new(patterns);
+        self.ignores.lock().unwrap().insert(directory.to_path_buf(), patterns.clone());
+
+        Ok(patterns)
+
+    }
+
+    /// Check whether given source directory holds an ignore file at any depth
+    fn ignore_file_below(&self, directory: &Path) -> Result<bool> {
+
+        for entry in read_dir(directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let entry = entry.with_context(|| "Reading source directory entry has failed")?;
+            let path = entry.path();
+
+            if entry.file_name() == IGNORE_FILE {
+                return Ok(true);
+            }
+
+            if path.is_dir() && !path.is_symlink() && self.ignore_file_below(&path)? {
+                return Ok(true);
+            }
+
+        }
+
+        Ok(false)
+
+    }
+
+    fn materialization(&self, relative: &Path, is_dir: bool, inherited: Materialization, trace: &mut Trace) -> Materialization {
+        match self.options.materialize.iter().rev().find(|rule| rule.pattern.matches(relative, is_dir)) {
+            Some(rule) => {
+                trace.note(|| Reason::MaterializeRule(rule.clone()));
+                Materialization { materialize: rule.materialize, mode: rule.mode }
+            },
+            None => inherited
+        }
+    }
+
+    /// Check whether any entry inside given (symlinked) source directory has to be copied
+    fn copies_below(&self, directory: &Path) -> Result<bool> {
+
+        if self.options.materialize.iter().all(|rule| rule.materialize == Materialize::Link) {
+            return Ok(false);
+        }
+
+        for entry in read_dir(directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let path = entry.with_context(|| "Reading source directory entry has failed")?.path();
+            let relative = self.relative(&path)?;
+            let is_dir = path.is_dir();
+
+            if self.materialization(relative, is_dir, Materialization::default(), &mut Trace::Off).materialize != Materialize::Link {
+                return Ok(true);
+            }
+
+            if is_dir && !path.is_symlink() && self.copies_below(&path)? {
+                return Ok(true);
+            }
+
+        }
+
+        Ok(false)
+
+    }
+
+    fn relative<'p>(&self, source_path: &'p Path) -> Result<&'p Path> {
+        let source = &self.source;
+        source_path.strip_prefix(source)
+            .with_context(|| format!("Couldn't strip base path ({source:?}) from source path ({source_path:?})"))
+    }
+
+    pub(crate) fn plan(&self) -> Result<Vec<Op>> {
+
+        let ops = Mutex::new(Vec::new());
+        self.plan_into(|found| {
+            ops.lock().unwrap().extend(found);
+            Ok(())
+        })?;
+
+        // Workers finish in arbitrary order, keep the result deterministic
+        let mut ops = ops.into_inner().unwrap();
+        ops.sort_by(|a, b| a.target.cmp(&b.target));
+
+        Ok(ops)
+
+    }
+
+    /// Plan holding at most `limit` operations in memory, the rest is spilled next to the target, see [MergeOptions::plan_memory]
+    pub(crate) fn plan_spilled(&self, limit: usize) -> Result<SpilledPlan> {
+
+        if !self.options.simulate.is_empty() || self.options.entry_limit.is_some() {
+            bail!("Simulated changes and entry limits need the whole plan at once, they can't be combined with a plan memory limit");
+        }
+
+        if self.options.refold {
+            bail!("Refolding unfolds directories on the way to all planned changes, it can't be combined with a plan memory limit");
+        }
+
+        let plan = Mutex::new(SpilledPlan::new(temp_path(&self.target.join("plan")), limit));
+        self.plan_into(|found| plan.lock().unwrap().extend(found))?;
+
+        Ok(plan.into_inner().unwrap())
+
+    }
+
+    /// Walk the source, passing operations planned for every directory to `sink`
+    fn plan_into(&self, sink: impl Fn(Vec<Op>) -> Result<()> + Sync) -> Result<()> {
+
+        let root = Directory { path: self.source.clone(), fresh: false, materialize: Materialization::default() };
+
+        for_each_queued(self.options.concurrency.traversal, vec![root], |directory, queue| {
+            match self.visit(&directory, queue) {
+                Ok(ops) => sink(ops),
+                // Failure of the root means the whole source is unreadable
+                Err(error) if directory.path != self.source && self.operation.continues(&error) => {
+                    self.warn(Warning::EntryFailed { path: self.target.join(self.relative(&directory.path)?), error: format!("{error:#}") });
+                    Ok(())
+                },
+                Err(error) => Err(error)
+            }
+        }).map_err(|error| self.planning_failed(error))?;
+
+        self.finish_plan()
+
+    }
+
+    /// Explain the failure of the planning by the source root gone missing, if it did
+    fn planning_failed(&self, error: anyhow::Error) -> anyhow::Error {
+        match self.source_gone() {
+            true => error.context(SourceUnavailable { source: self.source.clone(), completed: 0, remaining: Vec::new() }),
+            false => error
+        }
+    }
+
+    /// Complete the warnings found while planning and save the modification time cache
+    fn finish_plan(&self) -> Result<()> {
+
+        let shadowed = self.shadowed.lock().unwrap();
+        let mut warnings = self.warnings.lock().unwrap();
+
+        warnings.sort();
+        warnings.extend(shadowed.iter().map(|(marker, paths)| Warning::KeepMarkerShadowing { marker: marker.clone(), skipped: paths.len() }));
+
+        match &self.mtimes {
+            Some(mtimes) => mtimes.save(),
+            None => Ok(())
+        }
+
+    }
+
+    /// Plan changes of the listed paths only, see [merge_listed]
+    pub(crate) fn plan_listed(&self, list: &str) -> Result<Vec<Op>> {
+
+        let mut paths = BTreeSet::new();
+
+        for line in list.lines().map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty()) {
+
+            let path = normalize_rel_path(Path::new(line)).with_context(|| format!("Listed path ({line:?}) has to lead inside the source directory"))?;
+
+            if path.as_os_str().is_empty() {
+                bail!("Listed path ({line:?}) has to lead inside the source directory");
+            }
+
+            paths.insert(path);
+
+        }
+
+        self.plan_paths(paths)
+
+    }
+
+    /// Plan changes of given relative paths (and their parent directories) only
+    pub(crate) fn plan_paths(&self, listed: BTreeSet<PathBuf>) -> Result<Vec<Op>> {
+
+        let paths: BTreeSet<PathBuf> = listed.iter()
+            .flat_map(|path| path.ancestors().filter(|ancestor| !ancestor.as_os_str().is_empty()).map(Path::to_path_buf))
+            .collect();
+
+        // Sorted paths come right after their parent directory, whose state is known by then
+        let mut directories: HashMap<PathBuf, Directory> = HashMap::new();
+        let mut ops = Vec::new();
+
+        for relative in paths {
+
+            let parent = relative.parent().filter(|parent| !parent.as_os_str().is_empty());
+            let source_path = self.source.join(&relative);
+            let target_path = self.target.join(&relative);
+
+            let directory = match parent {
+                Some(parent) => match directories.get(parent) {
+                    Some(directory) => directory,
+                    // Parent was skipped or linked, so is everything inside it
+                    None => continue
+                },
+                None => &Directory { path: self.source.clone(), fresh: false, materialize: Materialization::default() }
+            };
+
+            if source_path.symlink_metadata().is_err() {
+                bail!("Listed path ({relative:?}) doesn't exist in the source directory");
+            }
+
+            let (fresh, materialize) = (directory.fresh, directory.materialize);
+
+            let (replace, kind, mode) = match self.step(&source_path, &relative, fresh, materialize, &mut Trace::Off)? {
+                Step::Symlink { replace } => (replace, self.link_kind(), None),
+                Step::Copy { replace, mode } => (replace, OpKind::Copy, mode),
+                Step::Mirror { replace, materialize } => {
+                    directories.insert(relative, Directory { path: source_path.clone(), fresh: true, materialize });
+                    (replace, OpKind::Directory, None)
+                },
+                Step::Descend { materialize } => {
+                    directories.insert(relative, Directory { path: source_path, fresh: false, materialize });
+                    continue;
+                },
+                Step::Skip | Step::Merged => continue
+            };
+
+            ops.push(Op { source: source_path, target: target_path, replace, kind, mode });
+
+        }
+
+        ops.sort_by(|a, b| a.target.cmp(&b.target));
+        Ok(ops)
+
+    }
+
+    fn visit(&self, directory: &Directory, queue: &mut Vec<Directory>) -> Result<Vec<Op>> {
+
+        let stamps = self.stamps(directory)?;
+
+        if let Some(cached) = stamps.and_then(|(source, target)| self.mtimes.as_ref()?.unchanged(self.relative(&directory.path).ok()?, source, target)) {
+            self.revisit(directory, cached, queue)?;
+            return Ok(Vec::new());
+        }
+
+        let mut ops = Vec::new();
+        let mut descended = Vec::new();
+        let mut names: HashMap<String, Vec<OsString>> = HashMap::new();
+        let listing = read_dir(&directory.path).with_context(|| format!("Directory listing ({:?}) failed", directory.path))?;
+        let existing = self.target_listing(directory)?;
+        Counters::count(&self.counters.listings);
+
+        for source_entry in listing {
+
+            let source_entry = source_entry.with_context(|| "Reading source directory entry has failed")?;
+            let name = source_entry.file_name();
+            let missing = directory.fresh || existing.as_ref().is_some_and(|existing| !existing.contains(&name));
+
+            names.entry(name.to_string_lossy().to_lowercase()).or_default().push(name);
+
+            let source_path = source_entry.path();
+
+            match self.visit_entry(directory, source_path.clone(), source_entry.file_type()?, missing, queue, &mut descended) {
+                Ok(Some(op)) => ops.push(op),
+                Ok(None) => {},
+                Err(error) if self.operation.continues(&error) => {
+                    self.warn(Warning::EntryFailed { path: self.target.join(self.relative(&source_path)?), error: format!("{error:#}") });
+                },
+                Err(error) => return Err(error)
+            }
+
+        }
+
+        self.warn_case_collisions(directory, names)?;
+
+        // Only directories already in their final state are worth skipping next time
+        if let (Some(mtimes), Some((source, target)), true) = (&self.mtimes, stamps, ops.is_empty()) {
+            mtimes.record(self.relative(&directory.path)?.to_path_buf(), CachedDirectory { source, target, descended });
+        }
+
+        Ok(ops)
+
+    }
+
+    /// Plan the change of a single source entry, directories to be merged into are queued instead
+    fn visit_entry(&self, directory: &Directory, source_path: PathBuf, file_type: FileType, missing: bool, queue: &mut Vec<Directory>, descended: &mut Vec<OsString>) -> Result<Option<Op>> {
+
+        self.operation.check(self.kind)?;
+        let visited = self.visited.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if self.kind == OperationKind::Verify {
+            self.operation.report(self.kind, visited, None);
+        }
+
+        let relative = self.relative(&source_path)?;
+        let target_path = self.target.join(relative);
+
+        if let Some(observer) = &self.options.observer {
+            observer.on_entry_start(&source_path);
+        }
+
+        // Periodic runs mostly find the very symlink the merge would create, without copy rules nothing else could happen to it
+        if !missing && self.options.materialize.iter().all(|rule| rule.materialize == Materialize::Link) && self.linked_already(&source_path, &target_path) {
+            self.linked.fetch_add(1, Ordering::Relaxed);
+            self.note_skipped(&source_path, &target_path, || Trace::On(vec![Reason::AlreadyMerged(self.options.identity)]));
+            return Ok(None);
+        }
+
+        let mut trace = match self.options.record_skipped {
+            true => Trace::On(Vec::new()),
+            false => Trace::Off
+        };
+
+        let (replace, kind, mode) = match self.step(&source_path, relative, missing, directory.materialize, &mut trace)? {
+            Step::Symlink { replace } => (replace, self.link_kind(), None),
+            // Reading a FIFO or a device would block or never end
+            Step::Copy { .. } if is_special(&file_type) => {
+                self.warn(Warning::SkippedSpecialFile { path: source_path });
+                return Ok(None);
+            },
+            Step::Copy { replace, mode } => (replace, OpKind::Copy, mode),
+            Step::Mirror { replace, materialize } => {
+                queue.push(Directory { path: source_path.clone(), fresh: true, materialize });
+                (replace, OpKind::Directory, None)
+            },
+            Step::Descend { materialize } => {
+                descended.extend(source_path.file_name().map(OsStr::to_os_string));
+                queue.push(Directory { path: source_path, fresh: false, materialize });
+                return Ok(None);
+            },
+            Step::Skip => {
+                if !missing {
+                    self.note_shadowed(&source_path, &target_path);
+                }
+                self.note_skipped(&source_path, &target_path, || trace);
+                return Ok(None);
+            },
+            // Nothing can be shadowed by a keep marker here, the target already is the symlink
+            Step::Merged => {
+                self.note_skipped(&source_path, &target_path, || trace);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(Op { source: source_path, target: target_path, replace, kind, mode }))
+
+    }
+
+    fn warn_case_collisions(&self, directory: &Directory, names: HashMap<String, Vec<OsString>>) -> Result<()> {
+
+        for (_, mut names) in names.into_iter().filter(|(_, names)| names.len() > 1) {
+            names.sort();
+            self.warn(Warning::CaseCollision { directory: self.target.join(self.relative(&directory.path)?), names });
+        }
+
+        Ok(())
+
+    }
+
+    /// Modification times of the source directory and its target counterpart, when they're cached
+    fn stamps(&self, directory: &Directory) -> Result<Option<(Stamp, Stamp)>> {
+
+        if self.mtimes.is_none() || directory.fresh {
+            return Ok(None);
+        }
+
+        let source = directory.path.metadata().with_context(|| format!("Couldn't read metadata ({:?})", directory.path))?;
+
+        Ok(self.target.join(self.relative(&directory.path)?).metadata().ok().map(|target| (stamp(&source), stamp(&target))))
+
+    }
+
+    /// Queue subdirectories of the directory unchanged since the previous merge without looking at its entries,
+    /// they're checked on their own as their changes don't show in the modification time of the directory
+    fn revisit(&self, directory: &Directory, cached: &CachedDirectory, queue: &mut Vec<Directory>) -> Result<()> {
+
+        let relative = self.relative(&directory.path)?;
+
+        for name in &cached.descended {
+            let materialize = self.materialization(&relative.join(name), true, directory.materialize, &mut Trace::Off);
+            queue.push(Directory { path: directory.path.join(name), fresh: false, materialize });
+        }
+
+        self.mtimes.as_ref().unwrap().record(relative.to_path_buf(), cached.clone());
+
+        Ok(())
+
+    }
+
+    /// Names in the target counterpart of the source directory, listed once instead of checking every entry on its own
+    fn target_listing(&self, directory: &Directory) -> Result<Option<HashSet<OsString>>> {
+
+        if directory.fresh || self.options.stat_each_target {
+            return Ok(None);
+        }
+
+        let path = self.target.join(self.relative(&directory.path)?);
+
+        Counters::count(&self.counters.listings);
+
+        // Missing or unreadable directory (e.g. a file in the way) is left to the per-entry checks
+        Ok(match read_dir(&path) {
+            Ok(listing) => Some(listing.map(|entry| entry.map(|entry| entry.file_name())).collect::<io::Result<_>>()
+                .with_context(|| format!("Directory listing ({path:?}) failed"))?),
+            Err(_) => None
+        })
+
+    }
+
+    /// Strategy the merge follows, [Folding::Never] turns any of them into [Strategy::Deep]
+    pub(crate) fn strategy(&self) -> Strategy {
+        match self.options.folding {
+            Folding::Auto => self.options.strategy,
+            Folding::Never => Strategy::Deep
+        }
+    }
+
+    /// Check whether the source root was removed, unmounted or replaced since the start
+    fn source_gone(&self) -> bool {
+        !identity(&self.source).is_ok_and(|identity| identity == self.source_identity)
+    }
+
+    fn warn(&self, warning: Warning) {
+        self.warnings.lock().unwrap().push(warning);
+    }
+
+    /// Notify the observer about the skipped entry and record it together with the reasons, when asked to
+    fn note_skipped(&self, source_path: &Path, target_path: &Path, trace: impl FnOnce() -> Trace) {
+
+        if let Some(observer) = &self.options.observer {
+            observer.on_skip(source_path, target_path);
+        }
+
+        if self.options.record_skipped {
+            let skipped = Skipped { source: source_path.to_path_buf(), target: target_path.to_path_buf(), reasons: trace().into_reasons() };
+            self.skipped.lock().unwrap().push(skipped);
+        }
+
+    }
+
+    /// Count the skipped entry towards the keep marker protecting its target, if there is one
+    fn note_shadowed(&self, source_path: &Path, target_path: &Path) {
+
+        let mut trace = Trace::On(Vec::new());
+        decide(source_path, target_path, self.options.overwrite, &mut trace);
+
+        let marker = trace.into_reasons().into_iter().find_map(|reason| match reason {
+            Reason::KeepMarker(marker) => Some(marker),
+            _ => None
+        });
+
+        if let Some(marker) = marker {
+            self.shadowed.lock().unwrap().entry(marker).or_default().push(target_path.to_path_buf());
+        }
+
+    }
+
+    /// Target paths left untouched by the planning because of a keep marker, together with the marker
+    pub(crate) fn kept(&self) -> Vec<(PathBuf, PathBuf)> {
+
+        let mut kept: Vec<_> = self.shadowed.lock().unwrap().iter()
+            .flat_map(|(marker, paths)| paths.iter().map(|path| (path.clone(), marker.clone())))
+            .collect();
+
+        kept.sort();
+        kept
+
+    }
+
+    /// Check limits of the planned changes and make them, warnings found while planning end up in the report
+    pub(crate) fn execute(&self, ops: Vec<Op>) -> Result<MergeReport> {
+
+        let (mut report, ops) = self.prepare(ops)?;
+        let _maintenance = self.maintenance()?;
+        let result = match self.options.refold {
+            true => self.apply_refolded(&mut report, ops),
+            false => self.apply(ops)
+        };
+
+        self.finish(report, result)
+
+    }
+
+    /// Report of the planning together with the changes left to make, simulated ones are only reported
+    fn prepare(&self, ops: Vec<Op>) -> Result<(MergeReport, Vec<Op>)> {
+
+        let mut report = MergeReport::with_options(self.options);
+        report.warnings = std::mem::take(&mut *self.warnings.lock().unwrap());
+        report.skipped = self.take_skipped();
+        let (simulated, ops) = self.simulated(ops)?;
+
+        report.simulated = simulated.iter().map(Op::change).collect();
+
+        self.check_entry_limit(&ops, &mut report)?;
+
+        // Strict merge doesn't start with anything worth attention
+        let mut report = report.escalate(|_| self.options.strict)?;
+        report.changes = ops.iter().map(Op::change).collect();
+        self.counters.plan_memory.fetch_max(ops.iter().map(Op::memory).sum(), Ordering::Relaxed);
+
+        Ok((report, ops))
+
+    }
+
+    /// Complete the report once the changes were made, or roll them back when the transaction failed
+    fn finish(&self, mut report: MergeReport, result: Result<()>) -> Result<MergeReport> {
+
+        match &self.journal {
+            Some(journal) => {
+                journal.finish(result, self.backup.as_deref())?;
+                report.staging = journal.staging();
+            },
+            None => result?
+        }
+
+        let mut warnings = std::mem::take(&mut *self.warnings.lock().unwrap());
+        let failed: HashSet<&Path> = warnings.iter().filter_map(|warning| match warning {
+            Warning::EntryFailed { path, .. } => Some(path.as_path()),
+            _ => None
+        }).collect();
+
+        report.changes.retain(|change| !failed.contains(change.target.as_path()));
+        self.fallback_copies(&mut report.changes);
+        report.warnings.append(&mut warnings);
+        report.already_linked = self.linked.load(Ordering::Relaxed);
+        report.usage = self.counters.usage(self.started.elapsed());
+        self.run_triggers(&mut report);
+
+        let report = report.escalate(|_| self.options.strict)?;
+        self.delegate()?;
+
+        Ok(report)
+
+    }
+
+    /// Make the spilled plan batch by batch, changes are only counted in the report
+    pub(crate) fn execute_spilled(&self, plan: SpilledPlan) -> Result<MergeReport> {
+
+        let mut report = MergeReport::with_options(self.options);
+        report.warnings = std::mem::take(&mut *self.warnings.lock().unwrap());
+        report.skipped = self.take_skipped();
+
+        let mut report = report.escalate(|_| self.options.strict)?;
+        let total = plan.len();
+        let _maintenance = self.maintenance()?;
+
+        let result = plan.batches().and_then(|mut batches| batches.try_for_each(|batch| {
+            let batch = batch?;
+            self.counters.plan_memory.fetch_max(batch.iter().map(Op::memory).sum(), Ordering::Relaxed);
+            self.apply(batch)
+        }));
+
+        match &self.journal {
+            Some(journal) => {
+                journal.finish(result, self.backup.as_deref())?;
+                report.staging = journal.staging();
+            },
+            None => result?
+        }
+
+        let mut warnings = std::mem::take(&mut *self.warnings.lock().unwrap());
+        let failed = warnings.iter().filter(|warning| matches!(warning, Warning::EntryFailed { .. })).count();
+
+        report.unlisted = total - failed;
+        report.warnings.append(&mut warnings);
+        report.already_linked = self.linked.load(Ordering::Relaxed);
+        report.usage = self.counters.usage(self.started.elapsed());
+        self.run_triggers(&mut report);
+
+        let report = report.escalate(|_| self.options.strict)?;
+        self.delegate()?;
+
+        Ok(report)
+
+    }
+
+    /// Mark the target as under maintenance until the returned guard is dropped, when asked to
+    pub(crate) fn maintenance(&self) -> Result<Option<Maintenance>> {
+        match self.options.maintenance_marker {
+            true => Maintenance::start(&self.target).map(Some),
+            false => Ok(None)
+        }
+    }
+
+    /// Skipped entries recorded while planning, in the order of target paths
+    pub(crate) fn take_skipped(&self) -> Vec<Skipped> {
+        let mut skipped = std::mem::take(&mut *self.skipped.lock().unwrap());
+        skipped.sort_by(|a, b| a.target.cmp(&b.target));
+        skipped
+    }
+
+    /// Hand nested deployments over to the delegate, see [NestedManagement::Delegate]
+    fn delegate(&self) -> Result<()> {
+
+        if let NestedManagement::Delegate(delegate) = &self.options.nested {
+
+            let mut delegated = std::mem::take(&mut *self.delegated.lock().unwrap());
+            delegated.sort();
+
+            for (source, target) in delegated {
+                delegate(&source, &target).with_context(|| format!("Merging nested deployment ({target:?}) failed"))?;
+            }
+
+        }
+
+        Ok(())
+
+    }
+
+    /// Remember the triggers fired by the change of the target path
+    fn fire(&self, target: &Path) {
+        if let Ok(relative) = target.strip_prefix(&self.target) {
+            let fired = self.options.triggers.iter().enumerate().filter(|(_, trigger)| trigger.fires(relative)).map(|(index, _)| index);
+            self.fired.lock().unwrap().extend(fired);
+        }
+    }
+
+    /// Run the fired triggers in the order they're configured, failures are only reported
+    fn run_triggers(&self, report: &mut MergeReport) {
+
+        for index in std::mem::take(&mut *self.fired.lock().unwrap()) {
+
+            let trigger = &self.options.triggers[index];
+
+            if let Err(error) = trigger.run(&self.target) {
+                report.warnings.push(Warning::TriggerFailed { path: trigger.path.clone(), error: format!("{error:#}") });
+            }
+
+            report.triggered.push(trigger.clone());
+
+        }
+
+    }
+
+    /// Split off operations matching the simulate patterns, together with everything inside simulated directories
+    pub(crate) fn simulated(&self, ops: Vec<Op>) -> Result<(Vec<Op>, Vec<Op>)> {
+
+        if self.options.simulate.is_empty() {
+            return Ok((Vec::new(), ops));
+        }
+
+        let mut directories: Vec<PathBuf> = Vec::new();
+        let (mut simulated, mut real) = (Vec::new(), Vec::new());
+
+        // Sorted plan lists directories before their content
+        for op in ops {
+
+            let relative = self.relative(&op.source)?;
+            let matched = directories.iter().any(|directory| op.target.starts_with(directory))
+                || self.options.simulate.iter().any(|pattern| pattern.matches(relative, op.source.is_dir()));
+
+            match matched {
+                true => {
+                    if matches!(op.kind, OpKind::Directory) {
+                        directories.push(op.target.clone());
+                    }
+                    simulated.push(op);
+                },
+                false => real.push(op)
+            }
+
+        }
+
+        Ok((simulated, real))
+
+    }
+
+    pub(crate) fn apply(&self, ops: Vec<Op>) -> Result<()> {
+
+        let done: Vec<AtomicBool> = ops.iter().map(|_| AtomicBool::new(false)).collect();
+        let failed = Mutex::new(None);
+        let processed = AtomicUsize::new(0);
+        let vanished = AtomicBool::new(false);
+
+        let apply = |index: usize| {
+
+            self.operation.check(self.kind)?;
+
+            match self.apply_op(&ops[index]) {
+                Ok(()) => {
+                    if let Some(journal) = &self.journal {
+                        journal.created(&ops[index].target)?;
+                    }
+                    self.fire(&ops[index].target);
+                    if let Some(observer) = &self.options.observer {
+                        match ops[index].kind {
+                            OpKind::Symlink => observer.on_symlink_created(&ops[index].source, &ops[index].target),
+                            _ => observer.on_created(&ops[index].change())
+                        }
+                    }
+                    done[index].store(true, Ordering::Relaxed);
+                },
+                // Every other entry would fail the same way
+                Err(error) if self.source_gone() => {
+                    vanished.store(true, Ordering::Relaxed);
+                    failed.lock().unwrap().get_or_insert(index);
+                    return Err(error);
+                },
+                Err(error) if self.operation.skips(&error) => self.warn(Warning::EntryFailed { path: ops[index].target.clone(), error: format!("{error:#}") }),
+                Err(error) => {
+                    failed.lock().unwrap().get_or_insert(index);
+                    return Err(error);
+                }
+            }
+
+            self.operation.report(self.kind, processed.fetch_add(1, Ordering::Relaxed) + 1, Some(ops.len()));
+            Ok(())
+
+        };
+
+        // Directories have to exist before anything is placed inside them, sorted plan creates parents first
+        let (directories, entries): (Vec<_>, Vec<_>) = (0..ops.len()).partition(|&index| matches!(ops[index].kind, OpKind::Directory));
+
+        let concurrency = self.options.concurrency;
+        let prefetch = |&index: &usize| {
+            let _ = ops[index].target.symlink_metadata();
+            let _ = ops[index].source.symlink_metadata();
+        };
+
+        let result = directories.into_iter().try_for_each(apply).and_then(|()| match concurrency.mutation {
+            0 | 1 if concurrency.prefetch > 0 => consume_prefetched(concurrency.prefetch, PREFETCH_WINDOW, &entries, prefetch, |&index| apply(index)),
+            threads => for_each_queued(threads, entries, |index, _| apply(index))
+        });
+
+        match result {
+            Err(error) if is_out_of_space(&error) || vanished.load(Ordering::Relaxed) => {
+
+                let failed = failed.into_inner().unwrap().map(|index| ops[index].target.clone()).unwrap_or_default();
+                let (applied, remaining): (Vec<_>, Vec<_>) = ops.into_iter().zip(&done).partition(|(_, done)| done.load(Ordering::Relaxed));
+                let (completed, remaining) = (applied.len(), remaining.into_iter().map(|(op, _)| op.target).collect());
+
+                Err(match vanished.load(Ordering::Relaxed) {
+                    true => error.context(SourceUnavailable { source: self.source.clone(), completed, remaining }),
+                    false => error.context(OutOfSpace { completed, failed, remaining })
+                })
+
+            },
+            result => result
+        }
+
+    }
+
+    /// Check the number of entries in every target directory the plan adds entries to
+    fn check_entry_limit(&self, ops: &[Op], report: &mut MergeReport) -> Result<()> {
+
+        let limit = match self.options.entry_limit {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+
+        let mut added: HashMap<&Path, usize> = HashMap::new();
+
+        for op in ops.iter().filter(|op| !op.replace) {
+            if let Some(directory) = op.target.parent() {
+                *added.entry(directory).or_default() += 1;
+            }
+        }
+
+        let mut crowded = Vec::new();
+
+        for (directory, added) in added {
+
+            // Directories created by the merge don't exist yet
+            let existing = match read_dir(directory) {
+                Ok(listing) => listing.count(),
+                Err(_) => 0
+            };
+
+            if existing + added > limit.max_entries {
+                crowded.push(TooManyEntries { directory: directory.to_path_buf(), entries: existing + added, limit: limit.max_entries });
+            }
+
+        }
+
+        crowded.sort_by(|a, b| a.directory.cmp(&b.directory));
+
+        match limit.exceeded {
+            LimitAction::Error if !crowded.is_empty() => Err(crowded.remove(0).into()),
+            LimitAction::Error => Ok(()),
+            LimitAction::Warn => {
+                report.warnings.extend(crowded.into_iter().map(|c| Warning::TooManyEntries { directory: c.directory, entries: c.entries, limit: c.limit }));
+                Ok(())
+            }
+        }
+
+    }
+
+    fn apply_op(&self, op: &Op) -> Result<()> {
+
+        let (source, target) = (&op.source, &op.target);
+        self.check_confined(target)?;
+
+        if op.replace {
+
+            Counters::count(&self.counters.removals);
+
+            // Backup of a staged path is made once the transaction succeeds
+            let staged = match &self.journal {
+                Some(journal) => journal.stage(target)
+                    .with_context(|| format!("Error while moving ({target:?}) aside before overwriting it with ({source:?})"))?,
+                None => false
+            };
+
+            if self.journal.is_some() && !staged {
+                self.warn(Warning::NotStaged { path: target.clone() });
+            }
+
+            match (staged, &self.backup) {
+                (true, _) => {},
+                (false, Some(suffix)) => backup(target, suffix).with_context(|| format!("Error while backing up ({target:?}) before overwriting it with ({source:?})"))?,
+                (false, None) => self.privileged(remove_path(target), |executor| executor.remove(target))
+                    .with_context(|| format!("Error while deleting ({target:?}) before overwriting it with ({source:?})"))?
+            }
+
+            if let Some(observer) = &self.options.observer {
+                observer.on_remove(target);
+            }
+
+        }
+
+        let counter = match op.kind {
+            OpKind::Symlink | OpKind::Hardlink => &self.counters.links,
+            OpKind::Copy => &self.counters.copies,
+            OpKind::Directory => &self.counters.directories
+        };
+        Counters::count(counter);
+
+        let mode = match op.kind {
+            OpKind::Symlink => return self.link(source, target),
+            OpKind::Hardlink => return self.hardlink(source, target),
+            OpKind::Copy => {
+
+                // Copy is completed under a temporary name, so the target never holds a partially written file
+                let temporary = temp_path(target);
+                let mode = match self.mode_override(source, false)?.or(op.mode) {
+                    Some(mode) => Some(mode),
+                    None => self.options.umask.map(|umask| file_mode(source, umask)).transpose()?
+                };
+
+                // Preserved symlinks have no permissions of their own, changing them would change their destination
+                let result = self.copy_file(source, &temporary)
+                    .and_then(|()| Ok(mode.filter(|_| !temporary.is_symlink()).map(|mode| set_mode(&temporary, mode)).transpose()?))
+                    .and_then(|_| Ok(rename(&temporary, target)?));
+
+                if let Err(error) = result {
+                    let _ = remove_file(&temporary);
+                    return Err(error.context(format!("Failed to copy ({source:?}) to ({target:?})")));
+                }
+
+                return Ok(());
+
+            },
+            OpKind::Directory => {
+                create_dir(target).with_context(|| format!("Failed to create directory ({target:?})"))?;
+                Some(match (self.mode_override(source, true)?, self.options.umask) {
+                    (Some(mode), _) => mode,
+                    (None, Some(umask)) => 0o777 & !umask,
+                    (None, None) => mode(&source.metadata()?.permissions())
+                })
+            }
+        };
+
+        match mode {
+            Some(mode) => set_mode(target, mode).with_context(|| format!("Failed to set permissions of ({target:?})")),
+            None => Ok(())
+        }
+
+    }
+
+    /// Create symlink, or use the fallback backend on filesystems which turned out not to support symlinks
+    fn link(&self, source: &Path, target: &Path) -> Result<()> {
+
+        let directory = target.parent().unwrap_or(target);
+        let device = device(directory).ok();
+
+        if device.is_some_and(|device| self.downgraded.lock().unwrap().contains(&device)) {
+            return self.copy_fallback(source, target);
+        }
+
+        let destination = self.link_destination(source, target)?;
+
+        match create_link(&destination, target, self.options.directory_links) {
+            Err(error) if self.options.fallback == FallbackStrategy::Copy && symlinks_unsupported(&error) => {
+
+                // Remember the filesystem, so the rest of its entries don't fail the same way
+                if device.is_some_and(|device| self.downgraded.lock().unwrap().insert(device)) {
+                    self.warn(Warning::SymlinksUnsupported { directory: directory.to_path_buf(), fallback: FallbackStrategy::Copy, error: error.to_string() });
+                }
+
+                self.copy_fallback(source, target)
+
+            },
+            result => self.privileged(result, |executor| executor.create_link(&destination, target))
+                .with_context(|| format!("Failed to create symlink from ({destination:?}) to ({target:?})"))
+        }
+
+    }
+
+    /// Operation creating links of the configured kind
+    fn link_kind(&self) -> OpKind {
+        match self.options.link_kind {
+            LinkKind::Symlink => OpKind::Symlink,
+            LinkKind::Hardlink => OpKind::Hardlink
+        }
+    }
+
+    /// Create hard link, source symlinks are followed unless they're preserved
+    fn hardlink(&self, source: &Path, target: &Path) -> Result<()> {
+
+        let source = match self.options.preserve_symlinks {
+            true => source.to_path_buf(),
+            false => source.canonicalize().with_context(|| format!("Couldn't resolve source path ({source:?})"))?
+        };
+
+        hard_link(&source, target).with_context(|| format!("Failed to create hard link from ({source:?}) to ({target:?})"))
+
+    }
+
+    /// Fail when the target directory became a symlink since the planning, see [MergeOptions::confine_target]
+    fn check_confined(&self, target: &Path) -> Result<()> {
+
+        if self.options.confine_target && through_symlink(&self.target, target) {
+            bail!("Target path ({target:?}) is reached through a symlink, which a confined merge never follows");
+        }
+
+        Ok(())
+
+    }
+
+    /// Report symlinks the fallback made copies of as copies
+    fn fallback_copies(&self, changes: &mut [Change]) {
+
+        let copied = self.copied.lock().unwrap();
+
+        for change in changes.iter_mut().filter(|change| copied.contains(&change.target)) {
+            change.kind = ChangeKind::Copy;
+        }
+
+    }
+
+    /// Copy the source entry (following its symlink) instead of linking it, symlinks inside copied directories
+    /// can't be created either, so they're skipped with a warning
+    fn copy_fallback(&self, source: &Path, target: &Path) -> Result<()> {
+
+        let temporary = temp_path(target);
+
+        let result = match source.is_dir() {
+            true => copy_tree(source, &temporary, TreeLinks::Skip).map(|skipped| {
+                skipped.into_iter().for_each(|path| self.warn(Warning::SkippedSymlink { path }));
+            }),
+            false => copy(source, &temporary).map(|_| ())
+        };
+
+        let result = result.and_then(|()| rename(&temporary, target));
+
+        match &result {
+            Ok(()) => { self.copied.lock().unwrap().insert(target.to_path_buf()); },
+            Err(_) => { let _ = remove_path(&temporary); }
+        }
+
+        result.with_context(|| format!("Failed to copy ({source:?}) to ({target:?}) in place of a symlink"))
+
+    }
+
+    /// Check whether the target already is the symlink the merge would create, symlinks without an anchor
+    /// are compared component by component instead of building their destination
+    fn linked_already(&self, source: &Path, target: &Path) -> bool {
+
+        let Ok(stored) = read_link(target) else {
+            return false;
+        };
+
+        let matching = match (&self.options.anchor, self.options.link_style) {
+            (None, LinkStyle::Absolute) => stored == source,
+            (None, LinkStyle::Relative) => is_relative_path(&stored, target.parent().unwrap_or(Path::new("/")), source),
+            _ => false
+        };
+
+        // Destinations translated by the platform (or through the anchor) have to be built
+        matching || self.link_destination(source, target).is_ok_and(|destination| stored == destination)
+
+    }
+
+    /// Path the symlink at `target` stores, pointing to `source` directly or through the anchor
+    pub(crate) fn link_destination(&self, source: &Path, target: &Path) -> Result<PathBuf> {
+
+        let anchor = match &self.options.anchor {
+            Some(anchor) => anchor,
+            None => return Ok(self.styled(source.to_path_buf(), target))
+        };
+
+        let destination = anchor.join(self.relative(source)?);
+
+        if anchor.is_absolute() {
+            return Ok(self.styled(destination, target));
+        }
+
+        // Relative anchor starts in the target root, climb there from the directory holding the symlink
+        let depth = target.strip_prefix(&self.target).map(|relative| relative.components().count()).unwrap_or(1) - 1;
+        Ok(std::iter::repeat_n(Path::new(".."), depth).collect::<PathBuf>().join(destination))
+
+    }
+
+    /// Check whether both paths are files with the same content, symlinks are followed
+    fn same_content(&self, source: &Path, target: &Path) -> bool {
+
+        let (Ok(source_metadata), Ok(target_metadata)) = (source.metadata(), target.metadata()) else {
+            return false;
+        };
+
+        if !source_metadata.is_file() || !target_metadata.is_file() || source_metadata.len() != target_metadata.len() {
+            return false;
+        }
+
+        let hasher = self.options.hasher.clone().unwrap_or_else(default_hasher);
+        matches!((hash_file(hasher.as_ref(), source), hash_file(hasher.as_ref(), target)), (Ok(a), Ok(b)) if a == b)
+
+    }
+
+    /// Absolute destination in the form required by the link style
+    fn styled(&self, destination: PathBuf, target: &Path) -> PathBuf {
+
+        let directory = plain_path(target.parent().unwrap_or(Path::new("/")).to_path_buf());
+        let destination = plain_path(destination);
+
+        match self.options.link_style {
+            // Relative path can't lead to another drive or share on Windows, the absolute one is kept then
+            LinkStyle::Relative if same_volume(&directory, &destination) => relative_path(&directory, &destination),
+            _ => destination
+        }
+
+    }
+
+    /// Retry operation denied by permissions through the privileged executor, if there is one
+    fn privileged(&self, result: io::Result<()>, retry: impl FnOnce(&dyn PrivilegedExecutor) -> io::Result<()>) -> io::Result<()> {
+        match (result, &self.options.privileged) {
+            (Err(error), Some(executor)) if error.kind() == ErrorKind::PermissionDenied => {
+                self.hook("Privileged executor", || retry(executor.as_ref())).unwrap_or_else(|error| Err(io::Error::other(error)))
+            },
+            (result, _) => result
+        }
+    }
+
+    /// Call user hook, its panic is turned into an error when [MergeOptions::catch_panics] is set
+    fn hook<T>(&self, name: &str, call: impl FnOnce() -> T) -> Result<T> {
+
+        if !self.options.catch_panics {
+            return Ok(call());
+        }
+
+        panic::catch_unwind(AssertUnwindSafe(call)).map_err(|payload| {
+            let message = payload.downcast_ref::<&str>().copied().or(payload.downcast_ref::<String>().map(String::as_str)).unwrap_or("unknown cause");
+            anyhow!("{name} panicked: {message}")
+        })
+
+    }
+
+    fn mode_override(&self, source_path: &Path, is_dir: bool) -> Result<Option<u32>> {
+        let relative = self.relative(source_path)?;
+        Ok(self.options.mode_overrides.iter().rev().find(|(pattern, _)| pattern.matches(relative, is_dir)).map(|&(_, mode)| mode))
+    }
+
+    fn copy_file(&self, source: &Path, target: &Path) -> Result<()> {
+
+        // Preserved symlink is copied as a symlink
+        if self.options.preserve_symlinks && source.is_symlink() {
+            symlink(read_link(source)?, target)?;
+            return Ok(());
+        }
+
+        let render = match &self.options.render {
+            Some(render) => render,
+            None => {
+                self.counters.bytes_copied.fetch_add(copy(source, target)?, Ordering::Relaxed);
+                return Ok(());
+            }
+        };
+
+        let content = read(source)?;
+        let relative = self.relative(source)?;
+        let rendered = self.hook("Render hook", || render(relative, &content))?;
+        write(target, &rendered)?;
+        self.counters.bytes_copied.fetch_add(rendered.len() as u64, Ordering::Relaxed);
+        set_permissions(target, source.metadata()?.permissions())?;
+
+        Ok(())
+
+    }
+
+}
+
+/// Planning and changes of [generate_symlinks_async](crate::generate_symlinks_async), one entry at a time
+#[cfg(feature = "tokio")]
+impl Walk<'_> {
+
+    /// Walk the source like [plan](Walk::plan), listing directories through `tokio` and yielding after every entry
+    pub(crate) async fn plan_async(&self) -> Result<Vec<Op>> {
+
+        let mut queue = vec![Directory { path: self.source.clone(), fresh: false, materialize: Materialization::default() }];
+        let mut ops = Vec::new();
+
+        while let Some(directory) = queue.pop() {
+            ops.extend(self.visit_async(&directory, &mut queue).await.map_err(|error| self.planning_failed(error))?);
+        }
+
+        self.finish_plan()?;
+        ops.sort_by(|a, b| a.target.cmp(&b.target));
+
+        Ok(ops)
+
+    }
+
+    async fn visit_async(&self, directory: &Directory, queue: &mut Vec<Directory>) -> Result<Vec<Op>> {
+
+        let mut ops = Vec::new();
+        let mut names: HashMap<String, Vec<OsString>> = HashMap::new();
+        let mut listing = tokio::fs::read_dir(&directory.path).await.with_context(|| format!("Directory listing ({:?}) failed", directory.path))?;
+        let existing = self.target_listing_async(directory).await?;
+        Counters::count(&self.counters.listings);
+
+        while let Some(source_entry) = listing.next_entry().await.with_context(|| "Reading source directory entry has failed")? {
+
+            let name = source_entry.file_name();
+            let missing = directory.fresh || existing.as_ref().is_some_and(|existing| !existing.contains(&name));
+
+            names.entry(name.to_string_lossy().to_lowercase()).or_default().push(name);
+
+            if let Some(op) = self.visit_entry(directory, source_entry.path(), source_entry.file_type().await?, missing, queue, &mut Vec::new())? {
+                ops.push(op);
+            }
+
+            tokio::task::yield_now().await;
+
+        }
+
+        self.warn_case_collisions(directory, names)?;
+
+        Ok(ops)
+
+    }
+
+    async fn target_listing_async(&self, directory: &Directory) -> Result<Option<HashSet<OsString>>> {
+
+        if directory.fresh || self.options.stat_each_target {
+            return Ok(None);
+        }
+
+        let path = self.target.join(self.relative(&directory.path)?);
+
+        Counters::count(&self.counters.listings);
+
+        // Missing or unreadable directory (e.g. a file in the way) is left to the per-entry checks
+        let Ok(mut listing) = tokio::fs::read_dir(&path).await else {
+            return Ok(None);
+        };
+
+        let mut names = HashSet::new();
+
+        while let Some(entry) = listing.next_entry().await.with_context(|| format!("Directory listing ({path:?}) failed"))? {
+            names.insert(entry.file_name());
+        }
+
+        Ok(Some(names))
+
+    }
+
+    /// Make the planned changes like [execute](Walk::execute), one at a time
+    pub(crate) async fn execute_async(&self, ops: Vec<Op>) -> Result<MergeReport> {
+
+        let (report, ops) = self.prepare(ops)?;
+        let _maintenance = self.maintenance()?;
+        let result = self.apply_async(&ops).await;
+
+        self.finish(report, result)
+
+    }
+
+    async fn apply_async(&self, ops: &[Op]) -> Result<()> {
+
+        for (index, op) in ops.iter().enumerate() {
+
+            self.operation.check(self.kind)?;
+
+            match self.apply_op_async(op).await {
+                Ok(()) => {
+                    self.fire(&op.target);
+                    if let Some(observer) = &self.options.observer {
+                        observer.on_symlink_created(&op.source, &op.target);
+                    }
+                },
+                Err(error) if self.operation.skips(&error) => self.warn(Warning::EntryFailed { path: op.target.clone(), error: format!("{error:#}") }),
+                Err(error) => return Err(error)
+            }
+
+            self.operation.report(self.kind, index + 1, Some(ops.len()));
+            tokio::task::yield_now().await;
+
+        }
+
+        Ok(())
+
+    }
+
+    async fn apply_op_async(&self, op: &Op) -> Result<()> {
+
+        let (source, target) = (&op.source, &op.target);
+        self.check_confined(target)?;
+
+        // Options of the asynchronous merge never lead to copies, hard links or mirrored directories
+        if !matches!(op.kind, OpKind::Symlink) {
+            bail!("Asynchronous merge creates only symlinks, ({source:?}) needs another change");
+        }
+
+        if op.replace {
+
+            Counters::count(&self.counters.removals);
+
+            let removed = match tokio::fs::metadata(target).await.is_ok_and(|metadata| metadata.is_file()) {
+                true => tokio::fs::remove_file(target).await,
+                false => tokio::fs::remove_dir_all(target).await
+            };
+
+            removed.with_context(|| format!("Error while deleting ({target:?}) before overwriting it with ({source:?})"))?;
+
+            if let Some(observer) = &self.options.observer {
+                observer.on_remove(target);
+            }
+
+        }
+
+        Counters::count(&self.counters.links);
+
+        let destination = self.link_destination(source, target)?;
+        create_link_async(&destination, target, self.options.directory_links).await.with_context(|| format!("Failed to create symlink from ({destination:?}) to ({target:?})"))
+
+    }
+
+}
+
+/// Materialization of the source entry (relative to the source directory), inherited through its parent directories
+#[cfg(feature = "manifest")]
+pub(crate) fn materialized(options: &MergeOptions, relative: &Path, is_dir: bool) -> Materialize {
+
+    let depth = relative.components().count();
+    let mut prefix = PathBuf::new();
+    let mut materialize = Materialize::default();
+
+    for (level, component) in relative.components().enumerate() {
+        prefix.push(component);
+        let is_dir = is_dir || level + 1 < depth;
+        if let Some(rule) = options.materialize.iter().rev().find(|rule| rule.pattern.matches(&prefix, is_dir)) {
+            materialize = rule.materialize;
+        }
+    }
+
+    materialize
+
+}
+
+/// Canonical source path, a missing source is reported as [SolderiumError::SourceMissing]
+pub(crate) fn source_root(source: &Path) -> Result<PathBuf> {
+    match source.canonicalize() {
+        Ok(source) => Ok(source),
+        Err(error) if error.kind() == ErrorKind::NotFound => Err(anyhow::Error::new(error).context(SolderiumError::SourceMissing(source.to_path_buf()))),
+        Err(error) => Err(anyhow::Error::new(error).context(format!("Couldn't resolve source path ({source:?})")))
+    }
+}
+
+/// Absolute target path the merge works with, see [MergeOptions::preserve_target_path]
+pub(crate) fn target_root(target: &Path, options: &MergeOptions) -> Result<PathBuf> {
+    match options.preserve_target_path {
+        true => std::path::absolute(target),
+        false => target.canonicalize()
+    }.with_context(|| "Couldn't resolve target path")
+}
+
+pub(crate) fn decide(source_path: &Path, target_path: &Path, overwrite: Overwrite, trace: &mut Trace) -> Decision {
+
+    // Broken symlink is still in the way of the new one
+    if overwrite == Overwrite::ForeignLinksOnly && !target_path.exists() && target_path.is_symlink() {
+        trace.note(|| Reason::TargetExists { directory: false });
+        trace.note(|| Reason::Overwrite(overwrite));
+        return Decision::Place { replace: true };
+    }
+
+    // Nothing to overwrite, the whole entry can be symlinked
+    if !target_path.exists() {
+        trace.note(|| Reason::TargetMissing);
+        return Decision::Place { replace: false };
+    }
+
+    let target_is_file = target_path.is_file();
+    trace.note(|| Reason::TargetExists { directory: !target_is_file });
+    trace.note(|| Reason::Overwrite(overwrite));
+
+    let descend = |trace: &mut Trace| match source_path.is_dir() && target_path.is_dir() {
+        true => Decision::Descend,
+        false => {
+            trace.note(|| Reason::NotBothDirectories);
+            Decision::Skip
+        }
+    };
+
+    let kept = |trace: &mut Trace, keep: &[&str]| match keep_marker(target_path, keep) {
+        Some(marker) => {
+            trace.note(|| Reason::KeepMarker(marker));
+            true
+        },
+        None => false
+    };
+
+    match overwrite {
+        Overwrite::All => match target_is_file {
+            // Check for .keep or .keep_files file existence
+            true if kept(trace, &[".keep", ".keep_files"]) => Decision::Skip,
+            // Check for .keep or .keep_dirs file existence
+            false if kept(trace, &[".keep", ".keep_dirs"]) => descend(trace),
+            _ => Decision::Place { replace: true }
+        },
+        Overwrite::Dirs => match target_path.is_dir() {
+            false => Decision::Skip,
+            // Check for .keep or .keep_dirs file existence
+            true if kept(trace, &[".keep", ".keep_dirs"]) => descend(trace),
+            true => Decision::Place { replace: true }
+        },
+        Overwrite::Files | Overwrite::IfDifferent => match target_is_file {
+            false => descend(trace),
+            // Check for .keep or .keep_files file existence
+            true if kept(trace, &[".keep", ".keep_files"]) => Decision::Skip,
+            true => Decision::Place { replace: true }
+        },
+        Overwrite::ForeignLinksOnly => match target_path.is_symlink() {
+            false => descend(trace),
+            // Check for .keep or .keep_files file existence
+            true if kept(trace, &[".keep", ".keep_files"]) => Decision::Skip,
+            true => Decision::Place { replace: true }
+        },
+        // Don't overwrite anything, try to find differences and symlink individual files/folders
+        Overwrite::None => descend(trace)
+    }
+
+}
+
+/// Permissions of a new file with given umask, executable when the source file is
+fn file_mode(source: &Path, umask: u32) -> io::Result<u32> {
+    Ok(match mode(&source.metadata()?.permissions()) & 0o111 {
+        0 => 0o666 & !umask,
+        _ => 0o777 & !umask
+    })
+}
+
+/// Move the target path aside by appending the suffix to its name, replacing an older backup
+pub(crate) fn backup(target: &Path, suffix: &str) -> io::Result<()> {
+
+    let backup = backup_path(target, suffix);
+
+    if backup.symlink_metadata().is_ok() {
+        remove_path(&backup)?;
+    }
+
+    rename(target, backup)
+
+}
+
+/// Suffix replaced target paths are renamed with instead of being removed, if any
+pub(crate) fn backup_suffix(options: &MergeOptions) -> Option<String> {
+    match options.soft_delete {
+        true => Some(format!("{SAVED_SUFFIX}{}", SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default())),
+        false => options.backup.clone()
+    }
+}
+
+/// Path leading from the `from` directory to `to`, both being absolute
+pub(crate) fn relative_path(from: &Path, to: &Path) -> PathBuf {
+
+    let common = from.components().zip(to.components()).take_while(|(a, b)| a == b).count();
+    let climb = from.components().count() - common;
+
+    std::iter::repeat_n(Path::new(".."), climb).chain(to.components().skip(common).map(|component| Path::new(component.as_os_str()))).collect()
+
+}
+
+/// Check whether `path` is the one [relative_path] builds from `from` to `to`, without building it
+fn is_relative_path(path: &Path, from: &Path, to: &Path) -> bool {
+
+    let common = from.components().zip(to.components()).take_while(|(a, b)| a == b).count();
+    let climb = from.components().count() - common;
+    let mut components = path.components();
+
+    (0..climb).all(|_| components.next() == Some(Component::ParentDir)) && components.eq(to.components().skip(common))
+
+}
+
+/// Path the target is renamed to by the backup
+pub(crate) fn backup_path(target: &Path, suffix: &str) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Check whether the symlink chain starting at `path` is longer than `limit`, cycles never end so they always are
+fn chain_exceeds(path: &Path, limit: usize) -> bool {
+
+    let mut current = path.to_path_buf();
+
+    for _ in 0..=limit {
+        match read_link(&current) {
+            Ok(stored) => current = current.parent().unwrap_or(Path::new("/")).join(stored),
+            Err(_) => return false
+        }
+    }
+
+    true
+
+}
+
+/// Find the keep marker protecting given path, either inside it or next to any of its ancestors
+fn keep_marker(path: &Path, keep: &[&str]) -> Option<PathBuf> {
+
+    if let Some(marker) = keep.iter().map(|&k| path.join(k)).find(|p| p.exists()) {
+        return Some(marker);
+    }
+
+    path.ancestors().find_map(|ancestor| {
+        keep.iter().find_map(|&k| {
+            let mut p = ancestor.to_path_buf();
+            p.set_file_name(k);
+            p.exists().then_some(p)
+        })
+    })
+
+}
+
+/// Check whether creating symlink failed, because the filesystem doesn't support them (e.g. vfat or some FUSE mounts),
+/// or Windows refuses them without Developer Mode
+pub(crate) fn symlinks_unsupported(error: &io::Error) -> bool {
+    // ENOSYS is reported as unsupported
+    error.raw_os_error() == Some(LINK_REFUSED) || error.kind() == ErrorKind::Unsupported
+}
+
+/// Symlinks found inside a tree copied by [copy_tree].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TreeLinks {
+    /// Copy them as symlinks storing the same destination
+    Copy,
+    /// Leave them out, e.g. on filesystems not supporting symlinks
+    Skip
+}
+
+/// Copy directory with its content, the target directory is created unless it exists (empty) already.
+///
+/// Only the `source` itself is followed when it's a symlink, symlinks inside are never followed, so dangling
+/// ones and cycles are copied (or skipped) like any other. Returns the skipped symlinks.
+pub(crate) fn copy_tree(source: &Path, target: &Path, links: TreeLinks) -> io::Result<Vec<PathBuf>> {
+
+    let mut skipped = Vec::new();
+    copy_tree_into(source, target, links, &mut skipped)?;
+
+    Ok(skipped)
+
+}
+
+fn copy_tree_into(source: &Path, target: &Path, links: TreeLinks, skipped: &mut Vec<PathBuf>) -> io::Result<()> {
+
+    create_dir_all(target)?;
+
+    for entry in read_dir(source)? {
+
+        let path = entry?.path();
+        let target = target.join(path.file_name().unwrap_or_default());
+        let metadata = symlink_metadata(&path)?;
+
+        if metadata.is_symlink() {
+            match links {
+                TreeLinks::Copy => symlink(read_link(&path)?, &target)?,
+                TreeLinks::Skip => skipped.push(path)
+            }
+        } else if metadata.is_dir() {
+            copy_tree_into(&path, &target, links, skipped)?;
+        } else {
+            copy(&path, &target)?;
+        }
+
+    }
+
+    // Read-only directories get their permissions once filled
+    set_permissions(target, source.metadata()?.permissions())
+
+}
+
+/// Check whether any directory between the `root` and the `path` is a symlink
+pub(crate) fn through_symlink(root: &Path, path: &Path) -> bool {
+    path.ancestors().skip(1).take_while(|ancestor| ancestor.starts_with(root) && *ancestor != root).any(Path::is_symlink)
+}
+
+/// Check whether the entry is a FIFO, socket or device
+fn is_special(file_type: &FileType) -> bool {
+    !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink()
+}
+
+pub(crate) fn remove_path(path: &Path) -> io::Result<()> {
+    match path.is_file() {
+        true => remove_file(path),
+        false => remove_dir_all(path)
+    }
+}
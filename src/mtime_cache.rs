@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, Metadata, read, remove_file, rename};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use crate::{Concurrency, MergeOptions};
+use crate::platform::{modified, os_str};
+use crate::temp::temp_path;
+
+const MAGIC: &[u8; 4] = b"SLDC";
+const VERSION: u32 = 1;
+
+/// Modification time of a directory, in seconds and nanoseconds
+pub(crate) type Stamp = (i64, i64);
+
+/// Directory found in its final state by the previous merge, see [MergeOptions::mtime_cache](crate::MergeOptions::mtime_cache)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CachedDirectory {
+    pub source: Stamp,
+    pub target: Stamp,
+    /// Names of the subdirectories merged into entry by entry
+    pub descended: Vec<OsString>
+}
+
+/// Directory modification times recorded by the previous merge, keyed by path relative to the source.
+///
+/// Layout (all integers little endian): magic `SLDC`, format version (`u32`), fingerprint of the merge
+/// (`u64`) and number of directories (`u64`), followed by the directories, each being a length prefixed (`u32`)
+/// path, both stamps (`i64` pairs) and the number (`u32`) of length prefixed subdirectory names.
+pub(crate) struct MtimeCache {
+    path: PathBuf,
+    fingerprint: u64,
+    previous: HashMap<PathBuf, CachedDirectory>,
+    current: Mutex<HashMap<PathBuf, CachedDirectory>>
+}
+
+impl MtimeCache {
+
+    /// Load the cache of the merge with given fingerprint, cache of any other merge is ignored
+    pub fn load(path: &Path, fingerprint: u64) -> Self {
+
+        // Cache is only an optimization, unreadable one is rebuilt from scratch
+        let previous = read(path).ok().and_then(|content| decode(&content, fingerprint)).unwrap_or_default();
+
+        Self { path: path.to_path_buf(), fingerprint, previous, current: Mutex::default() }
+
+    }
+
+    /// Directory recorded by the previous merge, as long as neither of its stamps changed since
+    pub fn unchanged(&self, relative: &Path, source: Stamp, target: Stamp) -> Option<&CachedDirectory> {
+        self.previous.get(relative).filter(|cached| cached.source == source && cached.target == target)
+    }
+
+    pub fn record(&self, relative: PathBuf, directory: CachedDirectory) {
+        self.current.lock().unwrap().insert(relative, directory);
+    }
+
+    /// Replace the cache file by the directories recorded by this merge
+    pub fn save(&self) -> Result<()> {
+
+        let path = &self.path;
+        let temporary = temp_path(path);
+        let current = self.current.lock().unwrap();
+
+        let result = File::create(&temporary).and_then(|file| {
+
+            let mut writer = BufWriter::new(file);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_le_bytes())?;
+            writer.write_all(&self.fingerprint.to_le_bytes())?;
+            writer.write_all(&(current.len() as u64).to_le_bytes())?;
+
+            for (relative, directory) in current.iter() {
+
+                write_bytes(&mut writer, relative.as_os_str().as_encoded_bytes())?;
+
+                for value in [directory.source.0, directory.source.1, directory.target.0, directory.target.1] {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+
+                writer.write_all(&(directory.descended.len() as u32).to_le_bytes())?;
+
+                for name in &directory.descended {
+                    write_bytes(&mut writer, name.as_encoded_bytes())?;
+                }
+
+            }
+
+            writer.flush()
+
+        });
+
+        let result = result.and_then(|()| rename(&temporary, path));
+
+        if result.is_err() {
+            let _ = remove_file(&temporary);
+        }
+
+        result.with_context(|| format!("Couldn't write modification time cache ({path:?})"))
+
+    }
+
+}
+
+/// Modification time of the directory
+pub(crate) fn stamp(metadata: &Metadata) -> Stamp {
+    modified(metadata)
+}
+
+/// Fingerprint of a merge, the cache is valid only for the same source, target and options
+pub(crate) fn fingerprint(source: &Path, target: &Path, options: &MergeOptions) -> u64 {
+
+    // Worker limits don't change the outcome, so verification can share the cache with the merge
+    let options = MergeOptions { concurrency: Concurrency::default(), ..options.clone() };
+
+    let mut hasher = DefaultHasher::new();
+    (source, target, format!("{options:?}")).hash(&mut hasher);
+    hasher.finish()
+
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&u32::try_from(bytes.len()).map_err(io::Error::other)?.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn decode(content: &[u8], fingerprint: u64) -> Option<HashMap<PathBuf, CachedDirectory>> {
+
+    let mut reader = Reader(content);
+
+    if reader.take(4)? != MAGIC || reader.u32()? != VERSION || reader.u64()? != fingerprint {
+        return None;
+    }
+
+    let len = reader.u64()?;
+    let mut directories = HashMap::new();
+
+    for _ in 0..len {
+
+        let relative = PathBuf::from(os_str(reader.bytes()?)?);
+        let source = (reader.i64()?, reader.i64()?);
+        let target = (reader.i64()?, reader.i64()?);
+        let descended = (0..reader.u32()?).map(|_| reader.bytes().and_then(os_str).map(OsString::from)).collect::<Option<_>>()?;
+
+        directories.insert(relative, CachedDirectory { source, target, descended });
+
+    }
+
+    Some(directories)
+
+}
+
+/// Cursor over the cache file content, every read fails once the content is exhausted
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.0.split_at_checked(len)?;
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+}
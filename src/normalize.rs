@@ -0,0 +1,26 @@
+//! Normalization of paths coming from other tools (lists, manifests, patterns), so the same entry compares equal
+//! whichever producer wrote it
+
+use std::path::{Component, Path, PathBuf};
+use anyhow::{bail, Result};
+
+/// Normalize a path relative to the source or target directory.
+///
+/// `./` prefixes, `.` components, repeated and trailing slashes are dropped, so `./share//man/` becomes `share/man`.
+/// An empty path stands for the directory itself. Absolute paths and paths climbing out with `..` are rejected.
+pub fn normalize_rel_path(path: &Path) -> Result<PathBuf> {
+
+    let normalized = clean_path(path);
+
+    if !normalized.components().all(|component| matches!(component, Component::Normal(_))) {
+        bail!("Path ({path:?}) has to lead inside the directory it is relative to");
+    }
+
+    Ok(normalized)
+
+}
+
+/// Lexically clean path, relative or not, with the same rules as [normalize_rel_path]
+pub(crate) fn clean_path(path: &Path) -> PathBuf {
+    path.components().filter(|component| *component != Component::CurDir).collect()
+}
@@ -0,0 +1,189 @@
+//! Unpacked OCI image layers merged into a single root filesystem
+
+use std::fs::{read_dir, remove_dir_all, symlink_metadata};
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::{Glob, merge, MergeOptions, MergeReport, Overwrite, Strategy};
+use crate::platform::remove_link;
+
+/// Prefix of a whiteout file, `.wh.name` hides `name` of the lower layers
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// Opaque whiteout, hides the whole content of its directory in the lower layers
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Merge unpacked OCI layer directories into the `rootfs` directory, the lowest layer comes first.
+///
+/// Every layer is merged using the [Strategy::Deep] strategy, so directories of the root filesystem
+/// are real and entries of upper layers replace the ones from the lower layers (unless protected by
+/// a keep marker). Before a layer is merged, its whiteouts remove hidden entries from the root
+/// filesystem, and entries changing type (e.g. a file replacing a directory) are removed as well.
+/// Whiteout files themselves never end up in the root filesystem.
+///
+/// Remaining `options` apply to every layer, their overwrite policy and strategy are ignored.
+pub fn merge_layers(layers: &[PathBuf], rootfs: &Path, options: &MergeOptions) -> Result<MergeReport> {
+
+    let mut options = MergeOptions { overwrite: Overwrite::Files, strategy: Strategy::Deep, ..options.clone() };
+    options.exclude.push(Glob::new(&format!("{WHITEOUT_PREFIX}*"))?);
+
+    let mut report = MergeReport::default();
+
+    for layer in layers {
+        apply_whiteouts(layer, rootfs).with_context(|| format!("Couldn't apply whiteouts of layer ({layer:?})"))?;
+        report.warnings.extend(merge(layer, rootfs, &options).with_context(|| format!("Couldn't merge layer ({layer:?})"))?.warnings);
+    }
+
+    Ok(report)
+
+}
+
+/// Remove root filesystem entries hidden or replaced by the layer
+fn apply_whiteouts(layer: &Path, rootfs: &Path) -> io::Result<()> {
+
+    let mut stack = vec![PathBuf::new()];
+
+    while let Some(relative) = stack.pop() {
+
+        let directory = rootfs.join(&relative);
+        let entries: Vec<_> = read_dir(layer.join(&relative))?.collect::<io::Result<_>>()?;
+
+        if entries.iter().any(|entry| entry.file_name() == OPAQUE_WHITEOUT) && is_real_dir(&directory) {
+            for entry in read_dir(&directory)? {
+                remove(&entry?.path())?;
+            }
+        }
+
+        for entry in entries {
+
+            let name = entry.file_name();
+            let layer_is_dir = entry.file_type()?.is_dir();
+
+            if name == OPAQUE_WHITEOUT {
+                continue;
+            }
+
+            if let Some(hidden) = name.to_str().and_then(|name| name.strip_prefix(WHITEOUT_PREFIX)) {
+
+                // Whiteouts like `.wh..` or `.wh...` would hide the directory itself or reach out of the root filesystem
+                let mut components = Path::new(hidden).components();
+                if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+                    let path = layer.join(&relative).join(&name);
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("Whiteout doesn't name a single entry ({path:?})")));
+                }
+
+                remove(&directory.join(hidden))?;
+                continue;
+            }
+
+            let target = directory.join(&name);
+
+            // Type changes between layers, only directories present in both are merged
+            if (target.is_symlink() || target.exists()) && layer_is_dir != is_real_dir(&target) {
+                remove(&target)?;
+            }
+
+            if layer_is_dir {
+                stack.push(relative.join(name));
+            }
+
+        }
+
+    }
+
+    Ok(())
+
+}
+
+fn is_real_dir(path: &Path) -> bool {
+    symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir())
+}
+
+/// Remove file, symlink or directory, nothing happens when the path doesn't exist
+fn remove(path: &Path) -> io::Result<()> {
+
+    let result = match is_real_dir(path) {
+        true => remove_dir_all(path),
+        false => remove_link(path)
+    };
+
+    match result {
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        result => result
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir_all, read_to_string, write};
+    use std::path::{Path, PathBuf};
+    use crate::MergeOptions;
+    use crate::oci::merge_layers;
+    use crate::tests::prepare_test_directory;
+
+    fn layer(name: &str, files: &[(&str, &str)]) -> PathBuf {
+
+        let root = Path::new("test_files").join(name);
+
+        for (path, content) in files {
+            create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            write(root.join(path), content).unwrap();
+        }
+
+        root
+
+    }
+
+    #[test]
+    fn merge_layers_with_whiteouts() {
+
+        let _lock = prepare_test_directory();
+        let rootfs = Path::new("test_files/rootfs");
+
+        create_dir_all(rootfs).unwrap();
+
+        let layers = [
+            layer("layer1", &[("etc/passwd", "root"), ("etc/hosts", "localhost"), ("usr/bin/tool", ""), ("var/cache/data", ""), ("opt", "file")]),
+            layer("layer2", &[("etc/passwd", "root\nuser"), ("etc/.wh.hosts", ""), ("var/.wh..wh..opq", ""), ("var/log", ""), ("opt/app", "")])
+        ];
+
+        merge_layers(&layers, rootfs, &MergeOptions::default()).unwrap();
+            assert!(!rootfs.join("etc").is_symlink());
+            assert_eq!(read_to_string(rootfs.join("etc/passwd")).unwrap(), "root\nuser");
+            assert!(!rootfs.join("etc/hosts").exists());
+            assert!(!rootfs.join("etc/.wh.hosts").exists());
+            assert!(rootfs.join("usr/bin/tool").is_symlink());
+            assert!(!rootfs.join("var/cache").exists());
+            assert!(!rootfs.join("var/.wh..wh..opq").exists());
+            assert!(rootfs.join("var/log").is_symlink());
+            assert!(rootfs.join("opt/app").is_symlink());
+
+        // Lower layers are never touched
+        assert!(Path::new("test_files/layer1/var/cache/data").exists());
+
+    }
+
+    #[test]
+    fn refuse_whiteouts_leaving_their_directory() {
+
+        let _lock = prepare_test_directory();
+        let rootfs = Path::new("test_files/rootfs");
+
+        create_dir_all(rootfs).unwrap();
+        let base = layer("layer1", &[("etc/passwd", "root")]);
+
+        for (index, whiteout) in ["etc/.wh..", "etc/.wh..."].into_iter().enumerate() {
+
+            let layers = [base.clone(), layer(&format!("evil{index}"), &[(whiteout, "")])];
+
+            let error = merge_layers(&layers, rootfs, &MergeOptions::default()).unwrap_err();
+                assert!(format!("{error:#}").contains("Whiteout doesn't name a single entry"));
+                assert!(rootfs.join("etc/passwd").is_symlink());
+                assert!(base.join("etc/passwd").is_file());
+
+        }
+
+    }
+
+}
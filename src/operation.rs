@@ -0,0 +1,171 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use anyhow::Result;
+use crate::{Change, MergeOptions, MergeReport, UnmergeOptions, UnmergeReport};
+use crate::error::is_out_of_space;
+use crate::merge::Walk;
+
+/// Hook receiving progress of an [Operation]
+pub type ProgressHook = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Observer of single entries handled by a merge, e.g. to render live logs, see [MergeOptions::observer].
+///
+/// Methods are called as the merge goes (possibly from multiple threads in the parallel mode) and do
+/// nothing by default. Planning examines and skips entries first, the changes are made afterwards.
+pub trait MergeObserver: Send + Sync {
+    /// Planning examines the source entry
+    fn on_entry_start(&self, _source: &Path) {}
+    /// Source entry is left out, see [MergeReport::skipped](crate::MergeReport::skipped) for the reasons
+    fn on_skip(&self, _source: &Path, _target: &Path) {}
+    /// Existing target path was removed (or moved aside) to make room for the change
+    fn on_remove(&self, _target: &Path) {}
+    fn on_symlink_created(&self, _source: &Path, _target: &Path) {}
+    /// Any other change (hard link, copy or directory) was made
+    fn on_created(&self, _change: &Change) {}
+}
+
+/// Kind of the work run by an [Operation].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OperationKind {
+    #[default]
+    Merge,
+    Verify,
+    Unmerge
+}
+
+/// Progress of a running [Operation].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub operation: OperationKind,
+    /// Number of processed entries (changes made by merge, examined source entries of verify, symlinks handled by unmerge)
+    pub done: usize,
+    /// Number of entries to process, when known up front
+    pub total: Option<usize>
+}
+
+/// What to do when a single entry fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop the whole operation and return the error
+    #[default]
+    Stop,
+    /// Record the failure in the report and continue with the other entries.
+    ///
+    /// Running out of space and cancellation always stop, errors of the directory walk as well.
+    Skip,
+    /// Same as [Skip](ErrorPolicy::Skip), failures of planning single source entries or listing source
+    /// subdirectories are recorded the same way, so only the source root itself has to be readable.
+    ///
+    /// Failures are listed by [MergeReport::failed](crate::MergeReport::failed) (path and cause) of the report returned as `Ok`.
+    Continue
+}
+
+/// Operation was stopped by [Operation::cancel] before finishing, changes made until then stay in place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled {
+    pub operation: OperationKind
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} operation was cancelled", self.operation)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Runner of merge, verify and unmerge with shared progress reporting, cancellation and error policy.
+///
+/// Clones share the cancellation flag, so the operation can be cancelled from another thread:
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use solderium::{MergeOptions, Operation};
+/// let operation = Operation::new().on_progress(|progress| println!("{}", progress.done));
+/// let handle = operation.clone();
+///
+/// std::thread::spawn(move || handle.cancel());
+/// operation.merge(Path::new("source"), Path::new("target"), &MergeOptions::default())?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct Operation {
+    progress: Option<ProgressHook>,
+    errors: ErrorPolicy,
+    cancelled: Arc<AtomicBool>
+}
+
+impl Operation {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `hook` after every processed entry, possibly from multiple threads in the parallel mode
+    pub fn on_progress(mut self, hook: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn errors(mut self, policy: ErrorPolicy) -> Self {
+        self.errors = policy;
+        self
+    }
+
+    /// Stop running operation before its next entry, it returns [Cancelled] error
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Same as [merge](crate::merge), failed entries skipped by [ErrorPolicy::Skip] (or [ErrorPolicy::Continue]) are reported
+    /// as [Warning::EntryFailed](crate::Warning::EntryFailed)
+    pub fn merge(&self, source: &Path, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+
+        let walk = Walk::new(source, target, options)?.operation(self.clone(), OperationKind::Merge);
+        let ops = walk.plan()?;
+
+        walk.execute(ops)
+
+    }
+
+    /// Same as [verify](crate::verify)
+    pub fn verify(&self, source: &Path, target: &Path, options: &MergeOptions) -> Result<Vec<Change>> {
+        crate::verify::run(source, target, options, self)
+    }
+
+    /// Same as [unmerge](crate::unmerge), failed symlinks skipped by [ErrorPolicy::Skip] are reported in [UnmergeReport::failed]
+    pub fn unmerge(&self, source: &Path, target: &Path, options: &UnmergeOptions) -> Result<UnmergeReport> {
+        crate::unmerge::run(source, target, options, self)
+    }
+
+    /// Fail when the operation was cancelled
+    pub(crate) fn check(&self, operation: OperationKind) -> Result<()> {
+        match self.is_cancelled() {
+            true => Err(Cancelled { operation }.into()),
+            false => Ok(())
+        }
+    }
+
+    pub(crate) fn report(&self, operation: OperationKind, done: usize, total: Option<usize>) {
+        if let Some(progress) = &self.progress {
+            progress(&Progress { operation, done, total });
+        }
+    }
+
+    /// Check whether the failed entry is skipped instead of stopping the operation
+    pub(crate) fn skips(&self, error: &anyhow::Error) -> bool {
+        self.errors != ErrorPolicy::Stop && !is_out_of_space(error) && !error.is::<Cancelled>()
+    }
+
+    /// Check whether the failure found while walking the source is skipped instead of stopping the operation
+    pub(crate) fn continues(&self, error: &anyhow::Error) -> bool {
+        self.errors == ErrorPolicy::Continue && self.skips(error)
+    }
+
+}
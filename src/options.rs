@@ -0,0 +1,719 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{bail, Context, Result};
+use crate::{Glob, Hasher, MergeObserver, Overwrite, PrivilegedExecutor};
+use crate::normalize::clean_path;
+use crate::platform::identity;
+
+/// Hook rendering content of copied files, receives path relative to the source directory and the original content
+pub type Render = Arc<dyn Fn(&Path, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Hook deciding what happens with target paths already existing, see [MergeOptions::on_conflict]
+pub type ConflictHook = Arc<dyn Fn(&Conflict) -> Resolution + Send + Sync>;
+
+/// Options controlling a single merge run, see [merge](crate::merge).
+#[derive(Clone, Default)]
+pub struct MergeOptions {
+    /// What to do with paths already existing in the target, see [Overwrite] enum
+    pub overwrite: Overwrite,
+    /// Whether directories are linked as a whole or recreated in the target, see [Strategy]
+    pub strategy: Strategy,
+    /// Whether directories may be symlinked as a whole, [Folding::Never] recreates all of them whatever the strategy is
+    pub folding: Folding,
+    /// Source entries matching any of these patterns (relative to the source directory) are left out
+    pub exclude: Vec<Glob>,
+    /// When not empty, only source files matching any of these patterns (or lying inside a matching directory) are merged.
+    ///
+    /// Directories not matching any pattern are recreated in the target, so their content can be picked entry by entry.
+    /// Exclude patterns take precedence.
+    pub include: Vec<Glob>,
+    /// Existing target paths matching any of these patterns are never replaced, whatever the overwrite policy.
+    ///
+    /// Protected directories are still merged into entry by entry, e.g. `/.ssh/` keeps the directory itself.
+    pub protect: Vec<Glob>,
+    /// Source files not passing the size and modification time limits are left out, see [Filter]
+    pub filter: Filter,
+    /// How target entries already leading to their source entry are recognized, those are left untouched
+    pub identity: Identity,
+    /// Changes of source entries matching any of these patterns are only reported in [MergeReport::simulated](crate::MergeReport::simulated).
+    ///
+    /// Useful for trial rollouts, e.g. `etc/**` leaves everything under `etc` untouched. Entries inside
+    /// a simulated directory creation are simulated as well.
+    pub simulate: Vec<Glob>,
+    /// Symlinks point through this directory instead of directly to the source, e.g. `../.store/<hash>`.
+    ///
+    /// Relative anchor is relative to the target directory and results in relative symlinks.
+    /// The anchor has to resolve to the source directory.
+    pub anchor: Option<PathBuf>,
+    /// Replaced target paths are renamed by appending this suffix (e.g. `.bak`) instead of being removed,
+    /// an older backup of the same path is replaced
+    pub backup: Option<String>,
+    /// What to do on target filesystems not supporting symlinks, see [FallbackStrategy]
+    pub fallback: FallbackStrategy,
+    /// Worker limits for the parallel mode, see [Concurrency]
+    pub concurrency: Concurrency,
+    /// Entries matching these rules are copied instead of symlinked (or the other way around), see [MaterializeRule]
+    pub materialize: Vec<MaterializeRule>,
+    /// Applied to the content of every file the merge copies, e.g. to expand templated configs at deploy time
+    pub render: Option<Render>,
+    /// Decides about every target path already existing in place of the [overwrite](MergeOptions::overwrite) policy.
+    ///
+    /// Keep markers aren't consulted then, protect patterns and already merged entries are still respected.
+    pub on_conflict: Option<ConflictHook>,
+    /// Permissions of files the merge copies and directories it creates, matched by path relative to the source directory.
+    ///
+    /// The last matching override wins and takes precedence over [MaterializeRule::mode].
+    /// Symlinks are never affected.
+    pub mode_overrides: Vec<(Glob, u32)>,
+    /// Created directories and copied files get the usual permissions (`0o777` for directories and executables,
+    /// `0o666` otherwise) without these bits, instead of permissions of their source.
+    ///
+    /// Applied by an explicit chmod, so the process umask is never changed. Mode overrides still take precedence.
+    pub umask: Option<u32>,
+    /// Retry symlink creation and removals denied by permissions through this executor, see [PrivilegedExecutor]
+    pub privileged: Option<Arc<dyn PrivilegedExecutor>>,
+    /// Notified about every examined, skipped and changed entry, see [MergeObserver]
+    pub observer: Option<Arc<dyn MergeObserver>>,
+    /// Guard against target directories growing beyond given number of entries, see [EntryLimit]
+    pub entry_limit: Option<EntryLimit>,
+    /// Catch panics of the user hooks ([render](MergeOptions::render), [on_conflict](MergeOptions::on_conflict) and [privileged](MergeOptions::privileged)),
+    /// failing only the affected entry the same way as any other error, instead of unwinding through the merge
+    pub catch_panics: bool,
+    /// Check every target path on its own, instead of listing each target directory once while planning.
+    ///
+    /// Much slower on wide directories, but sees target entries created or removed by someone else during the planning.
+    pub stat_each_target: bool,
+    /// What to do with target subdirectories managed by another deployment, see [NestedManagement]
+    pub nested: NestedManagement,
+    /// Longest chain of symlinks followed when checking an existing target, `None` uses [MAX_LINK_DEPTH](crate::MAX_LINK_DEPTH).
+    ///
+    /// Longer chains (including cycles) are reported by [Warning::LinkChainTooLong](crate::Warning::LinkChainTooLong)
+    /// and the target is treated as not merged yet.
+    pub max_link_depth: Option<usize>,
+    /// Algorithm comparing contents for [Overwrite::IfDifferent], `None` uses [default_hasher](crate::default_hasher)
+    pub hasher: Option<Arc<dyn Hasher>>,
+    /// Deepest level of source entries merged one by one (top-level entries being `1`), existing target
+    /// directories at this level are not merged into and directories missing there are linked as a whole
+    pub max_depth: Option<usize>,
+    /// Shallowest level of source entries placed in the target (top-level entries being `1`), directories above
+    /// it are always created (or merged into) entry by entry and files there are left out
+    pub min_depth: Option<usize>,
+    /// Source symlinks are merged as they are, never followed into the directories they point to
+    pub preserve_symlinks: bool,
+    /// Fail on any [Warning](crate::Warning), for pipelines demanding a perfectly clean merge.
+    ///
+    /// Warnings found while planning fail the merge before any change is made, those found while
+    /// making the changes (e.g. [ErrorPolicy::Skip](crate::ErrorPolicy::Skip) failures) fail it once it's done.
+    pub strict: bool,
+    /// Largest number of planned changes held in memory, bounding memory use of huge merges.
+    ///
+    /// The rest of the plan is spilled to a temporary file in the target directory (removed afterwards,
+    /// or by [remove_stale_temp](crate::remove_stale_temp) after a crash) and the changes are made in batches
+    /// of this size. They are only counted in [MergeReport::unlisted](crate::MergeReport::unlisted) then.
+    /// Can't be combined with [simulate](MergeOptions::simulate) and [entry_limit](MergeOptions::entry_limit),
+    /// which need the whole plan at once.
+    pub plan_memory: Option<usize>,
+    /// List skipped source entries together with the reasons in [MergeReport::skipped](crate::MergeReport::skipped),
+    /// costs extra work for every skipped entry
+    pub record_skipped: bool,
+    /// Form of the paths stored in created symlinks, see [LinkStyle]
+    pub link_style: LinkStyle,
+    /// Kind of links created for source files, see [LinkKind]
+    pub link_kind: LinkKind,
+    /// Kind of links created for source directories on Windows, see [DirectoryLinks]
+    pub directory_links: DirectoryLinks,
+    /// State file recording modification times of directories found in their final state, so the next merge
+    /// skips the entries of directories unchanged since then and only checks their subdirectories.
+    ///
+    /// Makes steady-state runs on huge trees cheap, but changes not touching any directory (e.g. new content
+    /// of a source file materialized as a copy) aren't noticed there, nor are warnings about them repeated.
+    /// The cache is only used by merges of the same source and target with the same options.
+    pub mtime_cache: Option<PathBuf>,
+    /// What to do with keep markers found in the source tree, see [SourceKeepMarkers]
+    pub source_keep_markers: SourceKeepMarkers,
+    /// Roll back all changes when the merge fails, restoring the target to its state before the merge.
+    ///
+    /// Replaced target paths are moved aside instead of being removed until the merge succeeds, those which can't
+    /// be moved on their filesystem are replaced right away and reported by [Warning::NotStaged](crate::Warning::NotStaged).
+    /// Entries failing under [ErrorPolicy::Skip](crate::ErrorPolicy::Skip) don't fail the merge, so they don't roll it back.
+    /// With [plan_memory](MergeOptions::plan_memory) the journal of the changes is spilled to a temporary file as well.
+    pub transactional: bool,
+    /// Skip source entries matching patterns of [IGNORE_FILE](crate::IGNORE_FILE) files found in the source tree.
+    ///
+    /// Every line of the file (except blank ones and `#` comments) is a [Glob] relative to the directory holding
+    /// the file, so rules stay with the data. Ignore files themselves are never merged, directories holding one
+    /// at any depth are recreated in the target instead of being symlinked as a whole.
+    pub ignore_files: bool,
+    /// Hold [MAINTENANCE_MARKER](crate::MAINTENANCE_MARKER) in the target root while making changes.
+    ///
+    /// Cooperating processes (backup agents, rsync jobs) can check it by [is_maintenance_active](crate::is_maintenance_active)
+    /// to avoid racing the merge. The merge fails when another process holds the marker already.
+    pub maintenance_marker: bool,
+    /// Keep the target path as given (only made absolute) instead of resolving the symlinks it's reached through.
+    ///
+    /// Relative symlinks are then computed from the logical path (e.g. `~/www` instead of `/srv/www` it points to)
+    /// and reported paths use it as well, so they stay valid as long as the target is reached the same way.
+    /// The target still has to resolve to a directory.
+    pub preserve_target_path: bool,
+    /// Commands run once the merge changed anything below their target subpath, see [Trigger]
+    pub triggers: Vec<Trigger>,
+    /// Replaced target paths are renamed in place to `<name>.solderium-saved-<timestamp>` (see [SAVED_SUFFIX](crate::SAVED_SUFFIX))
+    /// instead of being removed, so the user finds them right next to the new link and saved copies of earlier
+    /// merges are kept as well. Can't be combined with [backup](MergeOptions::backup).
+    pub soft_delete: bool,
+    /// Keep the tree folding of GNU stow up to date. Directory symlinks (e.g. folded by another package) standing
+    /// in the way of merged entries are unfolded into real directories holding a symlink for each of their entries,
+    /// and with [Strategy::Fold] target directories left holding symlinks to all entries of their source directory
+    /// (and nothing else) are folded back into a symlink to it. Can't be combined with a transactional merge,
+    /// hard links or a plan memory limit.
+    pub refold: bool,
+    /// Never merge into target directories reached through a symlink, nor make changes below one.
+    ///
+    /// Meant for privileged merges into directories controlled by someone else, where a symlink like
+    /// `~/.config -> /etc` would otherwise lead the merge out of the target. Such symlinks are handled as foreign
+    /// entries, left untouched unless the overwrite policy replaces them as a whole, see [deploy_to_homes](crate::deploy_to_homes).
+    pub confine_target: bool
+}
+
+/// Hooks are shown only as present or missing
+impl fmt::Debug for MergeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        let MergeOptions {
+            overwrite, strategy, folding, exclude, include, protect, filter, identity, simulate, anchor, backup, fallback, concurrency,
+            materialize, render, on_conflict, mode_overrides, umask, privileged, observer, entry_limit, catch_panics,
+            stat_each_target, nested, max_link_depth, hasher, max_depth, min_depth, preserve_symlinks, strict, plan_memory,
+            record_skipped, link_style, link_kind, directory_links, mtime_cache, source_keep_markers, transactional, ignore_files,
+            maintenance_marker, preserve_target_path, triggers, soft_delete, refold, confine_target
+        } = self;
+
+        f.debug_struct("MergeOptions")
+            .field("overwrite", overwrite)
+            .field("strategy", strategy)
+            .field("folding", folding)
+            .field("exclude", exclude)
+            .field("include", include)
+            .field("protect", protect)
+            .field("filter", filter)
+            .field("identity", identity)
+            .field("simulate", simulate)
+            .field("anchor", anchor)
+            .field("backup", backup)
+            .field("fallback", fallback)
+            .field("concurrency", concurrency)
+            .field("materialize", materialize)
+            .field("render", &render.as_ref().map(|_| "<hook>"))
+            .field("on_conflict", &on_conflict.as_ref().map(|_| "<hook>"))
+            .field("mode_overrides", mode_overrides)
+            .field("umask", umask)
+            .field("privileged", &privileged.as_ref().map(|_| "<executor>"))
+            .field("observer", &observer.as_ref().map(|_| "<observer>"))
+            .field("entry_limit", entry_limit)
+            .field("catch_panics", catch_panics)
+            .field("stat_each_target", stat_each_target)
+            .field("nested", nested)
+            .field("max_link_depth", max_link_depth)
+            .field("hasher", hasher)
+            .field("max_depth", max_depth)
+            .field("min_depth", min_depth)
+            .field("preserve_symlinks", preserve_symlinks)
+            .field("strict", strict)
+            .field("plan_memory", plan_memory)
+            .field("record_skipped", record_skipped)
+            .field("link_style", link_style)
+            .field("link_kind", link_kind)
+            .field("directory_links", directory_links)
+            .field("mtime_cache", mtime_cache)
+            .field("source_keep_markers", source_keep_markers)
+            .field("transactional", transactional)
+            .field("ignore_files", ignore_files)
+            .field("maintenance_marker", maintenance_marker)
+            .field("preserve_target_path", preserve_target_path)
+            .field("triggers", triggers)
+            .field("soft_delete", soft_delete)
+            .field("refold", refold)
+            .field("confine_target", confine_target)
+            .finish()
+
+    }
+}
+
+/// Hooks are equal only when they are the very same instance
+impl PartialEq for MergeOptions {
+    fn eq(&self, other: &Self) -> bool {
+
+        let MergeOptions {
+            overwrite, strategy, folding, exclude, include, protect, filter, identity, simulate, anchor, backup, fallback, concurrency,
+            materialize, render, on_conflict, mode_overrides, umask, privileged, observer, entry_limit, catch_panics,
+            stat_each_target, nested, max_link_depth, hasher, max_depth, min_depth, preserve_symlinks, strict, plan_memory,
+            record_skipped, link_style, link_kind, directory_links, mtime_cache, source_keep_markers, transactional, ignore_files,
+            maintenance_marker, preserve_target_path, triggers, soft_delete, refold, confine_target
+        } = self;
+
+        let same_render = match (render, &other.render) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none()
+        };
+
+        let same_on_conflict = match (on_conflict, &other.on_conflict) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none()
+        };
+
+        let same_privileged = match (privileged, &other.privileged) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none()
+        };
+
+        let same_observer = match (observer, &other.observer) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none()
+        };
+
+        let same_hasher = match (hasher, &other.hasher) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none()
+        };
+
+        *overwrite == other.overwrite && *strategy == other.strategy && *folding == other.folding && *exclude == other.exclude && *include == other.include
+            && *protect == other.protect && *filter == other.filter && *identity == other.identity && *simulate == other.simulate
+            && *anchor == other.anchor && *backup == other.backup && *fallback == other.fallback && *concurrency == other.concurrency
+            && *materialize == other.materialize && same_render && same_on_conflict && *mode_overrides == other.mode_overrides && *umask == other.umask
+            && same_privileged && same_observer && *entry_limit == other.entry_limit && *catch_panics == other.catch_panics
+            && *stat_each_target == other.stat_each_target && *nested == other.nested
+            && *max_link_depth == other.max_link_depth && same_hasher && *max_depth == other.max_depth && *min_depth == other.min_depth
+            && *preserve_symlinks == other.preserve_symlinks && *strict == other.strict
+            && *plan_memory == other.plan_memory && *record_skipped == other.record_skipped
+            && *link_style == other.link_style && *link_kind == other.link_kind && *directory_links == other.directory_links
+            && *mtime_cache == other.mtime_cache && *source_keep_markers == other.source_keep_markers
+            && *transactional == other.transactional && *ignore_files == other.ignore_files
+            && *maintenance_marker == other.maintenance_marker && *preserve_target_path == other.preserve_target_path
+            && *triggers == other.triggers && *soft_delete == other.soft_delete && *refold == other.refold
+            && *confine_target == other.confine_target
+
+    }
+}
+
+impl Eq for MergeOptions {}
+
+/// Hook merging a nested deployment, receives the source directory and the managed target directory
+pub type Delegate = Arc<dyn Fn(&Path, &Path) -> Result<()> + Send + Sync>;
+
+/// What to do with a target subdirectory managed by another deployment, recognized by [MANAGED_MARKER](crate::MANAGED_MARKER) inside it.
+///
+/// Prevents composed deployments from corrupting each other, the target root itself is never considered nested.
+#[derive(Clone, Default)]
+pub enum NestedManagement {
+    /// Merge into the subdirectory like into any other
+    #[default]
+    Merge,
+    /// Leave the subdirectory untouched, reported by [Warning::NestedDeployment](crate::Warning::NestedDeployment)
+    Skip,
+    /// Stop before making any change
+    Error,
+    /// Leave the subdirectory to the hook, called once the rest of the merge is done
+    Delegate(Delegate)
+}
+
+impl fmt::Debug for NestedManagement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NestedManagement::Merge => f.write_str("Merge"),
+            NestedManagement::Skip => f.write_str("Skip"),
+            NestedManagement::Error => f.write_str("Error"),
+            NestedManagement::Delegate(_) => f.write_str("Delegate(<hook>)")
+        }
+    }
+}
+
+/// Delegates are equal only when they are the very same hook
+impl PartialEq for NestedManagement {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (NestedManagement::Delegate(a), NestedManagement::Delegate(b)) => Arc::ptr_eq(a, b),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b)
+        }
+    }
+}
+
+impl Eq for NestedManagement {}
+
+/// Command run after a merge which changed anything below a target subpath, e.g. rebuilding an index of linked files.
+///
+/// Runs once per merge in the target directory, after all changes are made. Linking a parent of the subpath as a whole
+/// counts as its change too. Fired triggers are listed in [MergeReport::triggered](crate::MergeReport::triggered),
+/// failed ones are reported by [Warning::TriggerFailed](crate::Warning::TriggerFailed).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trigger {
+    /// Subpath relative to the target
+    pub path: PathBuf,
+    /// Program to run followed by its arguments
+    pub command: Vec<OsString>
+}
+
+impl Trigger {
+
+    pub fn new<I, S>(path: impl Into<PathBuf>, command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>
+    {
+        Self { path: clean_path(&path.into()), command: command.into_iter().map(Into::into).collect() }
+    }
+
+    /// Rebuild the index of man pages once anything below `share/man` changes
+    pub fn man_pages() -> Self {
+        Self::new("share/man", ["mandb", "--quiet"])
+    }
+
+    /// Rebuild the cache of desktop entries once anything below `share/applications` changes
+    pub fn desktop_files() -> Self {
+        Self::new("share/applications", ["update-desktop-database", "share/applications"])
+    }
+
+    /// Check whether the change of the path (relative to the target) fires the trigger
+    pub fn fires(&self, relative: &Path) -> bool {
+        let relative = clean_path(relative);
+        relative.starts_with(&self.path) || self.path.starts_with(&relative)
+    }
+
+    /// Run the command in the `target` directory
+    pub fn run(&self, target: &Path) -> Result<()> {
+
+        let (program, args) = match self.command.split_first() {
+            Some(command) => command,
+            None => bail!("Trigger command of ({:?}) is empty", self.path)
+        };
+
+        let output = Command::new(program).args(args).current_dir(target).output()
+            .with_context(|| format!("Couldn't run trigger command ({program:?})"))?;
+
+        if !output.status.success() {
+            bail!("Trigger command ({program:?}) failed with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        Ok(())
+
+    }
+
+}
+
+/// General approach to merging a source directory into a target.
+///
+/// See [recommend_strategy](crate::recommend_strategy) for picking one based on the actual trees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Only top-level source entries are linked, nested directories are linked as a whole
+    Shallow,
+    /// Directories are recreated in the target and only files are linked, leaving room for local files
+    /// (GNU stow `--no-folding`), so the whole target tree stays writable
+    Deep,
+    /// Directories missing in the target are linked as a whole, existing ones are merged entry by entry
+    /// (the tree folding of GNU stow)
+    #[default]
+    Fold
+}
+
+/// Whether source directories may be symlinked as a whole (the tree folding of GNU stow).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Folding {
+    /// Directories are folded as the [Strategy] decides
+    #[default]
+    Auto,
+    /// Directories are always recreated in the target and only files are linked (GNU stow `--no-folding`),
+    /// so the whole target tree stays writable. Same as [Strategy::Deep], whatever the strategy is
+    Never
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    /// Parse lowercase variant name, e.g. `deep`
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "shallow" => Ok(Strategy::Shallow),
+            "deep" => Ok(Strategy::Deep),
+            "fold" => Ok(Strategy::Fold),
+            _ => bail!("Unknown strategy ({value}), expected one of: shallow, deep, fold")
+        }
+    }
+}
+
+/// Limits source files have to pass to be merged, directories are never filtered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Smallest size in bytes
+    pub min_size: Option<u64>,
+    /// Largest size in bytes
+    pub max_size: Option<u64>,
+    /// Files modified at or before this time are left out
+    pub modified_after: Option<SystemTime>,
+    /// Files modified at or after this time are left out
+    pub modified_before: Option<SystemTime>
+}
+
+impl Filter {
+
+    /// Check whether file with given metadata passes all limits
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+
+        let size = metadata.len();
+        let modified = metadata.modified().ok();
+
+        self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self.modified_after.is_none_or(|after| modified.is_some_and(|modified| modified > after))
+            && self.modified_before.is_none_or(|before| modified.is_some_and(|modified| modified < before))
+
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+}
+
+/// How two paths are recognized as the same filesystem object.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Identity {
+    /// Paths are the same after resolving symlinks
+    #[default]
+    Path,
+    /// Paths lead to the same device and inode, recognizes the same file reachable through bind mounts or hardlinks
+    DevInode
+}
+
+impl Identity {
+
+    /// Check whether both paths lead to the same filesystem object, symlinks are followed
+    pub fn same(self, a: &Path, b: &Path) -> bool {
+        match self {
+            Identity::Path => matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b),
+            Identity::DevInode => matches!((identity(a), identity(b)), (Ok(a), Ok(b)) if a == b)
+        }
+    }
+
+}
+
+/// Form of the paths stored in created symlinks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkStyle {
+    /// Absolute canonical path of the source entry (or the [anchor](MergeOptions::anchor))
+    #[default]
+    Absolute,
+    /// Path relative to the directory holding the symlink, so links keep working when source and target
+    /// are moved or mounted elsewhere together (containers, chroots, backups)
+    Relative
+}
+
+/// What to do with keep markers (`.keep`, `.keep_files` and `.keep_dirs`) found in the source tree.
+///
+/// Sources often carry them only to keep empty directories in git, but once linked into the target
+/// they protect the target directory from future merges. Markers inside directories linked as a whole
+/// are never looked at, use the [Deep](Strategy::Deep) strategy to catch all of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SourceKeepMarkers {
+    /// Merge them like any other file
+    #[default]
+    LinkAsNormalFile,
+    /// Leave them out
+    Skip,
+    /// Stop before making any change
+    Error
+}
+
+/// Kind of links created for source files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinkKind {
+    #[default]
+    Symlink,
+    /// Hard links keep the content even once the source tree is deleted, source and target have to share
+    /// the filesystem. Directories can't be hard linked, so they're always recreated in the target and the
+    /// [max_depth](MergeOptions::max_depth) is ignored for them; merged entries are recognized by
+    /// [Identity::DevInode]
+    Hardlink
+}
+
+/// Kind of links created for source directories on Windows, other platforms always create symlinks.
+///
+/// Junctions need neither Developer Mode nor the symlink privilege, but they always store an absolute path
+/// (regardless of [LinkStyle]) and work only between local volumes. Directories on network shares (UNC paths
+/// or mapped network drives) and links placed on them get symlinks even when junctions are asked for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectoryLinks {
+    #[default]
+    Symlink,
+    Junction
+}
+
+/// What to do when the target filesystem doesn't support symlinks (e.g. vfat, some FUSE mounts or restricted containers).
+///
+/// The condition is detected on the first failure and the fallback is used for the rest of that
+/// filesystem right away, recorded by [Warning::SymlinksUnsupported](crate::Warning::SymlinksUnsupported).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FallbackStrategy {
+    /// Fail the merge
+    #[default]
+    Fail,
+    /// Copy the source entry instead, directories including their content.
+    /// Such entries are reported as [ChangeKind::Copy](crate::ChangeKind::Copy) changes
+    Copy
+}
+
+/// Worker limits for the parallel mode.
+///
+/// Directory listing, target mutation (symlink/unlink) and verification are limited separately,
+/// as network filesystems often tolerate very different loads for the two.
+/// Limit of `1` (the default) runs the given phase sequentially.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Concurrency {
+    /// Maximum number of directories listed at the same time
+    pub traversal: usize,
+    /// Maximum number of symlinks created (or paths removed) at the same time
+    pub mutation: usize,
+    /// Maximum number of directories scanned at the same time by [verify](crate::verify), which never mutates
+    /// the target, so it can usually afford more workers than the merge itself
+    pub verification: usize,
+    /// Number of threads reading metadata of the upcoming entries while the target is mutated sequentially
+    /// (`mutation` of `1`), so a high-latency network filesystem answers from its attribute cache. `0` disables it.
+    pub prefetch: usize
+}
+
+impl Concurrency {
+
+    /// Use the same limit for both directory listing and mutation
+    pub fn uniform(threads: usize) -> Self {
+        Self { traversal: threads, mutation: threads, verification: threads, prefetch: 0 }
+    }
+
+    /// Use as many workers as there are CPUs available to the process for every phase (a single one when unknown)
+    pub fn available() -> Self {
+        Self::uniform(std::thread::available_parallelism().map_or(1, usize::from))
+    }
+
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self::uniform(1)
+    }
+}
+
+/// Target path already existing in place of a source entry, passed to [MergeOptions::on_conflict].
+pub struct Conflict<'a> {
+    pub source: &'a Path,
+    pub target: &'a Path,
+    /// Metadata of the source entry, symlinks are followed unless [preserved](MergeOptions::preserve_symlinks)
+    pub source_metadata: &'a Metadata,
+    /// Metadata of the target path itself, symlinks aren't followed
+    pub target_metadata: &'a Metadata
+}
+
+/// What happens with a [Conflict].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Replace the target path by the source entry
+    Replace,
+    /// Leave the target path (and the source entry) alone
+    Skip,
+    /// Merge the source directory into the target directory entry by entry, skipped unless both are directories
+    Descend,
+    /// Fail the whole merge
+    Abort
+}
+
+/// How a source entry ends up in the target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Materialize {
+    /// Symlink pointing to the source entry
+    #[default]
+    Link,
+    /// Real copy of the source entry, directories are created and their content is materialized entry by entry
+    Copy,
+    /// Same as [Copy](Materialize::Copy), but only made while the target path doesn't exist, so the copy
+    /// (e.g. writable `var/` seeded from the source) is never replaced by later merges
+    CopyOnce
+}
+
+/// Materialization of source entries matching a pattern.
+///
+/// When more rules match the same entry, the last one wins. Entries not matched by any rule inherit
+/// the materialization of their parent directory, entries in the source root are symlinked.
+///
+/// Rules can be combined to mix backends in a single run, e.g. symlink everything except `secrets/**`,
+/// which is copied with mode `0600`:
+///
+/// ```
+/// # use solderium::{MaterializeRule, MergeOptions};
+/// let options = MergeOptions {
+///     materialize: vec![MaterializeRule::copy("secrets/**")?.mode(0o600)],
+///     ..Default::default()
+/// };
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaterializeRule {
+    /// Pattern matched against paths relative to the source directory
+    pub pattern: Glob,
+    pub materialize: Materialize,
+    /// Permissions of copied files (including those inheriting this rule), source permissions are kept otherwise
+    pub mode: Option<u32>
+}
+
+impl MaterializeRule {
+
+    /// Copy entries matching the pattern
+    pub fn copy(pattern: &str) -> Result<Self> {
+        Ok(Self { pattern: Glob::new(pattern)?, materialize: Materialize::Copy, mode: None })
+    }
+
+    /// Copy entries matching the pattern unless they already exist in the target, see [Materialize::CopyOnce]
+    pub fn copy_once(pattern: &str) -> Result<Self> {
+        Ok(Self { pattern: Glob::new(pattern)?, materialize: Materialize::CopyOnce, mode: None })
+    }
+
+    /// Symlink entries matching the pattern
+    pub fn link(pattern: &str) -> Result<Self> {
+        Ok(Self { pattern: Glob::new(pattern)?, materialize: Materialize::Link, mode: None })
+    }
+
+    /// Set permissions of files copied by this rule
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+}
+
+/// Maximum number of entries a merge may leave in any target directory it adds entries to.
+///
+/// Some filesystems and tools degrade with huge directories (often over 64k entries),
+/// symlinking the directory as a whole avoids the problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryLimit {
+    pub max_entries: usize,
+    /// What to do when the limit is exceeded, checked before anything is changed
+    pub exceeded: LimitAction
+}
+
+/// What to do when a limit is exceeded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Record a warning in the report and continue
+    #[default]
+    Warn,
+    /// Fail the merge without changing anything
+    Error
+}
+
+impl Default for EntryLimit {
+    fn default() -> Self {
+        Self { max_entries: 65536, exceeded: LimitAction::Warn }
+    }
+}
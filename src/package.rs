@@ -0,0 +1,204 @@
+//! Dotfiles managed like GNU stow packages: every subdirectory of a packages root is linked into
+//! the target (typically the home directory) as a unit, see [Package]
+
+use std::fs::{create_dir_all, read_dir, remove_dir, remove_file};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::{MergeOptions, MergeReport, PackageConflicts, Reason, unmerge, UnmergeOptions};
+use crate::manifest::{Manifest, merge_recorded};
+use crate::merge::Walk;
+use crate::reserved::{is_reserved, PACKAGES_DIR};
+
+/// Single subdirectory of a packages root linked into the target as a unit, like a GNU stow package.
+///
+/// Each stowed package gets its own manifest in [PACKAGES_DIR] of the target, so packages sharing
+/// the target can be unstowed independently.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Package {
+    /// Name of the package directory, naming its manifest as well
+    pub name: String,
+    pub path: PathBuf
+}
+
+impl Package {
+
+    /// Package stored in the directory
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+
+        let path = path.into();
+        let resolved = path.canonicalize().with_context(|| format!("Couldn't resolve package directory ({path:?})"))?;
+        let name = resolved.file_name().and_then(|name| name.to_str())
+            .with_context(|| format!("Package directory ({path:?}) doesn't have a valid name"))?
+            .to_string();
+
+        Ok(Self { name, path })
+
+    }
+
+    /// Packages of the root, hidden directories are left out, ordered by name
+    pub fn list(root: &Path) -> Result<Vec<Self>> {
+
+        let mut packages = Vec::new();
+
+        for entry in read_dir(root).with_context(|| format!("Couldn't read packages root ({root:?})"))? {
+
+            let entry = entry.with_context(|| format!("Couldn't read directory entry ({root:?})"))?;
+            let hidden = entry.file_name().to_str().is_none_or(|name| name.starts_with('.'));
+
+            if !hidden && entry.path().is_dir() {
+                packages.push(Self::new(entry.path())?);
+            }
+
+        }
+
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(packages)
+
+    }
+
+    /// Path of the package manifest in the target
+    pub fn manifest_path(&self, target: &Path) -> PathBuf {
+        target.join(PACKAGES_DIR).join(format!("{}.json", self.name))
+    }
+
+    /// Check whether the package is stowed in the target
+    pub fn is_stowed(&self, target: &Path) -> bool {
+        self.manifest_path(target).is_file()
+    }
+
+    /// Existing target paths the package would have to replace, but the `options` don't allow it.
+    ///
+    /// Target paths already leading to the package are not conflicts.
+    pub fn conflicts(&self, target: &Path, options: &MergeOptions) -> Result<Vec<PathBuf>> {
+
+        let options = MergeOptions { record_skipped: true, ..options.clone() };
+        let walk = Walk::new(&self.path, target, &options)?;
+        walk.plan()?;
+
+        let mut conflicts: Vec<PathBuf> = walk.take_skipped().into_iter()
+            .filter(|skipped| skipped.reasons.iter().any(|reason| matches!(reason, Reason::TargetExists { .. })))
+            .filter(|skipped| !matches!(skipped.reasons.last(), Some(Reason::AlreadyMerged(_))))
+            .map(|skipped| skipped.target)
+            .collect();
+
+        conflicts.sort();
+        Ok(conflicts)
+
+    }
+
+    /// Link the package into the target, recording created symlinks in the package manifest.
+    ///
+    /// Fails with [PackageConflicts] before making any change, when some of its entries would be left out
+    /// because of existing target paths. Stowing the package again links entries added since.
+    pub fn stow(&self, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+
+        let conflicts = self.conflicts(target, options)?;
+        if !conflicts.is_empty() {
+            return Err(PackageConflicts { package: self.name.clone(), paths: conflicts }.into());
+        }
+
+        let directory = target.join(PACKAGES_DIR);
+        create_dir_all(&directory).with_context(|| format!("Couldn't create packages directory ({directory:?})"))?;
+
+        merge_recorded(&self.path, target, options, &self.manifest_path(target))
+            .with_context(|| format!("Couldn't stow package ({})", self.name))
+
+    }
+
+    /// Remove symlinks of the package from the target together with its manifest, returns removed symlinks.
+    ///
+    /// Symlinks the package directories were [unfolded](MergeOptions::refold) into are removed as well,
+    /// directories created by stowing are left in place.
+    pub fn unstow(&self, target: &Path) -> Result<Vec<PathBuf>> {
+
+        let path = self.manifest_path(target);
+        let manifest = Manifest::read_from(&path).with_context(|| format!("Package ({}) isn't stowed in ({target:?})", self.name))?;
+        let mut removed = manifest.remove_links()?;
+
+        for link in manifest.links.iter().filter(|link| !link.target.is_symlink() && link.target.is_dir() && link.source.is_dir()) {
+            removed.extend(unmerge(&link.source, &link.target, &UnmergeOptions::default())?.removed);
+        }
+
+        remove_file(&path).with_context(|| format!("Couldn't remove package manifest ({path:?})"))?;
+
+        // Last unstowed package leaves no trace
+        let directory = target.join(PACKAGES_DIR);
+        if read_dir(&directory).is_ok_and(|mut entries| entries.all(|entry| entry.is_ok_and(|entry| is_reserved(&entry.file_name())))) {
+            let _ = remove_dir(&directory);
+        }
+
+        removed.sort();
+        Ok(removed)
+
+    }
+
+}
+
+/// Stow the package directory into the `target` like GNU stow, directory symlinks of other packages
+/// in the way are unfolded and directories are folded back once possible, see [Package::stow].
+pub fn stow(package: &Path, target: &Path) -> Result<MergeReport> {
+    Package::new(package)?.stow(target, &MergeOptions { refold: true, ..Default::default() })
+}
+
+/// Remove the package directory stowed into the `target`, see [Package::unstow].
+pub fn unstow(package: &Path, target: &Path) -> Result<Vec<PathBuf>> {
+    Package::new(package)?.unstow(target)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{create_dir_all, read_to_string, write};
+    use std::path::Path;
+    use crate::{MergeOptions, SolderiumError, stow, unstow};
+    use crate::package::Package;
+    use crate::reserved::PACKAGES_DIR;
+    use crate::tests::prepare_test_directory;
+
+    #[test]
+    fn stow_and_unstow_packages() {
+
+        let _lock = prepare_test_directory();
+        let (root, home) = (Path::new("test_files/test_dir3"), Path::new("test_files/test_dir4"));
+        create_dir_all(root.join("vim/.vim/colors")).unwrap();
+        create_dir_all(root.join("nvim/.vim/plugin")).unwrap();
+        create_dir_all(home).unwrap();
+        write(root.join("vim/.vimrc"), "set number").unwrap();
+        write(root.join("vim/.vim/colors/dark.vim"), "dark").unwrap();
+        write(root.join("nvim/.vim/plugin/lsp.vim"), "lsp").unwrap();
+
+        let packages = Package::list(root).unwrap();
+            assert_eq!(packages.iter().map(|package| package.name.as_str()).collect::<Vec<_>>(), ["nvim", "vim"]);
+
+        // Second package unfolds the directory linked by the first one
+        stow(&root.join("vim"), home).unwrap();
+            assert!(home.join(".vim").is_symlink());
+
+        stow(&root.join("nvim"), home).unwrap();
+            assert!(!home.join(".vim").is_symlink());
+            assert_eq!(read_to_string(home.join(".vim/colors/dark.vim")).unwrap(), "dark");
+            assert_eq!(read_to_string(home.join(".vim/plugin/lsp.vim")).unwrap(), "lsp");
+            assert!(!root.join("vim/.vim/plugin").exists());
+            assert!(packages.iter().all(|package| package.is_stowed(home)));
+
+        // Local file in the way stops the package before any change
+        write(root.join("vim/.gvimrc"), "set guifont").unwrap();
+        write(home.join(".gvimrc"), "local").unwrap();
+
+        let error = SolderiumError::from(packages[1].stow(home, &MergeOptions::default()).unwrap_err());
+            assert!(matches!(error, SolderiumError::PackageConflicts(conflicts) if conflicts.package == "vim" && conflicts.paths.len() == 1));
+
+        // Unstowing takes only the links of the package, unfolded ones included
+        let removed = unstow(&root.join("vim"), home).unwrap();
+            assert_eq!(removed.len(), 2);
+            assert!(!home.join(".vimrc").exists() && !home.join(".vim/colors").exists());
+            assert!(home.join(".vim/plugin").is_symlink());
+            assert_eq!(read_to_string(home.join(".gvimrc")).unwrap(), "local");
+
+        unstow(&root.join("nvim"), home).unwrap();
+            assert!(!home.join(PACKAGES_DIR).exists());
+            assert!(unstow(&root.join("nvim"), home).is_err());
+
+    }
+
+}
@@ -0,0 +1,404 @@
+#[cfg(unix)]
+pub(crate) use unix::*;
+#[cfg(windows)]
+pub(crate) use windows::*;
+
+#[cfg(unix)]
+mod unix {
+
+    use std::ffi::OsStr;
+    use std::fs::{Metadata, Permissions, remove_file, set_permissions};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use crate::DirectoryLinks;
+
+    /// Error code of a symlink the filesystem refuses to create
+    pub(crate) const LINK_REFUSED: i32 = libc::EPERM;
+
+    /// Create symlink at `link` pointing to `destination`
+    pub(crate) fn symlink(destination: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+        std::os::unix::fs::symlink(destination, link)
+    }
+
+    /// Create link at `link` pointing to `destination`, there are no junctions so directories get symlinks too
+    pub(crate) fn create_link(destination: &Path, link: &Path, _directories: DirectoryLinks) -> io::Result<()> {
+        symlink(destination, link)
+    }
+
+    /// Create link at `link` pointing to `destination`, see [create_link]
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn create_link_async(destination: &Path, link: &Path, _directories: DirectoryLinks) -> io::Result<()> {
+        tokio::fs::symlink(destination, link).await
+    }
+
+    /// Create link at `link` pointing to `destination` of the same kind as the link at `like`
+    pub(crate) fn relink(_like: &Path, destination: &Path, link: &Path) -> io::Result<()> {
+        symlink(destination, link)
+    }
+
+    /// Junctions exist only on Windows, creating one always fails as unsupported
+    pub(crate) fn junction(_destination: &Path, _link: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Junctions exist only on Windows"))
+    }
+
+    /// Any user can create symlinks
+    pub(crate) fn unprivileged_symlinks() -> bool {
+        true
+    }
+
+    /// Remove symlink, without touching its destination
+    pub(crate) fn remove_link(path: &Path) -> io::Result<()> {
+        remove_file(path)
+    }
+
+    /// Form of the path stored in links, paths are used as they are
+    pub(crate) fn plain_path(path: PathBuf) -> PathBuf {
+        path
+    }
+
+    /// Check whether a relative path can lead from one path to the other, always true with a single root
+    pub(crate) fn same_volume(_a: &Path, _b: &Path) -> bool {
+        true
+    }
+
+    /// Device and inode number of the entry the path leads to
+    pub(crate) fn identity(path: &Path) -> io::Result<(u64, u64)> {
+        path.metadata().map(|metadata| (metadata.dev(), metadata.ino()))
+    }
+
+    /// Device holding the entry the path leads to
+    pub(crate) fn device(path: &Path) -> io::Result<u64> {
+        path.metadata().map(|metadata| metadata.dev())
+    }
+
+    /// Permission bits, e.g. `0o644`
+    pub(crate) fn mode(permissions: &Permissions) -> u32 {
+        permissions.mode()
+    }
+
+    /// Set permission bits of the entry the path leads to
+    pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+        set_permissions(path, Permissions::from_mode(mode))
+    }
+
+    /// Modification time in seconds and nanoseconds
+    pub(crate) fn modified(metadata: &Metadata) -> (i64, i64) {
+        (metadata.mtime(), metadata.mtime_nsec())
+    }
+
+    /// Name stored as bytes of [OsStr::as_encoded_bytes]
+    pub(crate) fn os_str(bytes: &[u8]) -> Option<&OsStr> {
+        Some(OsStr::from_bytes(bytes))
+    }
+
+}
+
+#[cfg(windows)]
+mod windows {
+
+    use std::ffi::{OsStr, OsString};
+    use std::fs::{create_dir, Metadata, OpenOptions, Permissions, remove_dir, remove_file, set_permissions};
+    use std::io::{self, ErrorKind};
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::fs::{FileTypeExt, OpenOptionsExt, symlink_dir, symlink_file};
+    use std::os::windows::io::AsRawHandle;
+    use std::path::{self, Component, Path, PathBuf, Prefix};
+    use std::ptr;
+    use std::time::UNIX_EPOCH;
+    use windows_sys::Win32::Foundation::{ERROR_INVALID_FUNCTION, ERROR_SUCCESS};
+    use windows_sys::Win32::Storage::FileSystem::{BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT, GetDriveTypeW, GetFileInformationByHandle, MAXIMUM_REPARSE_DATA_BUFFER_SIZE};
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+    use windows_sys::Win32::System::Ioctl::{FSCTL_GET_REPARSE_POINT, FSCTL_SET_REPARSE_POINT};
+    use windows_sys::Win32::System::Registry::{HKEY_LOCAL_MACHINE, RegGetValueW, RRF_RT_REG_DWORD};
+    use windows_sys::Win32::System::SystemServices::IO_REPARSE_TAG_MOUNT_POINT;
+    use windows_sys::Win32::System::WindowsProgramming::DRIVE_REMOTE;
+    use crate::DirectoryLinks;
+
+    /// Registry key and value holding the Developer Mode switch, below `HKEY_LOCAL_MACHINE`
+    const DEVELOPER_MODE_KEY: &str = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\AppModelUnlock";
+    const DEVELOPER_MODE_VALUE: &str = "AllowDevelopmentWithoutDevLicense";
+    /// Longest path most programs accept without the verbatim `\\?\` prefix
+    const MAX_PATH: usize = 260;
+
+    /// Error code of a symlink refused without Developer Mode or the symlink privilege (`ERROR_PRIVILEGE_NOT_HELD`)
+    pub(crate) const LINK_REFUSED: i32 = 1314;
+
+    /// Create symlink at `link` pointing to `destination`.
+    ///
+    /// Windows distinguishes file and directory symlinks, the kind follows what the destination is
+    /// when the link is created. Dangling destinations get file symlinks.
+    pub(crate) fn symlink(destination: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+
+        let (destination, link) = (destination.as_ref(), link.as_ref());
+
+        match resolve_destination(destination, link).is_dir() {
+            true => symlink_dir(destination, link),
+            false => symlink_file(destination, link)
+        }
+
+    }
+
+    /// Create link at `link` pointing to `destination`.
+    ///
+    /// Directories get junctions when asked for, unless the link or its destination is on a network share
+    /// where junctions are invalid. Everything else gets symlinks, see [symlink].
+    pub(crate) fn create_link(destination: &Path, link: &Path, directories: DirectoryLinks) -> io::Result<()> {
+
+        let resolved = resolve_destination(destination, link);
+
+        match directories == DirectoryLinks::Junction && resolved.is_dir() && !is_remote(&resolved) && !is_remote(link) {
+            true => junction(&resolved, link),
+            false => symlink(destination, link)
+        }
+
+    }
+
+    /// Create link at `link` pointing to `destination`, see [create_link]
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn create_link_async(destination: &Path, link: &Path, directories: DirectoryLinks) -> io::Result<()> {
+
+        if directories == DirectoryLinks::Junction {
+            let (destination, link) = (destination.to_path_buf(), link.to_path_buf());
+            return tokio::task::spawn_blocking(move || create_link(&destination, &link, directories)).await.map_err(io::Error::other)?;
+        }
+
+        match tokio::fs::metadata(resolve_destination(destination, link)).await.is_ok_and(|metadata| metadata.is_dir()) {
+            true => tokio::fs::symlink_dir(destination, link).await,
+            false => tokio::fs::symlink_file(destination, link).await
+        }
+
+    }
+
+    /// Create link at `link` pointing to `destination` of the same kind as the link at `like`
+    pub(crate) fn relink(like: &Path, destination: &Path, link: &Path) -> io::Result<()> {
+        match is_junction(like) {
+            true => junction(&resolve_destination(destination, link), link),
+            false => symlink(destination, link)
+        }
+    }
+
+    /// Create junction at `link` leading to the `destination` directory.
+    ///
+    /// The destination is stored as an absolute NT path (`\??\C:\...`), together with its plain form shown to users.
+    pub(crate) fn junction(destination: &Path, link: &Path) -> io::Result<()> {
+
+        let absolute = path::absolute(destination)?;
+        let print: Vec<u16> = without_verbatim(&absolute).unwrap_or(absolute).as_os_str().encode_wide().collect();
+        let substitute: Vec<u16> = OsStr::new("\\??\\").encode_wide().chain(print.iter().copied()).collect();
+        let data = mount_point(&substitute, &print)?;
+
+        create_dir(link)?;
+
+        let directory = OpenOptions::new().write(true).custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT).open(link);
+        let mut returned = 0;
+
+        // SAFETY: the handle stays open until the directory is dropped, the data outlives the call and nothing is written back
+        let result = directory.and_then(|directory| match unsafe {
+            DeviceIoControl(directory.as_raw_handle(), FSCTL_SET_REPARSE_POINT, data.as_ptr().cast(), data.len() as u32, ptr::null_mut(), 0, &mut returned, ptr::null_mut())
+        } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(())
+        }).map_err(|error| match error.raw_os_error() {
+            // Filesystems without reparse points (e.g. FAT) don't know the request
+            Some(code) if code as u32 == ERROR_INVALID_FUNCTION => io::Error::new(ErrorKind::Unsupported, error),
+            _ => error
+        });
+
+        if result.is_err() {
+            let _ = remove_dir(link);
+        }
+
+        result
+
+    }
+
+    /// Reparse data of a junction (`REPARSE_DATA_BUFFER` of a mount point), both names are terminated by NUL
+    fn mount_point(substitute: &[u16], print: &[u16]) -> io::Result<Vec<u8>> {
+
+        let (substitute_len, print_len) = (2 * substitute.len(), 2 * print.len());
+        let data_len = 8 + substitute_len + 2 + print_len + 2;
+
+        if 8 + data_len > MAXIMUM_REPARSE_DATA_BUFFER_SIZE as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Junction destination is too long"));
+        }
+
+        let mut data = Vec::with_capacity(8 + data_len);
+        data.extend_from_slice(&IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes());
+
+        // Data length and reserved field, then offsets and lengths of the substitute and the print name
+        for value in [data_len, 0, 0, substitute_len, substitute_len + 2, print_len] {
+            data.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+
+        for unit in substitute.iter().chain(&[0]).chain(print).chain(&[0]) {
+            data.extend_from_slice(&unit.to_le_bytes());
+        }
+
+        Ok(data)
+
+    }
+
+    /// Check whether the path is a junction, by the tag of its reparse point
+    pub(crate) fn is_junction(path: &Path) -> bool {
+
+        let Ok(file) = OpenOptions::new().access_mode(0).custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT).open(path) else {
+            return false;
+        };
+
+        let mut data = vec![0u8; MAXIMUM_REPARSE_DATA_BUFFER_SIZE as usize];
+        let mut returned = 0;
+
+        // SAFETY: the handle stays open until the file is dropped, the output buffer is writable for its whole length
+        let read = unsafe {
+            DeviceIoControl(file.as_raw_handle(), FSCTL_GET_REPARSE_POINT, ptr::null(), 0, data.as_mut_ptr().cast(), data.len() as u32, &mut returned, ptr::null_mut())
+        };
+
+        read != 0 && data[..4] == IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes()
+
+    }
+
+    /// Check whether the path is on a network share, by its UNC form or the type of its drive
+    fn is_remote(path: &Path) -> bool {
+
+        let Ok(path) = path::absolute(path) else {
+            return true;
+        };
+
+        let Some(Component::Prefix(prefix)) = path.components().next() else {
+            return false;
+        };
+
+        match prefix.kind() {
+            Prefix::UNC(..) | Prefix::VerbatimUNC(..) => true,
+            Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                let root: Vec<u16> = format!("{}:\\", char::from(letter)).encode_utf16().chain([0]).collect();
+                // SAFETY: the root is terminated by NUL and lives until the call returns
+                unsafe { GetDriveTypeW(root.as_ptr()) == DRIVE_REMOTE }
+            },
+            _ => false
+        }
+
+    }
+
+    /// Form of the path stored in links, without the verbatim prefix canonical paths get (`\\?\C:\...` becomes `C:\...`,
+    /// `\\?\UNC\server\share` becomes `\\server\share`), unless the path is too long to do without it
+    pub(crate) fn plain_path(path: PathBuf) -> PathBuf {
+        match without_verbatim(&path) {
+            Some(plain) if plain.as_os_str().len() < MAX_PATH => plain,
+            _ => path
+        }
+    }
+
+    fn without_verbatim(path: &Path) -> Option<PathBuf> {
+
+        let mut components = path.components();
+
+        let Some(Component::Prefix(prefix)) = components.next() else {
+            return None;
+        };
+
+        let mut plain = match prefix.kind() {
+            Prefix::VerbatimDisk(letter) => PathBuf::from(format!("{}:", char::from(letter))),
+            Prefix::VerbatimUNC(server, share) => {
+                let mut root = OsString::from("\\\\");
+                root.push(server);
+                root.push("\\");
+                root.push(share);
+                PathBuf::from(root)
+            },
+            _ => return None
+        };
+
+        plain.extend(components);
+        Some(plain)
+
+    }
+
+    /// Check whether a relative path can lead from one path to the other, which needs both on the same drive or share
+    pub(crate) fn same_volume(a: &Path, b: &Path) -> bool {
+        let root = |path: &Path| plain_path(path.to_path_buf()).components().next().map(|component| component.as_os_str().to_ascii_lowercase());
+        root(a) == root(b)
+    }
+
+    /// Check whether Developer Mode is on, which lets users without the symlink privilege create symlinks
+    pub(crate) fn unprivileged_symlinks() -> bool {
+
+        let key: Vec<u16> = OsStr::new(DEVELOPER_MODE_KEY).encode_wide().chain([0]).collect();
+        let value: Vec<u16> = OsStr::new(DEVELOPER_MODE_VALUE).encode_wide().chain([0]).collect();
+        let (mut data, mut size) = (0u32, size_of::<u32>() as u32);
+
+        // SAFETY: both names are terminated by NUL, the data is a local value of the given size
+        let status = unsafe {
+            RegGetValueW(HKEY_LOCAL_MACHINE, key.as_ptr(), value.as_ptr(), RRF_RT_REG_DWORD, ptr::null_mut(), (&raw mut data).cast(), &mut size)
+        };
+
+        status == ERROR_SUCCESS && data != 0
+
+    }
+
+    /// Remove symlink, without touching its destination. Directory symlinks are removed as directories
+    pub(crate) fn remove_link(path: &Path) -> io::Result<()> {
+        match path.symlink_metadata()?.file_type().is_symlink_dir() {
+            true => remove_dir(path),
+            false => remove_file(path)
+        }
+    }
+
+    /// Path the `destination` of a link at `link` leads to, relative destinations start at the directory holding the link
+    fn resolve_destination(destination: &Path, link: &Path) -> PathBuf {
+        link.parent().unwrap_or(Path::new("")).join(destination)
+    }
+
+    /// Volume serial number and file index of the entry the path leads to
+    pub(crate) fn identity(path: &Path) -> io::Result<(u64, u64)> {
+
+        // Directories can be opened only with backup semantics, no access rights are needed to read the identity
+        let file = OpenOptions::new().access_mode(0).custom_flags(FILE_FLAG_BACKUP_SEMANTICS).open(path)?;
+        let mut information = BY_HANDLE_FILE_INFORMATION::default();
+
+        // SAFETY: the handle stays open until the file is dropped, the information is written into a local struct
+        match unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut information) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok((u64::from(information.dwVolumeSerialNumber), (u64::from(information.nFileIndexHigh) << 32) | u64::from(information.nFileIndexLow)))
+        }
+
+    }
+
+    /// Volume holding the entry the path leads to
+    pub(crate) fn device(path: &Path) -> io::Result<u64> {
+        identity(path).map(|(volume, _)| volume)
+    }
+
+    /// Permission bits emulated from the read-only attribute, `0o444` or `0o666`
+    pub(crate) fn mode(permissions: &Permissions) -> u32 {
+        match permissions.readonly() {
+            true => 0o444,
+            false => 0o666
+        }
+    }
+
+    /// Set the read-only attribute when none of the write bits are set, other bits have no equivalent
+    pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+
+        let mut permissions = path.metadata()?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+
+        set_permissions(path, permissions)
+
+    }
+
+    /// Modification time in seconds and nanoseconds since the Unix epoch
+    pub(crate) fn modified(metadata: &Metadata) -> (i64, i64) {
+        metadata.modified().ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or((0, 0), |since| (i64::try_from(since.as_secs()).unwrap_or(i64::MAX), i64::from(since.subsec_nanos())))
+    }
+
+    /// Name stored as bytes of [OsStr::as_encoded_bytes], only names valid in UTF-8 are accepted
+    pub(crate) fn os_str(bytes: &[u8]) -> Option<&OsStr> {
+        std::str::from_utf8(bytes).ok().map(OsStr::new)
+    }
+
+}
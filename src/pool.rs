@@ -0,0 +1,174 @@
+//! Minimal scoped worker pool used by the parallel mode
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use anyhow::Result;
+
+struct Queue<T> {
+    items: Vec<T>,
+    active: usize,
+    failed: Option<anyhow::Error>,
+    /// Payload of a panicking `work`, raised again once all workers stopped
+    panicked: Option<Box<dyn Any + Send>>
+}
+
+/// Process `items` on up to `threads` workers, `work` may queue further items while processing one.
+///
+/// With a single thread, items are processed in place as a stack. The first error (or panic) stops all workers.
+pub(crate) fn for_each_queued<T, F>(threads: usize, items: Vec<T>, work: F) -> Result<()>
+where
+    T: Send,
+    F: Fn(T, &mut Vec<T>) -> Result<()> + Sync
+{
+
+    if threads <= 1 {
+
+        let mut stack = items;
+
+        while let Some(item) = stack.pop() {
+            work(item, &mut stack)?;
+        }
+
+        return Ok(());
+
+    }
+
+    let queue = Mutex::new(Queue { items, active: 0, failed: None, panicked: None });
+    let ready = Condvar::new();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+
+                let item = {
+
+                    let mut state = queue.lock().unwrap();
+
+                    loop {
+
+                        if state.failed.is_some() || state.panicked.is_some() {
+                            return;
+                        }
+
+                        if let Some(item) = state.items.pop() {
+                            state.active += 1;
+                            break item;
+                        }
+
+                        // Nothing queued and nobody left to queue more work
+                        if state.active == 0 {
+                            return;
+                        }
+
+                        state = ready.wait(state).unwrap();
+
+                    }
+
+                };
+
+                let mut found = Vec::new();
+                // Unwinding worker would never release its item, leaving the others waiting forever
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(item, &mut found)));
+
+                let mut state = queue.lock().unwrap();
+                state.active -= 1;
+
+                match result {
+                    Ok(Ok(())) => state.items.extend(found),
+                    Ok(Err(error)) => {
+                        state.failed.get_or_insert(error);
+                    },
+                    Err(payload) => {
+                        state.panicked.get_or_insert(payload);
+                    }
+                }
+
+                ready.notify_all();
+
+            });
+        }
+    });
+
+    let state = queue.into_inner().unwrap();
+
+    if let Some(payload) = state.panicked {
+        panic::resume_unwind(payload);
+    }
+
+    match state.failed {
+        Some(error) => Err(error),
+        None => Ok(())
+    }
+
+}
+
+/// Pass `items` to `consume` in order, while up to `threads` background workers run `prefetch` for the items
+/// coming next, staying at most `window` items ahead.
+///
+/// Meant for reads warming caches (e.g. attributes of a network filesystem), so their results are thrown away
+/// and the order of `consume` calls is the same as without workers. The first error stops everything.
+pub(crate) fn consume_prefetched<T, P, C>(threads: usize, window: usize, items: &[T], prefetch: P, mut consume: C) -> Result<()>
+where
+    T: Sync,
+    P: Fn(&T) + Sync,
+    C: FnMut(&T) -> Result<()>
+{
+
+    if threads == 0 {
+        return items.iter().try_for_each(consume);
+    }
+
+    let next = AtomicUsize::new(0);
+    // Number of consumed items, `None` once the consumer stopped
+    let consumed = Mutex::new(Some(0));
+    let advanced = Condvar::new();
+
+    thread::scope(|scope| {
+
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+
+                let index = next.fetch_add(1, Ordering::Relaxed);
+
+                if index >= items.len() {
+                    return;
+                }
+
+                let mut state = consumed.lock().unwrap();
+
+                let ahead = loop {
+                    match *state {
+                        None => return,
+                        Some(position) if index >= position + window => state = advanced.wait(state).unwrap(),
+                        // Already consumed items aren't worth reading anymore
+                        Some(position) => break index >= position
+                    }
+                };
+
+                drop(state);
+
+                if ahead {
+                    prefetch(&items[index]);
+                }
+
+            });
+        }
+
+        let result = items.iter().enumerate().try_for_each(|(index, item)| {
+            let result = consume(item);
+            *consumed.lock().unwrap() = Some(index + 1);
+            advanced.notify_all();
+            result
+        });
+
+        *consumed.lock().unwrap() = None;
+        advanced.notify_all();
+
+        result
+
+    })
+
+}
@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use anyhow::{bail, Result};
+use crate::{Glob, MergeOptions, Overwrite, Strategy};
+use crate::home::dotfiles;
+
+/// Presets registered by [register_preset], taking precedence over the built-in ones
+static PRESETS: RwLock<BTreeMap<String, MergeOptions>> = RwLock::new(BTreeMap::new());
+
+/// Names of the presets always available, see [MergeOptions::preset]
+pub const BUILTIN_PRESETS: [&str; 3] = ["dotfiles", "rootfs-overlay", "webroot"];
+
+/// Register options under the `name`, replacing any preset of the same name (built-in ones included).
+///
+/// Presets compose by building on each other, e.g. `MergeOptions { strict: true, ..MergeOptions::preset("webroot")? }`.
+pub fn register_preset(name: impl Into<String>, options: MergeOptions) {
+    PRESETS.write().unwrap().insert(name.into(), options);
+}
+
+/// Names of all available presets, ordered by name
+pub fn preset_names() -> Vec<String> {
+    let mut names: Vec<String> = PRESETS.read().unwrap().keys().cloned().collect();
+    names.extend(BUILTIN_PRESETS.iter().map(|name| name.to_string()));
+    names.sort();
+    names.dedup();
+    names
+}
+
+impl MergeOptions {
+
+    /// Options registered under the `name` by [register_preset], or one of the [BUILTIN_PRESETS]:
+    ///
+    /// - `dotfiles`: options of [home](crate::home), replacing only symlinks and backing up what they replace
+    /// - `rootfs-overlay`: directories are recreated ([Strategy::Deep]) and files replaced with a backup,
+    ///   source symlinks (e.g. `bin -> usr/bin`) are merged as they are
+    /// - `webroot`: files are replaced, `.git` is left out and failed merges are rolled back
+    ///
+    /// Override single fields by struct update syntax, e.g. `MergeOptions { strict: true, ..MergeOptions::preset("webroot")? }`.
+    pub fn preset(name: &str) -> Result<MergeOptions> {
+
+        if let Some(options) = PRESETS.read().unwrap().get(name) {
+            return Ok(options.clone());
+        }
+
+        Ok(match name {
+            "dotfiles" => dotfiles()?,
+            "rootfs-overlay" => MergeOptions {
+                overwrite: Overwrite::Files,
+                strategy: Strategy::Deep,
+                backup: Some(".solderium-backup".to_string()),
+                preserve_symlinks: true,
+                ..Default::default()
+            },
+            "webroot" => MergeOptions {
+                overwrite: Overwrite::Files,
+                exclude: vec![Glob::new("/.git")?, Glob::new("/.git/**")?],
+                transactional: true,
+                ..Default::default()
+            },
+            _ => bail!("Unknown preset ({name:?}), available are: {}", preset_names().join(", "))
+        })
+
+    }
+
+}
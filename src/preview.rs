@@ -0,0 +1,143 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::{Change, ChangeKind, MergeOptions, verify};
+use crate::merge::target_root;
+use crate::normalize::clean_path;
+
+/// Pending changes of a merge arranged into the target directory tree.
+///
+/// Meant for TUI and GUI frontends rendering expandable trees, every node carries totals of the changes
+/// below it, so collapsed directories can still tell what's going to happen inside them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlanTree {
+    /// Node of the target directory itself
+    pub root: PlanNode
+}
+
+/// Single target path of a [PlanTree], either changed itself or containing changed paths.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlanNode {
+    /// File name of the target path, empty for the root
+    pub name: OsString,
+    pub path: PathBuf,
+    /// Change of this very path, `None` for directories only containing changes
+    pub change: Option<Change>,
+    /// Nodes of the changed paths inside, ordered by name
+    pub children: Vec<PlanNode>,
+    /// Changes of this node together with all nodes below it
+    pub totals: PlanTotals
+}
+
+/// Number of changes by their kind, see [PlanNode::totals].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PlanTotals {
+    pub symlinks: usize,
+    pub hardlinks: usize,
+    pub copies: usize,
+    pub directories: usize,
+    /// Changes removing an existing target path first, counted in their kind as well
+    pub replaced: usize
+}
+
+impl PlanTotals {
+
+    pub fn total(&self) -> usize {
+        self.symlinks + self.hardlinks + self.copies + self.directories
+    }
+
+    fn add(&mut self, other: &PlanTotals) {
+        self.symlinks += other.symlinks;
+        self.hardlinks += other.hardlinks;
+        self.copies += other.copies;
+        self.directories += other.directories;
+        self.replaced += other.replaced;
+    }
+
+}
+
+impl PlanTree {
+
+    /// Arrange changes below the `target` directory, changes outside of it are left out
+    pub fn new(target: &Path, changes: &[Change]) -> Self {
+
+        let mut root = PlanNode { path: target.to_path_buf(), ..Default::default() };
+
+        for change in changes {
+            if let Ok(relative) = change.target.strip_prefix(target) {
+                root.insert(relative, change);
+            }
+        }
+
+        root.finish();
+
+        Self { root }
+
+    }
+
+    /// Preview of changes merging `source` into `target` would make, nothing is changed
+    pub fn preview(source: &Path, target: &Path, options: &MergeOptions) -> Result<Self> {
+        let changes = verify(source, target, options)?;
+        let target = target_root(target, options)?;
+        Ok(Self::new(&target, &changes))
+    }
+
+    /// Node of given target path, if it is changed or contains changes
+    pub fn find(&self, path: &Path) -> Option<&PlanNode> {
+        clean_path(path).strip_prefix(&self.root.path).ok()?.iter().try_fold(&self.root, |node, name| {
+            node.children.iter().find(|child| child.name == name)
+        })
+    }
+
+}
+
+impl PlanNode {
+
+    fn insert(&mut self, relative: &Path, change: &Change) {
+
+        let mut components = relative.iter();
+
+        let name = match components.next() {
+            Some(name) => name,
+            None => {
+                self.change = Some(change.clone());
+                return;
+            }
+        };
+
+        let position = match self.children.iter().position(|child| child.name == name) {
+            Some(position) => position,
+            None => {
+                self.children.push(PlanNode { name: name.to_os_string(), path: self.path.join(name), ..Default::default() });
+                self.children.len() - 1
+            }
+        };
+
+        self.children[position].insert(components.as_path(), change);
+
+    }
+
+    /// Order children and sum up the totals
+    fn finish(&mut self) {
+
+        self.children.sort_by(|a, b| a.name.cmp(&b.name));
+        self.totals = PlanTotals::default();
+
+        if let Some(change) = &self.change {
+            match change.kind {
+                ChangeKind::Symlink => self.totals.symlinks += 1,
+                ChangeKind::Hardlink => self.totals.hardlinks += 1,
+                ChangeKind::Copy => self.totals.copies += 1,
+                ChangeKind::Directory => self.totals.directories += 1
+            }
+            self.totals.replaced += usize::from(change.replace);
+        }
+
+        for child in &mut self.children {
+            child.finish();
+            self.totals.add(&child.totals);
+        }
+
+    }
+
+}
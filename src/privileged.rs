@@ -0,0 +1,102 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, ErrorKind, Result};
+#[cfg(windows)]
+use std::os::windows::fs::FileTypeExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Performs target changes the current process has no permission for.
+///
+/// When creating a symlink or removing a path fails with [ErrorKind::PermissionDenied], the merge retries
+/// the operation through the configured executor, so targets mixing user and system owned paths
+/// (e.g. home directory and `/etc`) can be handled in a single run.
+pub trait PrivilegedExecutor: Send + Sync {
+    /// Create symlink at `target` pointing to `source`
+    fn create_link(&self, source: &Path, target: &Path) -> Result<()>;
+    /// Remove file, symlink or whole directory at `path`
+    fn remove(&self, path: &Path) -> Result<()>;
+}
+
+/// Executor running `ln` and `rm` through a privileged command prefix, e.g. `sudo -n` or `pkexec`.
+///
+/// On Windows `mklink`, `rmdir` and `del` of `cmd` are run instead, e.g. prefixed by `gsudo`.
+#[derive(Clone, Debug)]
+pub struct CommandExecutor {
+    prefix: Vec<OsString>
+}
+
+impl CommandExecutor {
+
+    /// Create executor with given command prefix, its first item is the program to run
+    pub fn new<I, S>(prefix: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>
+    {
+        Self { prefix: prefix.into_iter().map(Into::into).collect() }
+    }
+
+    /// Executor using non-interactive `sudo`
+    pub fn sudo() -> Self {
+        Self::new(["sudo", "-n"])
+    }
+
+    fn run(&self, args: &[&OsStr]) -> Result<()> {
+
+        let (program, prefix) = self.prefix.split_first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Privileged command prefix is empty"))?;
+
+        let output = Command::new(program).args(prefix).args(args).output()?;
+
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(Error::other(format!(
+                "Privileged command ({:?}) failed with {}: {}",
+                program, output.status, String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+
+    }
+
+}
+
+#[cfg(unix)]
+impl PrivilegedExecutor for CommandExecutor {
+
+    fn create_link(&self, source: &Path, target: &Path) -> Result<()> {
+        self.run(&["ln".as_ref(), "-s".as_ref(), "--".as_ref(), source.as_os_str(), target.as_os_str()])
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.run(&["rm".as_ref(), "-rf".as_ref(), "--".as_ref(), path.as_os_str()])
+    }
+
+}
+
+#[cfg(windows)]
+impl PrivilegedExecutor for CommandExecutor {
+
+    fn create_link(&self, source: &Path, target: &Path) -> Result<()> {
+        // Symlink kind follows what the source is, like links created by the merge itself
+        match target.parent().unwrap_or(Path::new("")).join(source).is_dir() {
+            true => self.run(&["cmd".as_ref(), "/C".as_ref(), "mklink".as_ref(), "/D".as_ref(), target.as_os_str(), source.as_os_str()]),
+            false => self.run(&["cmd".as_ref(), "/C".as_ref(), "mklink".as_ref(), target.as_os_str(), source.as_os_str()])
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+
+        let file_type = path.symlink_metadata()?.file_type();
+
+        // Directory symlinks and junctions are removed by `rmdir` without `/S`, `del` would empty their destination
+        if file_type.is_symlink_dir() {
+            self.run(&["cmd".as_ref(), "/C".as_ref(), "rmdir".as_ref(), path.as_os_str()])
+        } else if file_type.is_dir() {
+            self.run(&["cmd".as_ref(), "/C".as_ref(), "rmdir".as_ref(), "/S".as_ref(), "/Q".as_ref(), path.as_os_str()])
+        } else {
+            self.run(&["cmd".as_ref(), "/C".as_ref(), "del".as_ref(), "/F".as_ref(), "/Q".as_ref(), path.as_os_str()])
+        }
+
+    }
+
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::{MergeOptions, Overwrite, Strategy};
+use crate::merge::Walk;
+
+/// Maximum number of source entries examined by [recommend_strategy]
+const SAMPLE_LIMIT: usize = 10_000;
+
+/// Suggested way of merging, see [recommend_strategy] function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recommendation {
+    pub strategy: Strategy,
+    pub overwrite: Overwrite,
+    /// What the recommendation is based on
+    pub sample: Sample,
+    /// Human readable explanation of the recommendation
+    pub rationale: Vec<String>
+}
+
+/// Statistics gathered by sampling the source and target trees.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sample {
+    /// Number of examined source entries
+    pub entries: usize,
+    /// Sampling stopped before examining the whole source
+    pub truncated: bool,
+    /// Deepest examined source entry, top-level entries have depth 1
+    pub max_depth: usize,
+    /// Size of examined source files
+    pub bytes: u64,
+    /// Source entries already existing in the target
+    pub conflicts: usize,
+    /// Conflicting target entries which are symlinks (e.g. from a previous merge)
+    pub conflicting_symlinks: usize,
+    /// Target entries not present in the source, found in directories existing on both sides
+    pub local_entries: usize
+}
+
+/// Sample the `source` and `target` trees and suggest a strategy and an overwrite policy.
+///
+/// Meant for users who don't know which options to pick. At most 10 000 source entries are examined,
+/// breadth first, so the top of the tree is always covered. Nothing is modified.
+pub fn recommend_strategy(source: &Path, target: &Path) -> Result<Recommendation> {
+
+    let options = MergeOptions::default();
+    let walk = Walk::new(source, target, &options)?;
+    let mut sample = Sample::default();
+    let mut queue = VecDeque::from([(PathBuf::new(), 1)]);
+
+    while let Some((relative, depth)) = queue.pop_front() {
+
+        let source_directory = walk.source.join(&relative);
+        let target_directory = walk.target.join(&relative);
+        let mut names = HashSet::new();
+
+        for entry in read_dir(&source_directory).with_context(|| format!("Directory listing ({source_directory:?}) failed"))? {
+
+            if sample.entries == SAMPLE_LIMIT {
+                sample.truncated = true;
+                break;
+            }
+
+            let entry = entry.with_context(|| "Reading source directory entry has failed")?;
+            let source_path = entry.path();
+            let target_path = target_directory.join(entry.file_name());
+
+            sample.entries += 1;
+            sample.max_depth = sample.max_depth.max(depth);
+            names.insert(entry.file_name());
+
+            if source_path.is_file() {
+                sample.bytes += source_path.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+
+            if target_path.exists() || target_path.is_symlink() {
+
+                sample.conflicts += 1;
+
+                if target_path.is_symlink() {
+                    sample.conflicting_symlinks += 1;
+                } else if source_path.is_dir() && target_path.is_dir() {
+                    queue.push_back((relative.join(entry.file_name()), depth + 1));
+                }
+
+            }
+
+        }
+
+        // Partially listed directory would make unseen source entries look local
+        if sample.truncated {
+            break;
+        }
+
+        if let Ok(listing) = read_dir(&target_directory) {
+            sample.local_entries += listing.flatten().filter(|entry| !names.contains(&entry.file_name())).count();
+        }
+
+    }
+
+    Ok(recommend(sample))
+
+}
+
+fn recommend(sample: Sample) -> Recommendation {
+
+    let mut rationale = Vec::new();
+
+    let strategy = if sample.conflicts == 0 {
+        rationale.push("Nothing from the source exists in the target yet, whole directories can be linked".to_string());
+        Strategy::Fold
+    } else if sample.local_entries > 0 {
+        rationale.push(format!("Target contains {} local entries next to the source ones, directories should stay real", sample.local_entries));
+        Strategy::Deep
+    } else if sample.max_depth <= 1 {
+        rationale.push("Conflicts exist only at the top level".to_string());
+        Strategy::Shallow
+    } else {
+        rationale.push(format!("{} source entries already exist in the target, without local additions", sample.conflicts));
+        Strategy::Fold
+    };
+
+    let overwrite = if sample.conflicts == 0 {
+        Overwrite::None
+    } else if sample.conflicting_symlinks == sample.conflicts {
+        rationale.push("All conflicting target entries are symlinks, replacing them loses no data".to_string());
+        Overwrite::Files
+    } else {
+        rationale.push(format!("{} conflicting target entries are real files or directories, keep them", sample.conflicts - sample.conflicting_symlinks));
+        Overwrite::None
+    };
+
+    if sample.truncated {
+        rationale.push(format!("Only the first {} source entries were examined", sample.entries));
+    }
+
+    Recommendation { strategy, overwrite, sample, rationale }
+
+}
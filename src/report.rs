@@ -0,0 +1,292 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{IsTerminal, stdout};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use crate::{FallbackStrategy, MergeOptions, Reason, Trigger};
+
+/// Outcome of a single merge run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// Changes made by the merge, in the order of target paths
+    pub changes: Vec<Change>,
+    /// Conditions worth attention, which didn't stop the merge
+    pub warnings: Vec<Warning>,
+    /// Changes which were only reported, because they match [MergeOptions::simulate](crate::MergeOptions::simulate)
+    pub simulated: Vec<Change>,
+    /// Number of changes made but not listed in [changes](MergeReport::changes), see [MergeOptions::plan_memory](crate::MergeOptions::plan_memory)
+    pub unlisted: usize,
+    /// Source entries left out by the merge, when [recorded](MergeOptions::record_skipped), in the order of target paths
+    pub skipped: Vec<Skipped>,
+    /// Number of target paths already leading to their source entry, left untouched whatever the overwrite policy,
+    /// so re-runs don't churn modification times or wake up watchers
+    pub already_linked: usize,
+    /// Staging directories a [transactional](MergeOptions::transactional) merge moved replaced paths into (all removed by now),
+    /// one for each filesystem holding such paths, since renames can't cross filesystems. Paths which couldn't be moved
+    /// aside are reported by [Warning::NotStaged].
+    pub staging: Vec<PathBuf>,
+    /// Time and work the merge took, to follow performance across runs
+    pub usage: Usage,
+    /// Triggers fired by the changes, in the order they ran, see [MergeOptions::triggers](crate::MergeOptions::triggers)
+    pub triggered: Vec<Trigger>,
+    /// Directory symlinks unfolded into real directories, see [MergeOptions::refold](crate::MergeOptions::refold)
+    pub unfolded: Vec<PathBuf>,
+    /// Target directories folded into a symlink to their source directory, see [MergeOptions::refold](crate::MergeOptions::refold)
+    pub folded: Vec<PathBuf>,
+    /// Source files renamed since the previous merge, as the old and the new target path, when merged with a manifest
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// Options the merge ran with
+    options: Option<Arc<MergeOptions>>
+}
+
+impl MergeReport {
+
+    pub(crate) fn with_options(options: &MergeOptions) -> Self {
+        Self { options: Some(Arc::new(options.clone())), ..Default::default() }
+    }
+
+    /// Fully resolved options the merge ran with (including defaults and adjustments made by profile
+    /// constructors like [home](crate::home)), to answer what policy created the changes
+    pub fn options(&self) -> Option<&MergeOptions> {
+        self.options.as_deref()
+    }
+
+    /// Target paths replaced by the merge
+    pub fn overwritten(&self) -> impl Iterator<Item = &Path> {
+        self.changes.iter().filter(|change| change.replace).map(|change| change.target.as_path())
+    }
+
+    /// Target paths whose change failed together with the error, see [ErrorPolicy::Skip](crate::ErrorPolicy::Skip)
+    pub fn failed(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.warnings.iter().filter_map(|warning| match warning {
+            Warning::EntryFailed { path, error } => Some((path.as_path(), error.as_str())),
+            _ => None
+        })
+    }
+
+    /// Numbers of changes, skipped entries and failures, e.g. for a summary line
+    pub fn counts(&self) -> ReportCounts {
+
+        let created = |kind| self.changes.iter().filter(|change| change.kind == kind).count();
+
+        ReportCounts {
+            symlinks: created(ChangeKind::Symlink),
+            hardlinks: created(ChangeKind::Hardlink),
+            copies: created(ChangeKind::Copy),
+            directories: created(ChangeKind::Directory),
+            overwritten: self.overwritten().count(),
+            skipped: self.skipped.len(),
+            already_linked: self.already_linked,
+            failed: self.failed().count()
+        }
+
+    }
+
+    /// Turn the first warning matching `escalate` into an error, e.g. to treat case collisions as failures
+    pub fn escalate(self, escalate: impl Fn(&Warning) -> bool) -> Result<Self> {
+        match self.warnings.iter().find(|warning| escalate(warning)) {
+            Some(warning) => Err(warning.clone().into()),
+            None => Ok(self)
+        }
+    }
+
+    /// Human readable rendering, with created paths in green, removed ones in red and warnings
+    /// (together with changes only simulated, so kept as they were) in yellow
+    pub fn display(&self, color: ColorChoice) -> ReportDisplay<'_> {
+        ReportDisplay { report: self, color: color.enabled() }
+    }
+
+}
+
+/// Resources used by a single merge run, see [MergeReport::usage].
+///
+/// Operations are counted when attempted, failed ones included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Wall time from the start of the planning until the report was made
+    pub elapsed: Duration,
+    /// Source and target directory listings
+    pub listings: usize,
+    /// Symbolic and hard links created
+    pub links: usize,
+    /// Files copied
+    pub copies: usize,
+    /// Directories created
+    pub directories: usize,
+    /// Replaced target paths removed or moved aside
+    pub removals: usize,
+    /// Bytes written by copies
+    pub bytes_copied: u64,
+    /// Largest amount of memory held by planned changes at once, in bytes (estimated from their paths)
+    pub plan_memory: usize
+}
+
+/// Source entry left out by the merge, see [MergeReport::skipped].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Skipped {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// Facts the merge considered, the last ones decided, see [Reason]
+    pub reasons: Vec<Reason>
+}
+
+/// Summary of a [MergeReport], see [MergeReport::counts].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReportCounts {
+    pub symlinks: usize,
+    pub hardlinks: usize,
+    pub copies: usize,
+    pub directories: usize,
+    /// Changes replacing an existing target path, counted in their kind as well
+    pub overwritten: usize,
+    pub skipped: usize,
+    /// Skipped entries already in place, counted even when skipped entries aren't recorded
+    pub already_linked: usize,
+    pub failed: usize
+}
+
+/// Whether [MergeReport::display] uses colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colors when the standard output is a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false
+        }
+    }
+}
+
+/// Rendering of a [MergeReport], see [MergeReport::display].
+pub struct ReportDisplay<'a> {
+    report: &'a MergeReport,
+    color: bool
+}
+
+impl ReportDisplay<'_> {
+
+    fn line(&self, f: &mut fmt::Formatter<'_>, color: &str, line: fmt::Arguments<'_>) -> fmt::Result {
+        match self.color {
+            true => writeln!(f, "\x1b[{color}m{line}\x1b[0m"),
+            false => writeln!(f, "{line}")
+        }
+    }
+
+}
+
+const GREEN: &str = "32";
+const RED: &str = "31";
+const YELLOW: &str = "33";
+
+impl fmt::Display for ReportDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        let report = self.report;
+        let removed = report.changes.iter().filter(|change| change.replace).count();
+
+        for change in &report.changes {
+
+            if change.replace {
+                self.line(f, RED, format_args!("- {}", change.target.display()))?;
+            }
+
+            match change.kind {
+                ChangeKind::Symlink => self.line(f, GREEN, format_args!("+ {} -> {}", change.target.display(), change.source.display()))?,
+                ChangeKind::Hardlink => self.line(f, GREEN, format_args!("+ {} => {}", change.target.display(), change.source.display()))?,
+                ChangeKind::Copy => self.line(f, GREEN, format_args!("+ {} (copy of {})", change.target.display(), change.source.display()))?,
+                ChangeKind::Directory => self.line(f, GREEN, format_args!("+ {}/", change.target.display()))?
+            }
+
+        }
+
+        for change in &report.simulated {
+            self.line(f, YELLOW, format_args!("= {} (simulated)", change.target.display()))?;
+        }
+
+        for warning in &report.warnings {
+            self.line(f, YELLOW, format_args!("! {warning}"))?;
+        }
+
+        writeln!(f, "{} created, {removed} removed, {} simulated, {} warnings", report.changes.len(), report.simulated.len(), report.warnings.len())
+
+    }
+}
+
+/// Single change of the target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub kind: ChangeKind,
+    /// Existing target path is removed first
+    pub replace: bool
+}
+
+/// What is created at the target path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Symlink,
+    Hardlink,
+    Copy,
+    Directory
+}
+
+/// Condition worth attention, which didn't stop the merge.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Warning {
+    /// Merge pushed the target directory over the configured entry limit, see [EntryLimit](crate::EntryLimit)
+    TooManyEntries { directory: PathBuf, entries: usize, limit: usize },
+    /// Source FIFO, socket or device wasn't copied, as reading it may block or never end
+    SkippedSpecialFile { path: PathBuf },
+    /// Source directory contains names differing only in case, they collide on case-insensitive targets
+    CaseCollision { directory: PathBuf, names: Vec<OsString> },
+    /// Keep marker protected given number of target paths from being merged
+    KeepMarkerShadowing { marker: PathBuf, skipped: usize },
+    /// Filesystem of given target directory doesn't support symlinks, the fallback was used for the rest of it
+    SymlinksUnsupported { directory: PathBuf, fallback: FallbackStrategy, error: String },
+    /// Change of the target path failed and was skipped, see [ErrorPolicy::Skip](crate::ErrorPolicy::Skip)
+    EntryFailed { path: PathBuf, error: String },
+    /// Target directory managed by another deployment was left untouched, see [NestedManagement](crate::NestedManagement)
+    NestedDeployment { directory: PathBuf, marker: PathBuf },
+    /// Symlink chain at the target path is longer than the limit (or a cycle), see [MergeOptions::max_link_depth](crate::MergeOptions::max_link_depth)
+    LinkChainTooLong { path: PathBuf, limit: usize },
+    /// Command of the trigger of given target subpath failed, see [Trigger]
+    TriggerFailed { path: PathBuf, error: String },
+    /// Symlink inside a source directory copied by the [fallback](crate::FallbackStrategy::Copy) wasn't copied,
+    /// as the target filesystem doesn't support symlinks
+    SkippedSymlink { path: PathBuf },
+    /// Replaced target path (e.g. a mount point) couldn't be moved aside on its filesystem, so the
+    /// [transactional](crate::MergeOptions::transactional) merge replaced it without a way to roll it back
+    NotStaged { path: PathBuf }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::TooManyEntries { directory, entries, limit } => write!(f, "Directory ({directory:?}) contains {entries} entries, over the limit of {limit}"),
+            Warning::SkippedSpecialFile { path } => write!(f, "Special file ({path:?}) was not copied"),
+            Warning::CaseCollision { directory, names } => write!(f, "Names {names:?} differ only in case and collide on case-insensitive filesystems ({directory:?})"),
+            Warning::KeepMarkerShadowing { marker, skipped } => write!(f, "Keep marker ({marker:?}) prevented {skipped} paths from being merged"),
+            Warning::SymlinksUnsupported { directory, fallback, error } => write!(f, "Filesystem of ({directory:?}) doesn't support symlinks ({error}), using {fallback:?} fallback"),
+            Warning::EntryFailed { path, error } => write!(f, "Change of ({path:?}) failed and was skipped: {error}"),
+            Warning::NestedDeployment { directory, marker } => write!(f, "Directory ({directory:?}) is managed by another deployment ({marker:?}) and was skipped"),
+            Warning::LinkChainTooLong { path, limit } => write!(f, "Symlink chain at ({path:?}) is longer than {limit} links"),
+            Warning::TriggerFailed { path, error } => write!(f, "Trigger of ({path:?}) failed: {error}"),
+            Warning::SkippedSymlink { path } => write!(f, "Symlink ({path:?}) was not copied, the target filesystem doesn't support symlinks"),
+            Warning::NotStaged { path } => write!(f, "Path ({path:?}) couldn't be moved aside on its filesystem, it was replaced without a way to roll it back")
+        }
+    }
+}
+
+impl std::error::Error for Warning {}
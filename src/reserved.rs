@@ -0,0 +1,29 @@
+//! Names of the files solderium itself keeps in target directories
+//!
+//! Entries named like this are never linked over, removed or reported as drift by merges, verification,
+//! unmerging or pruning, whichever tree they are found in.
+
+use std::ffi::OsStr;
+use crate::maintenance::MAINTENANCE_MARKER;
+use crate::merge::MANAGED_MARKER;
+use crate::temp::TEMP_PREFIX;
+
+/// Name of the manifest file in the target directory, written by the `manifest` feature
+pub const MANIFEST_NAME: &str = ".solderium.manifest";
+/// Name of the directory in the target holding manifests of stowed packages, written by the `manifest` feature
+pub const PACKAGES_DIR: &str = ".solderium-packages";
+/// Exact names of the reserved entries
+pub const RESERVED_NAMES: [&str; 4] = [MANIFEST_NAME, PACKAGES_DIR, MAINTENANCE_MARKER, MANAGED_MARKER];
+/// Prefixes of the reserved entries, temporary entries cover journals, spilled plans and staged links
+pub const RESERVED_PREFIXES: [&str; 1] = [TEMP_PREFIX];
+
+/// Check whether the entry name belongs to solderium's own metadata
+pub fn is_reserved(name: &OsStr) -> bool {
+
+    let Some(name) = name.to_str() else {
+        return false;
+    };
+
+    RESERVED_NAMES.contains(&name) || RESERVED_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+
+}
@@ -0,0 +1,118 @@
+use std::ffi::OsString;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use crate::{merge, MergeOptions, MergeReport};
+
+/// Makes the source directory available locally, e.g. by fetching it from a remote host into a cache.
+///
+/// Used by [merge_from], which fetches the source right before merging it.
+pub trait SourceProvider: Send + Sync {
+    /// Make the source available and return path of the local directory holding it
+    fn fetch(&self) -> Result<PathBuf>;
+    /// Drop the locally cached source, so the next [SourceProvider::fetch] gets a fresh copy
+    fn invalidate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Source which already is a local directory, nothing is fetched.
+impl SourceProvider for PathBuf {
+    fn fetch(&self) -> Result<PathBuf> {
+        Ok(self.clone())
+    }
+}
+
+/// Source fetched into a cache directory by an external command, e.g. `rsync`, `curl` or `aws s3 sync`.
+///
+/// The cache directory is created first and passed to the command as its last argument.
+pub struct CommandSource {
+    cache: PathBuf,
+    command: Vec<OsString>,
+    max_age: Option<Duration>,
+    fetched: Mutex<Option<Instant>>
+}
+
+impl CommandSource {
+
+    /// Create source fetched into `cache` by given command, its first item is the program to run
+    pub fn new<I, S>(cache: impl Into<PathBuf>, command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>
+    {
+        Self { cache: cache.into(), command: command.into_iter().map(Into::into).collect(), max_age: None, fetched: Mutex::new(None) }
+    }
+
+    /// Fetch again once the cached copy is older than `max_age`, by default it's fetched only once
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn is_stale(&self, fetched: Option<Instant>) -> bool {
+        match (fetched, self.max_age) {
+            (None, _) => true,
+            (Some(fetched), Some(max_age)) => fetched.elapsed() >= max_age,
+            (Some(_), None) => !self.cache.is_dir()
+        }
+    }
+
+}
+
+impl SourceProvider for CommandSource {
+
+    fn fetch(&self) -> Result<PathBuf> {
+
+        let mut fetched = self.fetched.lock().unwrap();
+
+        if !self.is_stale(*fetched) {
+            return Ok(self.cache.clone());
+        }
+
+        let (program, args) = match self.command.split_first() {
+            Some(command) => command,
+            None => bail!("Source fetch command is empty")
+        };
+
+        create_dir_all(&self.cache).with_context(|| format!("Couldn't create source cache directory ({:?})", self.cache))?;
+
+        let output = Command::new(program).args(args).arg(&self.cache).output()
+            .with_context(|| format!("Couldn't run source fetch command ({program:?})"))?;
+
+        if !output.status.success() {
+            bail!("Source fetch command ({program:?}) failed with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        *fetched = Some(Instant::now());
+        Ok(self.cache.clone())
+
+    }
+
+    fn invalidate(&self) -> Result<()> {
+
+        let mut fetched = self.fetched.lock().unwrap();
+
+        match remove_dir_all(&self.cache) {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                Err(error).with_context(|| format!("Couldn't remove source cache directory ({:?})", self.cache))
+            },
+            _ => {
+                *fetched = None;
+                Ok(())
+            }
+        }
+
+    }
+
+}
+
+/// Fetch the source from given provider and merge it into the `target` directory, see [merge].
+pub fn merge_from(provider: &dyn SourceProvider, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+    let source = provider.fetch()?;
+    merge(&source, target, options)
+}
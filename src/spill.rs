@@ -0,0 +1,413 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::vec;
+use anyhow::{Context, Result};
+use crate::merge::{Op, OpKind};
+use crate::platform::os_str;
+use crate::transaction::Record;
+
+/// Planned operations kept in memory up to the limit, spilled to a temporary file in sorted runs beyond it.
+///
+/// Runs are merged back while reading, so the operations come out sorted by target path in batches
+/// no larger than the limit, whatever the size of the whole plan.
+pub(crate) struct SpilledPlan {
+    path: PathBuf,
+    file: Option<File>,
+    /// Offset and number of operations of every run written so far
+    runs: Vec<(u64, usize)>,
+    buffer: Vec<Op>,
+    limit: usize
+}
+
+impl SpilledPlan {
+
+    /// Plan spilling to `path` (created only once needed) whenever more than `limit` operations are held
+    pub fn new(path: PathBuf, limit: usize) -> Self {
+        Self { path, file: None, runs: Vec::new(), buffer: Vec::new(), limit: limit.max(1) }
+    }
+
+    pub fn extend(&mut self, ops: Vec<Op>) -> Result<()> {
+
+        for op in ops {
+
+            self.buffer.push(op);
+
+            if self.buffer.len() >= self.limit {
+                self.spill()?;
+            }
+
+        }
+
+        Ok(())
+
+    }
+
+    /// Number of operations planned so far
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|(_, len)| len).sum::<usize>() + self.buffer.len()
+    }
+
+    /// Write the buffered operations as a single sorted run
+    fn spill(&mut self) -> Result<()> {
+
+        let path = &self.path;
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(OpenOptions::new().read(true).write(true).create_new(true).open(path)
+                .with_context(|| format!("Couldn't create plan spill file ({path:?})"))?)
+        };
+
+        self.buffer.sort_by(|a, b| a.target.cmp(&b.target));
+
+        let offset = file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&*file);
+
+        for op in &self.buffer {
+            write_op(&mut writer, op)?;
+        }
+
+        writer.flush().with_context(|| format!("Couldn't write plan spill file ({path:?})"))?;
+        self.runs.push((offset, self.buffer.len()));
+        self.buffer.clear();
+
+        Ok(())
+
+    }
+
+    /// Sorted operations in batches of at most the limit
+    pub fn batches(mut self) -> Result<Batches> {
+
+        self.buffer.sort_by(|a, b| a.target.cmp(&b.target));
+
+        let mut runs = Vec::new();
+
+        // Every run reads through its own descriptor, cloned ones would share the position
+        for &(offset, len) in &self.runs {
+            let path = &self.path;
+            let mut reader = BufReader::new(File::open(path).with_context(|| format!("Couldn't open plan spill file ({path:?})"))?);
+            reader.seek(SeekFrom::Start(offset))?;
+            runs.push(Run { reader, remaining: len });
+        }
+
+        let buffer = std::mem::take(&mut self.buffer).into_iter().peekable();
+        let mut batches = Batches { heap: BinaryHeap::new(), runs, buffer, limit: self.limit, _plan: self };
+
+        for index in 0..batches.runs.len() {
+            batches.advance(index)?;
+        }
+
+        Ok(batches)
+
+    }
+
+}
+
+impl Drop for SpilledPlan {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+/// Single sorted run of the spill file
+struct Run {
+    reader: BufReader<File>,
+    remaining: usize
+}
+
+/// Next operation of every run, ordered by target path
+struct Head {
+    op: Op,
+    run: usize
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.op.target == other.op.target && self.run == other.run
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.op.target.cmp(&other.op.target).then(self.run.cmp(&other.run))
+    }
+}
+
+/// Iterator over the sorted batches of a [SpilledPlan], see [SpilledPlan::batches]
+pub(crate) struct Batches {
+    heap: BinaryHeap<Reverse<Head>>,
+    runs: Vec<Run>,
+    /// Operations never spilled, already sorted
+    buffer: Peekable<vec::IntoIter<Op>>,
+    limit: usize,
+    /// Spill file is removed once the batches are dropped
+    _plan: SpilledPlan
+}
+
+impl Batches {
+
+    /// Read the next operation of the run into the heap
+    fn advance(&mut self, run: usize) -> Result<()> {
+
+        let current = &mut self.runs[run];
+
+        if current.remaining > 0 {
+            current.remaining -= 1;
+            let op = read_op(&mut current.reader).with_context(|| "Couldn't read plan spill file")?;
+            self.heap.push(Reverse(Head { op, run }));
+        }
+
+        Ok(())
+
+    }
+
+    /// Smallest operation of the runs and the unspilled buffer
+    fn next_op(&mut self) -> Result<Option<Op>> {
+
+        let spilled_first = match (self.heap.peek(), self.buffer.peek()) {
+            (Some(Reverse(head)), Some(op)) => head.op.target <= op.target,
+            (Some(_), None) => true,
+            (None, _) => false
+        };
+
+        if !spilled_first {
+            return Ok(self.buffer.next());
+        }
+
+        let Reverse(Head { op, run }) = self.heap.pop().unwrap();
+        self.advance(run)?;
+
+        Ok(Some(op))
+
+    }
+
+}
+
+impl Iterator for Batches {
+    type Item = Result<Vec<Op>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+
+        let mut batch = Vec::new();
+
+        while batch.len() < self.limit {
+            match self.next_op() {
+                Ok(Some(op)) => batch.push(op),
+                Ok(None) => break,
+                Err(error) => return Some(Err(error))
+            }
+        }
+
+        Some(Ok(batch)).filter(|batch| batch.as_ref().is_ok_and(|batch| !batch.is_empty()))
+
+    }
+}
+
+/// Journal records kept in memory up to the limit, spilled to a temporary file in chunks beyond it.
+///
+/// Records are read back chunk by chunk, so no more than the limit of them is held in memory at once.
+pub(crate) struct SpilledJournal {
+    path: PathBuf,
+    file: Option<File>,
+    /// Offset and number of records of every chunk written so far
+    chunks: Vec<(u64, usize)>,
+    buffer: Vec<Record>,
+    limit: usize
+}
+
+impl SpilledJournal {
+
+    /// Journal spilling to `path` (created only once needed) whenever more than `limit` records are held
+    pub fn new(path: PathBuf, limit: usize) -> Self {
+        Self { path, file: None, chunks: Vec::new(), buffer: Vec::new(), limit: limit.max(1) }
+    }
+
+    /// Number of records made so far
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|(_, len)| len).sum::<usize>() + self.buffer.len()
+    }
+
+    /// Add the record, a full buffer is spilled before, so the record can be taken back by [SpilledJournal::pop]
+    pub fn push(&mut self, record: Record) -> Result<()> {
+
+        if self.buffer.len() >= self.limit {
+            self.spill()?;
+        }
+
+        self.buffer.push(record);
+
+        Ok(())
+
+    }
+
+    /// Take back the record pushed last
+    pub fn pop(&mut self) -> Option<Record> {
+        self.buffer.pop()
+    }
+
+    fn spill(&mut self) -> Result<()> {
+
+        let path = &self.path;
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(OpenOptions::new().read(true).write(true).create_new(true).open(path)
+                .with_context(|| format!("Couldn't create journal spill file ({path:?})"))?)
+        };
+
+        let offset = file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&*file);
+
+        for record in &self.buffer {
+            write_record(&mut writer, record)?;
+        }
+
+        writer.flush().with_context(|| format!("Couldn't write journal spill file ({path:?})"))?;
+        self.chunks.push((offset, self.buffer.len()));
+        self.buffer.clear();
+
+        Ok(())
+
+    }
+
+    /// Records of the chunk, in the order they were made
+    fn chunk(&self, offset: u64, len: usize) -> Result<Vec<Record>> {
+
+        let path = &self.path;
+        let mut reader = BufReader::new(File::open(path).with_context(|| format!("Couldn't open journal spill file ({path:?})"))?);
+        reader.seek(SeekFrom::Start(offset))?;
+
+        (0..len).map(|_| read_record(&mut reader).with_context(|| format!("Couldn't read journal spill file ({path:?})"))).collect()
+
+    }
+
+    /// Pass all records to `apply` in the order they were made (newest first when `reverse`), then forget them
+    pub fn drain(&mut self, reverse: bool, mut apply: impl FnMut(Record) -> Result<()>) -> Result<()> {
+
+        let buffer = std::mem::take(&mut self.buffer);
+        let chunks = std::mem::take(&mut self.chunks);
+
+        if reverse {
+            buffer.into_iter().rev().try_for_each(&mut apply)?;
+            for &(offset, len) in chunks.iter().rev() {
+                self.chunk(offset, len)?.into_iter().rev().try_for_each(&mut apply)?;
+            }
+        } else {
+            for &(offset, len) in &chunks {
+                self.chunk(offset, len)?.into_iter().try_for_each(&mut apply)?;
+            }
+            buffer.into_iter().try_for_each(&mut apply)?;
+        }
+
+        if self.file.take().is_some() {
+            remove_file(&self.path).with_context(|| format!("Couldn't remove journal spill file ({:?})", self.path))?;
+        }
+
+        Ok(())
+
+    }
+
+}
+
+impl Drop for SpilledJournal {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+fn write_path(writer: &mut impl Write, path: &Path) -> io::Result<()> {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    writer.write_all(&u32::try_from(bytes.len()).map_err(io::Error::other)?.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_path(reader: &mut impl Read) -> io::Result<PathBuf> {
+
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+
+    os_str(&bytes).map(PathBuf::from).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Spilled path isn't valid on this platform"))
+
+}
+
+/// Length prefixed source and target path, followed by replace flag, kind and optional mode
+fn write_op(writer: &mut impl Write, op: &Op) -> io::Result<()> {
+
+    let kind = match op.kind {
+        OpKind::Symlink => 0,
+        OpKind::Copy => 1,
+        OpKind::Directory => 2,
+        OpKind::Hardlink => 3
+    };
+
+    write_path(writer, &op.source)?;
+    write_path(writer, &op.target)?;
+    writer.write_all(&[u8::from(op.replace), kind, u8::from(op.mode.is_some())])?;
+    writer.write_all(&op.mode.unwrap_or_default().to_le_bytes())
+
+}
+
+fn read_op(reader: &mut impl Read) -> io::Result<Op> {
+
+    let source = read_path(reader)?;
+    let target = read_path(reader)?;
+    let mut flags = [0; 3];
+    let mut mode = [0; 4];
+
+    reader.read_exact(&mut flags)?;
+    reader.read_exact(&mut mode)?;
+
+    let kind = match flags[1] {
+        0 => OpKind::Symlink,
+        1 => OpKind::Copy,
+        3 => OpKind::Hardlink,
+        _ => OpKind::Directory
+    };
+
+    Ok(Op { source, target, replace: flags[0] == 1, kind, mode: Some(u32::from_le_bytes(mode)).filter(|_| flags[2] == 1) })
+
+}
+
+/// Kind of the record (created or staged), followed by its length prefixed paths
+fn write_record(writer: &mut impl Write, record: &Record) -> io::Result<()> {
+    match record {
+        Record::Created(path) => {
+            writer.write_all(&[0])?;
+            write_path(writer, path)
+        },
+        Record::Staged(target, path) => {
+            writer.write_all(&[1])?;
+            write_path(writer, target)?;
+            write_path(writer, path)
+        }
+    }
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<Record> {
+
+    let mut kind = [0; 1];
+    reader.read_exact(&mut kind)?;
+
+    match kind[0] {
+        0 => Ok(Record::Created(read_path(reader)?)),
+        _ => Ok(Record::Staged(read_path(reader)?, read_path(reader)?))
+    }
+
+}
@@ -0,0 +1,197 @@
+use std::ffi::OsString;
+use std::fs::{create_dir_all, read_dir, read_link, remove_dir_all, rename, symlink_metadata, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use crate::{Hasher, remove_stale_temp, Sha256, SourceProvider, temp_path};
+use crate::hash::hex;
+use crate::merge::{copy_tree, TreeLinks};
+use crate::platform::mode;
+
+/// Store keeping fetched source trees under the digest of their content, SHA-256 unless [changed](ContentStore::with_hasher).
+///
+/// Identical trees (e.g. unchanged releases) are stored once, and every stored tree is complete
+/// and never modified, so it's safe to merge from while other processes fetch into the same store.
+/// Trees are kept in `<root>/<algorithm>/<digest>`, staged in `<root>/tmp` first.
+#[derive(Clone, Debug)]
+pub struct ContentStore {
+    root: PathBuf,
+    hasher: Arc<dyn Hasher>
+}
+
+impl ContentStore {
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into(), hasher: Arc::new(Sha256) }
+    }
+
+    /// Key stored trees by digests of given algorithm, trees stored by other algorithms are kept apart
+    pub fn with_hasher(mut self, hasher: Arc<dyn Hasher>) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Path of the tree stored under given digest, whether it exists or not
+    pub fn path(&self, digest: &str) -> PathBuf {
+        self.root.join(self.hasher.name()).join(digest)
+    }
+
+    /// Path of the tree stored under given digest, if it exists
+    pub fn get(&self, digest: &str) -> Option<PathBuf> {
+        Some(self.path(digest)).filter(|path| path.is_dir())
+    }
+
+    /// Let `fill` create the tree in an empty staging directory, then store it and return its stored path
+    pub fn insert_with(&self, fill: impl FnOnce(&Path) -> Result<()>) -> Result<PathBuf> {
+
+        let temporary = self.root.join("tmp");
+        let staging = temp_path(&temporary.join("staging"));
+
+        // Staging directories of crashed runs would stay forever otherwise
+        if temporary.is_dir() {
+            remove_stale_temp(&temporary).with_context(|| format!("Couldn't clean stale staging directories ({temporary:?})"))?;
+        }
+
+        create_dir_all(&staging).with_context(|| format!("Couldn't create staging directory ({staging:?})"))?;
+
+        let result = fill(&staging).and_then(|_| self.commit(&staging));
+
+        if staging.exists() {
+            let _ = remove_dir_all(&staging);
+        }
+
+        result
+
+    }
+
+    /// Remove the tree stored under given digest, make sure no target links into it anymore
+    pub fn remove(&self, digest: &str) -> Result<()> {
+        remove_tree(&self.path(digest))
+    }
+
+    /// Store a copy of the `source` tree and return its stored path
+    pub fn insert(&self, source: &Path) -> Result<PathBuf> {
+        self.insert_with(|staging| copy_tree(source, staging, TreeLinks::Copy).map(|_| ()).with_context(|| format!("Couldn't copy ({source:?}) into the store")))
+    }
+
+    /// Move the staged tree under its digest, unless the same tree was stored already
+    fn commit(&self, staging: &Path) -> Result<PathBuf> {
+
+        let path = self.path(&digest_tree_with(staging, self.hasher.as_ref())?);
+
+        if path.is_dir() {
+            return Ok(path);
+        }
+
+        create_dir_all(self.root.join(self.hasher.name()))?;
+
+        match rename(staging, &path) {
+            Ok(()) => Ok(path),
+            // Another process stored the same tree in the meantime
+            Err(_) if path.is_dir() => Ok(path),
+            Err(error) => Err(error).with_context(|| format!("Couldn't move staged tree into the store ({path:?})"))
+        }
+
+    }
+
+}
+
+/// Remove the stored tree together with its references
+pub(crate) fn remove_tree(tree: &Path) -> Result<()> {
+
+    if tree.is_dir() {
+        remove_dir_all(tree).with_context(|| format!("Couldn't remove stored tree ({tree:?})"))?;
+    }
+
+    let references = references(tree);
+
+    match references.is_dir() {
+        true => remove_dir_all(&references).with_context(|| format!("Couldn't remove references of stored tree ({references:?})")),
+        false => Ok(())
+    }
+
+}
+
+/// Directory next to the stored tree holding a file for each holder of the tree
+pub(crate) fn references(tree: &Path) -> PathBuf {
+    let mut name = OsString::from(tree.as_os_str());
+    name.push(".refs");
+    PathBuf::from(name)
+}
+
+/// Source fetched by another provider and kept in a [ContentStore], see [ContentStore] for details.
+pub struct StoredSource<P: SourceProvider> {
+    store: ContentStore,
+    provider: P
+}
+
+impl<P: SourceProvider> StoredSource<P> {
+    pub fn new(store: ContentStore, provider: P) -> Self {
+        Self { store, provider }
+    }
+}
+
+impl<P: SourceProvider> SourceProvider for StoredSource<P> {
+
+    fn fetch(&self) -> Result<PathBuf> {
+        self.store.insert(&self.provider.fetch()?)
+    }
+
+    /// Only the wrapped provider is invalidated, stored trees are immutable
+    fn invalidate(&self) -> Result<()> {
+        self.provider.invalidate()
+    }
+
+}
+
+/// Hex encoded SHA-256 digest of the tree content, covering entry names, types, permissions, file contents and symlink targets
+pub fn digest_tree(root: &Path) -> Result<String> {
+    digest_tree_with(root, &Sha256)
+}
+
+/// Hex encoded digest of the tree content by given algorithm, see [digest_tree]
+pub fn digest_tree_with(root: &Path, hasher: &dyn Hasher) -> Result<String> {
+
+    let mut state = hasher.start();
+    let mut stack = vec![PathBuf::new()];
+
+    while let Some(relative) = stack.pop() {
+
+        let directory = root.join(&relative);
+        let mut entries: Vec<_> = read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<_>>()?;
+
+        entries.sort();
+
+        for name in entries {
+
+            let relative = relative.join(&name);
+            let path = root.join(&relative);
+            let metadata = symlink_metadata(&path).with_context(|| format!("Couldn't read metadata ({path:?})"))?;
+
+            state.write_all(relative.as_os_str().as_encoded_bytes())?;
+            state.write_all(&[0])?;
+
+            if metadata.is_symlink() {
+                state.write_all(b"l")?;
+                state.write_all(read_link(&path)?.as_os_str().as_encoded_bytes())?;
+            } else if metadata.is_dir() {
+                state.write_all(b"d")?;
+                stack.push(relative);
+            } else {
+                state.write_all(b"f")?;
+                state.write_all(&metadata.len().to_le_bytes())?;
+                io::copy(&mut File::open(&path)?, &mut state).with_context(|| format!("Couldn't read file ({path:?})"))?;
+            }
+
+            state.write_all(&(mode(&metadata.permissions()) & 0o7777).to_le_bytes())?;
+
+        }
+
+    }
+
+    Ok(hex(&state.finish()))
+
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fs::{read_dir, read_link, rename, symlink_metadata};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use crate::merge::{relative_path, source_root};
+use crate::platform::{relink, remove_link};
+use crate::reserved::is_reserved;
+use crate::temp::temp_path;
+use crate::unmerge::{points_into, resolve_lexically};
+
+/// Symlink pointed from one source to the other by [swap_source].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Swapped {
+    /// Path of the symlink
+    pub link: PathBuf,
+    /// Path stored in the symlink before the swap
+    pub from: PathBuf,
+    /// Path stored in the symlink after the swap
+    pub to: PathBuf
+}
+
+/// Point symlinks of the `target` directory leading into the `from` source to the same entries of the `to` source,
+/// e.g. switching between blue/green deployments.
+///
+/// Every such symlink needs its counterpart in `to`, otherwise nothing is changed. New symlinks keep the form
+/// (relative or absolute) of the old ones, they're created under temporary names first and renamed over the old
+/// ones directory by directory, so no symlink is ever missing. Once all of them are swapped, they're checked to lead
+/// into `to` and should that (or any change) fail, the swapped symlinks are pointed back to `from`.
+///
+/// Entries only one of the sources has are neither added nor removed, [merge](crate::merge) or [unmerge](crate::unmerge) them afterwards.
+pub fn swap_source(target: &Path, from: &Path, to: &Path) -> Result<Vec<Swapped>> {
+
+    let from = source_root(from)?;
+    let to = source_root(to)?;
+    let target = target.canonicalize().with_context(|| "Couldn't resolve target path")?;
+
+    if !from.is_dir() || !to.is_dir() || !target.is_dir() {
+        bail!("Make sure both sources and the target path are directories");
+    }
+
+    let batches = plan(&target, &from, &to)?;
+    let mut swapped = Vec::new();
+
+    let result = batches.values().try_for_each(|batch| swap(batch, &mut swapped))
+        .and_then(|()| check(&swapped, &to));
+
+    if let Err(error) = result {
+        let reverted: Vec<Swapped> = swapped.iter().map(|swapped| Swapped { link: swapped.link.clone(), from: swapped.to.clone(), to: swapped.from.clone() }).collect();
+        return match swap(&reverted, &mut Vec::new()) {
+            Ok(()) => Err(error.context(format!("Swap failed, symlinks were pointed back to ({from:?})"))),
+            Err(revert) => Err(error.context(format!("Swap failed and pointing symlinks back failed as well: {revert:#}")))
+        };
+    }
+
+    swapped.sort_by(|a, b| a.link.cmp(&b.link));
+
+    Ok(swapped)
+
+}
+
+/// Symlinks of the target leading into `from` grouped by their directory, symlinks are never followed
+fn plan(target: &Path, from: &Path, to: &Path) -> Result<BTreeMap<PathBuf, Vec<Swapped>>> {
+
+    let mut batches: BTreeMap<PathBuf, Vec<Swapped>> = BTreeMap::new();
+    let mut missing = Vec::new();
+    let mut stack = vec![target.to_path_buf()];
+
+    while let Some(directory) = stack.pop() {
+
+        for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let link = entry.with_context(|| "Reading target directory entry has failed")?.path();
+
+            if link.file_name().is_some_and(is_reserved) {
+                continue;
+            }
+
+            let metadata = symlink_metadata(&link).with_context(|| format!("Couldn't read metadata ({link:?})"))?;
+
+            if metadata.is_dir() {
+                stack.push(link);
+                continue;
+            }
+
+            if !metadata.is_symlink() || !points_into(&link, from)? {
+                continue;
+            }
+
+            let stored = read_link(&link).with_context(|| format!("Couldn't read symlink ({link:?})"))?;
+            let destination = directory.join(&stored);
+            // Dangling symlinks can't be canonicalized, their destination is resolved the way points_into checked it
+            let resolved = destination.canonicalize().unwrap_or_else(|_| resolve_lexically(&destination));
+            let relative = resolved.strip_prefix(from).with_context(|| format!("Symlink ({link:?}) leads to ({resolved:?}) outside of the source ({from:?})"))?;
+            let counterpart = to.join(relative);
+
+            if counterpart.symlink_metadata().is_err() {
+                missing.push(counterpart);
+                continue;
+            }
+
+            let swapped = match stored.is_absolute() {
+                true => counterpart,
+                false => relative_path(&directory, &counterpart)
+            };
+
+            batches.entry(directory.clone()).or_default().push(Swapped { link, from: stored, to: swapped });
+
+        }
+
+    }
+
+    if let Some(first) = missing.iter().min() {
+        bail!("Source ({to:?}) lacks {} entries linked from the target, e.g. ({first:?})", missing.len());
+    }
+
+    Ok(batches)
+
+}
+
+/// Create new symlinks of a single directory under temporary names, then rename them over the old ones
+fn swap(batch: &[Swapped], swapped: &mut Vec<Swapped>) -> Result<()> {
+
+    let mut temporary: Vec<PathBuf> = Vec::new();
+
+    for link in batch {
+
+        let path = temp_path(&link.link);
+
+        if let Err(error) = relink(&link.link, &link.to, &path) {
+            temporary.iter().for_each(|path| { let _ = remove_link(path); });
+            return Err(error).with_context(|| format!("Failed to create symlink from ({:?}) to ({path:?})", link.to));
+        }
+
+        temporary.push(path);
+
+    }
+
+    for (index, (link, path)) in batch.iter().zip(&temporary).enumerate() {
+
+        if let Err(error) = rename(path, &link.link) {
+            temporary[index..].iter().for_each(|path| { let _ = remove_link(path); });
+            return Err(error).with_context(|| format!("Failed to replace symlink ({:?})", link.link));
+        }
+
+        swapped.push(link.clone());
+
+    }
+
+    Ok(())
+
+}
+
+/// Check that every swapped symlink holds the new path and leads into the `to` source
+fn check(swapped: &[Swapped], to: &Path) -> Result<()> {
+
+    for link in swapped {
+
+        let stored = read_link(&link.link).with_context(|| format!("Couldn't read symlink ({:?})", link.link))?;
+
+        if stored != link.to || !link.link.canonicalize().is_ok_and(|resolved| resolved.starts_with(to)) {
+            bail!("Symlink ({:?}) doesn't lead into the source ({to:?}) after the swap", link.link);
+        }
+
+    }
+
+    Ok(())
+
+}
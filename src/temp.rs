@@ -0,0 +1,117 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{read_dir, symlink_metadata};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use anyhow::{Context, Result};
+use crate::merge::remove_path;
+
+/// Prefix of every temporary entry created next to the final path
+pub const TEMP_PREFIX: &str = ".solderium-tmp-";
+/// Temporary entries of a process which can't be checked are considered abandoned after this time
+pub(crate) const STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Distinguishes temporary names generated by a single process
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Temporary path in the same directory as `path`, to be renamed over it once complete.
+///
+/// The name (`.solderium-tmp-<pid>-<random>`) can't collide with concurrent runs, and carries
+/// the process id, so entries abandoned by crashed runs can be recognized by [remove_stale_temp].
+pub fn temp_path(path: &Path) -> PathBuf {
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos());
+
+    path.with_file_name(format!("{TEMP_PREFIX}{}-{:016x}", process::id(), hasher.finish()))
+
+}
+
+/// Remove temporary entries left in the `directory` by runs which are no longer alive, returns removed paths
+pub fn remove_stale_temp(directory: &Path) -> io::Result<Vec<PathBuf>> {
+
+    let mut removed = Vec::new();
+
+    for entry in read_dir(directory)? {
+
+        let path = entry?.path();
+
+        if is_stale_temp(&path) {
+            remove_path(&path)?;
+            removed.push(path);
+        }
+
+    }
+
+    removed.sort();
+    Ok(removed)
+
+}
+
+/// Remove state left anywhere inside the `target` directory by interrupted runs, returns removed paths.
+///
+/// Staging directories, half-copied files and any other temporary entries are recognized by their
+/// [TEMP_PREFIX] name carrying the owner process id. Entries of running processes are kept, when the
+/// owner can't be checked, only entries older than a day are removed. Symlinks are never followed.
+pub fn clean_stale_state(target: &Path) -> Result<Vec<PathBuf>> {
+
+    let mut removed = Vec::new();
+    let mut stack = vec![target.to_path_buf()];
+
+    while let Some(directory) = stack.pop() {
+
+        for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let path = entry.with_context(|| "Reading target directory entry has failed")?.path();
+
+            if is_stale_temp(&path) {
+                remove_path(&path).with_context(|| format!("Couldn't remove stale entry ({path:?})"))?;
+                removed.push(path);
+                continue;
+            }
+
+            if symlink_metadata(&path).with_context(|| format!("Couldn't read metadata ({path:?})"))?.is_dir() {
+                stack.push(path);
+            }
+
+        }
+
+    }
+
+    removed.sort();
+    Ok(removed)
+
+}
+
+/// Check whether the path is a temporary entry of a process which is no longer running
+pub(crate) fn is_stale_temp(path: &Path) -> bool {
+
+    let pid = match path.file_name().and_then(|name| name.to_str()).and_then(temp_owner) {
+        Some(pid) => pid,
+        None => return false
+    };
+
+    pid != process::id() && owner_gone(pid, path)
+
+}
+
+/// Check whether the process owning the path is no longer running
+pub(crate) fn owner_gone(pid: u32, path: &Path) -> bool {
+
+    // Without procfs the owner can't be checked, only the age tells
+    match Path::new("/proc/self").exists() {
+        true => !Path::new("/proc").join(pid.to_string()).exists(),
+        false => symlink_metadata(path).and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > STALE_AGE)
+    }
+
+}
+
+/// Process id stored in the temporary name
+fn temp_owner(name: &str) -> Option<u32> {
+    name.strip_prefix(TEMP_PREFIX)?.split('-').next()?.parse().ok()
+}
@@ -0,0 +1,192 @@
+use std::fs::{create_dir, remove_dir_all, rename};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use crate::merge::{backup_path, remove_path};
+use crate::platform;
+use crate::spill::SpilledJournal;
+use crate::temp::temp_path;
+
+/// Changes made by a transactional merge so far, see [MergeOptions::transactional](crate::MergeOptions::transactional).
+///
+/// Replaced target paths aren't removed, but moved aside into a staging directory (created only once needed),
+/// so they can be moved back. Staging directories carry the usual temporary name, so they're cleaned up by
+/// [clean_stale_state](crate::clean_stale_state) should the process crash.
+///
+/// Renames can't cross filesystems (nor mounts of a single one), so the staging directory in the target root
+/// serves only paths on its filesystem, any other path gets a staging directory created right next to it.
+/// Paths which can't be moved even next to themselves (mount points) aren't staged, see [Journal::stage].
+///
+/// Records beyond the [plan memory](crate::MergeOptions::plan_memory) limit are spilled to a temporary file in the
+/// target root, so huge merges are journaled without holding all of their changes in memory.
+pub(crate) struct Journal {
+    root: PathBuf,
+    /// Staging directories created so far together with their device
+    staging: Mutex<Vec<(u64, PathBuf)>>,
+    /// Changes made by the merge, in the order they were made
+    records: Mutex<SpilledJournal>
+}
+
+/// Single change recorded by a [Journal]
+pub(crate) enum Record {
+    /// Path created by the merge
+    Created(PathBuf),
+    /// Replaced target path together with the path it was moved to
+    Staged(PathBuf, PathBuf)
+}
+
+/// Paths failing to move as if they were mount points, tests can't create those
+#[cfg(test)]
+pub(crate) static UNSTAGEABLE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn move_aside(target: &Path, path: &Path) -> io::Result<()> {
+
+    #[cfg(test)]
+    if UNSTAGEABLE.lock().unwrap().iter().any(|unstageable| target.ends_with(unstageable)) {
+        return Err(ErrorKind::CrossesDevices.into());
+    }
+
+    rename(target, path)
+
+}
+
+impl Journal {
+
+    /// Journal staging replaced paths in the `target` directory, holding at most `limit` records in memory
+    pub fn new(target: &Path, limit: Option<usize>) -> Self {
+        let records = SpilledJournal::new(temp_path(&target.join("journal")), limit.unwrap_or(usize::MAX));
+        Self { root: target.to_path_buf(), staging: Mutex::default(), records: Mutex::new(records) }
+    }
+
+    /// Move the existing target path aside instead of removing it, returns `false` when no staging directory
+    /// on its filesystem could take it, so it has to be replaced without a way back
+    pub fn stage(&self, target: &Path) -> Result<bool> {
+
+        let parent = target.parent().unwrap_or(target);
+        let device = platform::device(parent)?;
+        let mut staging = self.staging.lock().unwrap();
+        let mut records = self.records.lock().unwrap();
+
+        // Known directories on the same device go first, then a new one in the root and the last resort next to the path
+        let mut candidates: Vec<(PathBuf, Option<PathBuf>)> = staging.iter()
+            .filter(|(known, _)| *known == device)
+            .map(|(_, directory)| (directory.parent().unwrap_or(directory).to_path_buf(), Some(directory.clone())))
+            .collect();
+
+        let root_device = platform::device(&self.root)?;
+
+        for location in [self.root.as_path(), parent] {
+            if (location != self.root || root_device == device) && candidates.iter().all(|(known, _)| known != location) {
+                candidates.push((location.to_path_buf(), None));
+            }
+        }
+
+        let name = records.len().to_string();
+
+        for (location, directory) in candidates {
+
+            let directory = match directory {
+                Some(directory) => directory,
+                None => {
+                    let directory = temp_path(&location.join("transaction"));
+                    create_dir(&directory)?;
+                    staging.push((platform::device(&directory)?, directory.clone()));
+                    directory
+                }
+            };
+
+            let path = directory.join(&name);
+
+            // Recorded up front, so a spill failure doesn't leave the path moved aside without a record
+            records.push(Record::Staged(target.to_path_buf(), path.clone()))?;
+
+            match move_aside(target, &path) {
+                Ok(()) => return Ok(true),
+                Err(error) => {
+                    records.pop();
+                    match error.kind() {
+                        ErrorKind::CrossesDevices => continue,
+                        _ => return Err(error.into())
+                    }
+                }
+            }
+
+        }
+
+        Ok(false)
+
+    }
+
+    /// Staging directories used so far, more than one when replaced paths were spread across filesystems
+    pub fn staging(&self) -> Vec<PathBuf> {
+        self.staging.lock().unwrap().iter().map(|(_, directory)| directory.clone()).collect()
+    }
+
+    pub fn created(&self, target: &Path) -> Result<()> {
+        self.records.lock().unwrap().push(Record::Created(target.to_path_buf()))
+    }
+
+    /// Keep the changes when the merge succeeded, restore the target to its previous state otherwise
+    pub fn finish(&self, result: Result<()>, backup: Option<&str>) -> Result<()> {
+        match result {
+            Ok(()) => self.commit(backup),
+            Err(error) => match self.rollback() {
+                Ok(()) => Err(error.context("Merge failed, all of its changes were rolled back")),
+                Err(rollback) => Err(error.context(format!("Merge failed and rolling back its changes failed as well: {rollback:#}")))
+            }
+        }
+    }
+
+    /// Drop the staged paths, or turn them into backups when asked to
+    fn commit(&self, backup: Option<&str>) -> Result<()> {
+
+        self.records.lock().unwrap().drain(false, |record| {
+            if let (Record::Staged(target, path), Some(suffix)) = (record, backup) {
+                let backup = backup_path(&target, suffix);
+                if backup.symlink_metadata().is_ok() {
+                    remove_path(&backup).with_context(|| format!("Couldn't remove old backup ({backup:?})"))?;
+                }
+                rename(&path, &backup).with_context(|| format!("Error while backing up ({target:?})"))?;
+            }
+            Ok(())
+        })?;
+
+        self.remove_staging()
+
+    }
+
+    /// Remove created paths and move the staged ones back, newest first
+    fn rollback(&self) -> Result<()> {
+
+        self.records.lock().unwrap().drain(true, |record| match record {
+            Record::Created(path) => {
+                if path.symlink_metadata().is_ok() {
+                    remove_path(&path).with_context(|| format!("Couldn't remove ({path:?})"))?;
+                }
+                Ok(())
+            },
+            Record::Staged(target, path) => {
+                // Failed change may have left something in the way already
+                if target.symlink_metadata().is_ok() {
+                    remove_path(&target).with_context(|| format!("Couldn't remove ({target:?})"))?;
+                }
+                rename(&path, &target).with_context(|| format!("Couldn't restore ({target:?})"))
+            }
+        })?;
+
+        self.remove_staging()
+
+    }
+
+    fn remove_staging(&self) -> Result<()> {
+
+        for (_, directory) in self.staging.lock().unwrap().iter().filter(|(_, directory)| directory.exists()) {
+            remove_dir_all(directory).with_context(|| format!("Couldn't remove staging directory ({directory:?})"))?;
+        }
+
+        Ok(())
+
+    }
+
+}
@@ -0,0 +1,254 @@
+use std::fs::{copy, read_dir, read_link, remove_dir, rename, symlink_metadata};
+use std::path::{Component, Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use crate::merge::{copy_tree, remove_path, source_root, TreeLinks};
+use crate::operation::{Operation, OperationKind};
+use crate::platform::remove_link;
+use crate::reserved::is_reserved;
+use crate::temp::temp_path;
+
+/// Options controlling a single unmerge run, see [unmerge].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnmergeOptions {
+    /// Replace every managed symlink with a real copy of its destination instead of removing it,
+    /// so the target keeps working once the source is gone (e.g. when decommissioning it)
+    pub materialize: bool,
+    /// Remove directories left empty by the removed symlinks, up to the target directory itself
+    pub prune_empty: bool
+}
+
+/// Outcome of a single unmerge run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnmergeReport {
+    /// Managed symlinks which were removed
+    pub removed: Vec<PathBuf>,
+    /// Managed symlinks which were replaced by a copy of their destination
+    pub materialized: Vec<PathBuf>,
+    /// Directories left empty by the removed symlinks which were removed as well, see [UnmergeOptions::prune_empty]
+    pub pruned: Vec<PathBuf>,
+    /// Managed symlinks which couldn't be handled together with the error, see [ErrorPolicy::Skip](crate::ErrorPolicy::Skip)
+    pub failed: Vec<(PathBuf, String)>
+}
+
+/// Dangling symlinks removed by [prune_broken_symlinks].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PruneScope {
+    /// Every dangling symlink in the target
+    #[default]
+    All,
+    /// Only dangling symlinks whose stored path leads into the source directory, which may be gone as well
+    Source(PathBuf)
+}
+
+/// Remove symlinks pointing into the `source` directory from the `target` directory.
+///
+/// Only symlinks are touched, the rest of the target (including directories created by the merge)
+/// is left as it is. Symlinks are never followed while walking the target.
+pub fn unmerge(source: &Path, target: &Path, options: &UnmergeOptions) -> Result<UnmergeReport> {
+    run(source, target, options, &Operation::default())
+}
+
+pub(crate) fn run(source: &Path, target: &Path, options: &UnmergeOptions, operation: &Operation) -> Result<UnmergeReport> {
+
+    let source = source_root(source)?;
+    let target = target.canonicalize().with_context(|| "Couldn't resolve target path")?;
+
+    if !target.is_dir() {
+        bail!("Make sure the target path is a directory");
+    }
+
+    let mut report = UnmergeReport::default();
+    let mut stack = vec![target.clone()];
+
+    while let Some(directory) = stack.pop() {
+
+        for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let path = entry.with_context(|| "Reading target directory entry has failed")?.path();
+
+            if path.file_name().is_some_and(is_reserved) {
+                continue;
+            }
+
+            let metadata = symlink_metadata(&path).with_context(|| format!("Couldn't read metadata ({path:?})"))?;
+
+            if metadata.is_dir() {
+                stack.push(path);
+                continue;
+            }
+
+            if !metadata.is_symlink() || !points_into(&path, &source)? {
+                continue;
+            }
+
+            operation.check(OperationKind::Unmerge)?;
+
+            let result = match options.materialize && path.exists() {
+                true => materialize(&path).with_context(|| format!("Couldn't replace symlink ({path:?}) with a copy")).map(|()| &mut report.materialized),
+                false => remove_link(&path).with_context(|| format!("Couldn't remove symlink ({path:?})")).map(|()| &mut report.removed)
+            };
+
+            match result {
+                Ok(handled) => handled.push(path),
+                Err(error) if operation.skips(&error) => report.failed.push((path, format!("{error:#}"))),
+                Err(error) => return Err(error)
+            }
+
+            operation.report(OperationKind::Unmerge, report.removed.len() + report.materialized.len() + report.failed.len(), None);
+
+        }
+
+    }
+
+    if options.prune_empty {
+        report.pruned = prune(&target, &report.removed);
+    }
+
+    report.removed.sort();
+    report.materialized.sort();
+    report.failed.sort();
+
+    Ok(report)
+
+}
+
+/// Remove dangling symlinks from the `target` directory, e.g. after entries were deleted from the source.
+///
+/// Symlinks are never followed while walking the target, symlinks caught in a loop count as dangling too.
+/// Returns removed symlinks.
+pub fn prune_broken_symlinks(target: &Path, scope: &PruneScope) -> Result<Vec<PathBuf>> {
+
+    let target = target.canonicalize().with_context(|| "Couldn't resolve target path")?;
+
+    if !target.is_dir() {
+        bail!("Make sure the target path is a directory");
+    }
+
+    // Source may be gone together with the symlinks leading into it
+    let source = match scope {
+        PruneScope::All => None,
+        PruneScope::Source(source) => Some(source.canonicalize().or_else(|_| std::path::absolute(source)).with_context(|| "Couldn't resolve source path")?)
+    };
+
+    let mut pruned = Vec::new();
+    let mut stack = vec![target];
+
+    while let Some(directory) = stack.pop() {
+
+        for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let path = entry.with_context(|| "Reading target directory entry has failed")?.path();
+
+            if path.file_name().is_some_and(is_reserved) {
+                continue;
+            }
+
+            let metadata = symlink_metadata(&path).with_context(|| format!("Couldn't read metadata ({path:?})"))?;
+
+            if metadata.is_dir() {
+                stack.push(path);
+                continue;
+            }
+
+            if !metadata.is_symlink() || path.exists() {
+                continue;
+            }
+
+            if let Some(source) = &source {
+                let stored = read_link(&path).with_context(|| format!("Couldn't read symlink ({path:?})"))?;
+                if !leads_into(&directory, &stored, source) {
+                    continue;
+                }
+            }
+
+            remove_link(&path).with_context(|| format!("Couldn't remove symlink ({path:?})"))?;
+            pruned.push(path);
+
+        }
+
+    }
+
+    pruned.sort();
+
+    Ok(pruned)
+
+}
+
+/// Remove directories holding the removed symlinks and their parents, as long as they're empty, returns removed directories
+fn prune(target: &Path, removed: &[PathBuf]) -> Vec<PathBuf> {
+
+    let mut pruned = Vec::new();
+    let mut parents: Vec<&Path> = removed.iter().filter_map(|link| link.parent()).collect();
+
+    // Deepest directories first, so their parents are empty by the time they're reached
+    parents.sort_by_key(|parent| std::cmp::Reverse(parent.components().count()));
+    parents.dedup();
+
+    for parent in parents {
+        for directory in parent.ancestors().take_while(|directory| *directory != target && directory.starts_with(target)) {
+            match remove_dir(directory) {
+                Ok(()) => pruned.push(directory.to_path_buf()),
+                Err(_) => break
+            }
+        }
+    }
+
+    pruned.sort();
+    pruned
+
+}
+
+/// Check whether the symlink points into the source directory, broken symlinks are checked by their stored path
+pub(crate) fn points_into(link: &Path, source: &Path) -> Result<bool> {
+
+    let parent = link.parent().unwrap_or(link);
+    let stored = read_link(link)?;
+
+    Ok(match parent.join(&stored).canonicalize() {
+        Ok(resolved) => resolved.starts_with(source),
+        Err(_) => leads_into(parent, &stored, source)
+    })
+
+}
+
+/// Whether the unresolvable destination lexically leads inside the `root`
+pub(crate) fn leads_into(parent: &Path, destination: &Path, root: &Path) -> bool {
+    resolve_lexically(&parent.join(destination)).starts_with(root)
+}
+
+/// Drop `.` and resolve `..` components without touching the filesystem, for paths which can't be canonicalized
+pub(crate) fn resolve_lexically(path: &Path) -> PathBuf {
+
+    let mut resolved = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::ParentDir => { resolved.pop(); },
+            Component::CurDir => {},
+            component => resolved.push(component)
+        }
+    }
+
+    resolved
+
+}
+
+/// Copy the symlink destination next to it and move the copy over the symlink
+fn materialize(link: &Path) -> Result<()> {
+
+    let temporary = temp_path(link);
+
+    // Directory can't be renamed over a symlink, so it's removed first
+    let result = match link.is_dir() {
+        true => copy_tree(link, &temporary, TreeLinks::Copy).and_then(|_| remove_link(link)),
+        false => copy(link, &temporary).map(|_| ())
+    };
+
+    if let Err(error) = result.and_then(|()| rename(&temporary, link)) {
+        let _ = remove_path(&temporary);
+        return Err(error.into());
+    }
+
+    Ok(())
+
+}
@@ -0,0 +1,363 @@
+use std::collections::BTreeSet;
+use std::fs::{read, read_dir, read_link, rename, symlink_metadata, write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context, Result};
+use crate::{Change, ChangeKind, Concurrency, merge, MergeOptions, MergeReport, Overwrite, Reason, Strategy};
+use crate::explain::Trace;
+use crate::merge::{decide, source_root, Walk};
+use crate::operation::{Operation, OperationKind};
+use crate::platform::{os_str, remove_link};
+use crate::reserved::is_reserved;
+use crate::temp::temp_path;
+use crate::unmerge::points_into;
+
+/// Changes merging `source` into `target` would make, i.e. how far the target drifted from the source.
+///
+/// Nothing is changed. Directories are scanned on up to [Concurrency::verification] workers, independently
+/// of the limits used by the merge itself, the changes are ordered by target path regardless.
+pub fn verify(source: &Path, target: &Path, options: &MergeOptions) -> Result<Vec<Change>> {
+    run(source, target, options, &Operation::default())
+}
+
+pub(crate) fn run(source: &Path, target: &Path, options: &MergeOptions, operation: &Operation) -> Result<Vec<Change>> {
+
+    let concurrency = Concurrency { traversal: options.concurrency.verification, ..options.concurrency };
+    let options = MergeOptions { concurrency, ..options.clone() };
+
+    let walk = Walk::new(source, target, &options)?.operation(operation.clone(), OperationKind::Verify);
+    Ok(walk.plan()?.iter().map(|op| op.change()).collect())
+
+}
+
+/// Differences between the symlinks a merge would make and the target, see [verify_symlinks].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymlinkDiff {
+    /// Expected symlinks missing in the target
+    pub missing: Vec<PathBuf>,
+    /// Symlinks at expected paths leading somewhere else, together with the path they store
+    pub wrong_target: Vec<(PathBuf, PathBuf)>,
+    /// Expected symlinks whose place is taken by a real file or directory
+    pub replaced: Vec<PathBuf>,
+    /// Symlinks leading into the source from paths without a source entry, e.g. left after it was removed
+    pub extra: Vec<PathBuf>
+}
+
+impl SymlinkDiff {
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.wrong_target.is_empty() && self.replaced.is_empty() && self.extra.is_empty()
+    }
+
+    /// Remove the extra symlinks and merge the source again, which fixes the rest as far as the overwrite policy of
+    /// the `options` allows, e.g. [Overwrite::None](crate::Overwrite::None) only adds the missing symlinks
+    pub fn repair(&self, source: &Path, target: &Path, options: &MergeOptions) -> Result<MergeReport> {
+
+        for link in &self.extra {
+            remove_link(link).with_context(|| format!("Couldn't remove symlink ({link:?})"))?;
+        }
+
+        merge(source, target, options)
+
+    }
+
+    /// Drift which appeared since the `previous` run and drift repaired since, so monitoring can alert on
+    /// changes only. A path moving to another kind of drift counts as appeared, not as repaired.
+    pub fn diff(&self, previous: &SymlinkDiff) -> DriftDelta {
+
+        let appeared = SymlinkDiff {
+            missing: appeared(&self.missing, &previous.missing),
+            wrong_target: appeared(&self.wrong_target, &previous.wrong_target),
+            replaced: appeared(&self.replaced, &previous.replaced),
+            extra: appeared(&self.extra, &previous.extra)
+        };
+
+        let current: BTreeSet<&Path> = self.paths().collect();
+        let repaired: BTreeSet<&Path> = previous.paths().filter(|path| !current.contains(path)).collect();
+
+        DriftDelta { appeared, repaired: repaired.into_iter().map(Path::to_path_buf).collect() }
+
+    }
+
+    /// Drifted paths of all kinds
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.missing.iter().chain(self.wrong_target.iter().map(|(path, _)| path)).chain(&self.replaced).chain(&self.extra).map(PathBuf::as_path)
+    }
+
+}
+
+/// Change of the drift between two [verify_symlinks] runs, see [SymlinkDiff::diff].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DriftDelta {
+    /// Drift not found by the previous run, e.g. newly broken symlinks (missing or leading somewhere else)
+    /// and newly shadowed ones (replaced by real files)
+    pub appeared: SymlinkDiff,
+    /// Paths drifted in the previous run which are fine now, ordered
+    pub repaired: Vec<PathBuf>
+}
+
+impl DriftDelta {
+
+    pub fn is_empty(&self) -> bool {
+        self.appeared.is_empty() && self.repaired.is_empty()
+    }
+
+}
+
+/// Entries of the current run missing in the previous one, in their order
+fn appeared<T: Clone + Ord>(current: &[T], previous: &[T]) -> Vec<T> {
+    let previous: BTreeSet<&T> = previous.iter().collect();
+    current.iter().filter(|entry| !previous.contains(entry)).cloned().collect()
+}
+
+/// Compare symlinks in the `target` with those a merge of the `source` using the `options` would make, nothing is changed.
+///
+/// Unlike [verify], existing target paths the overwrite policy won't replace are reported too. Paths protected by keep
+/// markers or [protect](MergeOptions::protect) patterns are considered intentional and left out.
+pub fn verify_symlinks(source: &Path, target: &Path, options: &MergeOptions) -> Result<SymlinkDiff> {
+
+    let options = MergeOptions { record_skipped: true, ..options.clone() };
+    let walk = Walk::new(source, target, &options)?.operation(Operation::default(), OperationKind::Verify);
+    let mut diff = SymlinkDiff::default();
+
+    let existing = |path: PathBuf, diff: &mut SymlinkDiff| match read_link(&path) {
+        Ok(stored) => diff.wrong_target.push((path, stored)),
+        Err(_) => diff.replaced.push(path)
+    };
+
+    for change in walk.plan()?.iter().map(|op| op.change()).filter(|change| change.kind == ChangeKind::Symlink) {
+        match change.replace {
+            true => existing(change.target, &mut diff),
+            false => diff.missing.push(change.target)
+        }
+    }
+
+    for skipped in walk.take_skipped() {
+
+        // Keep markers are only looked for by policies which would replace the path otherwise
+        let mut trace = Trace::On(skipped.reasons);
+        decide(&skipped.source, &skipped.target, Overwrite::All, &mut trace);
+
+        let reasons = trace.into_reasons();
+        let kept = reasons.iter().any(|reason| matches!(reason, Reason::Reserved | Reason::KeepMarker(_) | Reason::Protected(_) | Reason::AlreadyMerged(_)));
+
+        if !kept && reasons.iter().any(|reason| matches!(reason, Reason::TargetExists { .. })) {
+            existing(skipped.target, &mut diff);
+        }
+
+    }
+
+    let mut stack = vec![walk.target.clone()];
+
+    while let Some(directory) = stack.pop() {
+
+        for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+            let path = entry.with_context(|| "Reading target directory entry has failed")?.path();
+
+            if path.file_name().is_some_and(is_reserved) {
+                continue;
+            }
+
+            let metadata = symlink_metadata(&path).with_context(|| format!("Couldn't read metadata ({path:?})"))?;
+
+            if metadata.is_dir() {
+                stack.push(path);
+            } else if metadata.is_symlink() && points_into(&path, &walk.source)? && walk.source.join(path.strip_prefix(&walk.target)?).symlink_metadata().is_err() {
+                diff.extra.push(path);
+            }
+
+        }
+
+    }
+
+    diff.missing.sort();
+    diff.wrong_target.sort();
+    diff.replaced.sort();
+    diff.extra.sort();
+
+    Ok(diff)
+
+}
+
+/// How much of the source a single [verify_sample] run checks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleBudget {
+    /// Share of all source entries, between 0 and 1
+    Fraction(f64),
+    /// Entries are checked in batches until the time runs out, at least one batch is always checked
+    Time(Duration)
+}
+
+/// Outcome of a single [verify_sample] run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleReport {
+    /// Changes found in the checked part of the target, see [verify]
+    pub changes: Vec<Change>,
+    /// Number of source entries checked by this run
+    pub checked: usize,
+    /// Number of all source entries
+    pub total: usize,
+    /// Share of the source entries checked since the current pass over the whole source started
+    pub coverage: f64,
+    /// Number of finished passes over the whole source, across all runs sharing the state file
+    pub passes: u64
+}
+
+impl SampleReport {
+
+    /// Estimated share of the drifted source entries, extrapolated from the checked ones
+    pub fn estimated_drift(&self) -> f64 {
+        let drifted = self.changes.iter().filter(|change| change.kind != ChangeKind::Directory).count();
+        match self.checked {
+            0 => 0.0,
+            checked => drifted as f64 / checked as f64
+        }
+    }
+
+}
+
+/// Progress of the sampling kept between runs
+#[derive(Debug, Default)]
+struct SampleState {
+    passes: u64,
+    /// Entries checked in the current pass
+    checked: usize,
+    /// Last checked entry, relative to the source
+    cursor: Option<PathBuf>
+}
+
+const STATE_HEADER: &str = "solderium-verify-sample 1";
+const SAMPLE_BATCH: usize = 64;
+
+/// Check a part of the target for drift, continuing where the previous run sharing the `state` file stopped.
+///
+/// Source entries are checked in the order of their paths, wrapping around once all of them were checked,
+/// so repeated runs cover the whole source in turns and bound the time every run takes. Drift is only found
+/// once its turn comes, [SampleReport::estimated_drift] tells how much of it is likely out there. Entries
+/// are checked as by the [Deep](Strategy::Deep) strategy, see [merge_listed](crate::merge_listed).
+pub fn verify_sample(source: &Path, target: &Path, options: &MergeOptions, budget: SampleBudget, state: &Path) -> Result<SampleReport> {
+
+    let started = Instant::now();
+    let options = MergeOptions { strategy: Strategy::Deep, ..options.clone() };
+    let walk = Walk::new(source, target, &options)?;
+    let source = source_root(source)?;
+
+    let mut entries = Vec::new();
+    collect_entries(&source, Path::new(""), &mut entries)?;
+    entries.sort();
+
+    let mut progress = match state.exists() {
+        true => read_state(state)?,
+        false => SampleState::default()
+    };
+
+    let total = entries.len();
+    let mut next = progress.cursor.as_ref().map_or(0, |cursor| entries.partition_point(|entry| entry <= cursor));
+    let mut report = SampleReport { total, ..Default::default() };
+
+    let limit = match budget {
+        SampleBudget::Fraction(fraction) => (fraction.clamp(0.0, 1.0) * total as f64).ceil() as usize,
+        SampleBudget::Time(_) => total
+    };
+
+    while report.checked < limit {
+
+        if let SampleBudget::Time(time) = budget {
+            if report.checked > 0 && started.elapsed() >= time {
+                break;
+            }
+        }
+
+        if next >= total {
+            next = 0;
+        }
+
+        let batch = SAMPLE_BATCH.min(limit - report.checked).min(total - next);
+        let paths: BTreeSet<PathBuf> = entries[next..next + batch].iter().cloned().collect();
+
+        report.changes.extend(walk.plan_paths(paths)?.iter().map(|op| op.change()));
+        report.checked += batch;
+        progress.checked += batch;
+        next += batch;
+
+        if next >= total {
+            progress.passes += 1;
+            progress.checked = 0;
+        }
+
+    }
+
+    progress.cursor = entries.get(next.wrapping_sub(1)).filter(|_| next < total).cloned();
+    write_state(state, &progress)?;
+
+    report.changes.sort_by(|a, b| a.target.cmp(&b.target));
+    report.changes.dedup();
+    report.coverage = match total {
+        0 => 1.0,
+        total => (progress.checked as f64 / total as f64).min(1.0)
+    };
+    report.passes = progress.passes;
+
+    Ok(report)
+
+}
+
+/// Relative paths of all entries below the directory, symlinks aren't followed
+fn collect_entries(root: &Path, relative: &Path, entries: &mut Vec<PathBuf>) -> Result<()> {
+
+    let directory = root.join(relative);
+
+    for entry in read_dir(&directory).with_context(|| format!("Directory listing ({directory:?}) failed"))? {
+
+        let entry = entry.with_context(|| "Reading source directory entry has failed")?;
+
+        if is_reserved(&entry.file_name()) {
+            continue;
+        }
+
+        let path = relative.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            collect_entries(root, &path, entries)?;
+        }
+
+        entries.push(path);
+
+    }
+
+    Ok(())
+
+}
+
+/// Header line followed by the number of passes, entries checked in the current pass and the cursor
+fn read_state(path: &Path) -> Result<SampleState> {
+
+    let content = read(path).with_context(|| format!("Couldn't read sampling state ({path:?})"))?;
+    let mut lines = content.splitn(4, |byte| *byte == b'\n');
+    let invalid = || anyhow!("Sampling state ({path:?}) is invalid");
+
+    if lines.next() != Some(STATE_HEADER.as_bytes()) {
+        return Err(invalid());
+    }
+
+    let mut number = || std::str::from_utf8(lines.next().ok_or_else(invalid)?).ok().and_then(|line| line.parse().ok()).ok_or_else(invalid);
+    let passes = number()?;
+    let checked = number()? as usize;
+
+    // Cursor is the rest of the file, paths may contain line breaks too
+    let cursor = lines.next().filter(|cursor| !cursor.is_empty()).map(|cursor| os_str(cursor).map(PathBuf::from).ok_or_else(invalid)).transpose()?;
+
+    Ok(SampleState { passes, checked, cursor })
+
+}
+
+fn write_state(path: &Path, state: &SampleState) -> Result<()> {
+
+    let mut content = format!("{STATE_HEADER}\n{}\n{}\n", state.passes, state.checked).into_bytes();
+    content.extend_from_slice(state.cursor.as_deref().map_or(&[][..], |cursor| cursor.as_os_str().as_encoded_bytes()));
+
+    let temporary = temp_path(path);
+    write(&temporary, content).and_then(|()| rename(&temporary, path)).with_context(|| format!("Couldn't write sampling state ({path:?})"))
+
+}